@@ -0,0 +1,300 @@
+use bytes::Bytes;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fmt::Write;
+use std::io;
+use tokio::net::TcpStream;
+
+use crate::connection::Connection;
+use crate::db::Db;
+use crate::frame::Frame;
+
+/// Keys inspected between yields, so a scan never holds up other connections for long.
+const BATCH_SIZE: usize = 100;
+
+pub const DEFAULT_COUNT: usize = 5;
+
+const TYPES: [&str; 5] = ["string", "list", "set", "zset", "hash"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Report {
+    BigKeys,
+    HotKeys,
+}
+
+impl Report {
+    fn name(self) -> &'static str {
+        match self {
+            Report::BigKeys => "BIGKEYS",
+            Report::HotKeys => "HOTKEYS",
+        }
+    }
+}
+
+struct Sample {
+    key: Bytes,
+    type_name: &'static str,
+    size: usize,
+    elements: usize,
+    frequency: u8,
+}
+
+/// The `count` greatest items pushed so far, kept in a min-heap so that each
+/// key costs O(log count) and a report never holds more than `count` per list.
+struct Top<T: Ord> {
+    count: usize,
+    heap: BinaryHeap<Reverse<T>>,
+}
+
+impl<T: Ord> Top<T> {
+    fn new(count: usize) -> Top<T> {
+        Top { count, heap: BinaryHeap::with_capacity(count + 1) }
+    }
+
+    fn push(&mut self, item: T) {
+        if self.heap.len() < self.count {
+            self.heap.push(Reverse(item));
+        } else if self.heap.peek().is_some_and(|Reverse(least)| item > *least) {
+            self.heap.pop();
+            self.heap.push(Reverse(item));
+        }
+    }
+
+    /// The items kept, greatest first.
+    fn into_sorted(self) -> Vec<T> {
+        self.heap.into_sorted_vec().into_iter().map(|Reverse(item)| item).collect()
+    }
+}
+
+/// Keys of one type seen so far. The biggest are ranked by size, then by
+/// name so that ties are reported in a stable order, and carry their number
+/// of elements.
+struct TypeTally {
+    keys: usize,
+    bytes: usize,
+    biggest: Top<(usize, Reverse<Bytes>, usize)>,
+}
+
+/// What a report needs from the keys seen so far.
+struct Tally {
+    scanned: usize,
+    types: Vec<TypeTally>,
+    /// by frequency counter, then by name
+    hottest: Top<(u8, Reverse<Bytes>, &'static str)>,
+}
+
+impl Tally {
+    fn new(count: usize) -> Tally {
+        Tally {
+            scanned: 0,
+            types: TYPES.iter().map(|_| TypeTally { keys: 0, bytes: 0, biggest: Top::new(count) }).collect(),
+            hottest: Top::new(count),
+        }
+    }
+
+    fn add(&mut self, report: Report, sample: Sample) {
+        self.scanned += 1;
+        match report {
+            Report::BigKeys => {
+                let Some(index) = TYPES.iter().position(|name| *name == sample.type_name) else {
+                    return;
+                };
+                let tally = &mut self.types[index];
+                tally.keys += 1;
+                tally.bytes += sample.size;
+                tally.biggest.push((sample.size, Reverse(sample.key), sample.elements));
+            }
+            Report::HotKeys => self.hottest.push((sample.frequency, Reverse(sample.key), sample.type_name)),
+        }
+    }
+}
+
+/// Walks every key of `db` in small batches and builds the requested report.
+pub async fn analyze(db: &Db, report: Report, count: usize) -> String {
+    let mut tally = Tally::new(count);
+    let mut cursor = 0;
+
+    loop {
+        let (next, keys) = db.scan(cursor, None, BATCH_SIZE, None);
+        for key in keys {
+            let sample = db.with_entry(&key, |entry| Sample {
+                type_name: entry.value.type_name(),
+                size: entry.size,
+                elements: entry.value.element_count(),
+                frequency: entry.frequency(),
+                key: key.clone(),
+            });
+            if let Some(sample) = sample {
+                tally.add(report, sample);
+            }
+        }
+
+        cursor = next;
+        if cursor == 0 {
+            break;
+        }
+        tokio::task::yield_now().await;
+    }
+
+    match report {
+        Report::BigKeys => bigkeys(tally),
+        Report::HotKeys => hotkeys(tally),
+    }
+}
+
+fn bigkeys(tally: Tally) -> String {
+    let total = tally.scanned;
+    let mut out = format!("scanned {} keys\n", total);
+
+    for (type_name, of_type) in TYPES.into_iter().zip(tally.types) {
+        if of_type.keys == 0 {
+            continue;
+        }
+
+        let unit = if type_name == "string" { "bytes of data" } else { "items" };
+        let _ = writeln!(
+            out,
+            "\n{}: {} keys with {} bytes ({:.2}% of keys, avg size {:.2})",
+            type_name,
+            of_type.keys,
+            of_type.bytes,
+            of_type.keys as f64 * 100.0 / total as f64,
+            of_type.bytes as f64 / of_type.keys as f64,
+        );
+
+        for (size, Reverse(key), elements) in of_type.biggest.into_sorted() {
+            let _ = writeln!(
+                out,
+                "  {:>10} bytes  {:?} ({} {})",
+                size,
+                String::from_utf8_lossy(&key),
+                elements,
+                unit
+            );
+        }
+    }
+
+    out
+}
+
+fn hotkeys(tally: Tally) -> String {
+    let mut out = format!("scanned {} keys\n", tally.scanned);
+    if tally.scanned == 0 {
+        return out;
+    }
+
+    let _ = writeln!(out, "\nhottest keys by access frequency counter:");
+    for (frequency, Reverse(key), type_name) in tally.hottest.into_sorted() {
+        let _ = writeln!(
+            out,
+            "  counter {:>3}  {:?} ({})",
+            frequency,
+            String::from_utf8_lossy(&key),
+            type_name
+        );
+    }
+
+    out
+}
+
+/// Client mode: asks the server at `addr` for a report on database `db`.
+pub async fn request(addr: &str, db: u32, report: Report, count: usize) -> io::Result<String> {
+    let stream = TcpStream::connect(addr).await?;
+    let mut connection = Connection::new(stream);
+
+    let requests = [
+        vec!["SELECT".to_string(), db.to_string()],
+        vec!["ANALYZE".to_string(), report.name().to_string(), "COUNT".to_string(), count.to_string()],
+    ];
+
+    let mut reply = None;
+    for args in requests {
+        let frame = Frame::Array(args.into_iter().map(|arg| Frame::Bulk(arg.into())).collect());
+        connection.write_frame(&frame).await?;
+        reply = connection.read_frame().await?;
+        if let Some(Frame::Error(msg)) = &reply {
+            return Err(io::Error::other(msg.clone()));
+        }
+    }
+
+    match reply {
+        Some(Frame::Bulk(text)) => Ok(String::from_utf8_lossy(&text).into_owned()),
+        Some(other) => Err(io::Error::other(format!("unexpected reply: {:?}", other))),
+        None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "server closed the connection")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, Settings};
+    use crate::db::Databases;
+    use crate::value::Value;
+    use clap::Parser;
+    use std::collections::VecDeque;
+    use std::sync::Arc;
+
+    fn databases() -> Databases {
+        let config = Config::parse_from(["rusty-redis", "--databases", "1"]);
+        Databases::new(1, Arc::new(Settings::new(&config)))
+    }
+
+    #[test]
+    fn top_keeps_only_the_greatest() {
+        let mut top = Top::new(3);
+        for item in [5, 1, 9, 3, 7, 9, 2] {
+            top.push(item);
+        }
+        assert_eq!(top.heap.len(), 3);
+        assert_eq!(top.into_sorted(), [9, 9, 7]);
+
+        let mut none = Top::new(0);
+        none.push(1);
+        assert!(none.into_sorted().is_empty());
+    }
+
+    #[tokio::test]
+    async fn bigkeys_lists_the_biggest_of_each_type() {
+        let databases = databases();
+        let db = databases.db(0);
+        for (key, len) in [("small", 10), ("medium", 100), ("large", 1000)] {
+            db.set(key.into(), Bytes::from(vec![b'x'; len]), None);
+        }
+        let items: VecDeque<Bytes> = ["a", "b", "c"].into_iter().map(Bytes::from).collect();
+        db.insert("list".into(), Value::list(items), None);
+
+        let report = analyze(&db, Report::BigKeys, 2).await;
+        assert!(report.starts_with("scanned 4 keys\n"));
+        assert!(report.contains("\nstring: 3 keys with "));
+        assert!(report.contains("(75.00% of keys, "));
+        let large = report.find("\"large\" (1000 bytes of data)").unwrap();
+        let medium = report.find("\"medium\" (100 bytes of data)").unwrap();
+        assert!(large < medium);
+        assert!(!report.contains("\"small\""));
+        assert!(report.contains("\nlist: 1 keys with "));
+        assert!(report.contains("\"list\" (3 items)"));
+        assert!(!report.contains("\nset: "));
+    }
+
+    #[tokio::test]
+    async fn hotkeys_lists_the_most_used_keys() {
+        let databases = databases();
+        let db = databases.db(0);
+        for (key, frequency) in [("cold", 1), ("warm", 10), ("hot", 100), ("also-hot", 100)] {
+            db.set(key.into(), "value".into(), None);
+            db.with_entry(key.as_bytes(), |entry| entry.set_frequency(frequency));
+        }
+
+        let report = analyze(&db, Report::HotKeys, 3).await;
+        assert!(report.starts_with("scanned 4 keys\n"));
+        let lines: Vec<&str> = report.lines().filter(|line| line.starts_with("  counter")).collect();
+        assert_eq!(
+            lines,
+            ["  counter 100  \"also-hot\" (string)", "  counter 100  \"hot\" (string)", "  counter  10  \"warm\" (string)"]
+        );
+
+        assert_eq!(analyze(&databases.db(0), Report::HotKeys, 0).await.lines().count(), 3);
+        databases.flush(0, false);
+        assert_eq!(analyze(&databases.db(0), Report::HotKeys, 3).await, "scanned 0 keys\n");
+    }
+}
@@ -0,0 +1,1291 @@
+//! Append-only file persistence.
+//!
+//! Every write command is appended to the log in RESP format, preceded by a
+//! SELECT whenever it targets a different database than the previous one.
+//! Commands with relative expirations are rewritten with absolute deadlines
+//! so that replaying the log later does not extend them.
+//!
+//! The log is split over several files in `appenddirname`, listed by a
+//! manifest in the format of redis 7: one base file recreating the dataset as
+//! of the last rewrite, followed by incremental files holding the commands
+//! logged since. A rewrite switches appends to a new incremental file, writes
+//! a new base from the dataset in the background, then swaps it in and
+//! deletes the files it replaces.
+//!
+//! With aof-timestamp-enabled, the commands of each second are preceded by a
+//! `#TS:<unix seconds>` annotation, as in redis, and every new incremental file
+//! starts with one. Rewrites can then keep the files they replace in a history
+//! directory, so point-in-time recovery can start from the latest base written
+//! before the target time and replay the log up to the first later annotation.
+
+use bytes::{Bytes, BytesMut};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tracing::{error, info, warn};
+
+use crate::cmd::{self, Command};
+use crate::config::Settings;
+use crate::connection;
+use crate::db::{self, Databases, Snapshot};
+use crate::frame::Frame;
+use crate::codec::{self, BlockReader, Codecs, Compression, Opener, Sealer};
+use crate::persistence::{self, SnapshotWriter};
+use crate::rdb::{self, RdbReader};
+use crate::server::{self, Session};
+use crate::value::Value;
+
+const READ_CHUNK: usize = 64 * 1024;
+
+/// Subdirectory of `appenddirname` holding the files replaced by rewrites.
+const HISTORY_DIR: &str = "history";
+
+/// When appended commands are flushed from the OS cache to disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// after every write command, before replying
+    Always,
+    /// at most once per second from a background task
+    EverySec,
+    /// whenever the OS decides
+    No,
+}
+
+impl FsyncPolicy {
+    pub const ALL: [FsyncPolicy; 3] = [FsyncPolicy::Always, FsyncPolicy::EverySec, FsyncPolicy::No];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FsyncPolicy::Always => "always",
+            FsyncPolicy::EverySec => "everysec",
+            FsyncPolicy::No => "no",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<FsyncPolicy> {
+        Self::ALL.into_iter().find(|policy| policy.name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// A line starting with `#` between the commands of the log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Annotation {
+    /// the unix time in seconds of the commands that follow
+    Timestamp(u64),
+    /// an annotation this version doesn't use, skipped on replay
+    Other,
+}
+
+/// Kind of a file listed in the manifest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FileKind {
+    Base,
+    /// replaced by a rewrite but not deleted yet
+    History,
+    Incr,
+}
+
+impl FileKind {
+    fn tag(self) -> &'static str {
+        match self {
+            FileKind::Base => "b",
+            FileKind::History => "h",
+            FileKind::Incr => "i",
+        }
+    }
+
+    fn parse(tag: &str) -> Option<FileKind> {
+        [FileKind::Base, FileKind::History, FileKind::Incr]
+            .into_iter()
+            .find(|kind| kind.tag() == tag)
+    }
+}
+
+#[derive(Clone, Debug)]
+struct AofFile {
+    name: String,
+    seq: u64,
+    kind: FileKind,
+}
+
+impl AofFile {
+    /// Whether the file starts with an RDB snapshot rather than commands.
+    fn has_preamble(&self) -> bool {
+        self.kind == FileKind::Base && self.name.ends_with(".rdb")
+    }
+}
+
+/// The files making up the log, in the order they are replayed.
+#[derive(Clone, Debug, Default)]
+struct Manifest {
+    files: Vec<AofFile>,
+}
+
+impl Manifest {
+    fn parse(text: &str) -> io::Result<Manifest> {
+        let mut files = Vec::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("invalid manifest line {}: {}", number + 1, line));
+
+            let mut fields = HashMap::new();
+            let mut words = line.split_whitespace();
+            while let Some(key) = words.next() {
+                fields.insert(key, words.next().ok_or_else(invalid)?);
+            }
+
+            let name = fields.get("file").ok_or_else(invalid)?;
+            if name.contains('/') || name.contains('\\') {
+                return Err(invalid());
+            }
+            files.push(AofFile {
+                name: name.to_string(),
+                seq: fields.get("seq").and_then(|seq| seq.parse().ok()).ok_or_else(invalid)?,
+                kind: fields.get("type").and_then(|tag| FileKind::parse(tag)).ok_or_else(invalid)?,
+            });
+        }
+
+        let mut manifest = Manifest { files };
+        if manifest.files.iter().filter(|file| file.kind == FileKind::Base).count() > 1 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "manifest lists more than one base file"));
+        }
+        // the base comes first, then the incremental files in sequence order
+        manifest.files.sort_by_key(|file| (file.kind != FileKind::Base, file.seq));
+        Ok(manifest)
+    }
+
+    fn render(&self) -> String {
+        self.files
+            .iter()
+            .map(|file| format!("file {} seq {} type {}\n", file.name, file.seq, file.kind.tag()))
+            .collect()
+    }
+
+    /// The files replayed on load: the base and incremental files.
+    fn live(&self) -> impl Iterator<Item = &AofFile> {
+        self.files.iter().filter(|file| file.kind != FileKind::History)
+    }
+
+    fn next_seq(&self, kind: FileKind) -> u64 {
+        self.files
+            .iter()
+            .filter(|file| file.kind == kind)
+            .map(|file| file.seq)
+            .max()
+            .unwrap_or(0)
+            + 1
+    }
+}
+
+pub struct Aof {
+    dir: PathBuf,
+    basename: String,
+    state: Mutex<State>,
+    dirty: AtomicBool,
+    rewriting: AtomicBool,
+    /// bytes in the base and incremental files
+    current_size: AtomicU64,
+    /// size of the log right after the last rewrite, which automatic rewrites grow from
+    base_size: AtomicU64,
+    settings: Arc<Settings>,
+}
+
+struct State {
+    /// the incremental file receiving appends
+    file: Arc<File>,
+    /// seals appends when the log is encrypted
+    sealer: Option<Sealer>,
+    selected_db: Option<usize>,
+    /// second of the last timestamp annotation written to the incremental file
+    last_timestamp: Option<u64>,
+    manifest: Manifest,
+}
+
+impl State {
+    /// Adds a timestamp annotation to `buf` unless the current second already has one.
+    fn annotate(&mut self, buf: &mut BytesMut) {
+        let now = db::unix_millis() / 1000;
+        if self.last_timestamp != Some(now) {
+            buf.extend_from_slice(format!("#TS:{}\r\n", now).as_bytes());
+            self.last_timestamp = Some(now);
+        }
+    }
+
+    /// Appends `buf` to the incremental file, sealed when the log is
+    /// encrypted, returning the number of bytes written.
+    fn write(&mut self, buf: &[u8]) -> io::Result<u64> {
+        let written = match &mut self.sealer {
+            Some(sealer) => sealer.seal(buf, false)?,
+            None => buf.to_vec(),
+        };
+        self.file.as_ref().write_all(&written)?;
+        Ok(written.len() as u64)
+    }
+
+    /// Switches appends to a new incremental file, which starts with a
+    /// timestamp annotation when `annotate` is set, returning the bytes written to it.
+    fn switch(&mut self, file: File, sealer: Option<Sealer>, annotate: bool) -> io::Result<u64> {
+        self.file = Arc::new(file);
+        self.sealer = sealer;
+        self.selected_db = None;
+        self.last_timestamp = None;
+        if !annotate {
+            return Ok(0);
+        }
+        let mut buf = BytesMut::new();
+        self.annotate(&mut buf);
+        self.write(&buf)
+    }
+}
+
+impl Aof {
+    /// Opens the log in `appenddirname` for appending.
+    ///
+    /// Without a manifest, the directory is initialized with a base file: the
+    /// single file written by earlier versions if there is one, otherwise the
+    /// current dataset.
+    pub async fn open(settings: Arc<Settings>, databases: &Databases) -> io::Result<Arc<Aof>> {
+        let dir = PathBuf::from(settings.appenddirname());
+        let basename = settings.appendfilename().to_string();
+        fs::create_dir_all(&dir)?;
+
+        let manifest_path = dir.join(manifest_name(&basename));
+        let mut manifest = if manifest_path.exists() {
+            Manifest::parse(&fs::read_to_string(&manifest_path)?)?
+        } else if Path::new(&basename).is_file() {
+            let name = base_name(&basename, 1, false);
+            fs::rename(&basename, dir.join(&name))?;
+            info!("moved append only file {} to {}", basename, dir.join(&name).display());
+            Manifest { files: vec![AofFile { name, seq: 1, kind: FileKind::Base }] }
+        } else {
+            let preamble = settings.aof_use_rdb_preamble();
+            let name = base_name(&basename, 1, preamble);
+            write_base(&dir.join(&name), databases.snapshot(), preamble, &settings.codecs()).await?;
+            Manifest { files: vec![AofFile { name, seq: 1, kind: FileKind::Base }] }
+        };
+
+        // appends never mix sealed and plain data, so a change of encryption starts a new file
+        let reusable = manifest.files.last().is_some_and(|file| {
+            file.kind == FileKind::Incr && sealing_matches(&dir.join(&file.name), settings.encryption_key().is_some())
+        });
+        if !reusable {
+            let seq = manifest.next_seq(FileKind::Incr);
+            manifest.files.push(AofFile { name: incr_name(&basename, seq), seq, kind: FileKind::Incr });
+        }
+        persist_manifest(&dir, &basename, &manifest)?;
+
+        let incr = manifest.files.last().expect("the manifest lists an incremental file");
+        let (file, sealer) = open_incr(&dir.join(&incr.name), &settings)?;
+        let mut state = State { file: Arc::new(file), sealer, selected_db: None, last_timestamp: None, manifest };
+        if !reusable && settings.aof_timestamp_enabled() {
+            let mut buf = BytesMut::new();
+            state.annotate(&mut buf);
+            state.write(&buf)?;
+        }
+        let size = state
+            .manifest
+            .live()
+            .map(|file| fs::metadata(dir.join(&file.name)).map(|meta| meta.len()).unwrap_or(0))
+            .sum();
+
+        let aof = Arc::new(Aof {
+            state: Mutex::new(state),
+            dirty: AtomicBool::new(false),
+            rewriting: AtomicBool::new(false),
+            current_size: AtomicU64::new(size),
+            base_size: AtomicU64::new(size),
+            dir,
+            basename,
+            settings,
+        });
+        aof.start_background_task(databases.clone());
+        Ok(aof)
+    }
+
+    /// Appends the records produced by one command executed against database `db`.
+    pub fn append(&self, db: usize, records: &[Frame]) -> io::Result<()> {
+        let mut buf = BytesMut::new();
+        let mut state = self.state.lock().expect("aof lock poisoned");
+
+        if self.settings.aof_timestamp_enabled() {
+            state.annotate(&mut buf);
+        }
+        if state.selected_db != Some(db) {
+            connection::serialize_frame(&command_frame(["SELECT".into(), db.to_string().into()]), &mut buf);
+            state.selected_db = Some(db);
+        }
+        for record in records {
+            connection::serialize_frame(record, &mut buf);
+        }
+
+        let written = state.write(&buf)?;
+        self.current_size.fetch_add(written, Ordering::Relaxed);
+        if self.settings.appendfsync() == FsyncPolicy::Always {
+            state.file.sync_data()?;
+        } else {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Flushes everything appended so far to disk.
+    pub fn fsync(&self) -> io::Result<()> {
+        self.dirty.store(false, Ordering::Relaxed);
+        let file = Arc::clone(&self.state.lock().expect("aof lock poisoned").file);
+        file.sync_data()
+    }
+
+    /// Starts compacting the log in the background, unless a rewrite is already running.
+    pub fn rewrite(self: &Arc<Self>, databases: &Databases) -> Result<(), &'static str> {
+        if self.rewriting.swap(true, Ordering::SeqCst) {
+            return Err("ERR Background append only file rewriting already in progress");
+        }
+
+        let aof = Arc::clone(self);
+        let databases = databases.clone();
+        tokio::spawn(async move {
+            let started = Instant::now();
+            match aof.run_rewrite(&databases).await {
+                Ok(()) => info!("append only file rewritten in {:?}", started.elapsed()),
+                Err(e) => error!("background append only file rewrite failed: {}", e),
+            }
+            aof.rewriting.store(false, Ordering::SeqCst);
+        });
+        Ok(())
+    }
+
+    pub fn rewrite_in_progress(&self) -> bool {
+        self.rewriting.load(Ordering::SeqCst)
+    }
+
+    async fn run_rewrite(&self, databases: &Databases) -> io::Result<()> {
+        // with writes paused, commands logged from here on go to a new
+        // incremental file and the snapshot the new base is written from is
+        // taken at the same point, so the base neither misses a key MOVE
+        // carries to a database already written nor holds a write the new
+        // file replays again. Writes go on while the snapshot is read.
+        let writes = databases.pause_writes().await;
+        let seq = {
+            let mut state = self.state.lock().expect("aof lock poisoned");
+            let seq = state.manifest.next_seq(FileKind::Incr);
+            let name = incr_name(&self.basename, seq);
+            let (file, sealer) = open_incr(&self.dir.join(&name), &self.settings)?;
+
+            let mut manifest = state.manifest.clone();
+            manifest.files.push(AofFile { name, seq, kind: FileKind::Incr });
+            persist_manifest(&self.dir, &self.basename, &manifest)?;
+
+            state.file.sync_data()?;
+            let written = state.switch(file, sealer, self.settings.aof_timestamp_enabled())?;
+            self.current_size.fetch_add(written, Ordering::Relaxed);
+            state.manifest = manifest;
+            state.manifest.next_seq(FileKind::Base)
+        };
+        let snapshot = databases.snapshot();
+        drop(writes);
+
+        let preamble = self.settings.aof_use_rdb_preamble();
+        let name = base_name(&self.basename, seq, preamble);
+        let temp = self.dir.join(format!("temp-rewrite-{}", name));
+        if let Err(e) = write_base(&temp, snapshot, preamble, &self.settings.codecs()).await {
+            let _ = fs::remove_file(&temp);
+            return Err(e);
+        }
+        fs::rename(&temp, self.dir.join(&name))?;
+
+        let replaced = {
+            let mut state = self.state.lock().expect("aof lock poisoned");
+            let incr = state.manifest.files.last().cloned().expect("the manifest lists an incremental file");
+            let manifest = Manifest { files: vec![AofFile { name, seq, kind: FileKind::Base }, incr] };
+            persist_manifest(&self.dir, &self.basename, &manifest)?;
+            std::mem::replace(&mut state.manifest, manifest)
+        };
+
+        // every file but the new incremental one is replaced
+        let replaced = &replaced.files[..replaced.files.len() - 1];
+        let limit = self.settings.aof_history_limit();
+        if limit > 0 {
+            if let Err(e) = self.archive(replaced, limit) {
+                warn!("failed to move replaced append only files to {}: {}", HISTORY_DIR, e);
+            }
+        } else {
+            for file in replaced {
+                if let Err(e) = fs::remove_file(self.dir.join(&file.name)) {
+                    warn!("failed to remove replaced append only file {}: {}", file.name, e);
+                }
+            }
+        }
+
+        let state = self.state.lock().expect("aof lock poisoned");
+        let size: u64 = state
+            .manifest
+            .live()
+            .map(|file| fs::metadata(self.dir.join(&file.name)).map(|meta| meta.len()).unwrap_or(0))
+            .sum();
+        self.current_size.store(size, Ordering::Relaxed);
+        self.base_size.store(size, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Moves the base and incremental files replaced by a rewrite to the
+    /// history directory, listed by a manifest named after the base, then
+    /// deletes the oldest of them beyond `limit`.
+    fn archive(&self, files: &[AofFile], limit: u64) -> io::Result<()> {
+        let history = self.dir.join(HISTORY_DIR);
+        fs::create_dir_all(&history)?;
+
+        for file in files {
+            fs::rename(self.dir.join(&file.name), history.join(&file.name))?;
+        }
+        let seq = files.first().map(|file| file.seq).unwrap_or(0);
+        persist_manifest(&history, &history_name(&self.basename, seq), &Manifest { files: files.to_vec() })?;
+
+        let manifests = history_manifests(&self.dir, &self.basename)?;
+        let excess = manifests.len().saturating_sub(limit as usize);
+        for path in &manifests[..excess] {
+            let manifest = Manifest::parse(&fs::read_to_string(path)?)?;
+            for file in &manifest.files {
+                if let Err(e) = fs::remove_file(history.join(&file.name)) {
+                    warn!("failed to remove append only file {} from history: {}", file.name, e);
+                }
+            }
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the log has grown enough since the last rewrite to be rewritten automatically.
+    fn needs_rewrite(&self) -> bool {
+        let percentage = self.settings.auto_aof_rewrite_percentage();
+        let current = self.current_size.load(Ordering::Relaxed);
+        if percentage == 0 || current < self.settings.auto_aof_rewrite_min_size() {
+            return false;
+        }
+        let base = self.base_size.load(Ordering::Relaxed).max(1);
+        current.saturating_sub(base) * 100 / base >= percentage
+    }
+
+    /// Every second: fsyncs under the everysec policy and starts automatic rewrites.
+    fn start_background_task(self: &Arc<Self>, databases: Databases) {
+        let aof: Weak<Aof> = Arc::downgrade(self);
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(1));
+
+            loop {
+                interval.tick().await;
+
+                let Some(aof) = aof.upgrade() else {
+                    break;
+                };
+                if !aof.rewrite_in_progress() && aof.needs_rewrite() {
+                    info!(
+                        "starting automatic append only file rewrite, grown to {} bytes",
+                        aof.current_size.load(Ordering::Relaxed)
+                    );
+                    let _ = aof.rewrite(&databases);
+                }
+                if aof.settings.appendfsync() != FsyncPolicy::EverySec || !aof.dirty.load(Ordering::Relaxed) {
+                    continue;
+                }
+
+                let result = tokio::task::spawn_blocking(move || aof.fsync()).await;
+                if let Ok(Err(e)) = result {
+                    error!("failed to fsync append only file: {}", e);
+                }
+            }
+        });
+    }
+}
+
+/// The files replayed from the manifest at `path`, in order, each with
+/// whether it starts with an RDB preamble.
+pub fn manifest_files(path: &Path) -> io::Result<Vec<(PathBuf, bool)>> {
+    let manifest = Manifest::parse(&fs::read_to_string(path)?)?;
+    let dir = path.parent().unwrap_or(Path::new("."));
+    Ok(manifest.live().map(|file| (dir.join(&file.name), file.has_preamble())).collect())
+}
+
+fn manifest_name(basename: &str) -> String {
+    format!("{}.manifest", basename)
+}
+
+/// Base name of the manifest listing the files replaced by the rewrite that
+/// wrote base `seq`, once they are moved to the history directory.
+fn history_name(basename: &str, seq: u64) -> String {
+    format!("{}.{}", basename, seq)
+}
+
+/// The manifests in the history directory of the log in `dir`, oldest first.
+fn history_manifests(dir: &Path, basename: &str) -> io::Result<Vec<PathBuf>> {
+    let history = dir.join(HISTORY_DIR);
+    if !history.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut manifests = Vec::new();
+    for entry in fs::read_dir(&history)? {
+        let name = entry?.file_name();
+        let seq = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(basename)?.strip_prefix('.')?.strip_suffix(".manifest"))
+            .and_then(|seq| seq.parse::<u64>().ok());
+        if let Some(seq) = seq {
+            manifests.push((seq, history.join(name)));
+        }
+    }
+    manifests.sort();
+    Ok(manifests.into_iter().map(|(_, path)| path).collect())
+}
+
+fn base_name(basename: &str, seq: u64, preamble: bool) -> String {
+    format!("{}.{}.base.{}", basename, seq, if preamble { "rdb" } else { "aof" })
+}
+
+fn incr_name(basename: &str, seq: u64) -> String {
+    format!("{}.{}.incr.aof", basename, seq)
+}
+
+/// Replaces the manifest in `dir` through a temporary file, so it is never seen half written.
+fn persist_manifest(dir: &Path, basename: &str, manifest: &Manifest) -> io::Result<()> {
+    let path = dir.join(manifest_name(basename));
+    let temp = dir.join(format!("temp-{}", manifest_name(basename)));
+
+    let mut file = File::create(&temp)?;
+    file.write_all(manifest.render().as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temp, &path)
+}
+
+/// Opens the incremental file at `path` for appending, sealing appends when
+/// the log is encrypted. A new sealed file starts with its header.
+fn open_incr(path: &Path, settings: &Settings) -> io::Result<(File, Option<Sealer>)> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    let Some(key) = settings.encryption_key() else {
+        return Ok((file, None));
+    };
+
+    let sealer = match codec::scan_frames(path)? {
+        Some((header, frames)) => Sealer::resume(&header, frames, Some(key))?,
+        None => {
+            // appends are too small to be worth compressing on their own
+            let sealer = Sealer::new(&Codecs { compression: Compression::None, key: Some(key.clone()) });
+            file.write_all(sealer.header())?;
+            sealer
+        }
+    };
+    Ok((file, Some(sealer)))
+}
+
+/// Whether appending to the file at `path` with encryption `encrypted`
+/// keeps it all sealed or all plain. Missing and empty files match anything.
+fn sealing_matches(path: &Path, encrypted: bool) -> bool {
+    let mut prefix = Vec::new();
+    match File::open(path) {
+        Ok(file) => {
+            if file.take(codec::HEADER_LEN as u64).read_to_end(&mut prefix).is_err() {
+                return false;
+            }
+        }
+        Err(_) => return true,
+    }
+    prefix.is_empty() || codec::is_sealed(&prefix) == encrypted
+}
+
+/// Writes a base file recreating every key of `snapshot`.
+async fn write_base(path: &Path, snapshot: Snapshot, preamble: bool, codecs: &Codecs) -> io::Result<()> {
+    if preamble {
+        let mut writer = SnapshotWriter::sealed(tokio::fs::File::create(path).await?, codecs).await?;
+        persistence::write_rdb(&snapshot, true, &mut writer).await?;
+        return writer.finish().await?.sync_all().await;
+    }
+
+    let path = path.to_path_buf();
+    let codecs = codecs.clone();
+    tokio::task::spawn_blocking(move || {
+        let file = File::create(&path)?;
+        let file = if codecs.is_plain() {
+            let mut out = io::BufWriter::new(file);
+            write_dataset(&snapshot, &mut out)?;
+            out.into_inner().map_err(io::IntoInnerError::into_error)?
+        } else {
+            let mut out = SealedWriter { file, sealer: Sealer::new(&codecs), block: Vec::with_capacity(codec::BLOCK_SIZE) };
+            out.file.write_all(out.sealer.header())?;
+            write_dataset(&snapshot, &mut out)?;
+            out.finish()?
+        };
+        file.sync_all()
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+/// Seals what is written through it into blocks of `codec::BLOCK_SIZE`.
+struct SealedWriter {
+    file: File,
+    sealer: Sealer,
+    /// plain bytes of the block being filled
+    block: Vec<u8>,
+}
+
+impl SealedWriter {
+    /// Seals the last block, which may be short or empty.
+    fn finish(mut self) -> io::Result<File> {
+        let frame = self.sealer.seal(&self.block, true)?;
+        self.file.write_all(&frame)?;
+        Ok(self.file)
+    }
+}
+
+impl Write for SealedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.block.extend_from_slice(buf);
+        while self.block.len() >= codec::BLOCK_SIZE {
+            let frame = self.sealer.seal(&self.block[..codec::BLOCK_SIZE], false)?;
+            self.file.write_all(&frame)?;
+            self.block.drain(..codec::BLOCK_SIZE);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The log records for a command about to run, or nothing for commands that don't write.
+///
+/// `request` is the frame the command was parsed from; it is logged as is
+/// unless the command carries a relative expiration. MIGRATE logs a DEL for
+/// each key it deletes itself, as only the target's replies tell which.
+pub fn records(command: &Command, request: &Frame) -> Vec<Frame> {
+    match command {
+        Command::Set { key, value, expiry: Some(expiry) } => vec![command_frame([
+            "SET".into(),
+            key.clone(),
+            value.clone(),
+            "PXAT".into(),
+            expiry.unix_millis().to_string().into(),
+        ])],
+        Command::Restore { key, ttl_millis, payload, replace, absolute_ttl, idle_seconds, frequency } => {
+            let deadline = match (*ttl_millis, *absolute_ttl) {
+                (0, _) => 0,
+                (deadline, true) => deadline,
+                (ttl, false) => db::unix_millis().saturating_add(ttl),
+            };
+            let mut args: Vec<Bytes> = vec!["RESTORE".into(), key.clone(), deadline.to_string().into(), payload.clone()];
+            if *replace {
+                args.push("REPLACE".into());
+            }
+            if deadline > 0 {
+                args.push("ABSTTL".into());
+            }
+            if let Some(seconds) = idle_seconds {
+                args.extend(["IDLETIME".into(), seconds.to_string().into()]);
+            }
+            if let Some(counter) = frequency {
+                args.extend(["FREQ".into(), counter.to_string().into()]);
+            }
+            vec![command_frame(args)]
+        }
+        Command::Set { .. }
+        | Command::Del { .. }
+        | Command::Move { .. }
+        | Command::SwapDb { .. }
+        | Command::FlushDb { .. }
+        | Command::FlushAll { .. }
+        | Command::Sort { store: Some(_), .. } => vec![request.clone()],
+        _ => Vec::new(),
+    }
+}
+
+pub fn command_frame(args: impl IntoIterator<Item = Bytes>) -> Frame {
+    Frame::Array(args.into_iter().map(Frame::Bulk).collect())
+}
+
+/// Writes commands that recreate every key of every database of `snapshot`
+/// to `out`, in batches of about `persistence::BATCH_BYTES`.
+pub fn write_dataset<W: Write>(snapshot: &Snapshot, out: &mut W) -> io::Result<()> {
+    let mut buf = BytesMut::new();
+    let now = Instant::now();
+    let now_millis = db::unix_millis();
+
+    for index in 0..snapshot.len() {
+        let mut selected = false;
+        for shard in 0..snapshot.shards(index) {
+            for (key, value, expiry) in snapshot.read_shard(index, shard) {
+                if !selected {
+                    connection::serialize_frame(&command_frame(["SELECT".into(), index.to_string().into()]), &mut buf);
+                    selected = true;
+                }
+                let deadline = expiry.map(|expiry| now_millis + expiry.saturating_duration_since(now).as_millis() as u64);
+                connection::serialize_frame(&key_record(key, &value, deadline), &mut buf);
+
+                if buf.len() >= persistence::BATCH_BYTES {
+                    out.write_all(&buf)?;
+                    buf.clear();
+                }
+            }
+        }
+    }
+    out.write_all(&buf)?;
+    out.flush()
+}
+
+/// The command recreating `key`, expiring at the unix time `deadline` in milliseconds.
+pub fn key_record(key: Bytes, value: &Value, deadline: Option<u64>) -> Frame {
+    match (value, deadline) {
+        (Value::String(string), None) => command_frame(["SET".into(), key, string.to_bytes()]),
+        (Value::String(string), Some(deadline)) => command_frame([
+            "SET".into(),
+            key,
+            string.to_bytes(),
+            "PXAT".into(),
+            deadline.max(1).to_string().into(),
+        ]),
+        (value, deadline) => {
+            let mut args = vec![
+                "RESTORE".into(),
+                key,
+                deadline.unwrap_or(0).to_string().into(),
+                Bytes::from(rdb::dump(value)),
+                "REPLACE".into(),
+            ];
+            if deadline.is_some() {
+                args.push("ABSTTL".into());
+            }
+            command_frame(args)
+        }
+    }
+}
+
+/// Consumes the annotation at the start of `buf`, returning `None` when `buf`
+/// doesn't start with one. Fails with `WouldBlock` while the line is incomplete.
+pub fn parse_annotation(buf: &mut BytesMut) -> io::Result<Option<Annotation>> {
+    if buf.first() != Some(&b'#') {
+        return Ok(None);
+    }
+    let Some(end) = buf.windows(2).position(|pair| pair == b"\r\n") else {
+        return Err(io::ErrorKind::WouldBlock.into());
+    };
+
+    let line = buf.split_to(end + 2);
+    let timestamp = line[1..end]
+        .strip_prefix(b"TS:")
+        .and_then(|digits| std::str::from_utf8(digits).ok())
+        .and_then(|digits| digits.parse().ok());
+    Ok(Some(timestamp.map_or(Annotation::Other, Annotation::Timestamp)))
+}
+
+/// Loads the log configured in `settings` into `databases`, returning the
+/// number of keys and commands loaded, or `None` when there is no log yet.
+///
+/// A single append only file left by earlier versions is loaded as is. Only
+/// the last file may end with an incomplete command, which is dropped and the
+/// file truncated when aof-load-truncated is set.
+pub async fn load(settings: &Settings, databases: &Databases) -> io::Result<Option<usize>> {
+    let dir = Path::new(settings.appenddirname());
+    let basename = settings.appendfilename();
+    let allow_truncated = settings.aof_load_truncated();
+
+    let manifest_path = dir.join(manifest_name(basename));
+    if !tokio::fs::try_exists(&manifest_path).await? {
+        if !tokio::fs::try_exists(basename).await? {
+            return Ok(None);
+        }
+        let (count, _) = replay(Path::new(basename), 0, databases, allow_truncated, None).await?;
+        databases.clear_dirty(databases.dirty());
+        return Ok(Some(count));
+    }
+
+    let manifest = Manifest::parse(&tokio::fs::read_to_string(&manifest_path).await?)?;
+    let files: Vec<&AofFile> = manifest.live().collect();
+    let mut count = 0;
+
+    for (index, file) in files.iter().enumerate() {
+        let path = dir.join(&file.name);
+        let allow_truncated = allow_truncated && index == files.len() - 1;
+
+        let mut offset = 0;
+        if file.has_preamble() {
+            let (keys, end) = load_preamble(&path, databases).await?;
+            count += keys;
+            offset = end;
+        }
+        count += replay(&path, offset, databases, allow_truncated, None).await?.0;
+    }
+
+    // the replayed commands rebuilt what was already saved
+    databases.clear_dirty(databases.dirty());
+    Ok(Some(count))
+}
+
+/// Outcome of a point-in-time recovery.
+#[derive(Debug)]
+pub struct Recovery {
+    /// the base file the dataset was rebuilt from
+    pub base: PathBuf,
+    /// unix time in seconds of the first commands logged after the base
+    pub started: u64,
+    /// keys and commands loaded
+    pub count: usize,
+    /// whether the log covers the target time rather than ending before it
+    pub reached: bool,
+}
+
+/// Rebuilds in `databases` the dataset as it was at the unix time `target`
+/// in seconds, from the latest base of the log or its history whose
+/// commands start no later than `target`, followed by the commands logged up
+/// to the first timestamp annotation past `target`. No file is modified.
+pub async fn recover(settings: &Settings, databases: &Databases, target: u64) -> io::Result<Recovery> {
+    let dir = Path::new(settings.appenddirname());
+    let basename = settings.appendfilename();
+    let mut manifests = history_manifests(dir, basename)?;
+    manifests.push(dir.join(manifest_name(basename)));
+
+    let mut chosen = None;
+    for (age, path) in manifests.iter().rev().enumerate().filter(|(_, path)| path.exists()) {
+        let files = manifest_files(path)?;
+        // the first incremental file starts with the time the base was written at
+        let Some((incr, _)) = files.get(1) else {
+            continue;
+        };
+        if let Some(started) = first_timestamp(incr, settings).await?
+            && started <= target
+        {
+            chosen = Some((files, started, age > 0));
+            break;
+        }
+    }
+    let Some((files, started, superseded)) = chosen else {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no append only file in {} starts with a timestamp at or before {}", dir.display(), target),
+        ));
+    };
+
+    let mut count = 0;
+    // a newer base was written after the target, so the log goes on past it
+    let mut reached = superseded;
+    for (path, preamble) in &files {
+        let mut offset = 0;
+        if *preamble {
+            let (keys, end) = load_preamble(path, databases).await?;
+            count += keys;
+            offset = end;
+        }
+        let (replayed, stopped) = replay(path, offset, databases, false, Some(target)).await?;
+        count += replayed;
+        if stopped {
+            reached = true;
+            break;
+        }
+    }
+
+    databases.clear_dirty(databases.dirty());
+    Ok(Recovery { base: files[0].0.clone(), started, count, reached })
+}
+
+/// The time of the annotation the file at `path` starts with, if any.
+async fn first_timestamp(path: &Path, settings: &Settings) -> io::Result<Option<u64>> {
+    let mut source = Source::open(path, 0, settings).await?;
+    let mut buf = BytesMut::new();
+
+    loop {
+        if !buf.is_empty() {
+            match parse_annotation(&mut buf) {
+                Ok(Some(Annotation::Timestamp(timestamp))) => return Ok(Some(timestamp)),
+                Ok(_) => return Ok(None),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if source.read(&mut buf).await? == 0 {
+            return Ok(None);
+        }
+    }
+}
+
+/// Streams the RDB file at the start of `path` into `databases`, returning
+/// the number of keys loaded and the offset where the commands after it start.
+async fn load_preamble(path: &Path, databases: &Databases) -> io::Result<(usize, u64)> {
+    let file = File::open(path)?;
+    let path = path.to_path_buf();
+    let databases = databases.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut input = BufReader::new(file);
+        if codec::is_sealed(input.fill_buf()?) {
+            let blocks = BlockReader::new(input, databases.settings().encryption_key())
+                .map_err(|e| corrupt(&path, 0, &e.to_string()))?;
+            return read_preamble(&path, RdbReader::new(BufReader::new(blocks)), &databases);
+        }
+        read_preamble(&path, RdbReader::new(input), &databases)
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+fn read_preamble<R: Read>(path: &Path, mut reader: RdbReader<R>, databases: &Databases) -> io::Result<(usize, u64)> {
+    let keys = persistence::load_rdb_preamble(&mut reader, databases)
+        .map_err(|e| corrupt(path, reader.offset(), &format!("bad RDB preamble: {}", e)))?;
+    Ok((keys, reader.offset()))
+}
+
+/// Where replay reads commands from: the file itself, or the frames of a sealed file.
+enum Source {
+    Plain(tokio::fs::File),
+    Sealed {
+        file: tokio::fs::File,
+        opener: Opener,
+        /// plain bytes still to be skipped before the commands
+        skip: u64,
+        /// end of the last complete frame
+        end: u64,
+    },
+}
+
+impl Source {
+    /// Opens `path` positioned at the plain offset `offset`.
+    async fn open(path: &Path, offset: u64, settings: &Settings) -> io::Result<Source> {
+        let mut file = tokio::fs::File::open(path).await?;
+        let mut header = Vec::with_capacity(codec::HEADER_LEN);
+        (&mut file).take(codec::HEADER_LEN as u64).read_to_end(&mut header).await?;
+
+        if !codec::is_sealed(&header) {
+            file.seek(io::SeekFrom::Start(offset)).await?;
+            return Ok(Source::Plain(file));
+        }
+        let opener = Opener::new(&header, settings.encryption_key()).map_err(|e| corrupt(path, 0, &e.to_string()))?;
+        Ok(Source::Sealed { file, opener, skip: offset, end: header.len() as u64 })
+    }
+
+    /// Appends more of the log to `buf`, returning 0 at the end of the file.
+    /// A sealed file ending in the middle of a frame fails with `UnexpectedEof`.
+    async fn read(&mut self, buf: &mut BytesMut) -> io::Result<usize> {
+        let (file, opener, skip, end) = match self {
+            Source::Plain(file) => return file.read_buf(buf).await,
+            Source::Sealed { file, opener, skip, end } => (file, opener, skip, end),
+        };
+
+        loop {
+            let Some((word, payload)) = codec::read_frame(file).await? else {
+                return Ok(0);
+            };
+            *end += 4 + payload.len() as u64;
+            let block = opener.open(word, &payload)?;
+
+            let skipped = (*skip).min(block.len() as u64) as usize;
+            *skip -= skipped as u64;
+            if skipped < block.len() {
+                buf.extend_from_slice(&block[skipped..]);
+                return Ok(block.len() - skipped);
+            }
+        }
+    }
+}
+
+/// Replays the commands of the file at `path` from `offset` on, returning the
+/// number executed and whether replay stopped at a timestamp annotation past
+/// `until`, before the end of the file.
+///
+/// For a sealed file, `offset` counts plain bytes and the file is only ever
+/// truncated to a whole number of frames.
+async fn replay(
+    path: &Path,
+    offset: u64,
+    databases: &Databases,
+    allow_truncated: bool,
+    until: Option<u64>,
+) -> io::Result<(usize, bool)> {
+    let mut source = Source::open(path, offset, databases.settings()).await?;
+    let mut buf = BytesMut::with_capacity(READ_CHUNK);
+    let mut session = Session::new();
+    let mut offset = offset;
+    let mut count = 0;
+
+    loop {
+        let before = buf.len();
+        let parsed = match parse_annotation(&mut buf) {
+            Ok(Some(Annotation::Timestamp(timestamp))) if until.is_some_and(|until| timestamp > until) => {
+                return Ok((count, true));
+            }
+            Ok(Some(_)) => {
+                offset += (before - buf.len()) as u64;
+                continue;
+            }
+            Ok(None) => connection::parse_frame(&mut buf),
+            Err(e) => Err(e),
+        };
+        let parsed = match parsed {
+            Ok(frame) => frame,
+            // an incomplete frame or annotation: more data is needed
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => None,
+            Err(e) => return Err(corrupt(path, offset, &e.to_string())),
+        };
+
+        let Some(frame) = parsed else {
+            let valid_len = match (source.read(&mut buf).await, &source) {
+                (Ok(n), _) if n > 0 => continue,
+                (Ok(_), _) if buf.is_empty() => break,
+                (Ok(_), Source::Plain(_)) => offset,
+                (Ok(_), Source::Sealed { .. }) => {
+                    return Err(corrupt(path, offset, "incomplete command in a complete frame"));
+                }
+                (Err(e), Source::Sealed { end, .. }) if e.kind() == io::ErrorKind::UnexpectedEof => *end,
+                (Err(e), _) if e.kind() == io::ErrorKind::InvalidData => {
+                    return Err(corrupt(path, offset, &e.to_string()));
+                }
+                (Err(e), _) => return Err(e),
+            };
+            if !allow_truncated {
+                return Err(corrupt(path, offset, "unexpected end of file (enable aof-load-truncated to recover)"));
+            }
+            warn!(
+                "append only file {} ends with an incomplete command, truncating it to {} bytes",
+                path.display(),
+                valid_len
+            );
+            OpenOptions::new().write(true).open(path)?.set_len(valid_len)?;
+            break;
+        };
+
+        offset += (before - buf.len()) as u64;
+
+        let command = cmd::from_frame(frame).map_err(|e| corrupt(path, offset, &e.to_string()))?;
+        if let Frame::Error(e) = server::execute(command, databases, &mut session).await {
+            warn!("command at offset {} of {} failed during replay: {}", offset, path.display(), e);
+        }
+        count += 1;
+    }
+
+    Ok((count, false))
+}
+
+fn corrupt(path: &Path, offset: u64, reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("bad append only file {} at offset {}: {}", path.display(), offset, reason),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use clap::Parser;
+
+    fn databases(count: usize) -> Databases {
+        let config = Config::parse_from(["rusty-redis", "--databases", &count.to_string()]);
+        Databases::new(count, Arc::new(Settings::new(&config)))
+    }
+
+    #[tokio::test]
+    async fn sealed_base_spans_several_blocks() {
+        let databases = databases(2);
+        for i in 0..4000 {
+            databases.db(i % 2).set(format!("key:{}", i).into(), Bytes::from(vec![b'v'; 128]), None);
+        }
+        let mut expected = Vec::new();
+        write_dataset(&databases.snapshot(), &mut expected).unwrap();
+        assert!(expected.len() > 2 * codec::BLOCK_SIZE);
+
+        let path = std::env::temp_dir().join(format!("rusty-redis-base-{}.aof", std::process::id()));
+        let codecs = Codecs { compression: Compression::Lz4, key: None };
+        write_base(&path, databases.snapshot(), false, &codecs).await.unwrap();
+
+        let mut plain = Vec::new();
+        let reader = BlockReader::new(BufReader::new(File::open(&path).unwrap()), None);
+        let read = reader.and_then(|mut reader| reader.read_to_end(&mut plain));
+        fs::remove_file(&path).unwrap();
+        read.unwrap();
+        assert_eq!(plain, expected);
+    }
+
+    #[tokio::test]
+    async fn loading_leaves_nothing_to_save() {
+        let dir = std::env::temp_dir().join(format!("rusty-redis-aof-load-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("appendonly.aof.manifest"), "file appendonly.aof.1.incr.aof seq 1 type i\n").unwrap();
+        fs::write(
+            dir.join("appendonly.aof.1.incr.aof"),
+            "*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n*3\r\n$3\r\nSET\r\n$1\r\nb\r\n$1\r\n2\r\n",
+        )
+        .unwrap();
+
+        let config = Config::parse_from(["rusty-redis", "--appenddirname", dir.to_str().unwrap()]);
+        let settings = Arc::new(Settings::new(&config));
+        let databases = Databases::new(16, Arc::clone(&settings));
+        let loaded = load(&settings, &databases).await;
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(loaded.unwrap(), Some(2));
+        assert_eq!(databases.db(0).len(), 2);
+        assert_eq!(databases.dirty(), 0);
+    }
+
+    #[test]
+    fn manifest_round_trip() {
+        let text = "# written by a rewrite\n\
+                    file appendonly.aof.3.incr.aof seq 3 type i\n\
+                    \n\
+                    file appendonly.aof.2.incr.aof seq 2 type i\n\
+                    file appendonly.aof.2.base.rdb seq 2 type b\n\
+                    file appendonly.aof.1.incr.aof type h seq 1\n";
+        let manifest = Manifest::parse(text).unwrap();
+        let names: Vec<_> = manifest.files.iter().map(|file| file.name.as_str()).collect();
+        // the base first, then by sequence
+        assert_eq!(
+            names,
+            ["appendonly.aof.2.base.rdb", "appendonly.aof.1.incr.aof", "appendonly.aof.2.incr.aof", "appendonly.aof.3.incr.aof"]
+        );
+        assert!(manifest.files[0].has_preamble());
+        assert_eq!(manifest.live().count(), 3);
+        assert_eq!(manifest.next_seq(FileKind::Incr), 4);
+
+        let rendered = Manifest::parse(&manifest.render()).unwrap();
+        assert_eq!(rendered.render(), manifest.render());
+    }
+
+    #[test]
+    fn manifest_rejects_bad_lines() {
+        for text in [
+            "file a.aof seq 1",
+            "file a.aof seq x type i",
+            "file a.aof seq 1 type q",
+            "seq 1 type i",
+            "file a.aof seq 1 type",
+            "file ../a.aof seq 1 type i",
+            "file dir\\a.aof seq 1 type i",
+            "file a.aof seq 1 type b\nfile b.aof seq 2 type b",
+        ] {
+            let err = Manifest::parse(text).err().unwrap_or_else(|| panic!("{:?} parsed", text));
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    fn args(frames: &[Frame]) -> Vec<Vec<Bytes>> {
+        frames
+            .iter()
+            .map(|frame| match frame {
+                Frame::Array(args) => args
+                    .iter()
+                    .map(|arg| match arg {
+                        Frame::Bulk(bytes) => bytes.clone(),
+                        other => panic!("not a bulk string: {:?}", other),
+                    })
+                    .collect(),
+                other => panic!("not a command: {:?}", other),
+            })
+            .collect()
+    }
+
+    fn records_for(request: &[&str]) -> Vec<Vec<Bytes>> {
+        let request = command_frame(request.iter().map(|arg| Bytes::copy_from_slice(arg.as_bytes())));
+        let command = cmd::from_frame(request.clone()).unwrap();
+        args(&records(&command, &request))
+    }
+
+    #[test]
+    fn relative_expirations_are_logged_as_absolute() {
+        let before = db::unix_millis();
+        let logged = records_for(&["SET", "k", "v", "EX", "100"]);
+        assert_eq!(logged[0][..4], [Bytes::from("SET"), Bytes::from("k"), Bytes::from("v"), Bytes::from("PXAT")]);
+        let deadline: u64 = std::str::from_utf8(&logged[0][4]).unwrap().parse().unwrap();
+        assert!(deadline >= before + 100_000 && deadline <= db::unix_millis() + 100_000);
+
+        let logged = records_for(&["RESTORE", "k", "5000", "payload"]);
+        assert_eq!(logged[0].last(), Some(&Bytes::from("ABSTTL")));
+    }
+
+    #[test]
+    fn writes_are_logged_as_sent_and_reads_not_at_all() {
+        assert_eq!(records_for(&["SET", "k", "v"]), vec![vec![Bytes::from("SET"), Bytes::from("k"), Bytes::from("v")]]);
+        assert_eq!(records_for(&["DEL", "a"]).len(), 1);
+        assert!(records_for(&["GET", "k"]).is_empty());
+        assert!(records_for(&["SORT", "k"]).is_empty());
+        // MIGRATE logs the DELs of the keys it moved once it knows them
+        assert!(records_for(&["MIGRATE", "127.0.0.1", "1", "k", "0", "5000"]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn base_without_preamble_replays_into_the_same_dataset() {
+        let databases = databases(2);
+        databases.db(0).set("a".into(), "1".into(), None);
+        databases.db(1).insert("l".into(), Value::list(["x".into(), "y".into()].into()), None);
+        databases.db(1).set("t".into(), "2".into(), Some(Duration::from_secs(3600)));
+
+        let path = std::env::temp_dir().join(format!("rusty-redis-replay-{}.aof", std::process::id()));
+        write_base(&path, databases.snapshot(), false, &Codecs { compression: Compression::None, key: None }).await.unwrap();
+        let loaded = self::databases(2);
+        let replayed = replay(&path, 0, &loaded, false, None).await;
+        fs::remove_file(&path).unwrap();
+
+        // a SELECT for each database, then a command per key
+        assert_eq!(replayed.unwrap(), (5, false));
+        assert_eq!(loaded.db(0).get(b"a").unwrap(), Some("1".into()));
+        assert!(loaded.db(1).expiry(b"t").is_some());
+        assert_eq!(loaded.db(1).len(), 2);
+    }
+
+    #[test]
+    fn annotations() {
+        let mut buf = BytesMut::from(&b"#TS:1700000000\r\n#note\r\n#TS:x\r\n*1\r\n"[..]);
+        assert_eq!(parse_annotation(&mut buf).unwrap(), Some(Annotation::Timestamp(1_700_000_000)));
+        assert_eq!(parse_annotation(&mut buf).unwrap(), Some(Annotation::Other));
+        assert_eq!(parse_annotation(&mut buf).unwrap(), Some(Annotation::Other));
+        assert_eq!(parse_annotation(&mut buf).unwrap(), None);
+        assert_eq!(&buf[..], b"*1\r\n");
+
+        let mut partial = BytesMut::from(&b"#TS:17"[..]);
+        assert_eq!(parse_annotation(&mut partial).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(&partial[..], b"#TS:17");
+    }
+
+    #[tokio::test]
+    async fn replay_stops_at_the_first_timestamp_past_the_target() {
+        let path = std::env::temp_dir().join(format!("rusty-redis-until-{}.aof", std::process::id()));
+        fs::write(
+            &path,
+            "#TS:100\r\n*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n\
+             #TS:200\r\n*3\r\n$3\r\nSET\r\n$1\r\nb\r\n$1\r\n2\r\n",
+        )
+        .unwrap();
+
+        let until_150 = databases(1);
+        let stopped = replay(&path, 0, &until_150, false, Some(150)).await;
+        let until_200 = databases(1);
+        let whole = replay(&path, 0, &until_200, false, Some(200)).await;
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(stopped.unwrap(), (1, true));
+        assert_eq!(until_150.db(0).len(), 1);
+        assert_eq!(whole.unwrap(), (2, false));
+        assert_eq!(until_200.db(0).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn truncated_last_command_is_dropped_only_when_allowed() {
+        let path = std::env::temp_dir().join(format!("rusty-redis-truncated-{}.aof", std::process::id()));
+        let complete = "*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n";
+        fs::write(&path, format!("{}*3\r\n$3\r\nSET\r\n$1", complete)).unwrap();
+
+        let refused = replay(&path, 0, &databases(1), false, None).await;
+        let allowed = replay(&path, 0, &databases(1), true, None).await;
+        let len = fs::metadata(&path).unwrap().len();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(refused.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(allowed.unwrap(), (1, false));
+        assert_eq!(len, complete.len() as u64);
+    }
+}
@@ -0,0 +1,110 @@
+use clap::Parser;
+use rusty_redis::check::{self, ExportFormat, Summary};
+use rusty_redis::codec::EncryptionKey;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::PathBuf;
+use std::process::exit;
+
+/// Checks a snapshot or append only file without a running server, reporting
+/// where it is corrupt, and repairs or exports it.
+///
+/// Exits with 0 when the file is valid, 1 when it is corrupt and 2 when it
+/// could not be read at all.
+#[derive(Parser, Debug)]
+#[command(name = "rusty-redis-check")]
+pub struct Args {
+    /// snapshot, append only file, manifest or append only directory to check
+    pub path: PathBuf,
+
+    /// key file the files were encrypted with, as given to the server
+    #[arg(long, value_parser = parse_key_file)]
+    pub encryption_key_file: Option<EncryptionKey>,
+
+    /// truncate an append only file to the last valid command
+    #[arg(long)]
+    pub fix: bool,
+
+    /// write every key of a snapshot as json lines or resp commands instead of checking it
+    #[arg(long, value_parser = parse_export_format)]
+    pub export: Option<ExportFormat>,
+
+    /// file the export is written to instead of stdout
+    #[arg(long, requires = "export")]
+    pub output: Option<PathBuf>,
+}
+
+fn parse_key_file(value: &str) -> Result<EncryptionKey, String> {
+    EncryptionKey::read(value)
+}
+
+fn parse_export_format(value: &str) -> Result<ExportFormat, String> {
+    ExportFormat::parse(value).ok_or_else(|| format!("unknown export format '{}', expected json or resp", value))
+}
+
+fn main() {
+    let args = Args::parse();
+    match run(&args) {
+        Ok(code) => exit(code),
+        Err(e) => {
+            eprintln!("{}: {}", args.path.display(), e);
+            exit(2);
+        }
+    }
+}
+
+fn run(args: &Args) -> io::Result<i32> {
+    let key = args.encryption_key_file.as_ref();
+
+    if let Some(format) = args.export {
+        let count = match &args.output {
+            Some(output) => check::export(&args.path, key, format, &mut BufWriter::new(File::create(output)?))?,
+            None => check::export(&args.path, key, format, &mut BufWriter::new(io::stdout().lock()))?,
+        };
+        eprintln!("exported {} keys", count);
+        return Ok(0);
+    }
+
+    let aof = check::is_aof(&args.path, key)?;
+    let summary = if aof { check::check_aof(&args.path, key)? } else { check::check_snapshot(&args.path, key)? };
+    report(&summary, aof);
+
+    let Some(corruption) = &summary.corruption else {
+        return Ok(0);
+    };
+    if args.fix {
+        if !aof {
+            eprintln!("only append only files can be repaired");
+            return Ok(1);
+        }
+        let len = check::repair(corruption)?;
+        println!("truncated {} to {} bytes", corruption.file.display(), len);
+        return Ok(0);
+    }
+    if corruption.truncate_to.is_some() {
+        println!("run again with --fix to truncate it to the last valid command");
+    }
+    Ok(1)
+}
+
+fn report(summary: &Summary, aof: bool) {
+    for (db, keys) in &summary.keys {
+        println!("db{}: {} keys", db, keys);
+    }
+    if aof {
+        println!("{} commands", summary.commands);
+    }
+
+    let Some(corruption) = &summary.corruption else {
+        println!("OK");
+        return;
+    };
+    match corruption.offset {
+        Some(offset) => println!("CORRUPT {} at offset {}: {}", corruption.file.display(), offset, corruption.reason),
+        None => println!("CORRUPT {}: {}", corruption.file.display(), corruption.reason),
+    }
+    match &corruption.last_valid {
+        Some(last) => println!("last valid {}: {}", if aof { "entry" } else { "key" }, last),
+        None => println!("nothing before it is valid"),
+    }
+}
@@ -0,0 +1,347 @@
+//! Offline validation, repair and export of snapshots and append only files,
+//! behind the `rusty-redis-check` tool.
+//!
+//! Files are read with the same code the server loads them with, but keys
+//! and commands are only counted, never executed, so a check can run next to
+//! a live server. Offsets in sealed files count decoded bytes.
+
+use bytes::BytesMut;
+use serde_json::{json, Map};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use crate::aof;
+use crate::cmd;
+use crate::codec::{self, BlockReader, EncryptionKey};
+use crate::connection;
+use crate::frame::Frame;
+use crate::persistence;
+use crate::rdb::{RdbReader, Record};
+use crate::value::Value;
+
+const READ_CHUNK: usize = 64 * 1024;
+
+/// Format written by `export`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// one JSON object per key and line
+    Json,
+    /// the commands recreating every key, as an append only file would hold them
+    Resp,
+}
+
+impl ExportFormat {
+    pub const ALL: [ExportFormat; 2] = [ExportFormat::Json, ExportFormat::Resp];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ExportFormat::Json => "json",
+            ExportFormat::Resp => "resp",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<ExportFormat> {
+        Self::ALL.into_iter().find(|format| format.name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// Where and why a file stops being valid.
+#[derive(Debug)]
+pub struct Corruption {
+    pub file: PathBuf,
+    /// offset of the first invalid command of an append only file
+    pub offset: Option<u64>,
+    pub reason: String,
+    /// the last key or command read intact before the corruption
+    pub last_valid: Option<String>,
+    /// length the file can be truncated to, dropping everything from the
+    /// first invalid command on; only the last file of a log can be repaired
+    pub truncate_to: Option<u64>,
+}
+
+/// What a check found.
+#[derive(Debug, Default)]
+pub struct Summary {
+    /// keys read, by database
+    pub keys: BTreeMap<usize, usize>,
+    /// commands read from append only files
+    pub commands: usize,
+    pub corruption: Option<Corruption>,
+}
+
+/// Whether `path` is an append only file rather than a snapshot: a log
+/// directory, a manifest, a `.aof` file or a file starting with a command.
+pub fn is_aof(path: &Path, key: Option<&EncryptionKey>) -> io::Result<bool> {
+    let name = path.to_string_lossy();
+    if path.is_dir() || name.ends_with(".manifest") || name.ends_with(".aof") {
+        return Ok(true);
+    }
+    Ok(LogInput::open(path, key)?.reader().fill_buf()?.starts_with(b"*"))
+}
+
+/// Reads every key of the snapshot at `path`.
+pub fn check_snapshot(path: &Path, key: Option<&EncryptionKey>) -> io::Result<Summary> {
+    let input = BufReader::new(File::open(path)?);
+    let mut keys = BTreeMap::new();
+    let mut last_valid = None;
+
+    let result = persistence::read_snapshot(input, key, &mut |record| {
+        *keys.entry(record.db).or_default() += 1;
+        last_valid = Some(describe_key(&record));
+        Ok(())
+    });
+
+    let corruption = result.err().map(|e| Corruption {
+        file: path.to_path_buf(),
+        offset: None,
+        reason: reason(&e),
+        last_valid,
+        truncate_to: None,
+    });
+    Ok(Summary { keys, commands: 0, corruption })
+}
+
+/// Reads every file of the append only file at `path`, which is a log
+/// directory, its manifest or a single file, stopping at the first corruption.
+pub fn check_aof(path: &Path, key: Option<&EncryptionKey>) -> io::Result<Summary> {
+    let files = if path.is_dir() {
+        aof::manifest_files(&find_manifest(path)?)?
+    } else if path.to_string_lossy().ends_with(".manifest") {
+        aof::manifest_files(path)?
+    } else {
+        vec![(path.to_path_buf(), false)]
+    };
+
+    let mut summary = Summary::default();
+    for (index, (file, _)) in files.iter().enumerate() {
+        check_log_file(file, key, index == files.len() - 1, &mut summary)?;
+        if summary.corruption.is_some() {
+            break;
+        }
+    }
+    Ok(summary)
+}
+
+/// Drops the invalid end of the file `corruption` was found in.
+pub fn repair(corruption: &Corruption) -> io::Result<u64> {
+    let len = corruption
+        .truncate_to
+        .ok_or_else(|| io::Error::other("only the commands at the end of the last file of a log can be repaired"))?;
+    OpenOptions::new().write(true).open(&corruption.file)?.set_len(len)?;
+    Ok(len)
+}
+
+/// Writes every key of the snapshot at `path` to `out`, returning how many there were.
+pub fn export(path: &Path, key: Option<&EncryptionKey>, format: ExportFormat, out: &mut dyn Write) -> io::Result<usize> {
+    let input = BufReader::new(File::open(path)?);
+    let mut count = 0;
+    let mut selected_db = None;
+    let mut buf = BytesMut::new();
+
+    persistence::read_snapshot(input, key, &mut |record| {
+        count += 1;
+        match format {
+            ExportFormat::Json => {
+                serde_json::to_writer(&mut *out, &json_record(&record))?;
+                out.write_all(b"\n")
+            }
+            ExportFormat::Resp => {
+                if selected_db != Some(record.db) {
+                    let select = aof::command_frame(["SELECT".into(), record.db.to_string().into()]);
+                    connection::serialize_frame(&select, &mut buf);
+                    selected_db = Some(record.db);
+                }
+                connection::serialize_frame(&aof::key_record(record.key, &record.value, record.expire_at_ms), &mut buf);
+                out.write_all(&buf)?;
+                buf.clear();
+                Ok(())
+            }
+        }
+    })?;
+    out.flush()?;
+    Ok(count)
+}
+
+/// The plain contents of a file, decoded when it is sealed.
+enum LogInput {
+    Plain(BufReader<File>),
+    Sealed(BufReader<BlockReader<BufReader<File>>>),
+}
+
+impl LogInput {
+    fn open(path: &Path, key: Option<&EncryptionKey>) -> io::Result<LogInput> {
+        let mut input = BufReader::new(File::open(path)?);
+        if !codec::is_sealed(input.fill_buf()?) {
+            return Ok(LogInput::Plain(input));
+        }
+        Ok(LogInput::Sealed(BufReader::new(BlockReader::appended(input, key)?)))
+    }
+
+    fn reader(&mut self) -> &mut dyn BufRead {
+        match self {
+            LogInput::Plain(input) => input,
+            LogInput::Sealed(input) => input,
+        }
+    }
+
+    /// The length the file can be truncated to so that it ends just before the plain offset `offset`.
+    fn truncation_point(&self, offset: u64) -> u64 {
+        match self {
+            LogInput::Plain(_) => offset,
+            LogInput::Sealed(input) => input.get_ref().frame_boundary(offset),
+        }
+    }
+}
+
+fn check_log_file(path: &Path, key: Option<&EncryptionKey>, repairable: bool, summary: &mut Summary) -> io::Result<()> {
+    let mut input = LogInput::open(path, key)?;
+    let corrupt = |offset: Option<u64>, reason: String, last_valid: Option<String>, truncate_to: Option<u64>| Corruption {
+        file: path.to_path_buf(),
+        offset,
+        reason,
+        last_valid,
+        truncate_to: truncate_to.filter(|_| repairable),
+    };
+
+    let mut offset = 0;
+    let mut last_valid = None;
+    if input.reader().fill_buf()?.starts_with(b"REDIS") {
+        let mut rdb = RdbReader::new(input.reader());
+        let result = rdb.read_file(|record| {
+            *summary.keys.entry(record.db).or_default() += 1;
+            last_valid = Some(describe_key(&record));
+            Ok(())
+        });
+        if let Err(e) = result {
+            summary.corruption = Some(corrupt(None, format!("bad RDB preamble: {}", reason(&e)), last_valid, None));
+            return Ok(());
+        }
+        offset = rdb.offset();
+    }
+
+    let mut buf = BytesMut::with_capacity(READ_CHUNK);
+    let mut chunk = vec![0; READ_CHUNK];
+    loop {
+        let before = buf.len();
+        let parsed = match aof::parse_annotation(&mut buf) {
+            Ok(Some(_)) => {
+                offset += (before - buf.len()) as u64;
+                continue;
+            }
+            Ok(None) => connection::parse_frame(&mut buf),
+            Err(e) => Err(e),
+        };
+        let parsed = match parsed {
+            Ok(frame) => frame,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => None,
+            Err(e) => {
+                let truncate_to = input.truncation_point(offset);
+                summary.corruption = Some(corrupt(Some(offset), e.to_string(), last_valid, Some(truncate_to)));
+                return Ok(());
+            }
+        };
+
+        let Some(frame) = parsed else {
+            let read = match input.reader().read(&mut chunk) {
+                Ok(read) => read,
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    let truncate_to = input.truncation_point(offset);
+                    summary.corruption = Some(corrupt(Some(offset), e.to_string(), last_valid, Some(truncate_to)));
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            };
+            if read > 0 {
+                buf.extend_from_slice(&chunk[..read]);
+                continue;
+            }
+            if !buf.is_empty() {
+                let truncate_to = input.truncation_point(offset);
+                summary.corruption = Some(corrupt(Some(offset), "incomplete command at the end of the file".to_string(), last_valid, Some(truncate_to)));
+            }
+            return Ok(());
+        };
+
+        let description = describe_command(&frame, offset);
+        if let Err(e) = cmd::from_frame(frame) {
+            let truncate_to = input.truncation_point(offset);
+            summary.corruption = Some(corrupt(Some(offset), e.to_string(), last_valid, Some(truncate_to)));
+            return Ok(());
+        }
+        offset += (before - buf.len()) as u64;
+        last_valid = Some(description);
+        summary.commands += 1;
+    }
+}
+
+/// The manifest in the log directory `dir`.
+fn find_manifest(dir: &Path) -> io::Result<PathBuf> {
+    let mut manifests = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.to_string_lossy().ends_with(".manifest") {
+            manifests.push(path);
+        }
+    }
+    match manifests.len() {
+        1 => Ok(manifests.remove(0)),
+        0 => Err(io::Error::new(io::ErrorKind::NotFound, format!("no manifest in {}", dir.display()))),
+        _ => Err(io::Error::other(format!("more than one manifest in {}", dir.display()))),
+    }
+}
+
+fn reason(e: &io::Error) -> String {
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof if e.to_string() == "failed to fill whole buffer" => "file ends unexpectedly".to_string(),
+        _ => e.to_string(),
+    }
+}
+
+fn describe_key(record: &Record) -> String {
+    format!("{:?} in db {}", String::from_utf8_lossy(&record.key), record.db)
+}
+
+/// The command name and first argument of `frame`, which starts at `offset`.
+fn describe_command(frame: &Frame, offset: u64) -> String {
+    let Frame::Array(args) = frame else {
+        return format!("non-command frame at offset {}", offset);
+    };
+    let words: Vec<String> = args
+        .iter()
+        .take(2)
+        .map(|arg| match arg {
+            Frame::Bulk(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+            other => format!("{:?}", other),
+        })
+        .collect();
+    format!("{} at offset {}", words.join(" "), offset)
+}
+
+/// A key as JSON. Bytes that are not UTF-8 are replaced, so RESP is the
+/// format to use for an exact copy.
+fn json_record(record: &Record) -> serde_json::Value {
+    let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
+    let value = match &record.value {
+        Value::String(string) => json!(text(&string.to_bytes())),
+        Value::List(list) => json!(list.iter().map(|item| text(&item)).collect::<Vec<_>>()),
+        Value::Set(set) => json!(set.iter().map(|member| text(&member)).collect::<Vec<_>>()),
+        Value::ZSet(zset) => json!(zset
+            .iter()
+            .map(|(member, score)| json!({ "member": text(&member), "score": score }))
+            .collect::<Vec<_>>()),
+        Value::Hash(hash) => {
+            let fields: Map<String, serde_json::Value> = hash.iter().map(|(field, value)| (text(&field), json!(text(&value)))).collect();
+            json!(fields)
+        }
+    };
+
+    json!({
+        "db": record.db,
+        "key": text(&record.key),
+        "type": record.value.type_name(),
+        "expire_at_ms": record.expire_at_ms,
+        "value": value,
+    })
+}
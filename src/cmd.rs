@@ -1,15 +1,129 @@
 use bytes::Bytes;
+use crate::analyze::{self, Report};
+use crate::db;
 use crate::frame::Frame;
+use crate::scan;
+use crate::sort::SortOptions;
 
 #[derive(Debug)]
 pub enum Command {
-    Get { key: String },
-    Set { key: String, value: Bytes, expiry_seconds: Option<u64> },
-    Ping,
-    Subscribe { channel: String },
-    Publish { channel: String, message: Bytes },
+    Get { key: Bytes },
+    Set { key: Bytes, value: Bytes, expiry: Option<Expiry> },
+    Ping { message: Option<Bytes> },
+    Quit,
+    Reset,
+    Subscribe { channels: Vec<Bytes> },
+    /// no channels unsubscribes from all of them
+    Unsubscribe { channels: Vec<Bytes> },
+    PSubscribe { patterns: Vec<Bytes> },
+    /// no patterns unsubscribes from all of them
+    PUnsubscribe { patterns: Vec<Bytes> },
+    /// the channels must all hash to the same slot
+    SSubscribe { channels: Vec<Bytes> },
+    /// no channels unsubscribes from all shard channels
+    SUnsubscribe { channels: Vec<Bytes> },
+    PubSubChannels { pattern: Option<Bytes>, shard: bool },
+    PubSubNumSub { channels: Vec<Bytes>, shard: bool },
+    PubSubNumPat,
+    Publish { channel: Bytes, message: Bytes },
+    SPublish { channel: Bytes, message: Bytes },
     Save,
-    Del { key: String },
+    BgSave { schedule: bool },
+    LastSave,
+    BgRewriteAof,
+    Del { key: Bytes },
+    Keys { pattern: Bytes },
+    Scan { cursor: u64, pattern: Option<Bytes>, count: usize, key_type: Option<String> },
+    Select { index: i64 },
+    Move { key: Bytes, db: i64 },
+    SwapDb { first: i64, second: i64 },
+    DbSize,
+    FlushDb { lazy: bool },
+    FlushAll { lazy: bool },
+    Dump { key: Bytes },
+    Restore {
+        key: Bytes,
+        ttl_millis: u64,
+        payload: Bytes,
+        replace: bool,
+        absolute_ttl: bool,
+        idle_seconds: Option<u64>,
+        frequency: Option<u8>,
+    },
+    Migrate {
+        host: String,
+        port: u16,
+        keys: Vec<Bytes>,
+        db: i64,
+        timeout_millis: u64,
+        copy: bool,
+        replace: bool,
+        auth: Option<(Option<String>, String)>,
+    },
+    Sort { key: Bytes, options: SortOptions, store: Option<Bytes> },
+    ConfigGet { patterns: Vec<Bytes> },
+    ConfigSet { parameters: Vec<(String, String)> },
+    MemoryUsage { key: Bytes, samples: usize },
+    MemoryStats,
+    MemoryDoctor,
+    ObjectEncoding { key: Bytes },
+    ObjectIdleTime { key: Bytes },
+    ObjectFreq { key: Bytes },
+    ObjectRefCount { key: Bytes },
+    Analyze { report: Report, count: usize },
+    Info { sections: Vec<String> },
+}
+
+/// Collection elements MEMORY USAGE measures when SAMPLES is not given.
+const DEFAULT_MEMORY_SAMPLES: usize = 5;
+
+impl Command {
+    /// Whether the command may grow the dataset and must be refused while
+    /// memory is over `maxmemory` and nothing can be evicted.
+    pub fn denies_on_oom(&self) -> bool {
+        match self {
+            Command::Set { .. } | Command::Restore { .. } => true,
+            Command::Sort { store, .. } => store.is_some(),
+            _ => false,
+        }
+    }
+
+    /// Whether the command may run on a connection subscribed to channels.
+    pub fn allowed_when_subscribed(&self) -> bool {
+        matches!(
+            self,
+            Command::Ping { .. }
+                | Command::Quit
+                | Command::Reset
+                | Command::Subscribe { .. }
+                | Command::Unsubscribe { .. }
+                | Command::PSubscribe { .. }
+                | Command::PUnsubscribe { .. }
+                | Command::SSubscribe { .. }
+                | Command::SUnsubscribe { .. }
+        )
+    }
+}
+
+/// The expiration options of SET.
+#[derive(Clone, Copy, Debug)]
+pub enum Expiry {
+    Seconds(u64),
+    Millis(u64),
+    UnixSeconds(u64),
+    UnixMillis(u64),
+}
+
+impl Expiry {
+    /// The expiration as an absolute unix time in milliseconds.
+    pub fn unix_millis(self) -> u64 {
+        match self {
+            Expiry::Seconds(seconds) => db::unix_millis().saturating_add(seconds.saturating_mul(1000)),
+            Expiry::Millis(millis) => db::unix_millis().saturating_add(millis),
+            Expiry::UnixSeconds(seconds) => seconds.saturating_mul(1000),
+            Expiry::UnixMillis(millis) => millis,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -42,7 +156,20 @@ pub fn from_frame(frame: Frame) -> Result<Command, ParseError> {
             };
 
             match cmd_name.as_str() {
-                "PING" => Ok(Command::Ping),
+                "PING" => match &frames[1..] {
+                    [] => Ok(Command::Ping { message: None }),
+                    [message] => Ok(Command::Ping { message: Some(bulk_bytes(message, "message")?) }),
+                    _ => Err(ParseError::InvalidFormat("PING takes at most 1 argument".to_string())),
+                },
+                "QUIT" => Ok(Command::Quit),
+                "RESET" => {
+                    if frames.len() != 1 {
+                        return Err(ParseError::InvalidFormat(
+                            "RESET takes no arguments".to_string()
+                        ));
+                    }
+                    Ok(Command::Reset)
+                }
                 "GET" => {
                     if frames.len() != 2 {
                         return Err(ParseError::InvalidFormat(
@@ -51,7 +178,7 @@ pub fn from_frame(frame: Frame) -> Result<Command, ParseError> {
                     }
 
                     let key = match &frames[1] {
-                        Frame::Bulk(bytes) => String::from_utf8_lossy(bytes).to_string(),
+                        Frame::Bulk(bytes) => bytes.clone(),
                         _ => return Err(ParseError::InvalidFormat("key must be bulk string".to_string())),
                     };
 
@@ -65,7 +192,7 @@ pub fn from_frame(frame: Frame) -> Result<Command, ParseError> {
                     }
 
                     let key = match &frames[1] {
-                        Frame::Bulk(bytes) => String::from_utf8_lossy(bytes).to_string(),
+                        Frame::Bulk(bytes) => bytes.clone(),
                         _ => return Err(ParseError::InvalidFormat("key must be bulk string".to_string())),
                     };
 
@@ -74,52 +201,120 @@ pub fn from_frame(frame: Frame) -> Result<Command, ParseError> {
                         _ => return Err(ParseError::InvalidFormat("value must be bulk string".to_string())),
                     };
 
-                    let mut expiry_seconds = None;
+                    let mut expiry = None;
                     let mut i = 3;
                     while i < frames.len() {
-                        match &frames[i] {
-                            Frame::Bulk(option) => {
-                                let option_str = String::from_utf8_lossy(option).to_uppercase();
-                                match option_str.as_str() {
-                                    "EX" => {
-                                        if i + 1 >= frames.len() {
-                                            return Err(ParseError::InvalidFormat(
-                                                "EX requires a value".to_string()
-                                            ));
-                                        }
-                                        match &frames[i + 1] {
-                                            Frame::Bulk(seconds) => {
-                                                let seconds_str = String::from_utf8_lossy(seconds);
-                                                expiry_seconds = Some(seconds_str.parse::<u64>().map_err(|_| {
-                                                    ParseError::InvalidFormat("EX value must be an integer".to_string())
-                                                })?);
-                                                i += 2;
-                                            }
-                                            _ => return Err(ParseError::InvalidFormat("EX value must be a bulk string".to_string())),
-                                        }
-                                    }
-                                    _ => return Err(ParseError::InvalidFormat(format!("unknown option '{}'", option_str))),
-                                }
-                            }
-                            _ => return Err(ParseError::InvalidFormat("option must be bulk string".to_string())),
+                        let option_str = bulk_string(&frames[i], "option")?.to_uppercase();
+                        let amount = |name: &str| -> Result<u64, ParseError> {
+                            let frame = frames.get(i + 1).ok_or_else(|| {
+                                ParseError::InvalidFormat(format!("{} requires a value", name))
+                            })?;
+                            let value = integer(frame, &format!("{} value", name))?;
+                            u64::try_from(value)
+                                .ok()
+                                .filter(|value| *value > 0)
+                                .ok_or_else(|| ParseError::InvalidFormat(format!("invalid expire time in '{}'", name)))
+                        };
+                        let parsed = match option_str.as_str() {
+                            "EX" => Expiry::Seconds(amount("EX")?),
+                            "PX" => Expiry::Millis(amount("PX")?),
+                            "EXAT" => Expiry::UnixSeconds(amount("EXAT")?),
+                            "PXAT" => Expiry::UnixMillis(amount("PXAT")?),
+                            _ => return Err(ParseError::InvalidFormat(format!("unknown option '{}'", option_str))),
+                        };
+                        if expiry.replace(parsed).is_some() {
+                            return Err(ParseError::InvalidFormat("only one expiration option is allowed".to_string()));
                         }
+                        i += 2;
                     }
 
-                    Ok(Command::Set { key, value, expiry_seconds })
+                    Ok(Command::Set { key, value, expiry })
                 }
                 "SUBSCRIBE" => {
-                    if frames.len() != 2 {
+                    if frames.len() < 2 {
                         return Err(ParseError::InvalidFormat(
-                            "SUBSCRIBE requires exactly 1 argument".to_string()
+                            "SUBSCRIBE requires at least 1 argument".to_string()
                         ));
                     }
 
-                    let channel = match &frames[1] {
-                        Frame::Bulk(bytes) => String::from_utf8_lossy(bytes).to_string(),
-                        _ => return Err(ParseError::InvalidFormat("channel must be bulk string".to_string())),
-                    };
+                    let channels = frames[1..]
+                        .iter()
+                        .map(|frame| bulk_bytes(frame, "channel"))
+                        .collect::<Result<_, _>>()?;
+                    Ok(Command::Subscribe { channels })
+                }
+                "UNSUBSCRIBE" => {
+                    let channels = frames[1..]
+                        .iter()
+                        .map(|frame| bulk_bytes(frame, "channel"))
+                        .collect::<Result<_, _>>()?;
+                    Ok(Command::Unsubscribe { channels })
+                }
+                "PSUBSCRIBE" => {
+                    if frames.len() < 2 {
+                        return Err(ParseError::InvalidFormat(
+                            "PSUBSCRIBE requires at least 1 argument".to_string()
+                        ));
+                    }
+
+                    let patterns = frames[1..]
+                        .iter()
+                        .map(|frame| bulk_bytes(frame, "pattern"))
+                        .collect::<Result<_, _>>()?;
+                    Ok(Command::PSubscribe { patterns })
+                }
+                "PUNSUBSCRIBE" => {
+                    let patterns = frames[1..]
+                        .iter()
+                        .map(|frame| bulk_bytes(frame, "pattern"))
+                        .collect::<Result<_, _>>()?;
+                    Ok(Command::PUnsubscribe { patterns })
+                }
+                "SSUBSCRIBE" => {
+                    if frames.len() < 2 {
+                        return Err(ParseError::InvalidFormat(
+                            "SSUBSCRIBE requires at least 1 argument".to_string()
+                        ));
+                    }
 
-                    Ok(Command::Subscribe { channel })
+                    let channels = frames[1..]
+                        .iter()
+                        .map(|frame| bulk_bytes(frame, "channel"))
+                        .collect::<Result<_, _>>()?;
+                    Ok(Command::SSubscribe { channels })
+                }
+                "SUNSUBSCRIBE" => {
+                    let channels = frames[1..]
+                        .iter()
+                        .map(|frame| bulk_bytes(frame, "channel"))
+                        .collect::<Result<_, _>>()?;
+                    Ok(Command::SUnsubscribe { channels })
+                }
+                "PUBSUB" => {
+                    if frames.len() < 2 {
+                        return Err(ParseError::InvalidFormat(
+                            "PUBSUB requires a subcommand".to_string()
+                        ));
+                    }
+
+                    let subcommand = bulk_string(&frames[1], "subcommand")?.to_uppercase();
+                    match (subcommand.as_str(), &frames[2..]) {
+                        ("CHANNELS" | "SHARDCHANNELS", []) => Ok(Command::PubSubChannels { pattern: None, shard: subcommand == "SHARDCHANNELS" }),
+                        ("CHANNELS" | "SHARDCHANNELS", [pattern]) => Ok(Command::PubSubChannels {
+                            pattern: Some(bulk_bytes(pattern, "pattern")?),
+                            shard: subcommand == "SHARDCHANNELS",
+                        }),
+                        ("NUMSUB" | "SHARDNUMSUB", channels) => Ok(Command::PubSubNumSub {
+                            channels: channels.iter().map(|frame| bulk_bytes(frame, "channel")).collect::<Result<_, _>>()?,
+                            shard: subcommand == "SHARDNUMSUB",
+                        }),
+                        ("NUMPAT", []) => Ok(Command::PubSubNumPat),
+                        ("CHANNELS" | "SHARDCHANNELS" | "NUMPAT", _) => Err(ParseError::InvalidFormat(format!(
+                            "wrong number of arguments for 'PUBSUB {}'",
+                            subcommand
+                        ))),
+                        _ => Err(ParseError::InvalidCommand(format!("unknown subcommand 'PUBSUB {}'", subcommand))),
+                    }
                 }
                 "PUBLISH" => {
                     if frames.len() != 3 {
@@ -129,7 +324,7 @@ pub fn from_frame(frame: Frame) -> Result<Command, ParseError> {
                     }
 
                     let channel = match &frames[1] {
-                        Frame::Bulk(bytes) => String::from_utf8_lossy(bytes).to_string(),
+                        Frame::Bulk(bytes) => bytes.clone(),
                         _ => return Err(ParseError::InvalidFormat("channel must be bulk string".to_string())),
                     };
 
@@ -140,6 +335,17 @@ pub fn from_frame(frame: Frame) -> Result<Command, ParseError> {
 
                     Ok(Command::Publish { channel, message })
                 }
+                "SPUBLISH" => {
+                    if frames.len() != 3 {
+                        return Err(ParseError::InvalidFormat(
+                            "SPUBLISH requires exactly 2 arguments".to_string()
+                        ));
+                    }
+
+                    let channel = bulk_bytes(&frames[1], "channel")?;
+                    let message = bulk_bytes(&frames[2], "message")?;
+                    Ok(Command::SPublish { channel, message })
+                }
                 "SAVE" => {
                     if frames.len() != 1 {
                         return Err(ParseError::InvalidFormat(
@@ -148,6 +354,39 @@ pub fn from_frame(frame: Frame) -> Result<Command, ParseError> {
                     }
                     Ok(Command::Save)
                 }
+                "BGSAVE" => {
+                    let schedule = match &frames[1..] {
+                        [] => false,
+                        [option] if bulk_string(option, "option")?.eq_ignore_ascii_case("SCHEDULE") => true,
+                        _ => return Err(ParseError::InvalidFormat(
+                            "BGSAVE only accepts SCHEDULE".to_string()
+                        )),
+                    };
+                    Ok(Command::BgSave { schedule })
+                }
+                "LASTSAVE" => {
+                    if frames.len() != 1 {
+                        return Err(ParseError::InvalidFormat(
+                            "LASTSAVE takes no arguments".to_string()
+                        ));
+                    }
+                    Ok(Command::LastSave)
+                }
+                "INFO" => {
+                    let sections = frames[1..]
+                        .iter()
+                        .map(|frame| bulk_string(frame, "section").map(|section| section.to_lowercase()))
+                        .collect::<Result<_, _>>()?;
+                    Ok(Command::Info { sections })
+                }
+                "BGREWRITEAOF" => {
+                    if frames.len() != 1 {
+                        return Err(ParseError::InvalidFormat(
+                            "BGREWRITEAOF takes no arguments".to_string()
+                        ));
+                    }
+                    Ok(Command::BgRewriteAof)
+                }
                 "DEL" => {
                     if frames.len() != 2 {
                         return Err(ParseError::InvalidFormat(
@@ -156,15 +395,464 @@ pub fn from_frame(frame: Frame) -> Result<Command, ParseError> {
                     }
 
                     let key = match &frames[1] {
-                        Frame::Bulk(bytes) => String::from_utf8_lossy(bytes).to_string(),
+                        Frame::Bulk(bytes) => bytes.clone(),
                         _ => return Err(ParseError::InvalidFormat("key must be bulk string".to_string())),
                     };
 
                     Ok(Command::Del { key })
                 }
+                "KEYS" => {
+                    if frames.len() != 2 {
+                        return Err(ParseError::InvalidFormat(
+                            "KEYS requires exactly 1 argument".to_string()
+                        ));
+                    }
+
+                    let pattern = match &frames[1] {
+                        Frame::Bulk(bytes) => bytes.clone(),
+                        _ => return Err(ParseError::InvalidFormat("pattern must be bulk string".to_string())),
+                    };
+
+                    Ok(Command::Keys { pattern })
+                }
+                "SCAN" => {
+                    if frames.len() < 2 {
+                        return Err(ParseError::InvalidFormat(
+                            "SCAN requires at least 1 argument".to_string()
+                        ));
+                    }
+
+                    let cursor = match &frames[1] {
+                        Frame::Bulk(bytes) => String::from_utf8_lossy(bytes).parse::<u64>().map_err(|_| {
+                            ParseError::InvalidFormat("invalid cursor".to_string())
+                        })?,
+                        _ => return Err(ParseError::InvalidFormat("cursor must be bulk string".to_string())),
+                    };
+
+                    let mut pattern = None;
+                    let mut count = scan::DEFAULT_COUNT;
+                    let mut key_type = None;
+                    let mut i = 2;
+                    while i < frames.len() {
+                        let option_str = match &frames[i] {
+                            Frame::Bulk(option) => String::from_utf8_lossy(option).to_uppercase(),
+                            _ => return Err(ParseError::InvalidFormat("option must be bulk string".to_string())),
+                        };
+                        let value = match frames.get(i + 1) {
+                            Some(Frame::Bulk(value)) => value.clone(),
+                            Some(_) => return Err(ParseError::InvalidFormat(
+                                format!("{} value must be a bulk string", option_str)
+                            )),
+                            None => return Err(ParseError::InvalidFormat(
+                                format!("{} requires a value", option_str)
+                            )),
+                        };
+                        match option_str.as_str() {
+                            "MATCH" => pattern = Some(value),
+                            "COUNT" => {
+                                count = String::from_utf8_lossy(&value).parse::<usize>().ok().filter(|count| *count > 0).ok_or_else(|| {
+                                    ParseError::InvalidFormat("COUNT value must be a positive integer".to_string())
+                                })?;
+                            }
+                            "TYPE" => key_type = Some(String::from_utf8_lossy(&value).to_string()),
+                            _ => return Err(ParseError::InvalidFormat(format!("unknown option '{}'", option_str))),
+                        }
+                        i += 2;
+                    }
+
+                    Ok(Command::Scan { cursor, pattern, count, key_type })
+                }
+                "SELECT" => {
+                    if frames.len() != 2 {
+                        return Err(ParseError::InvalidFormat(
+                            "SELECT requires exactly 1 argument".to_string()
+                        ));
+                    }
+
+                    let index = integer(&frames[1], "DB index")?;
+                    Ok(Command::Select { index })
+                }
+                "MOVE" => {
+                    if frames.len() != 3 {
+                        return Err(ParseError::InvalidFormat(
+                            "MOVE requires exactly 2 arguments".to_string()
+                        ));
+                    }
+
+                    let key = bulk_bytes(&frames[1], "key")?;
+                    let db = integer(&frames[2], "DB index")?;
+                    Ok(Command::Move { key, db })
+                }
+                "SWAPDB" => {
+                    if frames.len() != 3 {
+                        return Err(ParseError::InvalidFormat(
+                            "SWAPDB requires exactly 2 arguments".to_string()
+                        ));
+                    }
+
+                    let first = integer(&frames[1], "DB index")?;
+                    let second = integer(&frames[2], "DB index")?;
+                    Ok(Command::SwapDb { first, second })
+                }
+                "DBSIZE" => {
+                    if frames.len() != 1 {
+                        return Err(ParseError::InvalidFormat(
+                            "DBSIZE takes no arguments".to_string()
+                        ));
+                    }
+                    Ok(Command::DbSize)
+                }
+                "FLUSHDB" | "FLUSHALL" => {
+                    let lazy = match frames.len() {
+                        1 => false,
+                        2 => match bulk_string(&frames[1], "flush mode")?.to_uppercase().as_str() {
+                            "ASYNC" => true,
+                            "SYNC" => false,
+                            other => return Err(ParseError::InvalidFormat(format!("unknown option '{}'", other))),
+                        },
+                        _ => return Err(ParseError::InvalidFormat(
+                            format!("{} takes at most 1 argument", cmd_name)
+                        )),
+                    };
+
+                    if cmd_name == "FLUSHDB" {
+                        Ok(Command::FlushDb { lazy })
+                    } else {
+                        Ok(Command::FlushAll { lazy })
+                    }
+                }
+                "DUMP" => {
+                    if frames.len() != 2 {
+                        return Err(ParseError::InvalidFormat(
+                            "DUMP requires exactly 1 argument".to_string()
+                        ));
+                    }
+
+                    let key = bulk_bytes(&frames[1], "key")?;
+                    Ok(Command::Dump { key })
+                }
+                "RESTORE" => {
+                    if frames.len() < 4 {
+                        return Err(ParseError::InvalidFormat(
+                            "RESTORE requires at least 3 arguments".to_string()
+                        ));
+                    }
+
+                    let key = bulk_bytes(&frames[1], "key")?;
+                    let ttl_millis = u64::try_from(integer(&frames[2], "TTL")?).map_err(|_| {
+                        ParseError::InvalidFormat("TTL must be >= 0".to_string())
+                    })?;
+                    let payload = bulk_bytes(&frames[3], "payload")?;
+
+                    let mut replace = false;
+                    let mut absolute_ttl = false;
+                    let mut idle_seconds = None;
+                    let mut frequency = None;
+                    let mut i = 4;
+                    while i < frames.len() {
+                        let option_str = bulk_string(&frames[i], "option")?.to_uppercase();
+                        match option_str.as_str() {
+                            "REPLACE" => replace = true,
+                            "ABSTTL" => absolute_ttl = true,
+                            "IDLETIME" if frequency.is_none() => {
+                                let value = frames.get(i + 1).ok_or_else(|| {
+                                    ParseError::InvalidFormat("IDLETIME requires a value".to_string())
+                                })?;
+                                idle_seconds = Some(u64::try_from(integer(value, "IDLETIME")?).map_err(|_| {
+                                    ParseError::InvalidFormat("IDLETIME must be >= 0".to_string())
+                                })?);
+                                i += 1;
+                            }
+                            "FREQ" if idle_seconds.is_none() => {
+                                let value = frames.get(i + 1).ok_or_else(|| {
+                                    ParseError::InvalidFormat("FREQ requires a value".to_string())
+                                })?;
+                                frequency = Some(u8::try_from(integer(value, "FREQ")?).map_err(|_| {
+                                    ParseError::InvalidFormat("FREQ must be >= 0 and <= 255".to_string())
+                                })?);
+                                i += 1;
+                            }
+                            "IDLETIME" | "FREQ" => {
+                                return Err(ParseError::InvalidFormat(
+                                    "IDLETIME and FREQ are mutually exclusive".to_string()
+                                ));
+                            }
+                            _ => return Err(ParseError::InvalidFormat(format!("unknown option '{}'", option_str))),
+                        }
+                        i += 1;
+                    }
+
+                    Ok(Command::Restore { key, ttl_millis, payload, replace, absolute_ttl, idle_seconds, frequency })
+                }
+                "MIGRATE" => {
+                    if frames.len() < 6 {
+                        return Err(ParseError::InvalidFormat(
+                            "MIGRATE requires at least 5 arguments".to_string()
+                        ));
+                    }
+
+                    let host = bulk_string(&frames[1], "host")?;
+                    let port = u16::try_from(integer(&frames[2], "port")?).map_err(|_| {
+                        ParseError::InvalidFormat("port is out of range".to_string())
+                    })?;
+                    let key = bulk_bytes(&frames[3], "key")?;
+                    let db = integer(&frames[4], "destination-db")?;
+                    let timeout_millis = u64::try_from(integer(&frames[5], "timeout")?).map_err(|_| {
+                        ParseError::InvalidFormat("timeout must be >= 0".to_string())
+                    })?;
+
+                    let mut copy = false;
+                    let mut replace = false;
+                    let mut auth = None;
+                    let mut keys = Vec::new();
+                    let mut i = 6;
+                    while i < frames.len() {
+                        let option_str = bulk_string(&frames[i], "option")?.to_uppercase();
+                        match option_str.as_str() {
+                            "COPY" => copy = true,
+                            "REPLACE" => replace = true,
+                            "AUTH" => {
+                                let password = frames.get(i + 1).ok_or_else(|| {
+                                    ParseError::InvalidFormat("AUTH requires a password".to_string())
+                                })?;
+                                auth = Some((None, bulk_string(password, "password")?));
+                                i += 1;
+                            }
+                            "AUTH2" => {
+                                let (Some(username), Some(password)) = (frames.get(i + 1), frames.get(i + 2)) else {
+                                    return Err(ParseError::InvalidFormat(
+                                        "AUTH2 requires a username and a password".to_string()
+                                    ));
+                                };
+                                auth = Some((Some(bulk_string(username, "username")?), bulk_string(password, "password")?));
+                                i += 2;
+                            }
+                            "KEYS" => {
+                                if !key.is_empty() {
+                                    return Err(ParseError::InvalidFormat(
+                                        "when using KEYS the key argument must be an empty string".to_string()
+                                    ));
+                                }
+                                for frame in &frames[i + 1..] {
+                                    keys.push(bulk_bytes(frame, "key")?);
+                                }
+                                break;
+                            }
+                            _ => return Err(ParseError::InvalidFormat(format!("unknown option '{}'", option_str))),
+                        }
+                        i += 1;
+                    }
+
+                    if keys.is_empty() {
+                        keys.push(key);
+                    }
+
+                    Ok(Command::Migrate { host, port, keys, db, timeout_millis, copy, replace, auth })
+                }
+                "SORT" | "SORT_RO" => {
+                    if frames.len() < 2 {
+                        return Err(ParseError::InvalidFormat(
+                            format!("{} requires at least 1 argument", cmd_name)
+                        ));
+                    }
+
+                    let key = bulk_bytes(&frames[1], "key")?;
+                    let mut options = SortOptions::default();
+                    let mut store = None;
+                    let mut i = 2;
+                    while i < frames.len() {
+                        let option_str = bulk_string(&frames[i], "option")?.to_uppercase();
+                        let value = |offset: usize| {
+                            frames.get(i + offset).ok_or_else(|| {
+                                ParseError::InvalidFormat(format!("{} requires a value", option_str))
+                            })
+                        };
+                        match option_str.as_str() {
+                            "ASC" => options.descending = false,
+                            "DESC" => options.descending = true,
+                            "ALPHA" => options.alpha = true,
+                            "BY" => {
+                                options.by = Some(bulk_bytes(value(1)?, "BY pattern")?);
+                                i += 1;
+                            }
+                            "GET" => {
+                                options.get.push(bulk_bytes(value(1)?, "GET pattern")?);
+                                i += 1;
+                            }
+                            "LIMIT" => {
+                                let offset = integer(value(1)?, "LIMIT offset")?;
+                                let count = integer(value(2)?, "LIMIT count")?;
+                                options.limit = Some((offset, count));
+                                i += 2;
+                            }
+                            "STORE" if cmd_name == "SORT" => {
+                                store = Some(bulk_bytes(value(1)?, "STORE destination")?);
+                                i += 1;
+                            }
+                            _ => return Err(ParseError::InvalidFormat(format!("unknown option '{}'", option_str))),
+                        }
+                        i += 1;
+                    }
+
+                    Ok(Command::Sort { key, options, store })
+                }
+                "CONFIG" => {
+                    if frames.len() < 2 {
+                        return Err(ParseError::InvalidFormat(
+                            "CONFIG requires a subcommand".to_string()
+                        ));
+                    }
+
+                    let subcommand = bulk_string(&frames[1], "subcommand")?.to_uppercase();
+                    match subcommand.as_str() {
+                        "GET" => {
+                            if frames.len() < 3 {
+                                return Err(ParseError::InvalidFormat(
+                                    "CONFIG GET requires at least 1 argument".to_string()
+                                ));
+                            }
+                            let patterns = frames[2..]
+                                .iter()
+                                .map(|frame| bulk_bytes(frame, "pattern"))
+                                .collect::<Result<_, _>>()?;
+                            Ok(Command::ConfigGet { patterns })
+                        }
+                        "SET" => {
+                            if frames.len() < 4 || frames.len() % 2 != 0 {
+                                return Err(ParseError::InvalidFormat(
+                                    "CONFIG SET requires parameter and value pairs".to_string()
+                                ));
+                            }
+                            let parameters = frames[2..]
+                                .chunks(2)
+                                .map(|pair| Ok((bulk_string(&pair[0], "parameter")?, bulk_string(&pair[1], "value")?)))
+                                .collect::<Result<_, ParseError>>()?;
+                            Ok(Command::ConfigSet { parameters })
+                        }
+                        _ => Err(ParseError::InvalidCommand(format!("unknown subcommand 'CONFIG {}'", subcommand))),
+                    }
+                }
+                "MEMORY" => {
+                    if frames.len() < 2 {
+                        return Err(ParseError::InvalidFormat(
+                            "MEMORY requires a subcommand".to_string()
+                        ));
+                    }
+
+                    let subcommand = bulk_string(&frames[1], "subcommand")?.to_uppercase();
+                    match subcommand.as_str() {
+                        "USAGE" => {
+                            let key = match frames.get(2) {
+                                Some(frame) => bulk_bytes(frame, "key")?,
+                                None => return Err(ParseError::InvalidFormat(
+                                    "MEMORY USAGE requires a key".to_string()
+                                )),
+                            };
+                            let samples = match &frames[3..] {
+                                [] => DEFAULT_MEMORY_SAMPLES,
+                                [option, count] if bulk_string(option, "option")?.eq_ignore_ascii_case("SAMPLES") => {
+                                    let count = integer(count, "SAMPLES count")?;
+                                    usize::try_from(count).map_err(|_| {
+                                        ParseError::InvalidFormat("SAMPLES count must not be negative".to_string())
+                                    })?
+                                }
+                                _ => return Err(ParseError::InvalidFormat(
+                                    "MEMORY USAGE only accepts SAMPLES count".to_string()
+                                )),
+                            };
+                            Ok(Command::MemoryUsage { key, samples })
+                        }
+                        "STATS" | "DOCTOR" => {
+                            if frames.len() != 2 {
+                                return Err(ParseError::InvalidFormat(
+                                    format!("MEMORY {} takes no arguments", subcommand)
+                                ));
+                            }
+                            if subcommand == "STATS" {
+                                Ok(Command::MemoryStats)
+                            } else {
+                                Ok(Command::MemoryDoctor)
+                            }
+                        }
+                        _ => Err(ParseError::InvalidCommand(format!("unknown subcommand 'MEMORY {}'", subcommand))),
+                    }
+                }
+                "ANALYZE" => {
+                    let report = match frames.get(1) {
+                        Some(frame) => match bulk_string(frame, "report")?.to_uppercase().as_str() {
+                            "BIGKEYS" => Report::BigKeys,
+                            "HOTKEYS" => Report::HotKeys,
+                            other => return Err(ParseError::InvalidFormat(format!("unknown report '{}'", other))),
+                        },
+                        None => return Err(ParseError::InvalidFormat(
+                            "ANALYZE requires BIGKEYS or HOTKEYS".to_string()
+                        )),
+                    };
+                    let count = match &frames[2..] {
+                        [] => analyze::DEFAULT_COUNT,
+                        [option, count] if bulk_string(option, "option")?.eq_ignore_ascii_case("COUNT") => {
+                            usize::try_from(integer(count, "COUNT")?)
+                                .ok()
+                                .filter(|count| *count > 0)
+                                .ok_or_else(|| ParseError::InvalidFormat("COUNT must be positive".to_string()))?
+                        }
+                        _ => return Err(ParseError::InvalidFormat(
+                            "ANALYZE only accepts COUNT n".to_string()
+                        )),
+                    };
+                    Ok(Command::Analyze { report, count })
+                }
+                "OBJECT" => {
+                    if frames.len() != 3 {
+                        return Err(ParseError::InvalidFormat(
+                            "OBJECT requires a subcommand and a key".to_string()
+                        ));
+                    }
+
+                    let subcommand = bulk_string(&frames[1], "subcommand")?.to_uppercase();
+                    let key = bulk_bytes(&frames[2], "key")?;
+                    match subcommand.as_str() {
+                        "ENCODING" => Ok(Command::ObjectEncoding { key }),
+                        "IDLETIME" => Ok(Command::ObjectIdleTime { key }),
+                        "FREQ" => Ok(Command::ObjectFreq { key }),
+                        "REFCOUNT" => Ok(Command::ObjectRefCount { key }),
+                        _ => Err(ParseError::InvalidCommand(format!("unknown subcommand 'OBJECT {}'", subcommand))),
+                    }
+                }
                 _ => Err(ParseError::InvalidCommand(format!("unknown command '{}'", cmd_name))),
             }
         }
         _ => Err(ParseError::InvalidFormat("command must be an array".to_string())),
     }
 }
+
+/// The lowercase name of the command in `frame`, for error messages.
+pub fn name(frame: &Frame) -> String {
+    match frame {
+        Frame::Array(frames) => match frames.first() {
+            Some(Frame::Bulk(bytes)) => String::from_utf8_lossy(bytes).to_lowercase(),
+            _ => String::new(),
+        },
+        _ => String::new(),
+    }
+}
+
+fn bulk_bytes(frame: &Frame, what: &str) -> Result<Bytes, ParseError> {
+    match frame {
+        Frame::Bulk(bytes) => Ok(bytes.clone()),
+        _ => Err(ParseError::InvalidFormat(format!("{} must be bulk string", what))),
+    }
+}
+
+fn bulk_string(frame: &Frame, what: &str) -> Result<String, ParseError> {
+    match frame {
+        Frame::Bulk(bytes) => Ok(String::from_utf8_lossy(bytes).to_string()),
+        _ => Err(ParseError::InvalidFormat(format!("{} must be bulk string", what))),
+    }
+}
+
+fn integer(frame: &Frame, what: &str) -> Result<i64, ParseError> {
+    bulk_string(frame, what)?
+        .parse::<i64>()
+        .map_err(|_| ParseError::InvalidFormat(format!("{} must be an integer", what)))
+}
@@ -0,0 +1,582 @@
+//! Compression and encryption of persistence files.
+//!
+//! A sealed file starts with a header naming the codecs it was written with
+//! and a random file id, followed by frames. Each frame is a little-endian
+//! u32 holding the payload length, with the top bit set on the last frame of
+//! a file that is written in one go, then the payload: the block compressed
+//! with the file's compression and, when encrypted, a random nonce followed
+//! by the XChaCha20-Poly1305 ciphertext. The header, the frame index and the
+//! length word are authenticated with each frame, so frames cannot be
+//! altered, reordered, moved between files or dropped from the end of a
+//! snapshot without the file being rejected.
+//!
+//! What is sealed is the file as it would otherwise be written, so any
+//! snapshot format and the append only file can be sealed alike, and readers
+//! detect sealed files from their header and decode them transparently.
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use std::fmt;
+use std::io::{self, Read};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+pub const MAGIC: &[u8] = b"RUSTYBLK";
+const VERSION: u8 = 1;
+const FILE_ID_LEN: usize = 16;
+/// Length of the header of a sealed file.
+pub const HEADER_LEN: usize = MAGIC.len() + 3 + FILE_ID_LEN;
+
+/// Plain bytes per frame of a file written in one go.
+pub const BLOCK_SIZE: usize = 256 * 1024;
+const LAST_FRAME: u32 = 1 << 31;
+/// The most an LZ4 block expands, a bound on its declared size.
+const LZ4_MAX_RATIO: usize = 255;
+const NONCE_LEN: usize = 24;
+const ENCRYPTION_NONE: u8 = 0;
+const ENCRYPTION_XCHACHA20_POLY1305: u8 = 1;
+
+/// Compression applied to each frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    /// fast, with a moderate ratio
+    Lz4,
+    /// slower, with a better ratio
+    Zstd,
+}
+
+impl Compression {
+    pub const ALL: [Compression; 3] = [Compression::None, Compression::Lz4, Compression::Zstd];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Lz4 => "lz4",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Compression> {
+        Self::ALL.into_iter().find(|compression| compression.name().eq_ignore_ascii_case(name))
+    }
+
+    fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Zstd => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Compression> {
+        Self::ALL.into_iter().find(|compression| compression.id() == id)
+    }
+}
+
+/// A 256-bit key read from a key file.
+#[derive(Clone)]
+pub struct EncryptionKey {
+    path: String,
+    key: [u8; 32],
+}
+
+impl EncryptionKey {
+    /// Reads the key file at `path`, holding either the 32 key bytes or
+    /// their 64 hex digits.
+    pub fn read(path: &str) -> Result<EncryptionKey, String> {
+        let data = std::fs::read(path).map_err(|e| format!("failed to read key file '{}': {}", path, e))?;
+        let invalid = || format!("key file '{}' must hold 32 bytes or 64 hex digits", path);
+
+        let key = if data.len() == 32 {
+            data.try_into().map_err(|_| invalid())?
+        } else {
+            let text = std::str::from_utf8(&data).map_err(|_| invalid())?.trim();
+            if text.len() != 64 || !text.is_ascii() {
+                return Err(invalid());
+            }
+            let mut key = [0; 32];
+            for (byte, digits) in key.iter_mut().zip(text.as_bytes().chunks(2)) {
+                let digits = std::str::from_utf8(digits).map_err(|_| invalid())?;
+                *byte = u8::from_str_radix(digits, 16).map_err(|_| invalid())?;
+            }
+            key
+        };
+        Ok(EncryptionKey { path: path.to_string(), key })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new((&self.key).into())
+    }
+}
+
+// the key itself is never printed
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptionKey").field("path", &self.path).finish_non_exhaustive()
+    }
+}
+
+/// The codecs files are written with.
+#[derive(Clone, Debug)]
+pub struct Codecs {
+    pub compression: Compression,
+    pub key: Option<EncryptionKey>,
+}
+
+impl Codecs {
+    /// Whether files are written as is, without a sealed header.
+    pub fn is_plain(&self) -> bool {
+        self.compression == Compression::None && self.key.is_none()
+    }
+}
+
+/// Whether `prefix`, the start of a file, is the header of a sealed file.
+pub fn is_sealed(prefix: &[u8]) -> bool {
+    prefix.starts_with(MAGIC)
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+/// What the frame at `index` is authenticated with.
+fn associated_data(header: &[u8], index: u64, word: u32) -> Vec<u8> {
+    let mut aad = header.to_vec();
+    aad.extend_from_slice(&index.to_le_bytes());
+    aad.extend_from_slice(&word.to_le_bytes());
+    aad
+}
+
+/// Turns plain blocks into frames.
+pub struct Sealer {
+    header: Vec<u8>,
+    compression: Compression,
+    cipher: Option<XChaCha20Poly1305>,
+    index: u64,
+}
+
+impl Sealer {
+    /// A sealer for a new file with a random id.
+    pub fn new(codecs: &Codecs) -> Sealer {
+        let mut header = MAGIC.to_vec();
+        header.push(VERSION);
+        header.push(codecs.compression.id());
+        header.push(if codecs.key.is_some() { ENCRYPTION_XCHACHA20_POLY1305 } else { ENCRYPTION_NONE });
+        let mut id = [0; FILE_ID_LEN];
+        OsRng.fill_bytes(&mut id);
+        header.extend_from_slice(&id);
+
+        Sealer {
+            header,
+            compression: codecs.compression,
+            cipher: codecs.key.as_ref().map(EncryptionKey::cipher),
+            index: 0,
+        }
+    }
+
+    /// A sealer appending to a file that starts with `header` and already holds `frames` frames.
+    pub fn resume(header: &[u8], frames: u64, key: Option<&EncryptionKey>) -> io::Result<Sealer> {
+        let opener = Opener::new(header, key)?;
+        Ok(Sealer { header: opener.header, compression: opener.compression, cipher: opener.cipher, index: frames })
+    }
+
+    /// The header the file must start with.
+    pub fn header(&self) -> &[u8] {
+        &self.header
+    }
+
+    /// Seals `block` into a frame, marked as the end of the file if `last`.
+    pub fn seal(&mut self, block: &[u8], last: bool) -> io::Result<Vec<u8>> {
+        let compressed = match self.compression {
+            Compression::None => block.to_vec(),
+            Compression::Lz4 => lz4_flex::compress_prepend_size(block),
+            Compression::Zstd => zstd::bulk::compress(block, 0)?,
+        };
+        let payload_len = compressed.len() + if self.cipher.is_some() { NONCE_LEN + 16 } else { 0 };
+        let length = u32::try_from(payload_len).ok().filter(|len| *len < LAST_FRAME).ok_or_else(|| invalid("block too large"))?;
+        let word = if last { length | LAST_FRAME } else { length };
+
+        let mut frame = Vec::with_capacity(4 + payload_len);
+        frame.extend_from_slice(&word.to_le_bytes());
+        match &self.cipher {
+            Some(cipher) => {
+                let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+                let aad = associated_data(&self.header, self.index, word);
+                let ciphertext = cipher
+                    .encrypt(&nonce, Payload { msg: &compressed, aad: &aad })
+                    .map_err(|_| invalid("encryption failed"))?;
+                frame.extend_from_slice(&nonce);
+                frame.extend_from_slice(&ciphertext);
+            }
+            None => frame.extend_from_slice(&compressed),
+        }
+        self.index += 1;
+        Ok(frame)
+    }
+}
+
+/// Turns the frames of a sealed file back into plain blocks.
+pub struct Opener {
+    header: Vec<u8>,
+    compression: Compression,
+    cipher: Option<XChaCha20Poly1305>,
+    index: u64,
+}
+
+impl Opener {
+    /// An opener for the file starting with `header`, which must be encrypted with `key` if at all.
+    pub fn new(header: &[u8], key: Option<&EncryptionKey>) -> io::Result<Opener> {
+        if header.len() < HEADER_LEN || !is_sealed(header) {
+            return Err(invalid("not a sealed file"));
+        }
+        let header = &header[..HEADER_LEN];
+        let [version, compression, encryption] = header[MAGIC.len()..MAGIC.len() + 3] else {
+            unreachable!("the header length was checked")
+        };
+        if version != VERSION {
+            return Err(invalid(format!("unsupported sealed file version {}", version)));
+        }
+        let compression = Compression::from_id(compression)
+            .ok_or_else(|| invalid(format!("unknown compression {}", compression)))?;
+        let cipher = match (encryption, key) {
+            (ENCRYPTION_NONE, _) => None,
+            (ENCRYPTION_XCHACHA20_POLY1305, Some(key)) => Some(key.cipher()),
+            (ENCRYPTION_XCHACHA20_POLY1305, None) => {
+                return Err(invalid("file is encrypted, set encryption-key-file to read it"));
+            }
+            (other, _) => return Err(invalid(format!("unknown encryption {}", other))),
+        };
+
+        Ok(Opener { header: header.to_vec(), compression, cipher, index: 0 })
+    }
+
+    /// Opens the frame with length word `word` and `payload`, returning its block.
+    pub fn open(&mut self, word: u32, payload: &[u8]) -> io::Result<Vec<u8>> {
+        let compressed = match &self.cipher {
+            Some(cipher) => {
+                if payload.len() < NONCE_LEN {
+                    return Err(invalid("frame too short"));
+                }
+                let (nonce, ciphertext) = payload.split_at(NONCE_LEN);
+                let aad = associated_data(&self.header, self.index, word);
+                cipher
+                    .decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad: &aad })
+                    .map_err(|_| invalid(format!("frame {} failed authentication (wrong key or tampered file)", self.index)))?
+            }
+            None => payload.to_vec(),
+        };
+        self.index += 1;
+
+        match self.compression {
+            Compression::None => Ok(compressed),
+            Compression::Lz4 => {
+                // the size prepended to the block is reserved before decompressing
+                let size = compressed.get(..4).map_or(0, |size| u32::from_le_bytes(size.try_into().unwrap()) as usize);
+                if size > compressed.len().saturating_mul(LZ4_MAX_RATIO) {
+                    return Err(invalid(format!("frame {} expands to more than LZ4 can", self.index - 1)));
+                }
+                lz4_flex::decompress_size_prepended(&compressed).map_err(|e| invalid(e.to_string()))
+            }
+            Compression::Zstd => zstd::decode_all(compressed.as_slice()),
+        }
+    }
+}
+
+/// Length of the payload following the length word `word`, and whether it is the last frame.
+pub fn frame_len(word: u32) -> (usize, bool) {
+    ((word & !LAST_FRAME) as usize, word & LAST_FRAME != 0)
+}
+
+/// Reads the next frame, or `None` at the end of the file. A frame cut
+/// short fails with `UnexpectedEof`.
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<(u32, Vec<u8>)>> {
+    let mut word = [0; 4];
+    let mut filled = 0;
+    while filled < word.len() {
+        match reader.read(&mut word[filled..]).await? {
+            0 if filled == 0 => return Ok(None),
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => filled += n,
+        }
+    }
+    let word = u32::from_le_bytes(word);
+    let len = frame_len(word).0;
+    // the length word is not authenticated until the frame is opened, so
+    // the payload grows as it is read rather than being allocated up front
+    let mut payload = Vec::new();
+    reader.take(len as u64).read_to_end(&mut payload).await?;
+    if payload.len() != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(Some((word, payload)))
+}
+
+/// Reads the plain contents of a sealed file, failing if it ends in the
+/// middle of a frame or, for a file written in one go, before its last frame.
+pub struct BlockReader<R> {
+    inner: R,
+    opener: Opener,
+    block: Vec<u8>,
+    pos: usize,
+    done: bool,
+    /// whether the file is appended to and so never has a last frame
+    appended: bool,
+    /// plain and file offsets of the end of each frame read so far
+    frame_ends: Vec<(u64, u64)>,
+}
+
+impl<R: Read> BlockReader<R> {
+    pub fn new(inner: R, key: Option<&EncryptionKey>) -> io::Result<BlockReader<R>> {
+        BlockReader::with_mode(inner, key, false)
+    }
+
+    /// A reader for an append only file, which may end after any frame.
+    pub fn appended(inner: R, key: Option<&EncryptionKey>) -> io::Result<BlockReader<R>> {
+        BlockReader::with_mode(inner, key, true)
+    }
+
+    fn with_mode(mut inner: R, key: Option<&EncryptionKey>, appended: bool) -> io::Result<BlockReader<R>> {
+        let mut header = [0; HEADER_LEN];
+        inner.read_exact(&mut header)?;
+        let opener = Opener::new(&header, key)?;
+        Ok(BlockReader { inner, opener, block: Vec::new(), pos: 0, done: false, appended, frame_ends: Vec::new() })
+    }
+
+    /// The file offset of the end of the last frame ending at or before the plain offset `plain`.
+    pub fn frame_boundary(&self, plain: u64) -> u64 {
+        self.frame_ends
+            .iter()
+            .rev()
+            .find(|(plain_end, _)| *plain_end <= plain)
+            .map_or(HEADER_LEN as u64, |(_, file_end)| *file_end)
+    }
+
+    /// Reads the next frame, returning false at the end of an appended file.
+    fn next_block(&mut self) -> io::Result<bool> {
+        let mut word = [0; 4];
+        let mut filled = 0;
+        while filled < word.len() {
+            match self.inner.read(&mut word[filled..])? {
+                0 if filled == 0 && self.appended => return Ok(false),
+                0 if filled == 0 => return Err(invalid("sealed file ends before its last frame")),
+                0 => return Err(invalid("sealed file ends in the middle of a frame")),
+                n => filled += n,
+            }
+        }
+        let word = u32::from_le_bytes(word);
+        let (len, last) = frame_len(word);
+        let mut payload = Vec::new();
+        (&mut self.inner).take(len as u64).read_to_end(&mut payload)?;
+        if payload.len() != len {
+            return Err(invalid("sealed file ends in the middle of a frame"));
+        }
+
+        self.block = self.opener.open(word, &payload)?;
+        self.pos = 0;
+        self.done = last;
+        let (plain, file) = self.frame_ends.last().copied().unwrap_or((0, HEADER_LEN as u64));
+        self.frame_ends.push((plain + self.block.len() as u64, file + 4 + len as u64));
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for BlockReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.block.len() {
+            if self.done || !self.next_block()? {
+                return Ok(0);
+            }
+        }
+        let n = buf.len().min(self.block.len() - self.pos);
+        buf[..n].copy_from_slice(&self.block[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// The header of the sealed file at `path` and the number of frames it
+/// holds, or `None` if the file is not sealed.
+pub fn scan_frames(path: &std::path::Path) -> io::Result<Option<(Vec<u8>, u64)>> {
+    let mut file = io::BufReader::new(std::fs::File::open(path)?);
+    let mut header = vec![0; HEADER_LEN];
+    match file.read_exact(&mut header) {
+        Ok(()) if is_sealed(&header) => {}
+        Ok(()) => return Ok(None),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let mut frames = 0;
+    let mut word = [0; 4];
+    loop {
+        match file.read_exact(&mut word) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        let (len, _) = frame_len(u32::from_le_bytes(word));
+        io::copy(&mut (&mut file).take(len as u64), &mut io::sink())?;
+        frames += 1;
+    }
+    Ok(Some((header, frames)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plain() -> Codecs {
+        Codecs { compression: Compression::None, key: None }
+    }
+
+    #[test]
+    fn huge_length_word_is_not_allocated() {
+        let mut file = Sealer::new(&plain()).header().to_vec();
+        file.extend_from_slice(&(LAST_FRAME - 1).to_le_bytes());
+        file.extend_from_slice(b"short");
+
+        let mut reader = BlockReader::new(file.as_slice(), None).unwrap();
+        let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn huge_length_word_is_not_allocated_when_appending() {
+        let mut frame = (LAST_FRAME - 1).to_le_bytes().to_vec();
+        frame.extend_from_slice(b"short");
+        let err = read_frame(&mut frame.as_slice()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn lz4_size_beyond_any_expansion_is_rejected() {
+        let codecs = Codecs { compression: Compression::Lz4, key: None };
+        let mut sealer = Sealer::new(&codecs);
+        let mut frame = sealer.seal(b"block", true).unwrap();
+        // the size lz4 prepends follows the length word
+        frame[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+
+        let mut opener = Opener::new(sealer.header(), None).unwrap();
+        let word = u32::from_le_bytes(frame[..4].try_into().unwrap());
+        assert!(opener.open(word, &frame[4..]).is_err());
+    }
+
+    fn key(byte: u8) -> EncryptionKey {
+        EncryptionKey { path: "test.key".to_string(), key: [byte; 32] }
+    }
+
+    /// A sealed file and the offsets at which each of its frames starts.
+    fn seal_file(codecs: &Codecs, blocks: &[&[u8]]) -> (Vec<u8>, Vec<usize>) {
+        let mut sealer = Sealer::new(codecs);
+        let mut file = sealer.header().to_vec();
+        let mut starts = Vec::new();
+        for (i, block) in blocks.iter().enumerate() {
+            starts.push(file.len());
+            file.extend_from_slice(&sealer.seal(block, i == blocks.len() - 1).unwrap());
+        }
+        (file, starts)
+    }
+
+    fn open_file(file: &[u8], key: Option<&EncryptionKey>) -> io::Result<Vec<u8>> {
+        let mut plain = Vec::new();
+        BlockReader::new(file, key)?.read_to_end(&mut plain)?;
+        Ok(plain)
+    }
+
+    #[test]
+    fn every_codec_round_trips() {
+        let blocks: [&[u8]; 3] = [b"first block", &[b'x'; 5000], b""];
+        for compression in Compression::ALL {
+            for key in [None, Some(key(1))] {
+                let codecs = Codecs { compression, key: key.clone() };
+                let (file, _) = seal_file(&codecs, &blocks);
+                assert_eq!(open_file(&file, key.as_ref()).unwrap(), blocks.concat(), "{:?}", codecs);
+            }
+        }
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let codecs = Codecs { compression: Compression::Lz4, key: Some(key(1)) };
+        let (file, _) = seal_file(&codecs, &[b"one", b"two"]);
+        for at in 0..file.len() {
+            let mut tampered = file.clone();
+            tampered[at] ^= 0x01;
+            assert!(open_file(&tampered, Some(&key(1))).is_err(), "byte {} flipped", at);
+        }
+    }
+
+    #[test]
+    fn wrong_or_missing_key_is_rejected() {
+        let codecs = Codecs { compression: Compression::None, key: Some(key(1)) };
+        let (file, _) = seal_file(&codecs, &[b"secret"]);
+        assert!(open_file(&file, Some(&key(2))).is_err());
+        assert!(open_file(&file, None).is_err());
+    }
+
+    #[test]
+    fn frames_cannot_be_reordered_moved_or_dropped() {
+        let codecs = Codecs { compression: Compression::None, key: Some(key(1)) };
+        let (file, starts) = seal_file(&codecs, &[b"one", b"two", b"three"]);
+        let frame = |i: usize| &file[starts[i]..starts.get(i + 1).copied().unwrap_or(file.len())];
+
+        let mut reordered = file[..HEADER_LEN].to_vec();
+        for i in [1, 0, 2] {
+            reordered.extend_from_slice(frame(i));
+        }
+        assert!(open_file(&reordered, Some(&key(1))).is_err());
+
+        // the same frames under the header of another file
+        let (other, _) = seal_file(&codecs, &[b"other"]);
+        let mut moved = other[..HEADER_LEN].to_vec();
+        moved.extend_from_slice(&file[HEADER_LEN..]);
+        assert!(open_file(&moved, Some(&key(1))).is_err());
+
+        // a snapshot must end with its last frame
+        assert!(open_file(&file[..starts[2]], Some(&key(1))).is_err());
+        // and the last frame cannot be passed off as an earlier one
+        let mut unmarked = file.clone();
+        unmarked[starts[2] + 3] &= 0x7f;
+        assert!(open_file(&unmarked, Some(&key(1))).is_err());
+    }
+
+    #[test]
+    fn appended_files_may_end_after_any_frame() {
+        let codecs = Codecs { compression: Compression::Zstd, key: None };
+        let mut sealer = Sealer::new(&codecs);
+        let mut file = sealer.header().to_vec();
+        file.extend_from_slice(&sealer.seal(b"SET a 1", false).unwrap());
+
+        let mut plain = Vec::new();
+        BlockReader::appended(file.as_slice(), None).unwrap().read_to_end(&mut plain).unwrap();
+        assert_eq!(plain, b"SET a 1");
+        assert!(open_file(&file, None).is_err());
+    }
+
+    #[test]
+    fn key_files_hold_raw_bytes_or_hex() {
+        let dir = std::env::temp_dir().join(format!("rusty-redis-keys-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        std::fs::write(path("raw"), [0xab; 32]).unwrap();
+        std::fs::write(path("hex"), format!("{}\n", "ab".repeat(32))).unwrap();
+        std::fs::write(path("short"), "abcd").unwrap();
+        std::fs::write(path("not-hex"), "zz".repeat(32)).unwrap();
+
+        let raw = EncryptionKey::read(&path("raw"));
+        let hex = EncryptionKey::read(&path("hex"));
+        let short = EncryptionKey::read(&path("short"));
+        let not_hex = EncryptionKey::read(&path("not-hex"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(raw.unwrap().key, [0xab; 32]);
+        assert_eq!(hex.unwrap().key, [0xab; 32]);
+        assert!(short.is_err());
+        assert!(not_hex.is_err());
+    }
+}
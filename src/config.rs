@@ -0,0 +1,618 @@
+use clap::Parser;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU16, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+use crate::aof::FsyncPolicy;
+use crate::codec::{Codecs, Compression, EncryptionKey};
+use crate::evict::EvictionPolicy;
+use crate::notify::Events;
+use crate::persistence::{SaveRules, SnapshotFormat};
+use crate::pubsub::{LagPolicy, OutputBufferLimit};
+use crate::scan;
+use crate::value::EncodingLimits;
+
+#[derive(Parser, Debug, Clone)]
+#[command(name = "rusty-redis", about = "a concurrent redis-compatible key-value store")]
+pub struct Config {
+    /// address to listen on
+    #[arg(long, default_value = "127.0.0.1:6379")]
+    pub bind: String,
+
+    /// number of logical databases available to SELECT
+    #[arg(long, default_value_t = 16, value_parser = clap::value_parser!(u32).range(1..))]
+    pub databases: u32,
+
+    /// memory limit for the dataset, e.g. `100mb`; 0 disables the limit
+    #[arg(long, default_value = "0", value_parser = parse_memory)]
+    pub maxmemory: u64,
+
+    /// eviction policy applied once maxmemory is reached
+    #[arg(long, default_value = "noeviction", value_parser = parse_policy)]
+    pub maxmemory_policy: EvictionPolicy,
+
+    /// number of keys sampled per database when choosing a key to evict
+    #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u64).range(1..))]
+    pub maxmemory_samples: u64,
+
+    /// hashes with at most this many fields use the listpack encoding
+    #[arg(long, default_value_t = 128)]
+    pub hash_max_listpack_entries: usize,
+
+    /// hashes whose fields and values are all at most this long use the listpack encoding
+    #[arg(long, default_value_t = 64)]
+    pub hash_max_listpack_value: usize,
+
+    /// lists use the listpack encoding up to this many elements, or when negative,
+    /// up to 4kb (-1), 8kb (-2), 16kb (-3), 32kb (-4) or 64kb (-5)
+    #[arg(long, default_value_t = -2, allow_negative_numbers = true)]
+    pub list_max_listpack_size: i64,
+
+    /// sets of at most this many integers use the intset encoding
+    #[arg(long, default_value_t = 512)]
+    pub set_max_intset_entries: usize,
+
+    /// sets with at most this many members use the listpack encoding
+    #[arg(long, default_value_t = 128)]
+    pub set_max_listpack_entries: usize,
+
+    /// sets whose members are all at most this long use the listpack encoding
+    #[arg(long, default_value_t = 64)]
+    pub set_max_listpack_value: usize,
+
+    /// sorted sets with at most this many members use the listpack encoding
+    #[arg(long, default_value_t = 128)]
+    pub zset_max_listpack_entries: usize,
+
+    /// sorted sets whose members are all at most this long use the listpack encoding
+    #[arg(long, default_value_t = 64)]
+    pub zset_max_listpack_value: usize,
+
+    /// format of dump.rdb: native, or rdb for files redis and RDB tools can read
+    #[arg(long, default_value = "native", value_parser = parse_snapshot_format)]
+    pub snapshot_format: SnapshotFormat,
+
+    /// snapshot rules as pairs of seconds and changes: save once that many
+    /// writes were made within that many seconds; "" disables periodic snapshots
+    #[arg(long, default_value = "60 1", value_parser = parse_save_rules)]
+    pub save: SaveRules,
+
+    /// compression of snapshots and append only file base files: none, lz4 or zstd
+    #[arg(long, default_value = "none", value_parser = parse_compression)]
+    pub snapshot_compression: Compression,
+
+    /// file holding a 256-bit key, as 32 bytes or 64 hex digits, with which
+    /// snapshots and the append only file are encrypted
+    #[arg(long, value_parser = parse_key_file)]
+    pub encryption_key_file: Option<EncryptionKey>,
+
+    /// refuse to start when the snapshot on disk is corrupt instead of moving
+    /// it aside to dump.rdb.corrupt and starting with an empty database
+    #[arg(long)]
+    pub abort_on_corrupt_dump: bool,
+
+    /// log every write command to the append only file and load it on startup
+    #[arg(long)]
+    pub appendonly: bool,
+
+    /// directory holding the append only files and their manifest
+    #[arg(long, default_value = "appendonlydir")]
+    pub appenddirname: String,
+
+    /// base name of the append only files
+    #[arg(long, default_value = "appendonly.aof")]
+    pub appendfilename: String,
+
+    /// when the append only file is fsynced: always, everysec or no
+    #[arg(long, default_value = "everysec", value_parser = parse_fsync_policy)]
+    pub appendfsync: FsyncPolicy,
+
+    /// load an append only file whose last command was cut short, dropping that command
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    pub aof_load_truncated: bool,
+
+    /// rewrite the append only file once it has grown by this percentage since the
+    /// last rewrite; 0 disables automatic rewrites
+    #[arg(long, default_value_t = 100)]
+    pub auto_aof_rewrite_percentage: u64,
+
+    /// size the append only file must reach before it is rewritten automatically
+    #[arg(long, default_value = "64mb", value_parser = parse_memory)]
+    pub auto_aof_rewrite_min_size: u64,
+
+    /// start rewritten append only files with a snapshot of the dataset, which loads faster
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    pub aof_use_rdb_preamble: bool,
+
+    /// annotate the append only file with the unix time of the commands that
+    /// follow, once per second, so it can be replayed up to a point in time
+    #[arg(long, default_value_t = false, action = clap::ArgAction::Set)]
+    pub aof_timestamp_enabled: bool,
+
+    /// number of replaced append only files kept in a history directory by
+    /// rewrites, from which --recover-to can start; 0 deletes them
+    #[arg(long, default_value_t = 0)]
+    pub aof_history_limit: u64,
+
+    /// output buffer limit of pub/sub clients as "pubsub <hard> <soft> <seconds>":
+    /// a client whose unsent messages exceed the hard limit, or the soft limit
+    /// for that many seconds, is handled by --pubsub-lag-policy; 0 disables a limit
+    #[arg(long, default_value = "pubsub 32mb 8mb 60", value_parser = parse_output_buffer_limit)]
+    pub client_output_buffer_limit: OutputBufferLimit,
+
+    /// what happens to a pub/sub client over its output buffer limit or too slow
+    /// to keep up with a channel: disconnect it, or skip the messages it cannot take
+    #[arg(long, default_value = "disconnect", value_parser = parse_lag_policy)]
+    pub pubsub_lag_policy: LagPolicy,
+
+    /// keyspace events published through pub/sub, as redis flags: K and E for the
+    /// keyspace and keyevent channels, then the classes g, $, l, s, h, z, x, e, t,
+    /// m and n, or A for g$lshzxet; "" disables notifications
+    #[arg(long, default_value = "", value_parser = parse_events)]
+    pub notify_keyspace_events: Events,
+
+    /// instead of starting a server, rebuild the dataset as it was at this unix
+    /// time in seconds from the append only file and write it to a new snapshot
+    #[arg(long)]
+    pub recover_to: Option<u64>,
+
+    /// instead of starting a server, report the biggest keys of the server at --bind
+    #[arg(long, conflicts_with = "hotkeys")]
+    pub bigkeys: bool,
+
+    /// instead of starting a server, report the most accessed keys of the server at --bind
+    #[arg(long)]
+    pub hotkeys: bool,
+
+    /// database analyzed by --bigkeys and --hotkeys
+    #[arg(long, default_value_t = 0)]
+    pub db: u32,
+}
+
+/// Parses a memory amount such as `1024`, `100kb`, `64mb` or `2gb`.
+pub fn parse_memory(value: &str) -> Result<u64, String> {
+    let lower = value.trim().to_ascii_lowercase();
+    let split = lower.find(|c: char| !c.is_ascii_digit()).unwrap_or(lower.len());
+    let (digits, unit) = lower.split_at(split);
+
+    let multiplier: u64 = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return Err(format!("invalid memory unit in '{}'", value)),
+    };
+
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid memory value '{}'", value))
+}
+
+fn parse_policy(value: &str) -> Result<EvictionPolicy, String> {
+    EvictionPolicy::parse(value).ok_or_else(|| format!("unknown maxmemory policy '{}'", value))
+}
+
+fn parse_snapshot_format(value: &str) -> Result<SnapshotFormat, String> {
+    SnapshotFormat::parse(value).ok_or_else(|| format!("unknown snapshot format '{}'", value))
+}
+
+fn parse_save_rules(value: &str) -> Result<SaveRules, String> {
+    SaveRules::parse(value).ok_or_else(|| format!("invalid save rules '{}', expected pairs of seconds and changes", value))
+}
+
+fn parse_compression(value: &str) -> Result<Compression, String> {
+    Compression::parse(value).ok_or_else(|| format!("unknown compression '{}'", value))
+}
+
+fn parse_key_file(value: &str) -> Result<EncryptionKey, String> {
+    EncryptionKey::read(value)
+}
+
+fn parse_fsync_policy(value: &str) -> Result<FsyncPolicy, String> {
+    FsyncPolicy::parse(value).ok_or_else(|| format!("unknown appendfsync policy '{}'", value))
+}
+
+fn parse_output_buffer_limit(value: &str) -> Result<OutputBufferLimit, String> {
+    OutputBufferLimit::parse(value)
+        .ok_or_else(|| format!("invalid output buffer limit '{}', expected pubsub followed by the hard limit, soft limit and seconds", value))
+}
+
+fn parse_events(value: &str) -> Result<Events, String> {
+    Events::parse(value).ok_or_else(|| format!("invalid keyspace events '{}'", value))
+}
+
+fn parse_lag_policy(value: &str) -> Result<LagPolicy, String> {
+    LagPolicy::parse(value).ok_or_else(|| format!("unknown pubsub lag policy '{}'", value))
+}
+
+/// Parameters readable with CONFIG GET and, unless noted, changeable at runtime with CONFIG SET.
+pub struct Settings {
+    databases: usize,
+    maxmemory: AtomicU64,
+    maxmemory_policy: AtomicU8,
+    maxmemory_samples: AtomicUsize,
+    hash_max_listpack_entries: AtomicUsize,
+    hash_max_listpack_value: AtomicUsize,
+    list_max_listpack_size: AtomicI64,
+    set_max_intset_entries: AtomicUsize,
+    set_max_listpack_entries: AtomicUsize,
+    set_max_listpack_value: AtomicUsize,
+    zset_max_listpack_entries: AtomicUsize,
+    zset_max_listpack_value: AtomicUsize,
+    snapshot_format: AtomicU8,
+    save: RwLock<SaveRules>,
+    snapshot_compression: AtomicU8,
+    encryption_key: Option<EncryptionKey>,
+    abort_on_corrupt_dump: bool,
+    appendonly: bool,
+    appenddirname: String,
+    appendfilename: String,
+    appendfsync: AtomicU8,
+    aof_load_truncated: AtomicBool,
+    auto_aof_rewrite_percentage: AtomicU64,
+    auto_aof_rewrite_min_size: AtomicU64,
+    aof_use_rdb_preamble: AtomicBool,
+    aof_timestamp_enabled: AtomicBool,
+    aof_history_limit: AtomicU64,
+    client_output_buffer_limit: RwLock<OutputBufferLimit>,
+    pubsub_lag_policy: AtomicU8,
+    notify_keyspace_events: AtomicU16,
+}
+
+const PARAMETERS: &[&str] = &[
+    "databases",
+    "maxmemory",
+    "maxmemory-policy",
+    "maxmemory-samples",
+    "hash-max-listpack-entries",
+    "hash-max-listpack-value",
+    "list-max-listpack-size",
+    "set-max-intset-entries",
+    "set-max-listpack-entries",
+    "set-max-listpack-value",
+    "zset-max-listpack-entries",
+    "zset-max-listpack-value",
+    "snapshot-format",
+    "save",
+    "snapshot-compression",
+    "encryption-key-file",
+    "abort-on-corrupt-dump",
+    "appendonly",
+    "appenddirname",
+    "appendfilename",
+    "appendfsync",
+    "aof-load-truncated",
+    "auto-aof-rewrite-percentage",
+    "auto-aof-rewrite-min-size",
+    "aof-use-rdb-preamble",
+    "aof-timestamp-enabled",
+    "aof-history-limit",
+    "client-output-buffer-limit",
+    "pubsub-lag-policy",
+    "notify-keyspace-events",
+];
+
+impl Settings {
+    pub fn new(config: &Config) -> Settings {
+        Settings {
+            databases: config.databases as usize,
+            maxmemory: AtomicU64::new(config.maxmemory),
+            maxmemory_policy: AtomicU8::new(policy_index(config.maxmemory_policy)),
+            maxmemory_samples: AtomicUsize::new(config.maxmemory_samples as usize),
+            hash_max_listpack_entries: AtomicUsize::new(config.hash_max_listpack_entries),
+            hash_max_listpack_value: AtomicUsize::new(config.hash_max_listpack_value),
+            list_max_listpack_size: AtomicI64::new(config.list_max_listpack_size),
+            set_max_intset_entries: AtomicUsize::new(config.set_max_intset_entries),
+            set_max_listpack_entries: AtomicUsize::new(config.set_max_listpack_entries),
+            set_max_listpack_value: AtomicUsize::new(config.set_max_listpack_value),
+            zset_max_listpack_entries: AtomicUsize::new(config.zset_max_listpack_entries),
+            zset_max_listpack_value: AtomicUsize::new(config.zset_max_listpack_value),
+            snapshot_format: AtomicU8::new(snapshot_format_index(config.snapshot_format)),
+            save: RwLock::new(config.save.clone()),
+            snapshot_compression: AtomicU8::new(compression_index(config.snapshot_compression)),
+            encryption_key: config.encryption_key_file.clone(),
+            abort_on_corrupt_dump: config.abort_on_corrupt_dump,
+            appendonly: config.appendonly,
+            appenddirname: config.appenddirname.clone(),
+            appendfilename: config.appendfilename.clone(),
+            appendfsync: AtomicU8::new(fsync_policy_index(config.appendfsync)),
+            aof_load_truncated: AtomicBool::new(config.aof_load_truncated),
+            auto_aof_rewrite_percentage: AtomicU64::new(config.auto_aof_rewrite_percentage),
+            auto_aof_rewrite_min_size: AtomicU64::new(config.auto_aof_rewrite_min_size),
+            aof_use_rdb_preamble: AtomicBool::new(config.aof_use_rdb_preamble),
+            aof_timestamp_enabled: AtomicBool::new(config.aof_timestamp_enabled),
+            aof_history_limit: AtomicU64::new(config.aof_history_limit),
+            client_output_buffer_limit: RwLock::new(config.client_output_buffer_limit),
+            pubsub_lag_policy: AtomicU8::new(lag_policy_index(config.pubsub_lag_policy)),
+            notify_keyspace_events: AtomicU16::new(config.notify_keyspace_events.bits()),
+        }
+    }
+
+    pub fn snapshot_format(&self) -> SnapshotFormat {
+        SnapshotFormat::ALL[self.snapshot_format.load(Ordering::Relaxed) as usize]
+    }
+
+    pub fn save_rules(&self) -> SaveRules {
+        self.save.read().unwrap().clone()
+    }
+
+    pub fn snapshot_compression(&self) -> Compression {
+        Compression::ALL[self.snapshot_compression.load(Ordering::Relaxed) as usize]
+    }
+
+    pub fn encryption_key(&self) -> Option<&EncryptionKey> {
+        self.encryption_key.as_ref()
+    }
+
+    pub fn abort_on_corrupt_dump(&self) -> bool {
+        self.abort_on_corrupt_dump
+    }
+
+    /// The codecs snapshots and append only file base files are written with.
+    pub fn codecs(&self) -> Codecs {
+        Codecs { compression: self.snapshot_compression(), key: self.encryption_key.clone() }
+    }
+
+    pub fn appenddirname(&self) -> &str {
+        &self.appenddirname
+    }
+
+    pub fn appendfilename(&self) -> &str {
+        &self.appendfilename
+    }
+
+    pub fn appendfsync(&self) -> FsyncPolicy {
+        FsyncPolicy::ALL[self.appendfsync.load(Ordering::Relaxed) as usize]
+    }
+
+    pub fn aof_load_truncated(&self) -> bool {
+        self.aof_load_truncated.load(Ordering::Relaxed)
+    }
+
+    pub fn auto_aof_rewrite_percentage(&self) -> u64 {
+        self.auto_aof_rewrite_percentage.load(Ordering::Relaxed)
+    }
+
+    pub fn auto_aof_rewrite_min_size(&self) -> u64 {
+        self.auto_aof_rewrite_min_size.load(Ordering::Relaxed)
+    }
+
+    pub fn aof_use_rdb_preamble(&self) -> bool {
+        self.aof_use_rdb_preamble.load(Ordering::Relaxed)
+    }
+
+    pub fn aof_timestamp_enabled(&self) -> bool {
+        self.aof_timestamp_enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn aof_history_limit(&self) -> u64 {
+        self.aof_history_limit.load(Ordering::Relaxed)
+    }
+
+    pub fn client_output_buffer_limit(&self) -> OutputBufferLimit {
+        *self.client_output_buffer_limit.read().unwrap()
+    }
+
+    pub fn pubsub_lag_policy(&self) -> LagPolicy {
+        LagPolicy::ALL[self.pubsub_lag_policy.load(Ordering::Relaxed) as usize]
+    }
+
+    pub fn notify_keyspace_events(&self) -> Events {
+        Events::from_bits(self.notify_keyspace_events.load(Ordering::Relaxed))
+    }
+
+    pub fn encoding_limits(&self) -> EncodingLimits {
+        EncodingLimits {
+            hash_max_listpack_entries: self.hash_max_listpack_entries.load(Ordering::Relaxed),
+            hash_max_listpack_value: self.hash_max_listpack_value.load(Ordering::Relaxed),
+            list_max_listpack_size: self.list_max_listpack_size.load(Ordering::Relaxed),
+            set_max_intset_entries: self.set_max_intset_entries.load(Ordering::Relaxed),
+            set_max_listpack_entries: self.set_max_listpack_entries.load(Ordering::Relaxed),
+            set_max_listpack_value: self.set_max_listpack_value.load(Ordering::Relaxed),
+            zset_max_listpack_entries: self.zset_max_listpack_entries.load(Ordering::Relaxed),
+            zset_max_listpack_value: self.zset_max_listpack_value.load(Ordering::Relaxed),
+        }
+    }
+
+    /// The unsigned size parameters, by name.
+    fn size_parameter(&self, name: &str) -> Option<&AtomicUsize> {
+        let parameter = match name {
+            "hash-max-listpack-entries" => &self.hash_max_listpack_entries,
+            "hash-max-listpack-value" => &self.hash_max_listpack_value,
+            "set-max-intset-entries" => &self.set_max_intset_entries,
+            "set-max-listpack-entries" => &self.set_max_listpack_entries,
+            "set-max-listpack-value" => &self.set_max_listpack_value,
+            "zset-max-listpack-entries" => &self.zset_max_listpack_entries,
+            "zset-max-listpack-value" => &self.zset_max_listpack_value,
+            _ => return None,
+        };
+        Some(parameter)
+    }
+
+    pub fn maxmemory(&self) -> u64 {
+        self.maxmemory.load(Ordering::Relaxed)
+    }
+
+    pub fn maxmemory_policy(&self) -> EvictionPolicy {
+        EvictionPolicy::ALL[self.maxmemory_policy.load(Ordering::Relaxed) as usize]
+    }
+
+    pub fn maxmemory_samples(&self) -> usize {
+        self.maxmemory_samples.load(Ordering::Relaxed)
+    }
+
+    /// Returns every parameter whose name matches `pattern`, with its current value.
+    pub fn get(&self, pattern: &[u8]) -> Vec<(&'static str, String)> {
+        PARAMETERS
+            .iter()
+            .filter(|name| scan::glob_match(pattern, name.as_bytes(), true))
+            .filter_map(|name| self.value(name).map(|value| (*name, value)))
+            .collect()
+    }
+
+    fn value(&self, name: &str) -> Option<String> {
+        let value = match name {
+            "databases" => self.databases.to_string(),
+            "maxmemory" => self.maxmemory().to_string(),
+            "maxmemory-policy" => self.maxmemory_policy().name().to_string(),
+            "maxmemory-samples" => self.maxmemory_samples().to_string(),
+            "list-max-listpack-size" => self.list_max_listpack_size.load(Ordering::Relaxed).to_string(),
+            "snapshot-format" => self.snapshot_format().name().to_string(),
+            "save" => self.save_rules().render(),
+            "snapshot-compression" => self.snapshot_compression().name().to_string(),
+            "encryption-key-file" => self.encryption_key().map(|key| key.path().to_string()).unwrap_or_default(),
+            "abort-on-corrupt-dump" => yes_no(self.abort_on_corrupt_dump).to_string(),
+            "appendonly" => yes_no(self.appendonly).to_string(),
+            "appenddirname" => self.appenddirname.clone(),
+            "appendfilename" => self.appendfilename.clone(),
+            "appendfsync" => self.appendfsync().name().to_string(),
+            "aof-load-truncated" => yes_no(self.aof_load_truncated()).to_string(),
+            "auto-aof-rewrite-percentage" => self.auto_aof_rewrite_percentage().to_string(),
+            "auto-aof-rewrite-min-size" => self.auto_aof_rewrite_min_size().to_string(),
+            "aof-use-rdb-preamble" => yes_no(self.aof_use_rdb_preamble()).to_string(),
+            "aof-timestamp-enabled" => yes_no(self.aof_timestamp_enabled()).to_string(),
+            "aof-history-limit" => self.aof_history_limit().to_string(),
+            "client-output-buffer-limit" => self.client_output_buffer_limit().render(),
+            "pubsub-lag-policy" => self.pubsub_lag_policy().name().to_string(),
+            "notify-keyspace-events" => self.notify_keyspace_events().render(),
+            name => self.size_parameter(name)?.load(Ordering::Relaxed).to_string(),
+        };
+        Some(value)
+    }
+
+    pub fn set(&self, name: &str, value: &str) -> Result<(), String> {
+        let invalid = || format!("ERR Invalid argument '{}' for CONFIG SET '{}'", value, name);
+
+        let lower = name.to_ascii_lowercase();
+        match lower.as_str() {
+            "maxmemory" => {
+                let bytes = parse_memory(value).map_err(|_| invalid())?;
+                self.maxmemory.store(bytes, Ordering::Relaxed);
+            }
+            "maxmemory-policy" => {
+                let policy = EvictionPolicy::parse(value).ok_or_else(invalid)?;
+                self.maxmemory_policy.store(policy_index(policy), Ordering::Relaxed);
+            }
+            "maxmemory-samples" => {
+                let samples = value.parse::<usize>().ok().filter(|n| *n > 0).ok_or_else(invalid)?;
+                self.maxmemory_samples.store(samples, Ordering::Relaxed);
+            }
+            "list-max-listpack-size" => {
+                let size = value.parse::<i64>().map_err(|_| invalid())?;
+                self.list_max_listpack_size.store(size, Ordering::Relaxed);
+            }
+            "snapshot-format" => {
+                let format = SnapshotFormat::parse(value).ok_or_else(invalid)?;
+                self.snapshot_format.store(snapshot_format_index(format), Ordering::Relaxed);
+            }
+            "save" => {
+                let rules = SaveRules::parse(value).ok_or_else(invalid)?;
+                *self.save.write().unwrap() = rules;
+            }
+            "snapshot-compression" => {
+                let compression = Compression::parse(value).ok_or_else(invalid)?;
+                self.snapshot_compression.store(compression_index(compression), Ordering::Relaxed);
+            }
+            "appendfsync" => {
+                let policy = FsyncPolicy::parse(value).ok_or_else(invalid)?;
+                self.appendfsync.store(fsync_policy_index(policy), Ordering::Relaxed);
+            }
+            "aof-load-truncated" => {
+                let enabled = parse_yes_no(value).ok_or_else(invalid)?;
+                self.aof_load_truncated.store(enabled, Ordering::Relaxed);
+            }
+            "auto-aof-rewrite-percentage" => {
+                let percentage = value.parse::<u64>().map_err(|_| invalid())?;
+                self.auto_aof_rewrite_percentage.store(percentage, Ordering::Relaxed);
+            }
+            "auto-aof-rewrite-min-size" => {
+                let bytes = parse_memory(value).map_err(|_| invalid())?;
+                self.auto_aof_rewrite_min_size.store(bytes, Ordering::Relaxed);
+            }
+            "aof-use-rdb-preamble" => {
+                let enabled = parse_yes_no(value).ok_or_else(invalid)?;
+                self.aof_use_rdb_preamble.store(enabled, Ordering::Relaxed);
+            }
+            "aof-timestamp-enabled" => {
+                let enabled = parse_yes_no(value).ok_or_else(invalid)?;
+                self.aof_timestamp_enabled.store(enabled, Ordering::Relaxed);
+            }
+            "aof-history-limit" => {
+                let limit = value.parse::<u64>().map_err(|_| invalid())?;
+                self.aof_history_limit.store(limit, Ordering::Relaxed);
+            }
+            "client-output-buffer-limit" => {
+                let limit = OutputBufferLimit::parse(value).ok_or_else(invalid)?;
+                *self.client_output_buffer_limit.write().unwrap() = limit;
+            }
+            "pubsub-lag-policy" => {
+                let policy = LagPolicy::parse(value).ok_or_else(invalid)?;
+                self.pubsub_lag_policy.store(lag_policy_index(policy), Ordering::Relaxed);
+            }
+            "notify-keyspace-events" => {
+                let events = Events::parse(value).ok_or_else(invalid)?;
+                self.notify_keyspace_events.store(events.bits(), Ordering::Relaxed);
+            }
+            "databases" | "encryption-key-file" | "abort-on-corrupt-dump" | "appendonly" | "appenddirname" | "appendfilename" => return Err(format!("ERR CONFIG SET failed - can't set immutable config '{}'", name)),
+            other => {
+                let Some(parameter) = self.size_parameter(other) else {
+                    return Err(format!("ERR Unknown option or number of arguments for CONFIG SET - '{}'", name));
+                };
+                let size = value.parse::<usize>().map_err(|_| invalid())?;
+                parameter.store(size, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn policy_index(policy: EvictionPolicy) -> u8 {
+    EvictionPolicy::ALL
+        .iter()
+        .position(|p| *p == policy)
+        .expect("every policy is listed in ALL") as u8
+}
+
+fn fsync_policy_index(policy: FsyncPolicy) -> u8 {
+    FsyncPolicy::ALL
+        .iter()
+        .position(|p| *p == policy)
+        .expect("every policy is listed in ALL") as u8
+}
+
+fn lag_policy_index(policy: LagPolicy) -> u8 {
+    LagPolicy::ALL
+        .iter()
+        .position(|p| *p == policy)
+        .expect("every policy is listed in ALL") as u8
+}
+
+fn snapshot_format_index(format: SnapshotFormat) -> u8 {
+    SnapshotFormat::ALL
+        .iter()
+        .position(|f| *f == format)
+        .expect("every format is listed in ALL") as u8
+}
+
+fn compression_index(compression: Compression) -> u8 {
+    Compression::ALL
+        .iter()
+        .position(|c| *c == compression)
+        .expect("every compression is listed in ALL") as u8
+}
+
+fn yes_no(enabled: bool) -> &'static str {
+    if enabled { "yes" } else { "no" }
+}
+
+fn parse_yes_no(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "yes" => Some(true),
+        "no" => Some(false),
+        _ => None,
+    }
+}
@@ -21,8 +21,13 @@ impl Connection {
 
     pub async fn read_frame(&mut self) -> Result<Option<Frame>, std::io::Error> {
         loop {
-            if let Some(frame) = parse_frame(&mut self.buffer)? {
-                return Ok(Some(frame));
+            match parse_frame(&mut self.buffer) {
+                Ok(Some(frame)) => return Ok(Some(frame)),
+                Ok(None) => {}
+                // read_line reports a line cut short as WouldBlock; like a bulk
+                // string cut short, it only means the rest has not arrived yet
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
             }
 
             let n = self.stream.read_buf(&mut self.buffer).await?;
@@ -49,7 +54,7 @@ impl Connection {
     }
 }
 
-fn serialize_frame(frame: &Frame, buf: &mut BytesMut) {
+pub fn serialize_frame(frame: &Frame, buf: &mut BytesMut) {
     match frame {
         Frame::Simple(s) => {
             buf.extend_from_slice(b"+");
@@ -87,7 +92,27 @@ fn serialize_frame(frame: &Frame, buf: &mut BytesMut) {
     }
 }
 
-fn parse_frame(buf: &mut BytesMut) -> Result<Option<Frame>, std::io::Error> {
+/// The number of bytes `serialize_frame` writes for `frame`.
+pub fn encoded_len(frame: &Frame) -> usize {
+    let digits = |n: usize| n.to_string().len();
+    match frame {
+        Frame::Simple(s) => 1 + s.len() + 2,
+        Frame::Error(e) => 1 + e.len() + 2,
+        Frame::Integer(i) => 1 + i.to_string().len() + 2,
+        Frame::Bulk(bytes) => 1 + digits(bytes.len()) + 2 + bytes.len() + 2,
+        Frame::Null => 5,
+        Frame::Array(frames) => 1 + digits(frames.len()) + 2 + frames.iter().map(encoded_len).sum::<usize>(),
+    }
+}
+
+/// Arrays nested deeper than this are rejected rather than parsed recursively.
+const MAX_NESTING: usize = 32;
+
+pub fn parse_frame(buf: &mut BytesMut) -> Result<Option<Frame>, std::io::Error> {
+    parse_nested(buf, 0)
+}
+
+fn parse_nested(buf: &mut BytesMut, depth: usize) -> Result<Option<Frame>, std::io::Error> {
     if buf.is_empty() {
         return Ok(None);
     }
@@ -119,9 +144,14 @@ fn parse_frame(buf: &mut BytesMut) -> Result<Option<Frame>, std::io::Error> {
             if len == -1 {
                 Frame::Null
             } else {
-                let len = len as usize;
+                let len = usize::try_from(len).map_err(|_| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid bulk length")
+                })?;
+                let Some(framed_len) = len.checked_add(2) else {
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid bulk length"));
+                };
 
-                if cursor.remaining() < len + 2 {
+                if cursor.remaining() < framed_len {
                     return Ok(None);
                 }
 
@@ -141,10 +171,15 @@ fn parse_frame(buf: &mut BytesMut) -> Result<Option<Frame>, std::io::Error> {
                 std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid array length")
             })?;
 
-            let mut frames = Vec::with_capacity(count);
+            if depth == MAX_NESTING {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "arrays nested too deeply"));
+            }
+
+            // every element takes at least 3 bytes, so no more can be in the buffer
+            let mut frames = Vec::with_capacity(count.min(cursor.remaining() / 3));
 
             for _ in 0..count {
-                match parse_frame_inner(&mut cursor, buf)? {
+                match parse_frame_inner(&mut cursor, buf, depth + 1)? {
                     Some(f) => frames.push(f),
                     None => return Ok(None),
                 }
@@ -169,13 +204,14 @@ fn parse_frame(buf: &mut BytesMut) -> Result<Option<Frame>, std::io::Error> {
 fn parse_frame_inner(
     cursor: &mut std::io::Cursor<&[u8]>,
     buf: &BytesMut,
+    depth: usize,
 ) -> Result<Option<Frame>, std::io::Error> {
     let start = cursor.position() as usize;
     let slice = &buf[start..];
 
     let mut tmp = BytesMut::from(slice);
 
-    match parse_frame(&mut tmp)? {
+    match parse_nested(&mut tmp, depth)? {
         Some(frame) => {
             let consumed = slice.len() - tmp.len();
             cursor.set_position((start + consumed) as u64);
@@ -204,3 +240,85 @@ fn read_line(cursor: &mut std::io::Cursor<&[u8]>) -> Result<String, std::io::Err
         "incomplete frame",
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &[u8]) -> Result<Option<Frame>, std::io::Error> {
+        parse_frame(&mut BytesMut::from(input))
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let frame = Frame::Array(vec![
+            Frame::Bulk("SET".into()),
+            Frame::Bulk(Bytes::from_static(b"k\r\ney")),
+            Frame::Bulk(Bytes::new()),
+            Frame::Integer(-42),
+            Frame::Simple("OK".to_string()),
+            Frame::Error("ERR no".to_string()),
+            Frame::Null,
+            Frame::Array(vec![]),
+        ]);
+        let mut buf = BytesMut::new();
+        serialize_frame(&frame, &mut buf);
+        assert_eq!(buf.len(), encoded_len(&frame));
+        let encoded = buf.clone();
+        buf.extend_from_slice(b"+next\r\n");
+
+        let parsed = parse_frame(&mut buf).unwrap().unwrap();
+        let mut reencoded = BytesMut::new();
+        serialize_frame(&parsed, &mut reencoded);
+        assert_eq!(reencoded, encoded);
+        assert_eq!(&buf[..], b"+next\r\n");
+    }
+
+    #[tokio::test]
+    async fn frame_split_inside_a_line_is_read_whole() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let mut connection = Connection::new(listener.accept().await.unwrap().0);
+
+        client.write_all(b"*1\r\n$").await.unwrap();
+        client.flush().await.unwrap();
+        let read = tokio::spawn(async move { connection.read_frame().await });
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        client.write_all(b"4\r\nPING\r\n").await.unwrap();
+
+        let frame = read.await.unwrap().unwrap();
+        assert!(matches!(frame, Some(Frame::Array(args)) if matches!(&args[..], [Frame::Bulk(ping)] if ping == "PING")));
+    }
+
+    #[test]
+    fn incomplete_frames_wait_for_more_data() {
+        let mut buf = BytesMut::new();
+        serialize_frame(&Frame::Array(vec![Frame::Bulk("GET".into()), Frame::Bulk("key".into())]), &mut buf);
+        for end in 1..buf.len() {
+            match parse(&buf[..end]) {
+                Ok(None) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                other => panic!("{} bytes parsed as {:?}", end, other),
+            }
+        }
+    }
+
+    #[test]
+    fn negative_bulk_lengths_other_than_null_are_rejected() {
+        assert!(parse(b"*1\r\n$-2\r\nxx\r\n").is_err());
+        assert!(parse(b"$-9223372036854775808\r\n").is_err());
+        assert!(matches!(parse(b"$-1\r\n").unwrap(), Some(Frame::Null)));
+    }
+
+    #[test]
+    fn huge_array_lengths_are_not_reserved() {
+        assert!(parse(b"*99999999999999999\r\n").unwrap().is_none());
+        assert!(parse(b"*18446744073709551615\r\n$1\r\na\r\n").unwrap().is_none());
+    }
+
+    #[test]
+    fn deeply_nested_arrays_are_rejected() {
+        let input = b"*1\r\n".repeat(100_000);
+        assert!(parse(&input).is_err());
+    }
+}
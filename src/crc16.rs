@@ -0,0 +1,74 @@
+/// CRC-16/XMODEM as used by redis cluster to assign keys to hash slots.
+///
+/// Polynomial 0x1021, zero init, not reflected, no final xor.
+const POLY: u16 = 0x1021;
+
+/// Number of hash slots keys and sharded channels are divided into.
+pub const HASH_SLOTS: u16 = 16384;
+
+const TABLE: [u16; 256] = build_table();
+
+const fn build_table() -> [u16; 256] {
+    let mut table = [0u16; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u16) << 8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ POLY } else { crc << 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+pub fn checksum(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for byte in data {
+        crc = TABLE[((crc >> 8) as u8 ^ *byte) as usize] ^ (crc << 8);
+    }
+    crc
+}
+
+/// The hash slot of `key`. Only the part between the first `{` and the
+/// next `}` is hashed when it is not empty, so keys sharing such a hash tag
+/// share a slot.
+pub fn key_slot(key: &[u8]) -> u16 {
+    let tag = key.iter().position(|&b| b == b'{').and_then(|open| {
+        let rest = &key[open + 1..];
+        rest.iter().position(|&b| b == b'}').filter(|&close| close > 0).map(|close| &rest[..close])
+    });
+    checksum(tag.unwrap_or(key)) % HASH_SLOTS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_value() {
+        assert_eq!(checksum(b"123456789"), 0x31c3);
+        assert_eq!(checksum(b""), 0);
+    }
+
+    #[test]
+    fn slots_match_redis_cluster() {
+        assert_eq!(key_slot(b"foo"), 12182);
+        assert_eq!(key_slot(b"bar"), 5061);
+        assert_eq!(key_slot(b"123456789"), 12739);
+    }
+
+    #[test]
+    fn hash_tags() {
+        assert_eq!(key_slot(b"{user1000}.following"), key_slot(b"{user1000}.followers"));
+        assert_eq!(key_slot(b"{user1000}.following"), key_slot(b"user1000"));
+        // an empty tag hashes the whole key
+        assert_eq!(key_slot(b"foo{}{bar}"), checksum(b"foo{}{bar}") % HASH_SLOTS);
+        // only the first tag counts, up to the first closing brace
+        assert_eq!(key_slot(b"foo{{bar}}zap"), key_slot(b"{bar"));
+        assert_eq!(key_slot(b"foo{bar}{zap}"), key_slot(b"bar"));
+        assert_eq!(key_slot(b"foo{bar"), checksum(b"foo{bar") % HASH_SLOTS);
+    }
+}
@@ -0,0 +1,53 @@
+/// CRC-64/Jones as used by redis for DUMP payloads and RDB files.
+///
+/// Reflected polynomial 0xad93d23594c935a9, zero init, no final xor.
+const POLY: u64 = 0x95ac9329ac4bc9b5;
+
+const TABLE: [u64; 256] = build_table();
+
+const fn build_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ POLY } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// Continues a checksum over `data`, starting from a previous `crc` (0 for a new one).
+pub fn update(mut crc: u64, data: &[u8]) -> u64 {
+    for byte in data {
+        crc = TABLE[((crc ^ *byte as u64) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+pub fn checksum(data: &[u8]) -> u64 {
+    update(0, data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_value() {
+        // the check value of CRC-64/Jones as reflected and initialized by redis
+        assert_eq!(checksum(b"123456789"), 0xe9c6d914c4b8d9ca);
+        assert_eq!(checksum(b""), 0);
+    }
+
+    #[test]
+    fn update_continues_a_checksum() {
+        let data = b"This is a test of the emergency broadcast system.";
+        let (head, tail) = data.split_at(17);
+        assert_eq!(update(update(0, head), tail), checksum(data));
+    }
+}
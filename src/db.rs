@@ -1,99 +1,929 @@
 use bytes::Bytes;
 use dashmap::DashMap;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock, Weak};
 use std::time::{Duration, Instant};
-use tokio::sync::broadcast;
-use tracing::{debug, info, error};
+use tracing::{debug, error, warn};
+
+use crate::aof::{self, Aof};
+use crate::config::Settings;
+use crate::evict::{self, EvictionPolicy};
+use crate::frame::Frame;
+use crate::notify::{self, Events};
+use crate::persistence::{self, SaveState};
+use crate::pubsub::PubSub;
+use crate::scan::{self, ScanIndex};
+use crate::value::{Value, WrongType};
+
+/// Minimum time between periodic snapshots after one failed.
+const SNAPSHOT_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Fixed per-key cost on top of the key and value bytes: the map slot, the
+/// key handle, the entry itself and the key's place in the scan index.
+const ENTRY_OVERHEAD: usize =
+    std::mem::size_of::<Bytes>() + std::mem::size_of::<Entry>() + 8 + std::mem::size_of::<(u64, Bytes)>();
+
+/// Milliseconds since the unix epoch.
+pub fn unix_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// A stored value together with the access metadata used by eviction.
+pub struct Entry {
+    pub value: Value,
+    /// approximate memory used by the key and value, in bytes
+    pub size: usize,
+    lru: AtomicU32,
+    lfu: AtomicU32,
+}
+
+impl Clone for Entry {
+    fn clone(&self) -> Entry {
+        Entry {
+            value: self.value.clone(),
+            size: self.size,
+            lru: AtomicU32::new(self.lru.load(Ordering::Relaxed)),
+            lfu: AtomicU32::new(self.lfu.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+impl Entry {
+    pub fn new(key: &[u8], value: Value) -> Entry {
+        let size = ENTRY_OVERHEAD + key.len() + value.memory_usage();
+        Entry {
+            value,
+            size,
+            lru: AtomicU32::new(evict::lru_clock()),
+            lfu: AtomicU32::new(evict::lfu_pack(evict::LFU_INIT_VAL)),
+        }
+    }
+
+    /// Records an access for the LRU and LFU policies.
+    pub fn touch(&self) {
+        self.lru.store(evict::lru_clock(), Ordering::Relaxed);
+        let lfu = self.lfu.load(Ordering::Relaxed);
+        self.lfu.store(evict::lfu_touch(lfu), Ordering::Relaxed);
+    }
+
+    /// Like `size`, but estimated from the first `samples` elements of a collection; 0 measures all of them.
+    pub fn sampled_size(&self, key: &[u8], samples: usize) -> usize {
+        ENTRY_OVERHEAD + key.len() + self.value.sampled_memory_usage(samples)
+    }
+
+    pub fn encoding(&self) -> &'static str {
+        self.value.encoding()
+    }
+
+    pub fn idle_seconds(&self) -> u64 {
+        evict::lru_idle_seconds(self.lru.load(Ordering::Relaxed))
+    }
+
+    pub fn set_idle_seconds(&self, seconds: u64) {
+        let clock = evict::lru_clock() as u64;
+        let lru = (clock + (1 << 24) - seconds.min((1 << 24) - 1)) % (1 << 24);
+        self.lru.store(lru as u32, Ordering::Relaxed);
+    }
+
+    pub fn frequency(&self) -> u8 {
+        evict::lfu_decayed(self.lfu.load(Ordering::Relaxed))
+    }
+
+    pub fn set_frequency(&self, counter: u8) {
+        self.lfu.store(evict::lfu_pack(counter), Ordering::Relaxed);
+    }
+}
 
 #[derive(Clone)]
 pub struct Db {
-    pub entries: Arc<DashMap<String, Bytes>>,
-    expirations: Arc<DashMap<String, Instant>>,
-    pub_sub: Arc<DashMap<String, broadcast::Sender<Bytes>>>,
-    changed: Arc<AtomicBool>,
+    pub entries: Arc<DashMap<Bytes, Entry>>,
+    /// the keys of `entries` in SCAN order
+    scan_index: Arc<ScanIndex>,
+    expirations: Arc<DashMap<Bytes, Instant>>,
+    /// the keys of `expirations`, to sample volatile keys from
+    volatile_index: Arc<ScanIndex>,
+    used_memory: Arc<AtomicUsize>,
+    /// position among the databases, which SWAPDB changes
+    index: Arc<AtomicUsize>,
+    pub_sub: Arc<PubSub>,
+    dirty: Arc<AtomicU64>,
+    settings: Arc<Settings>,
+    /// the snapshots being read from the database
+    frozen: Arc<RwLock<Vec<Arc<Frozen>>>>,
+}
+
+/// What a database held when a snapshot of it was taken, kept for the keys
+/// changed before the snapshot reads their shard.
+struct Frozen {
+    /// by shard of the entries map
+    shards: Vec<Mutex<FrozenShard>>,
+}
+
+#[derive(Default)]
+struct FrozenShard {
+    /// once the shard is read, changes to it are none of the snapshot's business
+    read: bool,
+    /// the value and expiry each changed key had, or `None` if it did not exist
+    saved: HashMap<Bytes, Option<(Value, Option<Instant>)>>,
+}
+
+impl Frozen {
+    /// Keeps what `key` holds unless its shard was read or the key was kept
+    /// already. The caller changes the key after this returns.
+    fn keep(&self, entries: &DashMap<Bytes, Entry>, expirations: &DashMap<Bytes, Instant>, key: &[u8]) {
+        let mut shard = self.shards[entries.determine_map(key)].lock().unwrap();
+        if shard.read || shard.saved.contains_key(key) {
+            return;
+        }
+        let held = entries
+            .get(key)
+            .map(|entry| (entry.value.clone(), expirations.get(key).map(|expiry| *expiry.value())));
+        shard.saved.insert(Bytes::copy_from_slice(key), held);
+    }
+}
+
+/// Every database as it was when the snapshot was taken, read shard by shard
+/// while clients go on writing.
+///
+/// Only one shard is copied at a time, and a write to a shard not read yet
+/// first keeps what the key held, so the snapshot costs the memory of a shard
+/// and of the keys changed while it is read, not of the whole dataset.
+pub struct Snapshot {
+    dbs: Vec<(Db, Arc<Frozen>)>,
+}
+
+impl Snapshot {
+    /// The number of databases.
+    pub fn len(&self) -> usize {
+        self.dbs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dbs.is_empty()
+    }
+
+    /// The number of shards of database `index`.
+    pub fn shards(&self, index: usize) -> usize {
+        self.dbs[index].1.shards.len()
+    }
+
+    /// The keys and the keys with an expiry of database `index` as it is now,
+    /// which is only good as a hint of what the snapshot holds.
+    pub fn sizes(&self, index: usize) -> (usize, usize) {
+        let (db, _) = &self.dbs[index];
+        (db.len(), db.expires_len())
+    }
+
+    pub fn used_memory(&self) -> usize {
+        self.dbs.iter().map(|(db, _)| db.used_memory()).sum()
+    }
+
+    /// The keys of shard `shard` of database `index` when the snapshot was
+    /// taken, each with its value and expiry. Each shard can be read once.
+    pub fn read_shard(&self, index: usize, shard: usize) -> Vec<(Bytes, Value, Option<Instant>)> {
+        let (db, frozen) = &self.dbs[index];
+        // writes to the shard wait for the copy, and those that kept a key
+        // already may change it while it is made
+        let mut frozen = frozen.shards[shard].lock().unwrap();
+        assert!(!frozen.read, "shard {} of database {} read twice from one snapshot", shard, index);
+        frozen.read = true;
+        let saved = std::mem::take(&mut frozen.saved);
+
+        let live: Vec<(Bytes, Value)> = db.entries.shards()[shard]
+            .read()
+            .iter()
+            .filter(|(key, _)| !saved.contains_key(*key))
+            .map(|(key, entry)| (key.clone(), entry.get().value.clone()))
+            .collect();
+        let mut keys: Vec<_> = live
+            .into_iter()
+            .map(|(key, value)| {
+                let expiry = db.expiry(&key);
+                (key, value, expiry)
+            })
+            .collect();
+        keys.extend(saved.into_iter().filter_map(|(key, held)| held.map(|(value, expiry)| (key, value, expiry))));
+        keys
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        for (db, frozen) in &self.dbs {
+            db.frozen.write().unwrap().retain(|other| !Arc::ptr_eq(other, frozen));
+        }
+    }
+}
+
+/// The set of logical databases selectable with `SELECT`.
+///
+/// Every database shares the pub/sub channels and the count of changes since
+/// the last snapshot, mirroring redis where pub/sub is not scoped to a database.
+#[derive(Clone)]
+pub struct Databases {
+    dbs: Arc<RwLock<Vec<Db>>>,
+    pub_sub: Arc<PubSub>,
+    dirty: Arc<AtomicU64>,
+    settings: Arc<Settings>,
+    evicted_keys: Arc<AtomicU64>,
+    peak_memory: Arc<AtomicUsize>,
+    aof: Arc<OnceLock<Arc<Aof>>>,
+    save_state: Arc<SaveState>,
+    /// held shared by logged writes, and exclusively by a rewrite of the append only file
+    writes: Arc<tokio::sync::RwLock<()>>,
+}
+
+impl Databases {
+    pub fn new(count: usize, settings: Arc<Settings>) -> Databases {
+        let pub_sub = Arc::new(PubSub::new(Arc::clone(&settings)));
+        let dirty = Arc::new(AtomicU64::new(0));
+
+        let dbs = (0..count)
+            .map(|index| Db::new(index, Arc::clone(&pub_sub), Arc::clone(&dirty), Arc::clone(&settings)))
+            .collect();
+
+        Databases {
+            dbs: Arc::new(RwLock::new(dbs)),
+            pub_sub,
+            dirty,
+            settings,
+            evicted_keys: Arc::new(AtomicU64::new(0)),
+            peak_memory: Arc::new(AtomicUsize::new(0)),
+            aof: Arc::new(OnceLock::new()),
+            save_state: Arc::new(SaveState::new()),
+            writes: Arc::new(tokio::sync::RwLock::new(())),
+        }
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    pub fn save_state(&self) -> &SaveState {
+        &self.save_state
+    }
+
+    pub fn pub_sub(&self) -> &Arc<PubSub> {
+        &self.pub_sub
+    }
+
+    /// Writes made since the last successful snapshot.
+    pub fn dirty(&self) -> u64 {
+        self.dirty.load(Ordering::Relaxed)
+    }
+
+    /// Marks `changes` writes as saved, keeping any made while the snapshot was written.
+    pub fn clear_dirty(&self, changes: u64) {
+        self.dirty.fetch_sub(changes.min(self.dirty()), Ordering::Relaxed);
+    }
+
+    /// Starts logging writes to `aof`; called once the dataset has been loaded.
+    pub fn set_aof(&self, aof: Arc<Aof>) {
+        if self.aof.set(aof).is_err() {
+            warn!("append only file is already open");
+        }
+    }
+
+    pub fn aof(&self) -> Option<&Arc<Aof>> {
+        self.aof.get()
+    }
+
+    /// Logs the records of a write command run against database `db` to the append only file, if enabled.
+    pub fn propagate(&self, db: usize, records: &[Frame]) {
+        if let Some(aof) = self.aof.get()
+            && let Err(e) = aof.append(db, records)
+        {
+            error!("failed to write to append only file: {}", e);
+        }
+    }
+
+    /// Held by a write command from when it runs until it is logged.
+    pub async fn write_guard(&self) -> tokio::sync::RwLockReadGuard<'_, ()> {
+        self.writes.read().await
+    }
+
+    /// Waits for the logged writes in progress and holds off new ones until
+    /// the guard is dropped.
+    pub async fn pause_writes(&self) -> tokio::sync::RwLockWriteGuard<'_, ()> {
+        self.writes.write().await
+    }
+
+    /// Approximate memory used by the keys and values of every database.
+    pub fn used_memory(&self) -> usize {
+        self.all().iter().map(|db| db.used_memory()).sum()
+    }
+
+    /// Highest `used_memory` observed before any command ran.
+    pub fn peak_memory(&self) -> usize {
+        self.peak_memory.load(Ordering::Relaxed).max(self.used_memory())
+    }
+
+    pub fn evicted_keys(&self) -> u64 {
+        self.evicted_keys.load(Ordering::Relaxed)
+    }
+
+    /// Evicts keys according to `maxmemory-policy` until usage is back under `maxmemory`.
+    ///
+    /// Returns false when the limit is exceeded and nothing can be evicted,
+    /// in which case commands that may grow the dataset must be refused.
+    pub fn free_memory_if_needed(&self) -> bool {
+        self.peak_memory.fetch_max(self.used_memory(), Ordering::Relaxed);
+
+        let maxmemory = self.settings.maxmemory();
+        if maxmemory == 0 {
+            return true;
+        }
+
+        let policy = self.settings.maxmemory_policy();
+        let mut evicted = 0;
+
+        while self.used_memory() as u64 > maxmemory {
+            if policy == EvictionPolicy::NoEviction {
+                return false;
+            }
+            let Some((index, key)) = self.eviction_candidate(policy) else {
+                return false;
+            };
+            let db = self.db(index);
+            if db.remove(&key) {
+                db.notify(Events::EVICTED, "evicted", &key);
+                evicted += 1;
+                self.evicted_keys.fetch_add(1, Ordering::Relaxed);
+                self.propagate(index, &[aof::command_frame(["DEL".into(), key])]);
+            }
+        }
+
+        if evicted > 0 {
+            debug!("evicted {} keys to stay under maxmemory", evicted);
+        }
+        true
+    }
+
+    /// Samples `maxmemory-samples` keys per database and returns the best one to evict.
+    fn eviction_candidate(&self, policy: EvictionPolicy) -> Option<(usize, Bytes)> {
+        let dbs = self.all();
+        let samples = self.settings.maxmemory_samples();
+        let offset = evict::random_u64() as usize % dbs.len();
+        let mut best: Option<(u64, usize, Bytes)> = None;
+
+        for i in 0..dbs.len() {
+            let index = (offset + i) % dbs.len();
+            let db = &dbs[index];
+
+            let keys = if policy.is_volatile() {
+                db.volatile_index.random_keys(samples)
+            } else {
+                db.scan_index.random_keys(samples)
+            };
+
+            for key in keys {
+
+                // higher scores are better candidates
+                let score = match policy {
+                    EvictionPolicy::AllKeysRandom | EvictionPolicy::VolatileRandom => {
+                        return Some((index, key));
+                    }
+                    EvictionPolicy::AllKeysLru | EvictionPolicy::VolatileLru => {
+                        db.entries.get(&key).map(|entry| entry.idle_seconds())
+                    }
+                    EvictionPolicy::AllKeysLfu | EvictionPolicy::VolatileLfu => {
+                        db.entries.get(&key).map(|entry| 255 - entry.frequency() as u64)
+                    }
+                    EvictionPolicy::VolatileTtl => db.expiry(&key).map(|expiry| {
+                        let remaining = expiry.saturating_duration_since(Instant::now()).as_millis() as u64;
+                        u64::MAX - remaining
+                    }),
+                    EvictionPolicy::NoEviction => None,
+                };
+
+                if let Some(score) = score
+                    && best.as_ref().is_none_or(|(best_score, _, _)| score > *best_score)
+                {
+                    best = Some((score, index, key));
+                }
+            }
+        }
+
+        best.map(|(_, index, key)| (index, key))
+    }
+
+    pub fn len(&self) -> usize {
+        self.dbs.read().unwrap().len()
+    }
+
+    pub fn db(&self, index: usize) -> Db {
+        self.dbs.read().unwrap()[index].clone()
+    }
+
+    pub fn all(&self) -> Vec<Db> {
+        self.dbs.read().unwrap().clone()
+    }
+
+    pub fn swap(&self, a: usize, b: usize) {
+        let mut dbs = self.dbs.write().unwrap();
+        dbs.swap(a, b);
+        dbs[a].index.store(a, Ordering::Relaxed);
+        dbs[b].index.store(b, Ordering::Relaxed);
+        drop(dbs);
+        self.dirty.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn flush(&self, index: usize, lazy: bool) {
+        // every removed key counts as a change, as does the flush itself
+        let removed = if lazy {
+            let fresh = Db::new(index, Arc::clone(&self.pub_sub), Arc::clone(&self.dirty), Arc::clone(&self.settings));
+            let old = std::mem::replace(&mut self.dbs.write().unwrap()[index], fresh);
+            let removed = old.len();
+            tokio::task::spawn_blocking(move || drop(old));
+            removed
+        } else {
+            let dbs = self.dbs.read().unwrap();
+            if dbs[index].frozen.read().unwrap().is_empty() {
+                dbs[index].clear()
+            } else {
+                // a snapshot reading the database keeps it whole, so it is
+                // replaced rather than cleared under the snapshot
+                drop(dbs);
+                return self.flush(index, true);
+            }
+        };
+        self.dirty.fetch_add(removed as u64 + 1, Ordering::Relaxed);
+    }
+
+    pub fn flush_all(&self, lazy: bool) {
+        for index in 0..self.len() {
+            self.flush(index, lazy);
+        }
+    }
+
+    /// Takes a snapshot of every database, as they are between two commands.
+    pub fn snapshot(&self) -> Snapshot {
+        // MOVE holds the list shared, so no key is caught between two databases
+        let dbs = self.dbs.write().unwrap();
+        let dbs = dbs
+            .iter()
+            .map(|db| {
+                let shards = (0..db.entries.shards().len()).map(|_| Mutex::default()).collect();
+                let frozen = Arc::new(Frozen { shards });
+                db.frozen.write().unwrap().push(Arc::clone(&frozen));
+                (db.clone(), frozen)
+            })
+            .collect();
+        Snapshot { dbs }
+    }
+
+    /// Moves `key` from database `from` to database `to`.
+    ///
+    /// Returns false if the key does not exist in the source or already exists
+    /// in the destination, in which case nothing is changed.
+    pub fn move_key(&self, key: &[u8], from: usize, to: usize) -> bool {
+        let dbs = self.dbs.read().unwrap();
+        let (source, target) = (&dbs[from], &dbs[to]);
+
+        if source.expire_if_needed(key) || !source.entries.contains_key(key) {
+            return false;
+        }
+        target.expire_if_needed(key);
+
+        // no shard of the target is held while the source is written, or two
+        // MOVEs in opposite directions could each wait for the other's shard
+        if target.entries.contains_key(key) {
+            return false;
+        }
+        let Some((entry, expiry)) = source.change(key, || {
+            let (_, entry) = source.entries.remove_if(key, |key, _| {
+                source.scan_index.remove(&source.entries, key);
+                true
+            })?;
+            let expiry = source.take_expiry(key);
+            source.used_memory.fetch_sub(entry.size, Ordering::Relaxed);
+            Some((entry, expiry))
+        }) else {
+            return false;
+        };
+
+        let key = Bytes::copy_from_slice(key);
+        let rejected = target.change(&key, || {
+            let dashmap::mapref::entry::Entry::Vacant(slot) = target.entries.entry(key.clone()) else {
+                return Some(entry);
+            };
+            target.used_memory.fetch_add(entry.size, Ordering::Relaxed);
+            target.scan_index.insert(&target.entries, key.clone());
+            slot.insert(entry);
+            if let Some(expiry) = expiry {
+                target.store_expiry(key.clone(), expiry);
+            }
+            None
+        });
+        // the key was written to the target in the meantime: that one stays
+        // and this one goes back, unless the source was written to as well
+        if let Some(entry) = rejected {
+            source.change(&key, || {
+                if let dashmap::mapref::entry::Entry::Vacant(slot) = source.entries.entry(key.clone()) {
+                    source.used_memory.fetch_add(entry.size, Ordering::Relaxed);
+                    source.scan_index.insert(&source.entries, key.clone());
+                    slot.insert(entry);
+                    if let Some(expiry) = expiry {
+                        source.store_expiry(key.clone(), expiry);
+                    }
+                }
+            });
+            return false;
+        }
+
+        self.dirty.fetch_add(1, Ordering::Relaxed);
+        target.notify(Events::NEW, "new", &key);
+        source.notify(Events::GENERIC, "move_from", &key);
+        target.notify(Events::GENERIC, "move_to", &key);
+        true
+    }
+
+    /// Starts saving the dataset whenever the save rules are met. Called once
+    /// the dataset is loaded: loading the append only file counts its writes
+    /// as changes, and a save rule met halfway would overwrite the dump file
+    /// with part of the dataset.
+    pub fn start_snapshot_task(&self) {
+        let databases = self.clone();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(1));
+
+            loop {
+                interval.tick().await;
+
+                let state = databases.save_state();
+                // a scheduled BGSAVE waits for the append only file rewrite to end
+                if state.in_progress() || databases.aof().is_some_and(|aof| aof.rewrite_in_progress()) {
+                    continue;
+                }
+                let since_last_save = (unix_millis() / 1000).saturating_sub(state.last_save());
+                let due = databases.settings.save_rules().due(databases.dirty(), since_last_save)
+                    // after a failure, retry no more often than every few seconds
+                    && (state.last_ok() || state.since_last_attempt_ms() >= SNAPSHOT_RETRY_DELAY.as_millis() as u64);
+
+                if (state.scheduled() || due)
+                    && let Err(e) = persistence::bgsave(&databases, false)
+                {
+                    warn!("periodic snapshot not started: {}", e);
+                }
+            }
+        });
+    }
 }
 
 impl Db {
-    pub fn new() -> Db {
+    fn new(
+        index: usize,
+        pub_sub: Arc<PubSub>,
+        dirty: Arc<AtomicU64>,
+        settings: Arc<Settings>,
+    ) -> Db {
+        let (entries, expirations) = (DashMap::new(), DashMap::new());
         let db = Db {
-            entries: Arc::new(DashMap::new()),
-            expirations: Arc::new(DashMap::new()),
-            pub_sub: Arc::new(DashMap::new()),
-            changed: Arc::new(AtomicBool::new(false)),
+            scan_index: Arc::new(ScanIndex::new(&entries)),
+            entries: Arc::new(entries),
+            volatile_index: Arc::new(ScanIndex::new(&expirations)),
+            expirations: Arc::new(expirations),
+            used_memory: Arc::new(AtomicUsize::new(0)),
+            index: Arc::new(AtomicUsize::new(index)),
+            pub_sub,
+            dirty,
+            settings,
+            frozen: Arc::new(RwLock::new(Vec::new())),
         };
         db.start_eviction_task();
-        db.start_snapshot_task();
         db
     }
 
-    pub fn set(&self, key: String, value: Bytes, duration: Option<Duration>) {
-        self.entries.insert(key.clone(), value);
-        self.changed.store(true, Ordering::Relaxed);
-        
-        if let Some(dur) = duration {
-            let expiry = Instant::now() + dur;
-            self.expirations.insert(key, expiry);
-        } else {
-            self.expirations.remove(&key);
+    pub fn set(&self, key: Bytes, value: Bytes, duration: Option<Duration>) {
+        self.insert(key.clone(), Value::string(value), duration.map(|dur| Instant::now() + dur));
+        self.notify(Events::STRING, "set", &key);
+        if duration.is_some() {
+            self.notify(Events::GENERIC, "expire", &key);
+        }
+    }
+
+    /// Stores a value of any type, replacing whatever the key held before.
+    ///
+    /// The value is converted to its compact or full encoding according to
+    /// the current `*-max-listpack-*` and `set-max-intset-entries` limits.
+    /// A key that did not exist is announced as new; the command storing it
+    /// announces its own event.
+    pub fn insert(&self, key: Bytes, value: Value, expiry: Option<Instant>) {
+        let value = value.encode(&self.settings.encoding_limits());
+        let added = self.change(&key, || {
+            let added = self.insert_entry(key.clone(), Entry::new(&key, value));
+            if let Some(expiry) = expiry {
+                self.store_expiry(key.clone(), expiry);
+            } else {
+                self.take_expiry(&key);
+            }
+            added
+        });
+        self.dirty.fetch_add(1, Ordering::Relaxed);
+
+        if added {
+            self.notify(Events::NEW, "new", &key);
+        }
+    }
+
+    /// Stores a key read from a snapshot or log at startup, without counting
+    /// it as a change to the dataset.
+    pub fn insert_loaded(&self, key: Bytes, value: Value, expiry: Option<Instant>) {
+        let entry = Entry::new(&key, value.encode(&self.settings.encoding_limits()));
+        self.change(&key, || {
+            if let Some(expiry) = expiry {
+                self.store_expiry(key.clone(), expiry);
+            } else {
+                self.take_expiry(&key);
+            }
+            self.insert_entry(key.clone(), entry);
+        });
+    }
+
+    /// Stores `entry`, returning whether `key` is new.
+    fn insert_entry(&self, key: Bytes, entry: Entry) -> bool {
+        self.used_memory.fetch_add(entry.size, Ordering::Relaxed);
+        match self.entries.entry(key) {
+            dashmap::mapref::entry::Entry::Occupied(mut slot) => {
+                let old = slot.insert(entry);
+                self.used_memory.fetch_sub(old.size, Ordering::Relaxed);
+                false
+            }
+            dashmap::mapref::entry::Entry::Vacant(slot) => {
+                self.scan_index.insert(&self.entries, slot.key().clone());
+                slot.insert(entry);
+                true
+            }
+        }
+    }
+
+    /// Removes every key, returning how many there were.
+    fn clear(&self) -> usize {
+        // keys stored while this runs are either removed with their size or
+        // kept with it, so only the sizes of those removed are taken off
+        let (mut removed, mut freed) = (0, 0);
+        self.entries.retain(|key, entry| {
+            self.scan_index.remove(&self.entries, key);
+            removed += 1;
+            freed += entry.size;
+            false
+        });
+        self.expirations.retain(|key, _| {
+            self.volatile_index.remove(&self.expirations, key);
+            false
+        });
+        self.used_memory.fetch_sub(freed, Ordering::Relaxed);
+        removed
+    }
+
+    fn store_expiry(&self, key: Bytes, expiry: Instant) {
+        match self.expirations.entry(key) {
+            dashmap::mapref::entry::Entry::Occupied(mut slot) => {
+                slot.insert(expiry);
+            }
+            dashmap::mapref::entry::Entry::Vacant(slot) => {
+                self.volatile_index.insert(&self.expirations, slot.key().clone());
+                slot.insert(expiry);
+            }
+        }
+    }
+
+    fn take_expiry(&self, key: &[u8]) -> Option<Instant> {
+        let removed = self.expirations.remove_if(key, |key, _| {
+            self.volatile_index.remove(&self.expirations, key);
+            true
+        });
+        removed.map(|(_, expiry)| expiry)
+    }
+
+    fn remove_entry(&self, key: &[u8]) -> bool {
+        let removed = self.entries.remove_if(key, |key, _| {
+            self.scan_index.remove(&self.entries, key);
+            true
+        });
+        match removed {
+            Some((_, entry)) => {
+                self.used_memory.fetch_sub(entry.size, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn used_memory(&self) -> usize {
+        self.used_memory.load(Ordering::Relaxed)
+    }
+
+    /// Number of keys with a TTL.
+    pub fn expires_len(&self) -> usize {
+        self.expirations.len()
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>, WrongType> {
+        match self.with_value(key, |value| match value {
+            Value::String(string) => Ok(string.to_bytes()),
+            _ => Err(WrongType),
+        }) {
+            Some(result) => result.map(Some),
+            None => {
+                self.notify(Events::KEY_MISS, "keymiss", key);
+                Ok(None)
+            }
         }
     }
 
-    pub fn bulk_insert(&self, entries: std::collections::HashMap<String, Bytes>) {
-        for (key, value) in entries {
-            self.entries.insert(key, value);
+    /// Runs `f` against the live value stored at `key`, if any, counting it as an access.
+    pub fn with_value<R>(&self, key: &[u8], f: impl FnOnce(&Value) -> R) -> Option<R> {
+        if self.expire_if_needed(key) {
+            return None;
+        }
+
+        self.entries.get(key).map(|entry| {
+            entry.touch();
+            f(&entry.value)
+        })
+    }
+
+    /// Runs `f` against the entry stored at `key` without counting it as an access.
+    pub fn with_entry<R>(&self, key: &[u8], f: impl FnOnce(&Entry) -> R) -> Option<R> {
+        if self.expire_if_needed(key) {
+            return None;
         }
+
+        self.entries.get(key).map(|entry| f(entry.value()))
+    }
+
+    pub fn exists(&self, key: &[u8]) -> bool {
+        self.with_value(key, |_| ()).is_some()
+    }
+
+    pub fn expiry(&self, key: &[u8]) -> Option<Instant> {
+        self.expirations.get(key).map(|entry| *entry.value())
+    }
+
+    pub fn key_type(&self, key: &[u8]) -> Option<&'static str> {
+        self.with_entry(key, |entry| entry.value.type_name())
+    }
+
+    pub fn keys(&self, pattern: &[u8]) -> Vec<Bytes> {
+        let keys: Vec<Bytes> = self
+            .entries
+            .iter()
+            .filter(|entry| scan::glob_match(pattern, entry.key(), false))
+            .map(|entry| entry.key().clone())
+            .collect();
+
+        keys.into_iter().filter(|key| !self.expire_if_needed(key)).collect()
+    }
+
+    pub fn scan(
+        &self,
+        cursor: u64,
+        pattern: Option<&[u8]>,
+        count: usize,
+        key_type: Option<&str>,
+    ) -> (u64, Vec<Bytes>) {
+        let (next, keys) = self.scan_index.scan(cursor, count);
+
+        let keys = keys
+            .into_iter()
+            .filter(|key| match pattern {
+                Some(pattern) => scan::glob_match(pattern, key, false),
+                None => true,
+            })
+            .filter(|key| match self.key_type(key) {
+                Some(found) => key_type.is_none_or(|wanted| wanted.eq_ignore_ascii_case(found)),
+                None => false,
+            })
+            .collect();
+
+        (next, keys)
     }
 
-    pub fn get(&self, key: &str) -> Option<Bytes> {
-        if let Some(expiry_entry) = self.expirations.get(key) {
-            if Instant::now() > *expiry_entry.value() {
-                drop(expiry_entry);
-                self.entries.remove(key);
-                self.expirations.remove(key);
-                return None;
+    pub fn expire_if_needed(&self, key: &[u8]) -> bool {
+        if let Some(expiry_entry) = self.expirations.get(key)
+            && Instant::now() > *expiry_entry.value()
+        {
+            drop(expiry_entry);
+            let removed = self.change(key, || {
+                self.take_expiry(key);
+                self.remove_entry(key)
+            });
+            if removed {
+                self.notify(Events::EXPIRED, "expired", key);
             }
+            return true;
+        }
+        false
+    }
+
+    pub fn del(&self, key: &[u8]) -> bool {
+        let removed = self.remove(key);
+        if removed {
+            self.notify(Events::GENERIC, "del", key);
         }
-        
-        self.entries.get(key).map(|entry| entry.value().clone())
+        removed
     }
 
-    pub fn del(&self, key: &str) -> bool {
-        let removed = self.entries.remove(key).is_some();
+    /// Deletes `key` without announcing it, for callers with an event of their own.
+    fn remove(&self, key: &[u8]) -> bool {
+        let removed = self.change(key, || {
+            self.take_expiry(key);
+            self.remove_entry(key)
+        });
         if removed {
-            self.changed.store(true, Ordering::Relaxed);
+            self.dirty.fetch_add(1, Ordering::Relaxed);
         }
-        self.expirations.remove(key);
         removed
     }
 
+    /// Runs `change`, which writes `key`, once what the key holds is kept
+    /// for the snapshots that have yet to read it.
+    fn change<R>(&self, key: &[u8], change: impl FnOnce() -> R) -> R {
+        // held until the change is made, so a snapshot starts before or after it
+        let frozen = self.frozen.read().unwrap();
+        for snapshot in frozen.iter() {
+            snapshot.keep(&self.entries, &self.expirations, key);
+        }
+        change()
+    }
+
+    /// The position of the database, as SELECT takes it.
+    pub fn index(&self) -> usize {
+        self.index.load(Ordering::Relaxed)
+    }
+
+    /// Publishes the keyspace and keyevent notifications of `event` on `key`
+    /// if `notify-keyspace-events` enables its `class`. Every write calls this
+    /// for the keys it changes, with the event name redis uses.
+    pub fn notify(&self, class: Events, event: &str, key: &[u8]) {
+        notify::notify(&self.pub_sub, self.settings.notify_keyspace_events(), class, event, key, self.index());
+    }
+
     fn start_eviction_task(&self) {
-        let entries = Arc::clone(&self.entries);
-        let expirations = Arc::clone(&self.expirations);
+        let entries: Weak<DashMap<Bytes, Entry>> = Arc::downgrade(&self.entries);
+        let expirations: Weak<DashMap<Bytes, Instant>> = Arc::downgrade(&self.expirations);
+        let used_memory = Arc::clone(&self.used_memory);
+        let index = Arc::clone(&self.index);
+        let pub_sub = Arc::clone(&self.pub_sub);
+        let settings = Arc::clone(&self.settings);
+        let frozen = Arc::clone(&self.frozen);
+        let scan_index = Arc::clone(&self.scan_index);
+        let volatile_index = Arc::clone(&self.volatile_index);
 
         tokio::spawn(async move {
             let mut interval = tokio::time::interval(Duration::from_millis(100));
             
             loop {
                 interval.tick().await;
+
+                // the database was flushed asynchronously and dropped
+                let (Some(entries), Some(expirations)) = (entries.upgrade(), expirations.upgrade()) else {
+                    break;
+                };
                 
                 let now = Instant::now();
                 let mut evicted = 0;
 
-                let keys_to_check: Vec<String> = expirations
+                let keys_to_check: Vec<Bytes> = expirations
                     .iter()
                     .take(20)
                     .map(|entry| entry.key().clone())
                     .collect();
 
                 for key in keys_to_check {
-                    if let Some(expiry_entry) = expirations.get(&key) {
-                        if now > *expiry_entry.value() {
-                            drop(expiry_entry);
-                            entries.remove(&key);
-                            expirations.remove(&key);
-                            evicted += 1;
+                    if let Some(expiry_entry) = expirations.get(&key)
+                        && now > *expiry_entry.value()
+                    {
+                        drop(expiry_entry);
+                        let snapshots = frozen.read().unwrap();
+                        for snapshot in snapshots.iter() {
+                            snapshot.keep(&entries, &expirations, &key);
                         }
+                        expirations.remove_if(&key, |key, _| {
+                            volatile_index.remove(&expirations, key);
+                            true
+                        });
+                        let removed = entries.remove_if(&key, |key, _| {
+                            scan_index.remove(&entries, key);
+                            true
+                        });
+                        drop(snapshots);
+                        if let Some((_, entry)) = removed {
+                            used_memory.fetch_sub(entry.size, Ordering::Relaxed);
+                            let events = settings.notify_keyspace_events();
+                            notify::notify(&pub_sub, events, Events::EXPIRED, "expired", &key, index.load(Ordering::Relaxed));
+                        }
+                        evicted += 1;
                     }
                 }
 
@@ -104,56 +934,235 @@ impl Db {
         });
     }
 
-    pub fn subscribe(&self, channel: String) -> broadcast::Receiver<Bytes> {
-        self.pub_sub
-            .entry(channel)
-            .or_insert_with(|| {
-                let (tx, _rx) = broadcast::channel(32);
-                tx
-            })
-            .value()
-            .subscribe()
+    /// Sends `msg` to the subscribers of `channel` and of every pattern
+    /// matching it, returning how many received it.
+    pub fn publish(&self, channel: Bytes, msg: Bytes) -> usize {
+        self.pub_sub.publish(channel, msg)
     }
+}
 
-    pub fn publish(&self, channel: String, msg: Bytes) -> usize {
-        if let Some(tx) = self.pub_sub.get(&channel) {
-            tx.send(msg).unwrap_or(0)
-        } else {
-            0
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use clap::Parser;
+
+    fn databases(count: usize) -> Databases {
+        let config = Config::parse_from(["rusty-redis", "--databases", &count.to_string()]);
+        Databases::new(count, Arc::new(Settings::new(&config)))
+    }
+
+    fn shard_contents(snapshot: &Snapshot, index: usize) -> Vec<(Bytes, Value)> {
+        let mut keys: Vec<_> = (0..snapshot.shards(index))
+            .flat_map(|shard| snapshot.read_shard(index, shard))
+            .map(|(key, value, _)| (key, value))
+            .collect();
+        keys.sort_by(|a, b| a.0.cmp(&b.0));
+        keys
+    }
+
+    fn string(value: &Value) -> Bytes {
+        match value {
+            Value::String(string) => string.to_bytes(),
+            _ => panic!("not a string"),
         }
     }
 
-    fn start_snapshot_task(&self) {
-        let changed = Arc::clone(&self.changed);
-        let entries = Arc::clone(&self.entries);
-        let expirations = Arc::clone(&self.expirations);
-        let pub_sub = Arc::clone(&self.pub_sub);
+    #[tokio::test]
+    async fn snapshot_keeps_the_keys_as_they_were() {
+        let databases = databases(2);
+        databases.db(0).set("moved".into(), "1".into(), None);
+        databases.db(0).set("changed".into(), "old".into(), Some(Duration::from_secs(100)));
+        databases.db(0).set("deleted".into(), "x".into(), None);
+        databases.db(1).set("kept".into(), "2".into(), None);
+        let snapshot = databases.snapshot();
 
-        tokio::spawn(async move {
-            let mut interval = tokio::time::interval(Duration::from_secs(60));
-            
-            loop {
-                interval.tick().await;
-                
-                if changed.swap(false, Ordering::Relaxed) {
-                    let snapshot_db = Db {
-                        entries: Arc::clone(&entries),
-                        expirations: Arc::clone(&expirations),
-                        pub_sub: Arc::clone(&pub_sub),
-                        changed: Arc::clone(&changed),
-                    };
-                    
-                    match crate::persistence::save(&snapshot_db, "dump.rdb").await {
-                        Ok(_) => {
-                            let count = snapshot_db.entries.len();
-                            info!("auto-saved {} keys to disk", count);
-                        }
-                        Err(e) => {
-                            error!("auto-save failed: {}", e);
+        assert!(databases.move_key(b"moved", 0, 1));
+        databases.swap(0, 1);
+        databases.db(1).set("changed".into(), "new".into(), None);
+        databases.db(1).del(b"deleted");
+        databases.db(0).set("added".into(), "3".into(), None);
+        databases.flush(0, false);
+
+        let db0 = shard_contents(&snapshot, 0);
+        let names: Vec<_> = db0.iter().map(|(key, _)| key.clone()).collect();
+        assert_eq!(names, ["changed", "deleted", "moved"]);
+        assert_eq!(string(&db0[0].1), "old");
+        let db1 = shard_contents(&snapshot, 1);
+        assert_eq!(db1.len(), 1);
+        assert_eq!(string(&db1[0].1), "2");
+
+        // the flush could not clear what the snapshot holds, so it replaced the database
+        assert_eq!(databases.db(0).len(), 0);
+        assert_eq!(databases.db(1).get(b"changed").unwrap(), Some("new".into()));
+        drop(snapshot);
+        assert!(databases.db(1).frozen.read().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn snapshot_keeps_changed_keys_only_until_their_shard_is_read() {
+        let databases = databases(1);
+        let db = databases.db(0);
+        for i in 0..100 {
+            db.set(format!("key{}", i).into(), "old".into(), None);
+        }
+        let snapshot = databases.snapshot();
+        let keys: usize = (0..snapshot.shards(0)).map(|shard| snapshot.read_shard(0, shard).len()).sum();
+        assert_eq!(keys, 100);
+
+        // every shard was read, so nothing is kept aside any more
+        for i in 0..100 {
+            db.set(format!("key{}", i).into(), "new".into(), None);
+        }
+        let frozen = &snapshot.dbs[0].1;
+        assert!(frozen.shards.iter().all(|shard| shard.lock().unwrap().saved.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn pausing_writes_waits_for_logged_writes() {
+        let databases = databases(1);
+        let write = databases.write_guard().await;
+        assert!(databases.writes.try_write().is_err());
+        drop(write);
+
+        let paused = databases.pause_writes().await;
+        assert!(databases.writes.try_read().is_err());
+        drop(paused);
+        assert!(databases.writes.try_read().is_ok());
+    }
+
+    #[tokio::test]
+    async fn move_keeps_a_key_already_in_the_target() {
+        let databases = databases(2);
+        databases.db(0).set("k".into(), "source".into(), Some(Duration::from_secs(100)));
+        databases.db(1).set("k".into(), "target".into(), None);
+        assert!(!databases.move_key(b"k", 0, 1));
+        assert_eq!(databases.db(0).get(b"k").unwrap(), Some("source".into()));
+        assert_eq!(databases.db(1).get(b"k").unwrap(), Some("target".into()));
+
+        databases.db(1).del(b"k");
+        let used = databases.used_memory();
+        assert!(databases.move_key(b"k", 0, 1));
+        assert_eq!(databases.db(0).len(), 0);
+        assert_eq!(databases.db(0).used_memory(), 0);
+        assert_eq!(databases.used_memory(), used);
+        assert!(databases.db(1).expiry(b"k").is_some());
+    }
+
+    #[tokio::test]
+    async fn moves_in_opposite_directions_do_not_deadlock() {
+        let databases = databases(2);
+        for i in 0..200 {
+            for (prefix, db) in [("a", 0), ("b", 1), ("c", 0), ("d", 1)] {
+                databases.db(db).set(format!("{}{}", prefix, i).into(), "1".into(), None);
+            }
+        }
+
+        std::thread::scope(|scope| {
+            for (prefix, from, to) in [("a", 0, 1), ("b", 1, 0), ("c", 0, 1), ("d", 1, 0)] {
+                let databases = &databases;
+                scope.spawn(move || {
+                    for round in 0..200 {
+                        let (from, to) = if round % 2 == 0 { (from, to) } else { (to, from) };
+                        for i in 0..200 {
+                            databases.move_key(format!("{}{}", prefix, i).as_bytes(), from, to);
                         }
                     }
+                });
+            }
+        });
+        assert_eq!(databases.db(0).len() + databases.db(1).len(), 800);
+    }
+
+    #[tokio::test]
+    async fn flush_takes_off_only_the_sizes_it_removed() {
+        let databases = databases(1);
+        let db = databases.db(0);
+        for i in 0..100 {
+            db.set(format!("key{}", i).into(), "value".into(), None);
+        }
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for i in 0..2000 {
+                    db.set(format!("late{}", i).into(), "value".into(), None);
                 }
+            });
+            for _ in 0..20 {
+                databases.flush(0, false);
             }
         });
+
+        let sizes: usize = db.entries.iter().map(|entry| entry.size).sum();
+        assert_eq!(db.used_memory(), sizes);
+        databases.flush(0, false);
+        assert_eq!(db.used_memory(), 0);
+        assert_eq!(db.len(), 0);
+    }
+
+    /// Stores `a`, `b` and `c` under `policy`, then lowers maxmemory so that
+    /// exactly one of them has to go. The default five samples cover them all.
+    fn over_maxmemory(policy: &str, prepare: impl FnOnce(&Db)) -> (Databases, Db) {
+        let databases = databases(1);
+        let db = databases.db(0);
+        databases.settings().set("maxmemory-policy", policy).unwrap();
+        for key in ["a", "b", "c"] {
+            db.set(key.into(), "value".into(), None);
+        }
+        prepare(&db);
+        let maxmemory = databases.used_memory() - 1;
+        databases.settings().set("maxmemory", &maxmemory.to_string()).unwrap();
+        (databases, db)
+    }
+
+    fn remaining(db: &Db) -> Vec<&'static str> {
+        ["a", "b", "c"].into_iter().filter(|key| db.entries.contains_key(key.as_bytes())).collect()
+    }
+
+    #[tokio::test]
+    async fn allkeys_lru_evicts_the_longest_idle_key() {
+        let (databases, db) = over_maxmemory("allkeys-lru", |db| {
+            db.entries.get(b"b".as_slice()).unwrap().set_idle_seconds(100);
+            db.entries.get(b"c".as_slice()).unwrap().set_idle_seconds(10);
+        });
+        assert!(databases.free_memory_if_needed());
+        assert_eq!(remaining(&db), ["a", "c"]);
+        assert_eq!(databases.evicted_keys(), 1);
+    }
+
+    #[tokio::test]
+    async fn allkeys_lfu_evicts_the_least_used_key() {
+        let (databases, db) = over_maxmemory("allkeys-lfu", |db| {
+            db.entries.get(b"a".as_slice()).unwrap().set_frequency(10);
+            db.entries.get(b"b".as_slice()).unwrap().set_frequency(20);
+            db.entries.get(b"c".as_slice()).unwrap().set_frequency(1);
+        });
+        assert!(databases.free_memory_if_needed());
+        assert_eq!(remaining(&db), ["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn volatile_ttl_evicts_the_key_expiring_first() {
+        let (databases, db) = over_maxmemory("volatile-ttl", |db| {
+            db.set("b".into(), "value".into(), Some(Duration::from_secs(100)));
+            db.set("c".into(), "value".into(), Some(Duration::from_secs(10)));
+        });
+        assert!(databases.free_memory_if_needed());
+        assert_eq!(remaining(&db), ["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn volatile_policies_fail_without_keys_to_expire() {
+        let (databases, db) = over_maxmemory("volatile-lru", |_| {});
+        assert!(!databases.free_memory_if_needed());
+        assert_eq!(remaining(&db), ["a", "b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn noeviction_keeps_every_key() {
+        let (databases, db) = over_maxmemory("noeviction", |_| {});
+        assert!(!databases.free_memory_if_needed());
+        assert_eq!(remaining(&db), ["a", "b", "c"]);
+        assert_eq!(databases.evicted_keys(), 0);
     }
 }
@@ -0,0 +1,205 @@
+//! Compact in-memory encodings for small collections.
+//!
+//! `Listpack` uses the exact byte layout of redis listpacks, so it is decoded
+//! with the same parser that reads listpacks out of RDB payloads. `Intset`
+//! likewise stores its integers as packed as a redis intset does.
+
+use bytes::Bytes;
+
+use crate::rdb;
+use crate::value::canonical_integer;
+
+const HEADER_SIZE: usize = 6;
+const TERMINATOR: u8 = 0xff;
+/// Element count stored in the header once the real count no longer fits.
+const UNKNOWN_LEN: u16 = u16::MAX;
+
+/// A sequence of strings packed into one allocation; integers take as little as one byte.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Listpack {
+    buf: Vec<u8>,
+}
+
+impl Listpack {
+    pub fn from_entries<T: AsRef<[u8]>>(entries: impl IntoIterator<Item = T>) -> Listpack {
+        let mut buf = vec![0; HEADER_SIZE];
+        let mut len = 0usize;
+        for entry in entries {
+            encode_entry(entry.as_ref(), &mut buf);
+            len += 1;
+        }
+        buf.push(TERMINATOR);
+
+        let total = buf.len() as u32;
+        buf[..4].copy_from_slice(&total.to_le_bytes());
+        let count = u16::try_from(len).unwrap_or(UNKNOWN_LEN);
+        buf[4..6].copy_from_slice(&count.to_le_bytes());
+
+        Listpack { buf }
+    }
+
+    pub fn len(&self) -> usize {
+        match u16::from_le_bytes([self.buf[4], self.buf[5]]) {
+            UNKNOWN_LEN => self.entries().len(),
+            len => len as usize,
+        }
+    }
+
+    /// Size of the packed buffer in bytes.
+    pub fn bytes_len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn entries(&self) -> Vec<Bytes> {
+        rdb::listpack_entries(&self.buf).expect("listpacks built by from_entries are well formed")
+    }
+
+    /// Entries taken two at a time, as stored by hashes and sorted sets.
+    pub fn pairs(&self) -> impl Iterator<Item = (Bytes, Bytes)> {
+        let mut entries = self.entries().into_iter();
+        std::iter::from_fn(move || Some((entries.next()?, entries.next()?)))
+    }
+}
+
+fn encode_entry(entry: &[u8], out: &mut Vec<u8>) {
+    let start = out.len();
+
+    match canonical_integer(entry) {
+        Some(n @ 0..=127) => out.push(n as u8),
+        Some(n @ -4096..=4095) => {
+            let n = n as u16 & 0x1fff;
+            out.push(0xc0 | (n >> 8) as u8);
+            out.push(n as u8);
+        }
+        Some(n) if i16::try_from(n).is_ok() => {
+            out.push(0xf1);
+            out.extend_from_slice(&(n as i16).to_le_bytes());
+        }
+        Some(n @ -0x80_0000..=0x7f_ffff) => {
+            out.push(0xf2);
+            out.extend_from_slice(&(n as i32).to_le_bytes()[..3]);
+        }
+        Some(n) if i32::try_from(n).is_ok() => {
+            out.push(0xf3);
+            out.extend_from_slice(&(n as i32).to_le_bytes());
+        }
+        Some(n) => {
+            out.push(0xf4);
+            out.extend_from_slice(&n.to_le_bytes());
+        }
+        None if entry.len() < 64 => {
+            out.push(0x80 | entry.len() as u8);
+            out.extend_from_slice(entry);
+        }
+        None if entry.len() < 4096 => {
+            out.push(0xe0 | (entry.len() >> 8) as u8);
+            out.push(entry.len() as u8);
+            out.extend_from_slice(entry);
+        }
+        None => {
+            out.push(0xf0);
+            out.extend_from_slice(&(entry.len() as u32).to_le_bytes());
+            out.extend_from_slice(entry);
+        }
+    }
+
+    encode_backlen(out.len() - start, out);
+}
+
+/// Appends the entry length, written so it can be read backwards from the next entry.
+fn encode_backlen(len: usize, out: &mut Vec<u8>) {
+    let groups = match len {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    };
+    for i in (0..groups).rev() {
+        let bits = ((len >> (7 * i)) & 0x7f) as u8;
+        // only the most significant group, read last, lacks the continuation bit
+        out.push(if i == groups - 1 { bits } else { bits | 0x80 });
+    }
+}
+
+/// A sorted array of integers, used for sets whose members are all integers.
+///
+/// Laid out like a redis intset: the element width and the count as
+/// little-endian u32s, then every element at the width of the widest one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Intset {
+    buf: Vec<u8>,
+}
+
+impl Intset {
+    pub fn from_values(values: impl IntoIterator<Item = i64>) -> Intset {
+        let mut values: Vec<i64> = values.into_iter().collect();
+        values.sort_unstable();
+        values.dedup();
+
+        let width = match (values.first(), values.last()) {
+            (Some(min), Some(max)) if i16::try_from(*min).is_ok() && i16::try_from(*max).is_ok() => 2,
+            (Some(min), Some(max)) if i32::try_from(*min).is_ok() && i32::try_from(*max).is_ok() => 4,
+            (Some(_), Some(_)) => 8,
+            _ => 2,
+        };
+        let mut buf = Vec::with_capacity(8 + width * values.len());
+        buf.extend_from_slice(&(width as u32).to_le_bytes());
+        buf.extend_from_slice(&(values.len() as u32).to_le_bytes());
+        for value in values {
+            buf.extend_from_slice(&value.to_le_bytes()[..width]);
+        }
+        Intset { buf }
+    }
+
+    pub fn len(&self) -> usize {
+        u32::from_le_bytes(self.buf[4..8].try_into().expect("4 bytes")) as usize
+    }
+
+    /// Size of the packed buffer in bytes.
+    pub fn bytes_len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = i64> + '_ {
+        let width = u32::from_le_bytes(self.buf[..4].try_into().expect("4 bytes")) as usize;
+        self.buf[8..].chunks_exact(width).map(move |element| match width {
+            2 => i16::from_le_bytes(element.try_into().expect("2 bytes")) as i64,
+            4 => i32::from_le_bytes(element.try_into().expect("4 bytes")) as i64,
+            _ => i64::from_le_bytes(element.try_into().expect("8 bytes")),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intset_packs_to_the_widest_element() {
+        for (values, width) in [
+            (vec![3, -7, 3, 1], 2),
+            (vec![1, 70_000, -2], 4),
+            (vec![i64::MIN, 0, i64::MAX], 8),
+        ] {
+            let intset = Intset::from_values(values.clone());
+            let mut sorted = values;
+            sorted.sort_unstable();
+            sorted.dedup();
+
+            assert_eq!(intset.iter().collect::<Vec<_>>(), sorted);
+            assert_eq!(intset.len(), sorted.len());
+            assert_eq!(intset.bytes_len(), 8 + width * sorted.len());
+            // the layout is that of a redis intset
+            assert_eq!(rdb::intset_entries(&intset.buf).unwrap(), sorted);
+        }
+    }
+
+    #[test]
+    fn empty_intset() {
+        let intset = Intset::from_values([]);
+        assert_eq!(intset.len(), 0);
+        assert_eq!(intset.iter().count(), 0);
+        assert_eq!(intset.bytes_len(), 8);
+    }
+}
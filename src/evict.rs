@@ -0,0 +1,136 @@
+use std::cell::Cell;
+use std::hash::{BuildHasher, RandomState};
+
+/// What to do when a write would push memory usage over `maxmemory`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    NoEviction,
+    AllKeysLru,
+    VolatileLru,
+    AllKeysLfu,
+    VolatileLfu,
+    AllKeysRandom,
+    VolatileRandom,
+    VolatileTtl,
+}
+
+impl EvictionPolicy {
+    pub const ALL: [EvictionPolicy; 8] = [
+        EvictionPolicy::NoEviction,
+        EvictionPolicy::AllKeysLru,
+        EvictionPolicy::VolatileLru,
+        EvictionPolicy::AllKeysLfu,
+        EvictionPolicy::VolatileLfu,
+        EvictionPolicy::AllKeysRandom,
+        EvictionPolicy::VolatileRandom,
+        EvictionPolicy::VolatileTtl,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            EvictionPolicy::NoEviction => "noeviction",
+            EvictionPolicy::AllKeysLru => "allkeys-lru",
+            EvictionPolicy::VolatileLru => "volatile-lru",
+            EvictionPolicy::AllKeysLfu => "allkeys-lfu",
+            EvictionPolicy::VolatileLfu => "volatile-lfu",
+            EvictionPolicy::AllKeysRandom => "allkeys-random",
+            EvictionPolicy::VolatileRandom => "volatile-random",
+            EvictionPolicy::VolatileTtl => "volatile-ttl",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<EvictionPolicy> {
+        Self::ALL.into_iter().find(|policy| policy.name().eq_ignore_ascii_case(name))
+    }
+
+    /// Whether keys are picked by access frequency, the only case where
+    /// OBJECT FREQ has a counter worth reporting.
+    pub fn is_lfu(self) -> bool {
+        matches!(self, EvictionPolicy::AllKeysLfu | EvictionPolicy::VolatileLfu)
+    }
+
+    /// Whether only keys with a TTL are candidates for eviction.
+    pub fn is_volatile(self) -> bool {
+        matches!(
+            self,
+            EvictionPolicy::VolatileLru
+                | EvictionPolicy::VolatileLfu
+                | EvictionPolicy::VolatileRandom
+                | EvictionPolicy::VolatileTtl
+        )
+    }
+}
+
+const LRU_CLOCK_MAX: u32 = (1 << 24) - 1;
+
+pub const LFU_INIT_VAL: u8 = 5;
+const LFU_LOG_FACTOR: f64 = 10.0;
+const LFU_DECAY_MINUTES: u32 = 1;
+
+/// The 24 bit LRU clock, in seconds, shared by every entry.
+pub fn lru_clock() -> u32 {
+    (crate::db::unix_millis() / 1000) as u32 & LRU_CLOCK_MAX
+}
+
+/// Seconds elapsed since `lru`, accounting for the clock wrapping around.
+pub fn lru_idle_seconds(lru: u32) -> u64 {
+    let now = lru_clock();
+    if now >= lru {
+        (now - lru) as u64
+    } else {
+        (now + (LRU_CLOCK_MAX - lru)) as u64
+    }
+}
+
+/// Packs the minutes clock used for LFU decay and a logarithmic counter.
+pub fn lfu_pack(counter: u8) -> u32 {
+    (lfu_minutes() << 8) | counter as u32
+}
+
+/// Returns the LFU counter after applying the decay for the time it was not accessed.
+pub fn lfu_decayed(packed: u32) -> u8 {
+    let counter = (packed & 0xff) as u8;
+    let last = packed >> 8;
+    let now = lfu_minutes();
+    let elapsed = if now >= last { now - last } else { now + (0xffff - last) };
+    let periods = elapsed / LFU_DECAY_MINUTES;
+    counter.saturating_sub(periods.min(255) as u8)
+}
+
+/// Records an access: decays the counter, then increments it with a
+/// probability that shrinks as the counter grows.
+pub fn lfu_touch(packed: u32) -> u32 {
+    let mut counter = lfu_decayed(packed);
+    if counter < 255 {
+        let base = counter.saturating_sub(LFU_INIT_VAL) as f64;
+        let probability = 1.0 / (base * LFU_LOG_FACTOR + 1.0);
+        if random_f64() < probability {
+            counter += 1;
+        }
+    }
+    lfu_pack(counter)
+}
+
+fn lfu_minutes() -> u32 {
+    (crate::db::unix_millis() / 60_000) as u32 & 0xffff
+}
+
+thread_local! {
+    static RNG_STATE: Cell<u64> = Cell::new(RandomState::new().hash_one(0u64) | 1);
+}
+
+/// xorshift64* — plenty for sampling eviction candidates.
+pub fn random_u64() -> u64 {
+    RNG_STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        state.set(x);
+        x.wrapping_mul(0x2545f4914f6cdd1d)
+    })
+}
+
+fn random_f64() -> f64 {
+    (random_u64() >> 11) as f64 / (1u64 << 53) as f64
+}
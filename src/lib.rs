@@ -0,0 +1,30 @@
+//! A concurrent redis-compatible key-value store.
+//!
+//! The modules are shared by the server and the `rusty-redis-check` tool
+//! rather than published as a library, so API lints aimed at library
+//! consumers are not followed.
+#![allow(clippy::len_without_is_empty)]
+
+pub mod analyze;
+pub mod aof;
+pub mod check;
+pub mod cmd;
+pub mod codec;
+pub mod config;
+pub mod connection;
+pub mod crc16;
+pub mod crc64;
+pub mod db;
+pub mod encoding;
+pub mod evict;
+pub mod frame;
+pub mod memory;
+pub mod migrate;
+pub mod notify;
+pub mod persistence;
+pub mod pubsub;
+pub mod rdb;
+pub mod scan;
+pub mod server;
+pub mod sort;
+pub mod value;
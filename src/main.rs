@@ -1,19 +1,34 @@
-mod frame;
-mod connection;
-mod db;
-mod cmd;
-mod persistence;
-
-use connection::Connection;
-use frame::Frame;
-use db::Db;
-use cmd::Command;
+use clap::Parser;
+use rusty_redis::analyze::{self, Report};
+use rusty_redis::aof::{self, Aof};
+use rusty_redis::config::{Config, Settings};
+use rusty_redis::db::Databases;
+use rusty_redis::{persistence, server};
+use std::sync::Arc;
 use tokio::net::TcpListener;
-use tracing::{error, info, Level};
+use tracing::{error, info, warn, Level};
 use tracing_subscriber::FmtSubscriber;
 
 #[tokio::main]
 async fn main() {
+    let config = Config::parse();
+
+    let report = match (config.bigkeys, config.hotkeys) {
+        (true, _) => Some(Report::BigKeys),
+        (_, true) => Some(Report::HotKeys),
+        _ => None,
+    };
+    if let Some(report) = report {
+        match analyze::request(&config.bind, config.db, report, analyze::DEFAULT_COUNT).await {
+            Ok(text) => print!("{}", text),
+            Err(e) => {
+                eprintln!("failed to analyze {}: {}", config.bind, e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
     let subscriber = FmtSubscriber::builder()
         .with_max_level(Level::DEBUG)
         .finish();
@@ -21,30 +36,43 @@ async fn main() {
     tracing::subscriber::set_global_default(subscriber)
         .expect("failed to set tracing subscriber");
 
-    let addr = "127.0.0.1:6379";
-    let db = Db::new();
+    let addr = config.bind.as_str();
+    let settings = Arc::new(Settings::new(&config));
+    let databases = Databases::new(config.databases as usize, Arc::clone(&settings));
 
-    let dump_file = "dump.rdb";
-    match tokio::fs::try_exists(dump_file).await {
-        Ok(true) => {
-            match persistence::load(dump_file).await {
-                Ok(entries) => {
-                    let count = entries.len();
-                    db.bulk_insert(entries);
-                    info!("loaded {} keys from disk", count);
-                }
-                Err(e) => {
-                    error!("failed to load dump file: {}", e);
-                }
+    if let Some(target) = config.recover_to {
+        recover(&settings, &databases, target).await;
+        return;
+    }
+
+    let mut loaded = false;
+    if config.appendonly {
+        match aof::load(&settings, &databases).await {
+            Ok(Some(count)) => {
+                info!("loaded {} keys and commands from the append only file", count);
+                loaded = true;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                error!("failed to load append only file: {}", e);
+                std::process::exit(1);
             }
         }
-        Ok(false) => {
-            info!("no dump file found, starting with empty database");
-        }
-        Err(e) => {
-            error!("failed to check for dump file: {}", e);
+    }
+    if !loaded {
+        load_dump(&databases, &settings).await;
+    }
+
+    if config.appendonly {
+        match Aof::open(Arc::clone(&settings), &databases).await {
+            Ok(aof) => databases.set_aof(aof),
+            Err(e) => {
+                error!("failed to open append only file in {}: {}", config.appenddirname, e);
+                std::process::exit(1);
+            }
         }
     }
+    databases.start_snapshot_task();
 
     let listener = match TcpListener::bind(addr).await {
         Ok(listener) => {
@@ -53,22 +81,33 @@ async fn main() {
         }
         Err(e) => {
             error!("failed to bind to {}: {}", addr, e);
-            return;
+            std::process::exit(1);
         }
     };
 
-    let db_for_shutdown = db.clone();
+    let databases_for_shutdown = databases.clone();
     tokio::spawn(async move {
         match tokio::signal::ctrl_c().await {
             Ok(()) => {
                 info!("received shutdown signal, saving database...");
-                match persistence::save(&db_for_shutdown, "dump.rdb").await {
-                    Ok(_) => {
-                        let count = db_for_shutdown.entries.len();
-                        info!("saved {} keys to disk", count);
-                    }
-                    Err(e) => {
-                        error!("failed to save on shutdown: {}", e);
+                if let Some(aof) = databases_for_shutdown.aof()
+                    && let Err(e) = aof.fsync()
+                {
+                    error!("failed to fsync append only file on shutdown: {}", e);
+                }
+                // with no save rules the server is a pure cache and leaves the dump file alone
+                if databases_for_shutdown.settings().save_rules().0.is_empty() {
+                    info!("snapshots are disabled, not saving on shutdown");
+                } else {
+                    // a background save still running would race with this one for the dump file
+                    match persistence::save_when_idle(&databases_for_shutdown).await {
+                        Ok(_) => {
+                            let count: usize = databases_for_shutdown.all().iter().map(|db| db.len()).sum();
+                            info!("saved {} keys to disk", count);
+                        }
+                        Err(e) => {
+                            error!("failed to save on shutdown: {}", e);
+                        }
                     }
                 }
                 std::process::exit(0);
@@ -82,156 +121,74 @@ async fn main() {
     loop {
         match listener.accept().await {
             Ok((socket, peer_addr)) => {
-                let db = db.clone();
-                tokio::spawn(async move {
-                    info!("accepted connection from: {}", peer_addr);
-
-                    let mut connection = Connection::new(socket);
-
-                    while let Ok(Some(frame)) = connection.read_frame().await {
-                        info!("received frame: {:?}", frame);
+                let databases = databases.clone();
+                tokio::spawn(server::handle_connection(socket, peer_addr, databases));
+            }
+            Err(e) => {
+                error!("failed to accept connection: {}", e);
+            }
+        }
+    }
+}
 
-                        match cmd::from_frame(frame) {
-                            Ok(command) => {
-                                info!("parsed command: {:?}", command);
-                                match command {
-                                    Command::Ping => {
-                                        let response = Frame::Simple("PONG".to_string());
-                                        if let Err(e) = connection.write_frame(&response).await {
-                                            error!("failed to write response: {}", e);
-                                            break;
-                                        }
-                                    }
-                                    Command::Set { key, value, expiry_seconds } => {
-                                        let duration = expiry_seconds.map(std::time::Duration::from_secs);
-                                        db.set(key, value, duration);
-                                        let response = Frame::Simple("OK".to_string());
-                                        if let Err(e) = connection.write_frame(&response).await {
-                                            error!("failed to write response: {}", e);
-                                            break;
-                                        }
-                                    }
-                                    Command::Get { key } => {
-                                        let response = match db.get(&key) {
-                                            Some(value) => Frame::Bulk(value),
-                                            None => Frame::Null,
-                                        };
-                                        if let Err(e) = connection.write_frame(&response).await {
-                                            error!("failed to write response: {}", e);
-                                            break;
-                                        }
-                                    }
-                                    Command::Del { key } => {
-                                        let deleted = db.del(&key);
-                                        let response = Frame::Integer(if deleted { 1 } else { 0 });
-                                        if let Err(e) = connection.write_frame(&response).await {
-                                            error!("failed to write response: {}", e);
-                                            break;
-                                        }
-                                    }
-                                    Command::Publish { channel, message } => {
-                                        let num_receivers = db.publish(channel, message);
-                                        let response = Frame::Integer(num_receivers as i64);
-                                        if let Err(e) = connection.write_frame(&response).await {
-                                            error!("failed to write response: {}", e);
-                                            break;
-                                        }
-                                    }
-                                    Command::Save => {
-                                        let db_clone = db.clone();
-                                        match persistence::save(&db_clone, "dump.rdb").await {
-                                            Ok(_) => {
-                                                info!("database saved to disk");
-                                                let response = Frame::Simple("OK".to_string());
-                                                if let Err(e) = connection.write_frame(&response).await {
-                                                    error!("failed to write response: {}", e);
-                                                    break;
-                                                }
-                                            }
-                                            Err(e) => {
-                                                error!("failed to save database: {}", e);
-                                                let response = Frame::Error(format!("ERR {}", e));
-                                                if let Err(e) = connection.write_frame(&response).await {
-                                                    error!("failed to write response: {}", e);
-                                                    break;
-                                                }
-                                            }
-                                        }
-                                    }
-                                    Command::Subscribe { channel } => {
-                                        let confirmation = Frame::Array(vec![
-                                            Frame::Bulk("subscribe".into()),
-                                            Frame::Bulk(channel.clone().into()),
-                                            Frame::Integer(1),
-                                        ]);
-                                        if let Err(e) = connection.write_frame(&confirmation).await {
-                                            error!("failed to write subscription confirmation: {}", e);
-                                            break;
-                                        }
+/// Rebuilds the dataset as it was at the unix time `target` from the append
+/// only file and writes it to a new snapshot, leaving the dump file alone.
+async fn recover(settings: &Settings, databases: &Databases, target: u64) {
+    // the snapshot task is never started, so the dump file is left alone
+    let recovery = match aof::recover(settings, databases, target).await {
+        Ok(recovery) => recovery,
+        Err(e) => {
+            error!("point-in-time recovery failed: {}", e);
+            std::process::exit(1);
+        }
+    };
+    info!(
+        "rebuilt the dataset from {} and the commands logged from {} on, {} keys and commands loaded",
+        recovery.base.display(),
+        recovery.started,
+        recovery.count
+    );
+    if !recovery.reached {
+        warn!("the append only file ends before {}, recovered everything it holds", target);
+    }
 
-                                        let mut rx = db.subscribe(channel.clone());
-                                        
-                                        loop {
-                                            tokio::select! {
-                                                result = rx.recv() => {
-                                                    match result {
-                                                        Ok(msg) => {
-                                                            let message_frame = Frame::Array(vec![
-                                                                Frame::Bulk("message".into()),
-                                                                Frame::Bulk(channel.clone().into()),
-                                                                Frame::Bulk(msg),
-                                                            ]);
-                                                            if let Err(e) = connection.write_frame(&message_frame).await {
-                                                                error!("failed to write message: {}", e);
-                                                                break;
-                                                            }
-                                                        }
-                                                        Err(e) => {
-                                                            error!("broadcast channel error: {}", e);
-                                                            break;
-                                                        }
-                                                    }
-                                                }
+    let path = format!("recovered-{}.rdb", target);
+    if let Err(e) = persistence::save(&databases.snapshot(), &path, settings.snapshot_format(), &settings.codecs()).await {
+        error!("failed to write recovered dataset to {}: {}", path, e);
+        std::process::exit(1);
+    }
+    info!("wrote the recovered dataset to {}", path);
+}
 
-                                                result = connection.read_frame() => {
-                                                    match result {
-                                                        Ok(Some(_frame)) => {
-                                                            info!("client sent command in subscription mode, exiting");
-                                                            break;
-                                                        }
-                                                        Ok(None) => {
-                                                            info!("client disconnected");
-                                                            break;
-                                                        }
-                                                        Err(e) => {
-                                                            error!("error reading frame: {}", e);
-                                                            break;
-                                                        }
-                                                    }
-                                                }
-                                            }
-                                        }
-                                        break;
-                                    }
-                                }
-                            }
-                            Err(e) => {
-                                error!("parse error: {}", e);
-                                let response = Frame::Error(format!("ERR {}", e));
-                                if let Err(e) = connection.write_frame(&response).await {
-                                    error!("failed to write response: {}", e);
-                                    break;
-                                }
-                            }
+async fn load_dump(databases: &Databases, settings: &Settings) {
+    let dump_file = persistence::DUMP_FILE;
+    match tokio::fs::try_exists(dump_file).await {
+        Ok(true) => {
+            match persistence::load(dump_file, databases).await {
+                Ok(count) => {
+                    info!("loaded {} keys from disk", count);
+                }
+                Err(e) => {
+                    error!("failed to load dump file: {} (run `rusty-redis-check {}` for details)", e, dump_file);
+                    if settings.abort_on_corrupt_dump() {
+                        error!("refusing to start with a corrupt dump file");
+                        std::process::exit(1);
+                    }
+                    match persistence::set_aside_corrupt(dump_file, databases) {
+                        Ok(path) => warn!("moved the corrupt dump file to {}, starting with an empty database", path),
+                        Err(e) => {
+                            error!("failed to move the corrupt dump file aside, refusing to start: {}", e);
+                            std::process::exit(1);
                         }
                     }
-
-                    info!("connection closed from: {}", peer_addr);
-                });
-            }
-            Err(e) => {
-                error!("failed to accept connection: {}", e);
+                }
             }
         }
+        Ok(false) => {
+            info!("no dump file found, starting with empty database");
+        }
+        Err(e) => {
+            error!("failed to check for dump file: {}", e);
+        }
     }
 }
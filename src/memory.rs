@@ -0,0 +1,192 @@
+use bytes::Bytes;
+
+use crate::db::Databases;
+use crate::evict::EvictionPolicy;
+use crate::frame::Frame;
+
+/// Below this the doctor has too little data to say anything useful.
+const DOCTOR_MIN_MEMORY: usize = 1024 * 1024;
+
+/// Builds the MEMORY STATS reply: a flat list of name/value pairs.
+pub fn stats(databases: &Databases) -> Frame {
+    let used = databases.used_memory();
+    let dbs = databases.all();
+    let keys: usize = dbs.iter().map(|db| db.len()).sum();
+
+    let mut fields = vec![
+        ("peak.allocated".to_string(), Frame::Integer(databases.peak_memory() as i64)),
+        ("total.allocated".to_string(), Frame::Integer(used as i64)),
+        ("keys.count".to_string(), Frame::Integer(keys as i64)),
+        (
+            "keys.bytes-per-key".to_string(),
+            Frame::Integer(used.checked_div(keys).unwrap_or(0) as i64),
+        ),
+        ("dataset.bytes".to_string(), Frame::Integer(used as i64)),
+        ("evicted.keys".to_string(), Frame::Integer(databases.evicted_keys() as i64)),
+        ("maxmemory".to_string(), Frame::Integer(databases.settings().maxmemory() as i64)),
+        (
+            "maxmemory.policy".to_string(),
+            Frame::Bulk(databases.settings().maxmemory_policy().name().into()),
+        ),
+    ];
+
+    for (index, db) in dbs.iter().enumerate() {
+        if db.len() == 0 {
+            continue;
+        }
+        fields.push((
+            format!("db.{}", index),
+            Frame::Array(vec![
+                Frame::Bulk("keys".into()),
+                Frame::Integer(db.len() as i64),
+                Frame::Bulk("expires".into()),
+                Frame::Integer(db.expires_len() as i64),
+                Frame::Bulk("bytes".into()),
+                Frame::Integer(db.used_memory() as i64),
+            ]),
+        ));
+    }
+
+    Frame::Array(
+        fields
+            .into_iter()
+            .flat_map(|(name, value)| [Frame::Bulk(Bytes::from(name)), value])
+            .collect(),
+    )
+}
+
+/// Builds the MEMORY DOCTOR report: a human readable list of likely problems.
+pub fn doctor(databases: &Databases) -> String {
+    let used = databases.used_memory();
+    if used < DOCTOR_MIN_MEMORY {
+        return "This instance is empty or is using very little memory, \
+                there is not enough data to look for memory issues."
+            .to_string();
+    }
+
+    let mut issues = Vec::new();
+    let peak = databases.peak_memory();
+    let maxmemory = databases.settings().maxmemory() as usize;
+    let policy = databases.settings().maxmemory_policy();
+
+    if peak > used + used / 2 {
+        issues.push(format!(
+            "Peak memory: the dataset peaked at {} bytes, more than 150% of the {} bytes used now. \
+             Keys were deleted or evicted in bulk; if that is unexpected, look for large expiring \
+             keys or a maxmemory setting that is too low.",
+            peak, used
+        ));
+    }
+
+    if maxmemory > 0 && used >= maxmemory / 10 * 9 {
+        if policy == EvictionPolicy::NoEviction {
+            issues.push(format!(
+                "Memory limit: {} of {} maxmemory bytes are in use and maxmemory-policy is noeviction, \
+                 so writes will soon be refused with OOM errors. Consider an eviction policy or a \
+                 higher limit.",
+                used, maxmemory
+            ));
+        } else {
+            issues.push(format!(
+                "Memory limit: {} of {} maxmemory bytes are in use, {} keys were evicted so far.",
+                used,
+                maxmemory,
+                databases.evicted_keys()
+            ));
+        }
+    }
+
+    if policy.is_volatile() {
+        let volatile: usize = databases.all().iter().map(|db| db.expires_len()).sum();
+        if volatile == 0 && maxmemory > 0 {
+            issues.push(format!(
+                "Eviction policy: maxmemory-policy is {} but no key has a TTL, so nothing can be evicted.",
+                policy.name()
+            ));
+        }
+    }
+
+    if issues.is_empty() {
+        return "No memory issues were found in this instance.".to_string();
+    }
+
+    let mut report = String::from("Memory issues found in this instance:\n\n");
+    for issue in issues {
+        report.push_str(" * ");
+        report.push_str(&issue);
+        report.push_str("\n\n");
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, Settings};
+    use clap::Parser;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn databases() -> Databases {
+        let config = Config::parse_from(["rusty-redis", "--databases", "2"]);
+        Databases::new(2, Arc::new(Settings::new(&config)))
+    }
+
+    fn field<'a>(stats: &'a Frame, name: &str) -> &'a Frame {
+        let Frame::Array(fields) = stats else {
+            panic!("not an array");
+        };
+        let position = fields
+            .iter()
+            .position(|field| matches!(field, Frame::Bulk(bytes) if bytes == name))
+            .unwrap_or_else(|| panic!("no {} field", name));
+        &fields[position + 1]
+    }
+
+    #[tokio::test]
+    async fn stats_count_keys_per_database() {
+        let databases = databases();
+        databases.db(1).set("a".into(), "1".into(), None);
+        databases.db(1).set("b".into(), "2".into(), Some(Duration::from_secs(100)));
+
+        let stats = stats(&databases);
+        assert!(matches!(field(&stats, "keys.count"), Frame::Integer(2)));
+        assert!(matches!(field(&stats, "maxmemory.policy"), Frame::Bulk(name) if name == "noeviction"));
+        let used = databases.used_memory() as i64;
+        assert!(matches!(field(&stats, "total.allocated"), Frame::Integer(total) if *total == used));
+        let Frame::Array(db) = field(&stats, "db.1") else {
+            panic!("db.1 is not an array");
+        };
+        assert!(matches!(db[1], Frame::Integer(2)));
+        assert!(matches!(db[3], Frame::Integer(1)));
+
+        let Frame::Array(fields) = &stats else { unreachable!() };
+        assert!(!fields.iter().any(|field| matches!(field, Frame::Bulk(name) if name == "db.0")));
+    }
+
+    #[tokio::test]
+    async fn doctor_needs_enough_data() {
+        let databases = databases();
+        assert!(doctor(&databases).starts_with("This instance is empty"));
+
+        databases.db(0).set("big".into(), Bytes::from(vec![b'x'; DOCTOR_MIN_MEMORY]), None);
+        assert_eq!(doctor(&databases), "No memory issues were found in this instance.");
+    }
+
+    #[tokio::test]
+    async fn doctor_reports_a_limit_writes_will_hit() {
+        let databases = databases();
+        databases.db(0).set("big".into(), Bytes::from(vec![b'x'; DOCTOR_MIN_MEMORY]), None);
+        let maxmemory = databases.used_memory() + 1;
+        databases.settings().set("maxmemory", &maxmemory.to_string()).unwrap();
+
+        let report = doctor(&databases);
+        assert!(report.starts_with("Memory issues found in this instance:"));
+        assert!(report.contains("maxmemory-policy is noeviction"));
+
+        databases.settings().set("maxmemory-policy", "volatile-lru").unwrap();
+        let report = doctor(&databases);
+        assert!(report.contains("0 keys were evicted so far"));
+        assert!(report.contains("maxmemory-policy is volatile-lru but no key has a TTL"));
+    }
+}
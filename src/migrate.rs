@@ -0,0 +1,168 @@
+use bytes::Bytes;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use crate::connection::Connection;
+use crate::db::Db;
+use crate::frame::Frame;
+use crate::rdb;
+
+pub struct MigrateOptions {
+    pub host: String,
+    pub port: u16,
+    pub db: i64,
+    pub timeout: Duration,
+    pub copy: bool,
+    pub replace: bool,
+    pub auth: Option<(Option<String>, String)>,
+}
+
+/// Transfers `keys` to another instance with RESTORE, deleting them locally
+/// on success unless COPY was requested.
+///
+/// Returns the reply together with the keys deleted locally. Keys the target
+/// accepted are deleted even when it refused others, so the reply can be an
+/// error while some keys are gone.
+pub async fn migrate(db: &Db, keys: Vec<Bytes>, options: MigrateOptions) -> (Frame, Vec<Bytes>) {
+    let mut payloads = Vec::new();
+    for key in keys {
+        if let Some(payload) = db.with_value(&key, rdb::dump) {
+            let ttl_millis = db
+                .expiry(&key)
+                .map(|expiry| expiry.saturating_duration_since(Instant::now()).as_millis().max(1) as u64)
+                .unwrap_or(0);
+            payloads.push((key, ttl_millis, payload));
+        }
+    }
+
+    if payloads.is_empty() {
+        return (Frame::Simple("NOKEY".to_string()), Vec::new());
+    }
+
+    let io_timeout = if options.timeout.is_zero() { Duration::from_secs(1) } else { options.timeout };
+    let addr = format!("{}:{}", options.host, options.port);
+
+    let stream = match timeout(io_timeout, TcpStream::connect(&addr)).await {
+        Ok(Ok(stream)) => stream,
+        _ => return (Frame::Error(format!("IOERR error or timeout connecting to the client {}", addr)), Vec::new()),
+    };
+    let mut connection = Connection::new(stream);
+
+    let mut requests = Vec::new();
+    if let Some((username, password)) = &options.auth {
+        let mut args = vec![Frame::Bulk("AUTH".into())];
+        if let Some(username) = username {
+            args.push(Frame::Bulk(username.clone().into()));
+        }
+        args.push(Frame::Bulk(password.clone().into()));
+        requests.push(Frame::Array(args));
+    }
+    requests.push(Frame::Array(vec![
+        Frame::Bulk("SELECT".into()),
+        Frame::Bulk(options.db.to_string().into()),
+    ]));
+    for (key, ttl_millis, payload) in &payloads {
+        let mut args = vec![
+            Frame::Bulk("RESTORE".into()),
+            Frame::Bulk(key.clone()),
+            Frame::Bulk(ttl_millis.to_string().into()),
+            Frame::Bulk(Bytes::from(payload.clone())),
+        ];
+        if options.replace {
+            args.push(Frame::Bulk("REPLACE".into()));
+        }
+        requests.push(Frame::Array(args));
+    }
+
+    for request in &requests {
+        if timeout(io_timeout, connection.write_frame(request)).await.map_or(true, |r| r.is_err()) {
+            return (Frame::Error(format!("IOERR error or timeout writing to target instance {}", addr)), Vec::new());
+        }
+    }
+
+    let preamble = requests.len() - payloads.len();
+    let mut first_error = None;
+    let mut preamble_failed = false;
+    let mut deleted = Vec::new();
+    for i in 0..requests.len() {
+        let reply = match timeout(io_timeout, connection.read_frame()).await {
+            Ok(Ok(Some(reply))) => reply,
+            _ => return (Frame::Error(format!("IOERR error or timeout reading to target instance {}", addr)), deleted),
+        };
+
+        match reply {
+            Frame::Error(msg) => {
+                preamble_failed |= i < preamble;
+                first_error.get_or_insert(msg);
+            }
+            _ if i >= preamble && !preamble_failed && !options.copy => {
+                let key = &payloads[i - preamble].0;
+                if db.del(key) {
+                    deleted.push(key.clone());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let reply = match first_error {
+        Some(msg) => Frame::Error(format!("ERR Target instance replied with error: {}", msg)),
+        None => Frame::Simple("OK".to_string()),
+    };
+    (reply, deleted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, Settings};
+    use crate::db::Databases;
+    use clap::Parser;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+
+    fn options(port: u16) -> MigrateOptions {
+        MigrateOptions {
+            host: "127.0.0.1".to_string(),
+            port,
+            db: 0,
+            timeout: Duration::from_secs(5),
+            copy: false,
+            replace: false,
+            auth: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn partial_failure_reports_the_keys_deleted() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // the target accepts the SELECT and the first key, and already holds the second
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut connection = Connection::new(stream);
+            let replies = [
+                Frame::Simple("OK".to_string()),
+                Frame::Simple("OK".to_string()),
+                Frame::Error("BUSYKEY Target key name already exists.".to_string()),
+            ];
+            for reply in &replies {
+                connection.read_frame().await.unwrap().unwrap();
+                connection.write_frame(reply).await.unwrap();
+            }
+        });
+
+        let config = Config::parse_from(["rusty-redis"]);
+        let databases = Databases::new(1, Arc::new(Settings::new(&config)));
+        let db = databases.db(0);
+        db.set("moved".into(), "1".into(), None);
+        db.set("busy".into(), "2".into(), None);
+
+        let (reply, deleted) = migrate(&db, vec!["moved".into(), "busy".into()], options(port)).await;
+        assert!(matches!(reply, Frame::Error(_)));
+        assert_eq!(deleted, vec![Bytes::from("moved")]);
+        assert_eq!(db.get(b"moved").unwrap(), None);
+        assert_eq!(db.get(b"busy").unwrap(), Some("2".into()));
+    }
+}
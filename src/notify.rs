@@ -0,0 +1,165 @@
+//! Keyspace and keyevent notifications.
+//!
+//! When `notify-keyspace-events` enables the class of an event, the event is
+//! published through pub/sub twice: to `__keyspace@<db>__:<key>` with the
+//! event name as the message (with `K`), and to `__keyevent@<db>__:<event>`
+//! with the key as the message (with `E`). Writes announce themselves with
+//! `Db::notify`, so a new write command only has to name its event.
+
+use bytes::Bytes;
+
+use crate::pubsub::PubSub;
+
+/// A set of the flags of `notify-keyspace-events`: the channels to publish
+/// to and the classes of events to publish.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Events(u16);
+
+impl Events {
+    pub const KEYSPACE: Events = Events(1 << 0);
+    pub const KEYEVENT: Events = Events(1 << 1);
+    /// commands that apply to keys of any type, such as DEL or MOVE
+    pub const GENERIC: Events = Events(1 << 2);
+    pub const STRING: Events = Events(1 << 3);
+    pub const LIST: Events = Events(1 << 4);
+    pub const SET: Events = Events(1 << 5);
+    pub const HASH: Events = Events(1 << 6);
+    pub const ZSET: Events = Events(1 << 7);
+    /// keys removed because their time to live ran out
+    pub const EXPIRED: Events = Events(1 << 8);
+    /// keys removed to stay under maxmemory
+    pub const EVICTED: Events = Events(1 << 9);
+    pub const STREAM: Events = Events(1 << 10);
+    /// reads of keys that do not exist
+    pub const KEY_MISS: Events = Events(1 << 11);
+    /// keys added to a database
+    pub const NEW: Events = Events(1 << 12);
+
+    /// the classes `A` stands for, which leave out key misses and new keys as in redis
+    const ALL: Events = Events(
+        Self::GENERIC.0
+            | Self::STRING.0
+            | Self::LIST.0
+            | Self::SET.0
+            | Self::HASH.0
+            | Self::ZSET.0
+            | Self::EXPIRED.0
+            | Self::EVICTED.0
+            | Self::STREAM.0,
+    );
+
+    /// Flag characters in the order redis renders them.
+    const FLAGS: [(char, Events); 13] = [
+        ('g', Self::GENERIC),
+        ('$', Self::STRING),
+        ('l', Self::LIST),
+        ('s', Self::SET),
+        ('h', Self::HASH),
+        ('z', Self::ZSET),
+        ('x', Self::EXPIRED),
+        ('e', Self::EVICTED),
+        ('t', Self::STREAM),
+        ('K', Self::KEYSPACE),
+        ('E', Self::KEYEVENT),
+        ('m', Self::KEY_MISS),
+        ('n', Self::NEW),
+    ];
+
+    /// Parses flags such as `KEA` or `Ex`; an empty string disables notifications.
+    pub fn parse(value: &str) -> Option<Events> {
+        value.chars().try_fold(Events::default(), |events, flag| {
+            let parsed = match flag {
+                'A' => Self::ALL,
+                flag => Self::FLAGS.iter().find(|(c, _)| *c == flag)?.1,
+            };
+            Some(Events(events.0 | parsed.0))
+        })
+    }
+
+    pub fn render(self) -> String {
+        let mut flags = String::new();
+        let mut rest = self;
+        if self.contains(Self::ALL) {
+            flags.push('A');
+            rest = Events(self.0 & !Self::ALL.0);
+        }
+        for (flag, events) in Self::FLAGS {
+            if rest.contains(events) {
+                flags.push(flag);
+            }
+        }
+        flags
+    }
+
+    pub fn bits(self) -> u16 {
+        self.0
+    }
+
+    pub fn from_bits(bits: u16) -> Events {
+        Events(bits)
+    }
+
+    pub fn contains(self, other: Events) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+/// Publishes `event` on `key` of database `db` if `enabled` includes its `class`.
+pub fn notify(pub_sub: &PubSub, enabled: Events, class: Events, event: &str, key: &[u8], db: usize) {
+    if !enabled.contains(class) {
+        return;
+    }
+    if enabled.contains(Events::KEYSPACE) {
+        let mut channel = format!("__keyspace@{}__:", db).into_bytes();
+        channel.extend_from_slice(key);
+        pub_sub.publish(channel.into(), Bytes::copy_from_slice(event.as_bytes()));
+    }
+    if enabled.contains(Events::KEYEVENT) {
+        let channel = format!("__keyevent@{}__:{}", db, event);
+        pub_sub.publish(channel.into(), Bytes::copy_from_slice(key));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, Settings};
+    use clap::Parser;
+    use std::sync::Arc;
+
+    #[test]
+    fn flags_parse_and_render_like_redis() {
+        let events = Events::parse("KEA").unwrap();
+        assert!(events.contains(Events::KEYSPACE) && events.contains(Events::STREAM));
+        assert!(!events.contains(Events::KEY_MISS) && !events.contains(Events::NEW));
+        assert_eq!(events.render(), "AKE");
+        assert_eq!(Events::parse("xEg").unwrap().render(), "gxE");
+        assert_eq!(Events::parse("g$lshzxetKE").unwrap().render(), "AKE");
+        assert_eq!(Events::parse("AKEmn").unwrap().render(), "AKEmn");
+        assert_eq!(Events::parse(""), Some(Events::default()));
+        assert_eq!(Events::parse("KEq"), None);
+        assert_eq!(Events::from_bits(events.bits()), events);
+    }
+
+    #[tokio::test]
+    async fn events_go_to_the_enabled_channels_of_enabled_classes() {
+        let config = Config::parse_from(["rusty-redis"]);
+        let pub_sub = Arc::new(PubSub::new(Arc::new(Settings::new(&config))));
+        let mut keyspace = pub_sub.subscribe("__keyspace@3__:k".into());
+        let mut keyevent = pub_sub.subscribe("__keyevent@3__:set".into());
+
+        notify(&pub_sub, Events::parse("Kg").unwrap(), Events::STRING, "set", b"k", 3);
+        notify(&pub_sub, Events::parse("K$").unwrap(), Events::STRING, "set", b"k", 3);
+        notify(&pub_sub, Events::parse("E$").unwrap(), Events::STRING, "set", b"k", 3);
+        notify(&pub_sub, Events::parse("$").unwrap(), Events::STRING, "set", b"k", 3);
+
+        assert_eq!(keyspace.recv().await.unwrap(), "set");
+        assert_eq!(keyevent.recv().await.unwrap(), "k");
+
+        // nothing else was published before these
+        pub_sub.publish("__keyspace@3__:k".into(), "end".into());
+        pub_sub.publish("__keyevent@3__:set".into(), "end".into());
+        assert_eq!(keyspace.recv().await.unwrap(), "end");
+        assert_eq!(keyevent.recv().await.unwrap(), "end");
+    }
+}
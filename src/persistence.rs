@@ -1,47 +1,946 @@
+//! Snapshots of the whole dataset, in this server's native format or as redis RDB files.
+//!
+//! A native snapshot starts with a magic string and a format version, then
+//! holds a series of sections, each a type tag and a length followed by its
+//! payload, and ends with an EOF tag and a CRC64 of everything before it.
+//! Sections of unknown types are skipped, so optional ones can be added
+//! without a new version; older versions are migrated when loaded.
+//!
+//! Either format may be sealed by `codec` with the configured compression and
+//! encryption; the sealed header records the codecs, so loading decodes any
+//! snapshot transparently.
+//!
+//! SAVE, BGSAVE and the periodic snapshot all write a `db::Snapshot`: the
+//! file holds every database exactly as it was between two commands when
+//! the save started, each key once, however clients write, MOVE or SWAPDB
+//! while it is written. Nothing written after that point is in the file.
+
 use bytes::Bytes;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::io;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 use tokio::fs;
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+use tracing::{error, info, warn};
+
+use crate::codec::{self, BlockReader, Codecs, EncryptionKey, Sealer};
+use crate::crc64;
+use crate::db::{self, Databases, Snapshot};
+use crate::rdb::{self, RdbReader, Record};
+use crate::value::Value;
+
+const MAGIC: &[u8] = b"RUSTYSNAP";
+/// Version of the native format written by `save`.
+pub const SNAPSHOT_VERSION: u16 = 1;
 
-use crate::db::Db;
+/// Name/value pairs describing the snapshot.
+const SECTION_AUX: u8 = 1;
+/// A database index followed by its keys.
+const SECTION_DB: u8 = 2;
+const SECTION_EOF: u8 = 0xff;
 
+/// Version 0, written by the first release before snapshots had a header or
+/// there was more than one database: every key with its string value,
+/// serialized with bincode.
 #[derive(Serialize, Deserialize)]
-pub struct Snapshot {
-    pub entries: HashMap<String, Vec<u8>>,
+struct SnapshotV0 {
+    entries: HashMap<String, Vec<u8>>,
+}
+
+/// File format written by SAVE and the periodic snapshot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SnapshotFormat {
+    /// this server's own versioned and checksummed format
+    Native,
+    /// a redis RDB file, readable by redis itself and RDB tooling
+    Rdb,
+}
+
+impl SnapshotFormat {
+    pub const ALL: [SnapshotFormat; 2] = [SnapshotFormat::Native, SnapshotFormat::Rdb];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SnapshotFormat::Native => "native",
+            SnapshotFormat::Rdb => "rdb",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<SnapshotFormat> {
+        Self::ALL.into_iter().find(|format| format.name().eq_ignore_ascii_case(name))
+    }
+}
+
+
+/// A `save <seconds> <changes>` rule: snapshot once at least `changes` writes
+/// were made and `seconds` have passed since the last snapshot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SaveRule {
+    pub seconds: u64,
+    pub changes: u64,
 }
 
-pub async fn save(db: &Db, filename: &str) -> io::Result<()> {
-    let mut entries = HashMap::new();
-    
-    for entry in db.entries.iter() {
-        let key = entry.key().clone();
-        let value = entry.value().to_vec();
-        entries.insert(key, value);
+/// The rules of the `save` parameter; a snapshot is due when any of them is
+/// met, and none are when the list is empty.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SaveRules(pub Vec<SaveRule>);
+
+impl SaveRules {
+    /// Parses pairs of seconds and changes such as `3600 1 300 100`; an empty
+    /// string disables periodic snapshots.
+    pub fn parse(value: &str) -> Option<SaveRules> {
+        let numbers = value
+            .split_whitespace()
+            .map(|n| n.parse::<u64>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        if numbers.len() % 2 != 0 {
+            return None;
+        }
+        let rules = numbers
+            .chunks(2)
+            .map(|pair| SaveRule { seconds: pair[0], changes: pair[1] })
+            .collect();
+        Some(SaveRules(rules))
+    }
+
+    pub fn render(&self) -> String {
+        self.0
+            .iter()
+            .map(|rule| format!("{} {}", rule.seconds, rule.changes))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Whether `dirty` writes made over `elapsed_seconds` since the last snapshot call for a new one.
+    pub fn due(&self, dirty: u64, elapsed_seconds: u64) -> bool {
+        self.0.iter().any(|rule| dirty >= rule.changes && elapsed_seconds >= rule.seconds)
     }
+}
+
+/// Keys encoded before they are handed to the file writer.
+pub const BATCH_BYTES: usize = 64 * 1024;
 
-    let snapshot = Snapshot { entries };
-    let serialized = bincode::serialize(&snapshot)
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
 
-    let temp_file = format!("{}.tmp", filename);
-    fs::write(&temp_file, serialized).await?;
+/// Buffered async writer that keeps a CRC64 of everything written through it.
+pub struct SnapshotWriter<W> {
+    inner: BufWriter<W>,
+    crc: u64,
+    /// when the file is sealed, the sealer and the plain bytes of the frame being filled
+    sealer: Option<(Sealer, Vec<u8>)>,
+}
+
+impl<W: AsyncWrite + Unpin> SnapshotWriter<W> {
+    pub fn new(inner: W) -> SnapshotWriter<W> {
+        SnapshotWriter { inner: BufWriter::new(inner), crc: 0, sealer: None }
+    }
+
+    /// A writer that seals the file with `codecs`, or writes it as is when they are plain.
+    pub async fn sealed(inner: W, codecs: &Codecs) -> io::Result<SnapshotWriter<W>> {
+        let mut writer = SnapshotWriter::new(inner);
+        if !codecs.is_plain() {
+            let sealer = Sealer::new(codecs);
+            writer.inner.write_all(sealer.header()).await?;
+            writer.sealer = Some((sealer, Vec::with_capacity(codec::BLOCK_SIZE)));
+        }
+        Ok(writer)
+    }
+
+    pub async fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.crc = crc64::update(self.crc, buf);
+        self.emit(buf).await
+    }
+
+    async fn emit(&mut self, buf: &[u8]) -> io::Result<()> {
+        let Some((sealer, block)) = &mut self.sealer else {
+            return self.inner.write_all(buf).await;
+        };
+        block.extend_from_slice(buf);
+        while block.len() >= codec::BLOCK_SIZE {
+            let frame = sealer.seal(&block[..codec::BLOCK_SIZE], false)?;
+            self.inner.write_all(&frame).await?;
+            block.drain(..codec::BLOCK_SIZE);
+        }
+        Ok(())
+    }
+
+    /// Appends the CRC64 of everything written so far and flushes.
+    pub async fn finish(mut self) -> io::Result<W> {
+        let crc = self.crc;
+        self.emit(&crc.to_le_bytes()).await?;
+        if let Some((mut sealer, block)) = self.sealer.take() {
+            let frame = sealer.seal(&block, true)?;
+            self.inner.write_all(&frame).await?;
+        }
+        self.inner.flush().await?;
+        Ok(self.inner.into_inner())
+    }
+}
+
+/// Writes `snapshot` to `filename` through a temporary file, so a crash
+/// never leaves a partial snapshot behind.
+pub async fn save(snapshot: &Snapshot, filename: impl AsRef<Path>, format: SnapshotFormat, codecs: &Codecs) -> io::Result<()> {
+    let filename = filename.as_ref();
+    let mut temp_file = OsString::from(filename);
+    temp_file.push(".tmp");
+
+    let mut writer = SnapshotWriter::sealed(fs::File::create(&temp_file).await?, codecs).await?;
+    match format {
+        SnapshotFormat::Native => write_native(snapshot, &mut writer).await?,
+        SnapshotFormat::Rdb => write_rdb(snapshot, false, &mut writer).await?,
+    }
+    writer.finish().await?.sync_all().await?;
 
     fs::rename(&temp_file, filename).await?;
 
     Ok(())
 }
 
-pub async fn load(filename: &str) -> io::Result<HashMap<String, Bytes>> {
-    let data = fs::read(filename).await?;
+/// Where SAVE, BGSAVE and the periodic snapshot write the dataset.
+pub const DUMP_FILE: &str = "dump.rdb";
+
+/// Progress and outcome of snapshots, shared by SAVE, BGSAVE and the
+/// periodic snapshot so only one of them writes the dump file at a time.
+pub struct SaveState {
+    in_progress: AtomicBool,
+    /// a BGSAVE SCHEDULE waiting for an append only file rewrite to finish
+    scheduled: AtomicBool,
+    /// unix time in seconds of the last successful save, or of startup
+    last_save: AtomicU64,
+    /// unix time in milliseconds of the last attempt, successful or not
+    last_attempt_ms: AtomicU64,
+    last_ok: AtomicBool,
+    /// duration of the last save in milliseconds, `u64::MAX` before the first one
+    last_duration_ms: AtomicU64,
+    /// unix time in milliseconds the running save started at
+    started_ms: AtomicU64,
+}
+
+impl SaveState {
+    pub fn new() -> SaveState {
+        SaveState {
+            in_progress: AtomicBool::new(false),
+            scheduled: AtomicBool::new(false),
+            last_save: AtomicU64::new(db::unix_millis() / 1000),
+            last_attempt_ms: AtomicU64::new(0),
+            last_ok: AtomicBool::new(true),
+            last_duration_ms: AtomicU64::new(u64::MAX),
+            started_ms: AtomicU64::new(0),
+        }
+    }
+
+    pub fn in_progress(&self) -> bool {
+        self.in_progress.load(Ordering::SeqCst)
+    }
+
+    pub fn scheduled(&self) -> bool {
+        self.scheduled.load(Ordering::SeqCst)
+    }
+
+    /// Unix time in seconds of the last successful save, as reported by LASTSAVE.
+    pub fn last_save(&self) -> u64 {
+        self.last_save.load(Ordering::Relaxed)
+    }
+
+    pub fn last_ok(&self) -> bool {
+        self.last_ok.load(Ordering::Relaxed)
+    }
+
+    /// Milliseconds since the last save attempt ended.
+    pub fn since_last_attempt_ms(&self) -> u64 {
+        db::unix_millis().saturating_sub(self.last_attempt_ms.load(Ordering::Relaxed))
+    }
+}
+
+impl Default for SaveState {
+    fn default() -> SaveState {
+        SaveState::new()
+    }
+}
+
+/// Saves the dataset on the calling task, failing if another save is running.
+pub async fn save_now(databases: &Databases) -> io::Result<()> {
+    if databases.save_state().in_progress.swap(true, Ordering::SeqCst) {
+        return Err(io::Error::other("Background save already in progress"));
+    }
+    run_save(databases).await
+}
+
+/// Waits for a running save to finish, then saves on the calling task.
+pub async fn save_when_idle(databases: &Databases) -> io::Result<()> {
+    while databases.save_state().in_progress.swap(true, Ordering::SeqCst) {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    run_save(databases).await
+}
+
+/// Starts saving the dataset on a background task.
+///
+/// With `schedule`, a save that cannot start because an append only file
+/// rewrite is running is started by the snapshot task once the rewrite ends.
+pub fn bgsave(databases: &Databases, schedule: bool) -> Result<&'static str, &'static str> {
+    let state = databases.save_state();
+    if state.in_progress() {
+        return Err("ERR Background save already in progress");
+    }
+    if databases.aof().is_some_and(|aof| aof.rewrite_in_progress()) {
+        if schedule {
+            state.scheduled.store(true, Ordering::SeqCst);
+            return Ok("Background saving scheduled");
+        }
+        return Err(
+            "ERR Another child process is active (AOF): can't BGSAVE right now. \
+             Use BGSAVE SCHEDULE in order to schedule a BGSAVE whenever possible",
+        );
+    }
+    if state.in_progress.swap(true, Ordering::SeqCst) {
+        return Err("ERR Background save already in progress");
+    }
+    state.scheduled.store(false, Ordering::SeqCst);
+
+    let databases = databases.clone();
+    tokio::spawn(async move {
+        // the outcome is logged and recorded in the save state
+        let _ = run_save(&databases).await;
+    });
+    Ok("Background saving started")
+}
+
+/// Writes the dump file and records the outcome. The caller must have set
+/// `in_progress`, which is cleared when the save ends.
+async fn run_save(databases: &Databases) -> io::Result<()> {
+    let state = databases.save_state();
+    let started = Instant::now();
+    state.started_ms.store(db::unix_millis(), Ordering::Relaxed);
+    // the dump holds the dataset as it was when the snapshot was taken, and
+    // the writes made while it is written are left for the next save
+    let dirty = databases.dirty();
+    let snapshot = databases.snapshot();
+    let settings = databases.settings();
+
+    let result = save(&snapshot, DUMP_FILE, settings.snapshot_format(), &settings.codecs()).await;
+    drop(snapshot);
+    match &result {
+        Ok(()) => {
+            databases.clear_dirty(dirty);
+            state.last_save.store(db::unix_millis() / 1000, Ordering::Relaxed);
+            info!("database saved to disk in {:?}", started.elapsed());
+        }
+        Err(e) => error!("failed to save database: {}", e),
+    }
+    state.last_ok.store(result.is_ok(), Ordering::Relaxed);
+    state.last_duration_ms.store(started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    state.last_attempt_ms.store(db::unix_millis(), Ordering::Relaxed);
+    state.in_progress.store(false, Ordering::SeqCst);
+    result
+}
 
-    let snapshot: Snapshot = bincode::deserialize(&data)
-        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+/// The fields of the persistence section of INFO.
+pub fn info(databases: &Databases) -> String {
+    let state = databases.save_state();
+    let in_progress = state.in_progress();
+    let seconds = |millis: u64| if millis == u64::MAX { -1 } else { (millis / 1000) as i64 };
+    let current = if in_progress {
+        seconds(db::unix_millis().saturating_sub(state.started_ms.load(Ordering::Relaxed)))
+    } else {
+        -1
+    };
+    let aof = databases.aof();
+
+    let fields = [
+        ("loading", "0".to_string()),
+        ("rdb_changes_since_last_save", databases.dirty().to_string()),
+        ("rdb_bgsave_in_progress", (in_progress as u8).to_string()),
+        ("rdb_bgsave_scheduled", (state.scheduled() as u8).to_string()),
+        ("rdb_last_save_time", state.last_save().to_string()),
+        ("rdb_last_bgsave_status", if state.last_ok() { "ok" } else { "err" }.to_string()),
+        ("rdb_last_bgsave_time_sec", seconds(state.last_duration_ms.load(Ordering::Relaxed)).to_string()),
+        ("rdb_current_bgsave_time_sec", current.to_string()),
+        ("aof_enabled", (aof.is_some() as u8).to_string()),
+        ("aof_rewrite_in_progress", (aof.is_some_and(|aof| aof.rewrite_in_progress()) as u8).to_string()),
+    ];
+
+    let mut info = String::from("# Persistence\r\n");
+    for (name, value) in fields {
+        info.push_str(&format!("{}:{}\r\n", name, value));
+    }
+    info
+}
+
+/// `expiry` as a unix time in milliseconds.
+fn deadline(expiry: Option<Instant>, now: Instant, now_millis: u64) -> Option<u64> {
+    expiry.map(|expiry| (now_millis + expiry.saturating_duration_since(now).as_millis() as u64).max(1))
+}
+
+async fn write_native<W: AsyncWrite + Unpin>(snapshot: &Snapshot, writer: &mut SnapshotWriter<W>) -> io::Result<()> {
+    let now = Instant::now();
+    let now_millis = db::unix_millis();
+
+    let mut header = MAGIC.to_vec();
+    header.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+    writer.write(&header).await?;
+
+    let mut aux = Vec::new();
+    for (name, value) in [("ctime", (now_millis / 1000).to_string()), ("databases", snapshot.len().to_string())] {
+        rdb::write_string(name.as_bytes(), &mut aux);
+        rdb::write_string(value.as_bytes(), &mut aux);
+    }
+    write_section(SECTION_AUX, &aux, writer).await?;
+
+    // each batch of keys becomes its own database section
+    let mut payload = Vec::new();
+    for index in 0..snapshot.len() {
+        for shard in 0..snapshot.shards(index) {
+            let mut keys = 0;
+            for (key, value, expiry) in snapshot.read_shard(index, shard) {
+                if keys == 0 {
+                    rdb::write_length(index as u64, &mut payload);
+                }
+                // the expiry, or 0 for none
+                let deadline = deadline(expiry, now, now_millis).unwrap_or(0);
+                rdb::write_string(&key, &mut payload);
+                payload.extend_from_slice(&deadline.to_le_bytes());
+                rdb::write_value(&value, &mut payload);
+                keys += 1;
+
+                if payload.len() >= BATCH_BYTES {
+                    write_section(SECTION_DB, &payload, writer).await?;
+                    payload.clear();
+                    keys = 0;
+                }
+            }
+            if keys > 0 {
+                write_section(SECTION_DB, &payload, writer).await?;
+                payload.clear();
+            }
+        }
+    }
+
+    writer.write(&[SECTION_EOF]).await
+}
+
+async fn write_section<W: AsyncWrite + Unpin>(tag: u8, payload: &[u8], writer: &mut SnapshotWriter<W>) -> io::Result<()> {
+    let mut header = vec![tag];
+    header.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    writer.write(&header).await?;
+    writer.write(payload).await
+}
+
+/// Writes every database of `snapshot` as a redis RDB file. `aof_base` marks
+/// the file as the base of an append only file, as redis does for RDB preambles.
+pub async fn write_rdb<W: AsyncWrite + Unpin>(snapshot: &Snapshot, aof_base: bool, writer: &mut SnapshotWriter<W>) -> io::Result<()> {
+    let now = Instant::now();
+    let now_millis = db::unix_millis();
+    let used_memory = snapshot.used_memory();
+
+    let mut buf = Vec::new();
+    rdb::write_header(&mut buf);
+    rdb::write_aux("redis-ver", rdb::REDIS_VERSION.as_bytes(), &mut buf);
+    rdb::write_aux("redis-bits", b"64", &mut buf);
+    rdb::write_aux("ctime", (now_millis / 1000).to_string().as_bytes(), &mut buf);
+    rdb::write_aux("used-mem", used_memory.to_string().as_bytes(), &mut buf);
+    rdb::write_aux("aof-base", if aof_base { b"1" } else { b"0" }, &mut buf);
+
+    for index in 0..snapshot.len() {
+        let mut selected = false;
+        for shard in 0..snapshot.shards(index) {
+            for (key, value, expiry) in snapshot.read_shard(index, shard) {
+                if !selected {
+                    let (keys, expires) = snapshot.sizes(index);
+                    rdb::write_select_db(index, keys, expires, &mut buf);
+                    selected = true;
+                }
+                rdb::write_record(&key, &value, deadline(expiry, now, now_millis), &mut buf);
+
+                if buf.len() >= BATCH_BYTES {
+                    writer.write(&buf).await?;
+                    buf.clear();
+                }
+            }
+        }
+    }
+
+    rdb::write_eof(&mut buf);
+    writer.write(&buf).await
+}
+
+/// Streams the snapshot at `filename` into `databases`, returning the number of keys loaded.
+pub async fn load(filename: &str, databases: &Databases) -> io::Result<usize> {
+    let file = std::fs::File::open(filename)?;
+    let databases = databases.clone();
+
+    tokio::task::spawn_blocking(move || load_from(BufReader::new(file), &databases))
+        .await
+        .map_err(io::Error::other)?
+}
+
+/// Drops every key loaded from the dump file at `filename`, which failed to
+/// load, and moves the file aside to `<filename>.corrupt`, returning its new
+/// path. Keys read before the corruption are not kept, so the next save
+/// writes neither part of the file nor over it.
+pub fn set_aside_corrupt(filename: &str, databases: &Databases) -> io::Result<String> {
+    databases.flush_all(false);
+    databases.clear_dirty(databases.dirty());
+    let target = format!("{}.corrupt", filename);
+    std::fs::rename(filename, &target)?;
+    Ok(target)
+}
+
+/// Loads a snapshot in any format, sealed or not, into `databases`.
+pub fn load_from<R: BufRead>(input: R, databases: &Databases) -> io::Result<usize> {
+    let mut loader = Loader::new(databases);
+    read_snapshot(input, databases.settings().encryption_key(), &mut |record| {
+        loader.insert(record.db, record.key, record.value, record.expire_at_ms);
+        Ok(())
+    })?;
+    Ok(loader.finish())
+}
+
+/// Reads a snapshot in any format, sealed or not, telling them apart by
+/// their magic, and passes each key to `on_record` as it is read.
+pub fn read_snapshot<R: BufRead>(
+    mut input: R,
+    key: Option<&EncryptionKey>,
+    on_record: &mut dyn FnMut(Record) -> io::Result<()>,
+) -> io::Result<()> {
+    if codec::is_sealed(input.fill_buf()?) {
+        let reader = BlockReader::new(input, key)?;
+        return read_plain(BufReader::new(reader), on_record);
+    }
+    read_plain(input, on_record)
+}
+
+fn read_plain<R: BufRead>(mut input: R, on_record: &mut dyn FnMut(Record) -> io::Result<()>) -> io::Result<()> {
+    let start = input.fill_buf()?;
+
+    if start.starts_with(b"REDIS") {
+        RdbReader::new(input).read_file(on_record)?;
+    } else if start.starts_with(MAGIC) {
+        let mut reader = RdbReader::new(input);
+        reader.skip(MAGIC.len() as u64)?;
+        let mut version = [0u8; 2];
+        reader.read_exact(&mut version)?;
+
+        match u16::from_le_bytes(version) {
+            1 => read_sections(&mut reader, on_record)?,
+            version => {
+                return Err(invalid(format!(
+                    "snapshot version {} is newer than the supported version {}",
+                    version, SNAPSHOT_VERSION
+                )));
+            }
+        }
+    } else {
+        let mut data = Vec::new();
+        input.read_to_end(&mut data)?;
+        migrate_v0(&data, on_record)?;
+    }
+
+    Ok(())
+}
+
+fn read_sections<R: Read>(reader: &mut RdbReader<R>, on_record: &mut dyn FnMut(Record) -> io::Result<()>) -> io::Result<()> {
+    loop {
+        let offset = reader.offset();
+        let tag = reader.read_u8()?;
+        if tag == SECTION_EOF {
+            break;
+        }
+
+        let mut len = [0u8; 8];
+        reader.read_exact(&mut len)?;
+        let end = reader
+            .offset()
+            .checked_add(u64::from_le_bytes(len))
+            .ok_or_else(|| invalid(format!("section at offset {} is longer than any file", offset)))?;
+
+        match tag {
+            SECTION_DB => read_db_section(reader, end, on_record)
+                .map_err(|e| invalid(format!("bad database section at offset {}: {}", offset, e)))?,
+            SECTION_AUX => reader.skip(end - reader.offset())?,
+            other => {
+                warn!("skipping snapshot section of unknown type {} at offset {}", other, offset);
+                reader.skip(end - reader.offset())?;
+            }
+        }
+    }
+
+    let computed = reader.checksum();
+    let mut stored = [0u8; 8];
+    reader.read_exact(&mut stored)?;
+    if u64::from_le_bytes(stored) != computed {
+        return Err(invalid(format!(
+            "snapshot checksum mismatch: file has {:016x}, contents give {:016x}",
+            u64::from_le_bytes(stored),
+            computed
+        )));
+    }
+
+    Ok(())
+}
+
+/// Reads the keys of a database section, which ends at offset `end`.
+fn read_db_section<R: Read>(
+    reader: &mut RdbReader<R>,
+    end: u64,
+    on_record: &mut dyn FnMut(Record) -> io::Result<()>,
+) -> io::Result<()> {
+    let db = reader.read_length()? as usize;
+
+    while reader.offset() < end {
+        let offset = reader.offset();
+        let key = reader
+            .read_string()
+            .map_err(|e| invalid(format!("key at offset {}: {}", offset, e)))?;
+        let bad_value = |e: io::Error| invalid(format!("key {:?} at offset {}: {}", String::from_utf8_lossy(&key), offset, e));
+        let mut deadline = [0u8; 8];
+        reader.read_exact(&mut deadline).map_err(bad_value)?;
+        let deadline = u64::from_le_bytes(deadline);
+        let value = reader.read_value().map_err(bad_value)?;
+        on_record(Record { db, key, value, expire_at_ms: (deadline > 0).then_some(deadline) })?;
+    }
+
+    if reader.offset() != end {
+        return Err(invalid("last key runs past the end of the section"));
+    }
+    Ok(())
+}
+
+/// Reads a version 0 snapshot into database 0. It only held strings and
+/// recorded no expirations.
+fn migrate_v0(data: &[u8], on_record: &mut dyn FnMut(Record) -> io::Result<()>) -> io::Result<()> {
+    let snapshot: SnapshotV0 = bincode::deserialize(data)
+        .map_err(|e| invalid(format!("not a snapshot or RDB file: {}", e)))?;
 
-    let mut entries = HashMap::new();
     for (key, value) in snapshot.entries {
-        entries.insert(key, Bytes::from(value));
+        let value = Value::string(Bytes::from(value));
+        on_record(Record { db: 0, key: Bytes::from(key), value, expire_at_ms: None })?;
     }
+    Ok(())
+}
+
+/// Loads the RDB file at the start of `input` into `databases`, returning the
+/// number of keys loaded. `reader` is left just past the file.
+pub fn load_rdb_preamble<R: Read>(reader: &mut RdbReader<R>, databases: &Databases) -> io::Result<usize> {
+    let mut loader = Loader::new(databases);
+    reader.read_file(|record| {
+        loader.insert(record.db, record.key, record.value, record.expire_at_ms);
+        Ok(())
+    })?;
+    Ok(loader.finish())
+}
 
-    Ok(entries)
+/// Inserts keys into the databases as they are read, dropping those that have already expired.
+struct Loader<'a> {
+    databases: &'a Databases,
+    now: Instant,
+    now_millis: u64,
+    loaded: usize,
+    /// keys of databases beyond the configured count, by database index
+    skipped: BTreeMap<usize, usize>,
+}
+
+impl<'a> Loader<'a> {
+    fn new(databases: &'a Databases) -> Loader<'a> {
+        Loader { databases, now: Instant::now(), now_millis: db::unix_millis(), loaded: 0, skipped: BTreeMap::new() }
+    }
+
+    /// Inserts `key` into database `index`, expiring at the unix time `expire_at_ms` in milliseconds.
+    fn insert(&mut self, index: usize, key: Bytes, value: Value, expire_at_ms: Option<u64>) {
+        if index >= self.databases.len() {
+            *self.skipped.entry(index).or_default() += 1;
+            return;
+        }
+
+        let expiry = match expire_at_ms {
+            Some(deadline) if deadline <= self.now_millis => return,
+            Some(deadline) => Some(self.now + Duration::from_millis(deadline - self.now_millis)),
+            None => None,
+        };
+        self.databases.db(index).insert_loaded(key, value, expiry);
+        self.loaded += 1;
+    }
+
+    fn finish(self) -> usize {
+        for (index, keys) in self.skipped {
+            warn!("skipping {} keys of db {}, only {} databases configured", keys, index, self.databases.len());
+        }
+        self.loaded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, Settings};
+    use clap::Parser;
+    use std::sync::Arc;
+
+    fn read_all(data: &[u8]) -> io::Result<Vec<Record>> {
+        let mut records = Vec::new();
+        read_snapshot(data, None, &mut |record| {
+            records.push(record);
+            Ok(())
+        })?;
+        Ok(records)
+    }
+
+    #[test]
+    fn first_release_dump_loads_into_db_0() {
+        let entries = HashMap::from([("name".to_string(), b"rusty".to_vec()), ("n".to_string(), b"42".to_vec())]);
+        let data = bincode::serialize(&SnapshotV0 { entries }).unwrap();
+
+        let mut records = read_all(&data).unwrap();
+        records.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(records.len(), 2);
+        assert!(records.iter().all(|record| record.db == 0 && record.expire_at_ms.is_none()));
+        assert_eq!(records[0].key, Bytes::from("n"));
+        assert_eq!(records[0].value, Value::string(Bytes::from("42")));
+        assert_eq!(records[1].value, Value::string(Bytes::from("rusty")));
+    }
+
+    #[tokio::test]
+    async fn dump_from_before_logical_databases_loads_into_db_0() {
+        let config = Config::parse_from(["rusty-redis", "--databases", "4"]);
+        let databases = Databases::new(4, Arc::new(Settings::new(&config)));
+        let entries = HashMap::from([("name".to_string(), b"rusty".to_vec())]);
+        let data = bincode::serialize(&SnapshotV0 { entries }).unwrap();
+
+        assert_eq!(load_from(data.as_slice(), &databases).unwrap(), 1);
+        assert_eq!(databases.db(0).get(b"name").unwrap(), Some(Bytes::from("rusty")));
+        assert!((1..4).all(|index| databases.db(index).len() == 0));
+    }
+
+    #[test]
+    fn truncated_first_release_dump_is_rejected() {
+        let entries = HashMap::from([("name".to_string(), b"rusty".to_vec())]);
+        let data = bincode::serialize(&SnapshotV0 { entries }).unwrap();
+
+        let err = read_all(&data[..data.len() - 2]).err().expect("a truncated dump must not load");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    fn native(sections: &[(u8, Vec<u8>)]) -> Vec<u8> {
+        let mut file = MAGIC.to_vec();
+        file.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+        for (tag, payload) in sections {
+            file.push(*tag);
+            file.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+            file.extend_from_slice(payload);
+        }
+        file.push(SECTION_EOF);
+        let crc = crc64::checksum(&file);
+        file.extend_from_slice(&crc.to_le_bytes());
+        file
+    }
+
+    fn db_section(db: u64, keys: &[(&str, &str, u64)]) -> Vec<u8> {
+        let mut payload = Vec::new();
+        rdb::write_length(db, &mut payload);
+        for (key, value, deadline) in keys {
+            rdb::write_string(key.as_bytes(), &mut payload);
+            payload.extend_from_slice(&deadline.to_le_bytes());
+            rdb::write_value(&Value::string(Bytes::copy_from_slice(value.as_bytes())), &mut payload);
+        }
+        payload
+    }
+
+    #[tokio::test]
+    async fn native_snapshot_round_trip() {
+        let config = Config::parse_from(["rusty-redis", "--databases", "2"]);
+        let databases = Databases::new(2, Arc::new(Settings::new(&config)));
+        databases.db(0).set("a".into(), "1".into(), None);
+        databases.db(1).set("b".into(), "2".into(), Some(Duration::from_secs(3600)));
+
+        let mut writer = SnapshotWriter::new(Vec::new());
+        write_native(&databases.snapshot(), &mut writer).await.unwrap();
+        let file = writer.finish().await.unwrap();
+
+        let mut records = read_all(&file).unwrap();
+        records.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(records.len(), 2);
+        assert_eq!((records[0].db, records[0].expire_at_ms), (0, None));
+        assert_eq!(records[1].db, 1);
+        assert!(records[1].expire_at_ms.is_some_and(|deadline| deadline > db::unix_millis()));
+    }
+
+    #[test]
+    fn unknown_sections_are_skipped() {
+        let file = native(&[(9, b"from a later release".to_vec()), (SECTION_DB, db_section(1, &[("k", "v", 0)]))]);
+        let records = read_all(&file).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!((records[0].db, &records[0].key), (1, &Bytes::from("k")));
+    }
+
+    #[test]
+    fn newer_versions_are_rejected() {
+        let mut file = native(&[]);
+        file[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&(SNAPSHOT_VERSION + 1).to_le_bytes());
+        assert!(read_all(&file).is_err());
+    }
+
+    #[test]
+    fn native_checksum_and_truncation() {
+        let file = native(&[(SECTION_DB, db_section(0, &[("k", "v", 0), ("l", "w", 5)]))]);
+        assert_eq!(read_all(&file).unwrap().len(), 2);
+
+        for end in 0..file.len() {
+            assert!(read_all(&file[..end]).is_err(), "{} of {} bytes read", end, file.len());
+        }
+        for at in MAGIC.len() + 2..file.len() {
+            let mut corrupt = file.clone();
+            corrupt[at] ^= 0x10;
+            assert!(read_all(&corrupt).is_err(), "byte {} flipped", at);
+        }
+    }
+
+    #[test]
+    fn section_lengths_are_checked() {
+        // the last key runs past the end of its section
+        let mut short = native(&[(SECTION_DB, db_section(0, &[("k", "v", 0)]))]);
+        let at = MAGIC.len() + 3;
+        let len = u64::from_le_bytes(short[at..at + 8].try_into().unwrap());
+        short[at..at + 8].copy_from_slice(&(len - 1).to_le_bytes());
+        assert!(read_all(&short).is_err());
+
+        let mut huge = native(&[(SECTION_AUX, Vec::new())]);
+        huge[at..at + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(read_all(&huge).is_err());
+    }
+
+    #[test]
+    fn save_rules_parse_and_render() {
+        let rules = SaveRules::parse("900 1  300 10").unwrap();
+        assert_eq!(rules.0, [SaveRule { seconds: 900, changes: 1 }, SaveRule { seconds: 300, changes: 10 }]);
+        assert_eq!(rules.render(), "900 1 300 10");
+        assert_eq!(SaveRules::parse(""), Some(SaveRules::default()));
+        assert_eq!(SaveRules::parse("900"), None);
+        assert_eq!(SaveRules::parse("900 1 300"), None);
+        assert_eq!(SaveRules::parse("900 one"), None);
+        assert_eq!(SaveRules::parse("-1 1"), None);
+    }
+
+    #[test]
+    fn save_is_due_once_any_rule_is_met() {
+        let rules = SaveRules::parse("900 1 300 10").unwrap();
+        assert!(!rules.due(0, 10_000));
+        assert!(!rules.due(1, 899));
+        assert!(rules.due(1, 900));
+        assert!(!rules.due(10, 299));
+        assert!(rules.due(10, 300));
+        assert!(!SaveRules::default().due(u64::MAX, u64::MAX));
+    }
+
+    #[test]
+    fn save_rules_default_and_config_set() {
+        let settings = Settings::new(&Config::parse_from(["rusty-redis"]));
+        assert_eq!(settings.save_rules().0, [SaveRule { seconds: 60, changes: 1 }]);
+        settings.set("save", "").unwrap();
+        assert_eq!(settings.save_rules(), SaveRules::default());
+        assert!(settings.set("save", "60").is_err());
+        assert_eq!(settings.save_rules(), SaveRules::default());
+    }
+
+    #[tokio::test]
+    async fn saves_hold_the_dataset_as_it_was_when_they_started() {
+        const KEYS: usize = 2000;
+        let config = Config::parse_from(["rusty-redis", "--databases", "2"]);
+        let databases = Databases::new(2, Arc::new(Settings::new(&config)));
+        let before = Bytes::from(vec![b'b'; 100]);
+        let fill = || {
+            for i in 0..KEYS {
+                databases.db(0).set(format!("key{}", i).into(), before.clone(), None);
+            }
+        };
+        fill();
+
+        for format in [SnapshotFormat::Native, SnapshotFormat::Rdb] {
+            let snapshot = databases.snapshot();
+            // a small pipe makes the writer wait for the reader, letting clients run in between
+            let (pipe, mut reader) = tokio::io::duplex(4096);
+            let mut writer = SnapshotWriter::new(pipe);
+            let written_while_saving = std::cell::Cell::new(0);
+            let write = async {
+                match format {
+                    SnapshotFormat::Native => write_native(&snapshot, &mut writer).await?,
+                    SnapshotFormat::Rdb => write_rdb(&snapshot, false, &mut writer).await?,
+                }
+                writer.finish().await.map(drop)
+            };
+            let clients = async {
+                for i in 0..KEYS {
+                    let key = format!("key{}", i);
+                    databases.db(0).set(key.clone().into(), "after".into(), None);
+                    databases.move_key(key.as_bytes(), 0, 1);
+                    if i % 100 == 0 {
+                        databases.swap(0, 1);
+                    }
+                    written_while_saving.set(i);
+                    tokio::task::yield_now().await;
+                }
+            };
+            let mut file = Vec::new();
+            let (written, (), read) = tokio::join!(
+                async {
+                    let written = write.await;
+                    let progress = written_while_saving.get();
+                    assert!(progress > 0 && progress < KEYS - 1, "the clients did not write during the save");
+                    written
+                },
+                clients,
+                tokio::io::AsyncReadExt::read_to_end(&mut reader, &mut file)
+            );
+            written.unwrap();
+            read.unwrap();
+            drop(snapshot);
+
+            let records = read_all(&file).unwrap();
+            assert_eq!(records.len(), KEYS);
+            assert!(records.iter().all(|record| record.db == 0));
+            assert!(records.iter().all(|record| matches!(&record.value, Value::String(s) if s.to_bytes() == before)));
+            let keys: std::collections::HashSet<_> = records.iter().map(|record| record.key.clone()).collect();
+            assert_eq!(keys.len(), KEYS);
+
+            databases.flush_all(false);
+            fill();
+        }
+    }
+
+    #[tokio::test]
+    async fn corrupt_dump_is_set_aside_and_nothing_of_it_kept() {
+        let config = Config::parse_from(["rusty-redis", "--databases", "2"]);
+        let databases = Databases::new(2, Arc::new(Settings::new(&config)));
+        for i in 0..2000 {
+            databases.db(i % 2).set(format!("key{}", i).into(), Bytes::from(vec![b'v'; 100]), None);
+        }
+        let path = std::env::temp_dir().join(format!("corrupt-dump-{}.rdb", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let codecs = Codecs { compression: codec::Compression::None, key: None };
+        save(&databases.snapshot(), &path, SnapshotFormat::Native, &codecs).await.unwrap();
+        let len = std::fs::metadata(&path).unwrap().len();
+        std::fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(len / 2).unwrap();
+
+        let loading = Databases::new(2, Arc::new(Settings::new(&config)));
+        let loaded = load(&path, &loading).await;
+        let partial = loading.db(0).len() + loading.db(1).len();
+        let moved = set_aside_corrupt(&path, &loading);
+        let corrupt = format!("{}.corrupt", path);
+        let (left, kept) = (std::path::Path::new(&path).exists(), std::fs::metadata(&corrupt).map(|meta| meta.len()));
+        let _ = std::fs::remove_file(&corrupt);
+
+        assert!(loaded.is_err());
+        assert!(partial > 0, "the keys before the corruption were loaded");
+        assert_eq!(moved.unwrap(), corrupt);
+        assert!(!left);
+        assert_eq!(kept.unwrap(), len / 2);
+        assert_eq!(loading.db(0).len() + loading.db(1).len(), 0);
+        assert_eq!(loading.used_memory(), 0);
+        assert_eq!(loading.dirty(), 0);
+    }
 }
@@ -0,0 +1,872 @@
+//! Pub/sub channels and per-connection subscription state.
+//!
+//! Each channel and pattern with subscribers has a broadcast sender, removed
+//! again when its last receiver is dropped. Patterns are indexed in a trie by
+//! their literal prefix, the bytes before the first glob character, so a
+//! publish only tries the patterns whose prefix the channel starts with
+//! instead of every pattern. Shard channels, used with SSUBSCRIBE and
+//! SPUBLISH, are a namespace of their own whose channels belong to a hash
+//! slot like keys, so that a cluster can route them to the node owning it.
+//!
+//! Each channel or pattern a connection subscribes to is a receiver drained
+//! by a forwarding task into one queue, which the connection loop reads next
+//! to incoming commands. Unsubscribing stops the task, dropping its receiver.
+//!
+//! Forwarding tasks never wait for the connection: the queue is unbounded,
+//! but the encoded size of what it holds is its output buffer, kept within
+//! `client-output-buffer-limit`. A client over it, or whose receivers lag
+//! because its tasks did not get to run, is disconnected or has messages
+//! skipped according to `pubsub-lag-policy`.
+
+use bytes::Bytes;
+use dashmap::DashMap;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc, Notify};
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+use crate::config::{self, Settings};
+use crate::connection;
+use crate::crc16;
+use crate::frame::Frame;
+use crate::scan;
+
+/// Messages buffered per channel or pattern for forwarding tasks that fall behind.
+const CHANNEL_CAPACITY: usize = 32;
+
+/// What happens to a client that cannot keep up with its subscriptions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LagPolicy {
+    /// close the connection, as redis does
+    Disconnect,
+    /// drop the messages that do not fit and keep the client
+    Skip,
+}
+
+impl LagPolicy {
+    pub const ALL: [LagPolicy; 2] = [LagPolicy::Disconnect, LagPolicy::Skip];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LagPolicy::Disconnect => "disconnect",
+            LagPolicy::Skip => "skip",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<LagPolicy> {
+        Self::ALL.into_iter().find(|policy| policy.name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// How many bytes of messages a pub/sub client may have waiting to be written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutputBufferLimit {
+    /// exceeded even once, the client is over its limit; 0 disables it
+    pub hard: u64,
+    /// exceeded for `soft_seconds` in a row, the client is over its limit; 0 disables it
+    pub soft: u64,
+    pub soft_seconds: u64,
+}
+
+impl OutputBufferLimit {
+    /// Parses `pubsub <hard> <soft> <seconds>` as in redis, where the limits
+    /// are memory amounts such as `32mb`. Other client classes are not
+    /// limited, so they are rejected.
+    pub fn parse(value: &str) -> Option<OutputBufferLimit> {
+        let [class, hard, soft, seconds] = value.split_whitespace().collect::<Vec<_>>()[..] else {
+            return None;
+        };
+        if !class.eq_ignore_ascii_case("pubsub") {
+            return None;
+        }
+        Some(OutputBufferLimit {
+            hard: config::parse_memory(hard).ok()?,
+            soft: config::parse_memory(soft).ok()?,
+            soft_seconds: seconds.parse().ok()?,
+        })
+    }
+
+    pub fn render(&self) -> String {
+        format!("pubsub {} {} {}", self.hard, self.soft, self.soft_seconds)
+    }
+}
+
+/// What a subscription is to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Channel,
+    Pattern,
+    ShardChannel,
+}
+
+/// The channels and patterns of a server, shared by every database.
+pub struct PubSub {
+    channels: ChannelMap,
+    shard_channels: ChannelMap,
+    patterns: RwLock<PatternNode>,
+    settings: Arc<Settings>,
+    /// messages never delivered because their client could not keep up
+    dropped_messages: AtomicU64,
+    /// clients disconnected for not keeping up
+    limit_disconnections: AtomicU64,
+}
+
+impl PubSub {
+    pub fn new(settings: Arc<Settings>) -> PubSub {
+        PubSub {
+            channels: ChannelMap::default(),
+            shard_channels: ChannelMap::default(),
+            patterns: RwLock::new(PatternNode::default()),
+            settings,
+            dropped_messages: AtomicU64::new(0),
+            limit_disconnections: AtomicU64::new(0),
+        }
+    }
+
+    pub fn subscribe(self: &Arc<Self>, channel: Bytes) -> Subscription<Bytes> {
+        let rx = self.channels.subscribe(&channel);
+        Subscription::new(rx, Arc::clone(self), channel, Kind::Channel)
+    }
+
+    pub fn ssubscribe(self: &Arc<Self>, channel: Bytes) -> Subscription<Bytes> {
+        let rx = self.shard_channels.subscribe(&channel);
+        Subscription::new(rx, Arc::clone(self), channel, Kind::ShardChannel)
+    }
+
+    /// Subscribes to the channels matching the glob `pattern`, receiving
+    /// each message with the channel it was published to.
+    pub fn psubscribe(self: &Arc<Self>, pattern: Bytes) -> Subscription<(Bytes, Bytes)> {
+        let rx = {
+            let mut root = self.patterns.write().expect("pattern index poisoned");
+            let mut node = &mut *root;
+            for &byte in literal_prefix(&pattern) {
+                node = node.children.entry(byte).or_default();
+            }
+            node.patterns
+                .entry(pattern.clone())
+                .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+                .subscribe()
+        };
+        Subscription::new(rx, Arc::clone(self), pattern, Kind::Pattern)
+    }
+
+    /// Sends `message` to the subscribers of `channel` and of every pattern
+    /// matching it, returning how many received it.
+    pub fn publish(&self, channel: Bytes, message: Bytes) -> usize {
+        let mut receivers = self.channels.publish(&channel, message.clone());
+
+        // only the nodes along the channel's own bytes hold patterns that can match it
+        let root = self.patterns.read().expect("pattern index poisoned");
+        let mut node = Some(&*root);
+        let mut depth = 0;
+        while let Some(current) = node {
+            for (pattern, tx) in &current.patterns {
+                if scan::glob_match(pattern, &channel, false) {
+                    receivers += tx.send((channel.clone(), message.clone())).unwrap_or(0);
+                }
+            }
+            node = channel.get(depth).and_then(|byte| current.children.get(byte));
+            depth += 1;
+        }
+        receivers
+    }
+
+    /// Sends `message` to the subscribers of the shard channel `channel`,
+    /// which patterns never match.
+    pub fn spublish(&self, channel: Bytes, message: Bytes) -> usize {
+        self.shard_channels.publish(&channel, message)
+    }
+
+    /// The channels with subscribers, optionally only those matching `pattern`.
+    pub fn channels(&self, shard: bool, pattern: Option<&[u8]>) -> Vec<Bytes> {
+        self.channel_map(shard).names(pattern)
+    }
+
+    /// The number of subscribers of `channel`.
+    pub fn numsub(&self, shard: bool, channel: &[u8]) -> usize {
+        self.channel_map(shard).receivers(channel)
+    }
+
+    /// The number of distinct patterns with subscribers.
+    pub fn numpat(&self) -> usize {
+        self.patterns.read().expect("pattern index poisoned").count()
+    }
+
+    /// The fields of the stats section of INFO.
+    pub fn info(&self) -> String {
+        let fields = [
+            ("pubsub_channels", self.channels(false, None).len() as u64),
+            ("pubsub_patterns", self.numpat() as u64),
+            ("pubsub_shardchannels", self.channels(true, None).len() as u64),
+            ("pubsub_dropped_messages", self.dropped_messages.load(Ordering::Relaxed)),
+            ("client_output_buffer_limit_disconnections", self.limit_disconnections.load(Ordering::Relaxed)),
+        ];
+
+        let mut info = String::from("# Stats\r\n");
+        for (name, value) in fields {
+            info.push_str(&format!("{}:{}\r\n", name, value));
+        }
+        info
+    }
+
+    fn channel_map(&self, shard: bool) -> &ChannelMap {
+        if shard { &self.shard_channels } else { &self.channels }
+    }
+
+    /// Removes `name` once no receivers are left, which also catches a
+    /// subscriber arriving between the last receiver going and this call.
+    fn release(&self, kind: Kind, name: &[u8]) {
+        match kind {
+            Kind::Channel => self.channels.release(name),
+            Kind::ShardChannel => self.shard_channels.release(name),
+            Kind::Pattern => {
+                let mut root = self.patterns.write().expect("pattern index poisoned");
+                root.release(literal_prefix(name), name);
+            }
+        }
+    }
+}
+
+/// Channels by name, each with the sender its subscribers receive from.
+#[derive(Default)]
+struct ChannelMap(DashMap<Bytes, broadcast::Sender<Bytes>>);
+
+impl ChannelMap {
+    fn subscribe(&self, channel: &Bytes) -> broadcast::Receiver<Bytes> {
+        self.0
+            .entry(channel.clone())
+            .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+            .subscribe()
+    }
+
+    fn publish(&self, channel: &[u8], message: Bytes) -> usize {
+        match self.0.get(channel) {
+            Some(tx) => tx.send(message).unwrap_or(0),
+            None => 0,
+        }
+    }
+
+    fn names(&self, pattern: Option<&[u8]>) -> Vec<Bytes> {
+        self.0
+            .iter()
+            .filter(|entry| entry.value().receiver_count() > 0)
+            .filter(|entry| pattern.is_none_or(|pattern| scan::glob_match(pattern, entry.key(), false)))
+            .map(|entry| entry.key().clone())
+            .collect()
+    }
+
+    fn receivers(&self, channel: &[u8]) -> usize {
+        self.0.get(channel).map_or(0, |tx| tx.receiver_count())
+    }
+
+    fn release(&self, channel: &[u8]) {
+        // the shard stays locked between the check and the removal, so a
+        // concurrent subscribe either comes before and keeps the channel or after
+        self.0.remove_if(channel, |_, tx| tx.receiver_count() == 0);
+    }
+}
+
+/// A node of the pattern trie, reached by the bytes of a literal prefix.
+#[derive(Default)]
+struct PatternNode {
+    children: HashMap<u8, PatternNode>,
+    /// the patterns whose literal prefix ends here
+    patterns: HashMap<Bytes, broadcast::Sender<(Bytes, Bytes)>>,
+}
+
+impl PatternNode {
+    fn count(&self) -> usize {
+        self.patterns.values().filter(|tx| tx.receiver_count() > 0).count()
+            + self.children.values().map(PatternNode::count).sum::<usize>()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.patterns.is_empty() && self.children.is_empty()
+    }
+
+    /// Removes `pattern`, found under `prefix`, if it has no receivers, then
+    /// the nodes left empty along the way.
+    fn release(&mut self, prefix: &[u8], pattern: &[u8]) {
+        let Some((&byte, rest)) = prefix.split_first() else {
+            if self.patterns.get(pattern).is_some_and(|tx| tx.receiver_count() == 0) {
+                self.patterns.remove(pattern);
+            }
+            return;
+        };
+        if let Some(child) = self.children.get_mut(&byte) {
+            child.release(rest, pattern);
+            if child.is_empty() {
+                self.children.remove(&byte);
+            }
+        }
+    }
+}
+
+/// The bytes every channel matching `pattern` starts with.
+fn literal_prefix(pattern: &[u8]) -> &[u8] {
+    let end = pattern
+        .iter()
+        .position(|byte| matches!(byte, b'*' | b'?' | b'[' | b'\\'))
+        .unwrap_or(pattern.len());
+    &pattern[..end]
+}
+
+/// A receiver of a channel or pattern, which is removed from the registry
+/// when its last subscription is dropped.
+pub struct Subscription<T> {
+    rx: Option<broadcast::Receiver<T>>,
+    pub_sub: Arc<PubSub>,
+    name: Bytes,
+    kind: Kind,
+}
+
+impl<T: Clone> Subscription<T> {
+    fn new(rx: broadcast::Receiver<T>, pub_sub: Arc<PubSub>, name: Bytes, kind: Kind) -> Subscription<T> {
+        Subscription { rx: Some(rx), pub_sub, name, kind }
+    }
+
+    pub async fn recv(&mut self) -> Result<T, RecvError> {
+        self.rx.as_mut().expect("the receiver lives until drop").recv().await
+    }
+}
+
+impl<T> Drop for Subscription<T> {
+    fn drop(&mut self) {
+        // the receiver must be gone before the registry counts what is left
+        drop(self.rx.take());
+        self.pub_sub.release(self.kind, &self.name);
+    }
+}
+
+/// The queue of a connection's messages, shared by its forwarding tasks.
+struct Outbox {
+    /// messages with their encoded size
+    tx: mpsc::UnboundedSender<(Frame, usize)>,
+    /// encoded size of the queued messages
+    pending: AtomicUsize,
+    /// since when the queue has been over the soft limit
+    soft_since: Mutex<Option<Instant>>,
+    closed: AtomicBool,
+    /// woken once the client is to be disconnected
+    close: Notify,
+}
+
+impl Outbox {
+    /// Queues `frame` unless that takes the client over its output buffer limit.
+    fn push(&self, pub_sub: &PubSub, frame: Frame) {
+        if self.closed.load(Ordering::Relaxed) {
+            return;
+        }
+        let size = connection::encoded_len(&frame);
+        let limit = pub_sub.settings.client_output_buffer_limit();
+        // the size is reserved and checked against the hard limit in one step,
+        // so forwarders pushing at once never pass the check on the same total
+        let reserved = self.pending.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |pending| {
+            let pending = pending + size;
+            (limit.hard == 0 || pending as u64 <= limit.hard).then_some(pending)
+        });
+        let accepted = match reserved {
+            Ok(before) if self.over_limit(limit, (before + size) as u64) => {
+                self.pending.fetch_sub(size, Ordering::Relaxed);
+                false
+            }
+            Ok(_) => true,
+            Err(_) => false,
+        };
+        if !accepted {
+            self.overflow(pub_sub, 1);
+            return;
+        }
+        let _ = self.tx.send((frame, size));
+    }
+
+    fn over_limit(&self, limit: OutputBufferLimit, pending: u64) -> bool {
+        if limit.hard > 0 && pending > limit.hard {
+            return true;
+        }
+        let mut soft_since = self.soft_since.lock().unwrap();
+        if limit.soft == 0 || pending <= limit.soft {
+            *soft_since = None;
+            return false;
+        }
+        soft_since.get_or_insert_with(Instant::now).elapsed() >= Duration::from_secs(limit.soft_seconds)
+    }
+
+    /// Gives up on `count` messages the client could not take, and on the
+    /// client itself unless the lag policy is to skip them.
+    fn overflow(&self, pub_sub: &PubSub, count: u64) {
+        pub_sub.dropped_messages.fetch_add(count, Ordering::Relaxed);
+        if pub_sub.settings.pubsub_lag_policy() == LagPolicy::Skip || self.closed.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        pub_sub.limit_disconnections.fetch_add(1, Ordering::Relaxed);
+        self.close.notify_one();
+    }
+}
+
+/// The channels and patterns a connection is subscribed to.
+pub struct Subscriber {
+    channels: HashMap<Bytes, JoinHandle<()>>,
+    patterns: HashMap<Bytes, JoinHandle<()>>,
+    shard_channels: HashMap<Bytes, JoinHandle<()>>,
+    outbox: Arc<Outbox>,
+    rx: mpsc::UnboundedReceiver<(Frame, usize)>,
+}
+
+impl Subscriber {
+    pub fn new() -> Subscriber {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let outbox = Outbox {
+            tx,
+            pending: AtomicUsize::new(0),
+            soft_since: Mutex::new(None),
+            closed: AtomicBool::new(false),
+            close: Notify::new(),
+        };
+        Subscriber {
+            channels: HashMap::new(),
+            patterns: HashMap::new(),
+            shard_channels: HashMap::new(),
+            outbox: Arc::new(outbox),
+            rx,
+        }
+    }
+
+    /// Whether the connection is in subscribed mode, where only pub/sub
+    /// commands, PING, QUIT and RESET are accepted.
+    pub fn is_active(&self) -> bool {
+        self.count(Kind::Channel) + self.count(Kind::ShardChannel) > 0
+    }
+
+    /// Subscriptions reported in the confirmations of `kind`: shard channels
+    /// are counted on their own, channels and patterns together.
+    fn count(&self, kind: Kind) -> usize {
+        match kind {
+            Kind::Channel | Kind::Pattern => self.channels.len() + self.patterns.len(),
+            Kind::ShardChannel => self.shard_channels.len(),
+        }
+    }
+
+    fn held(&mut self, kind: Kind) -> &mut HashMap<Bytes, JoinHandle<()>> {
+        match kind {
+            Kind::Channel => &mut self.channels,
+            Kind::Pattern => &mut self.patterns,
+            Kind::ShardChannel => &mut self.shard_channels,
+        }
+    }
+
+    /// Subscribes to `channels`, returning a confirmation for each with the
+    /// number of subscriptions held after it.
+    pub fn subscribe(&mut self, pub_sub: &Arc<PubSub>, channels: Vec<Bytes>) -> Vec<Frame> {
+        channels
+            .into_iter()
+            .map(|channel| {
+                if !self.channels.contains_key(&channel) {
+                    let name = channel.clone();
+                    let forwarder = forward(pub_sub.subscribe(channel.clone()), Arc::clone(&self.outbox), move |message| {
+                        Frame::Array(vec![Frame::Bulk("message".into()), Frame::Bulk(name.clone()), Frame::Bulk(message)])
+                    });
+                    self.channels.insert(channel.clone(), forwarder);
+                }
+                confirmation("subscribe", Frame::Bulk(channel), self.count(Kind::Channel))
+            })
+            .collect()
+    }
+
+    /// Subscribes to the channels matching each of `patterns`.
+    pub fn psubscribe(&mut self, pub_sub: &Arc<PubSub>, patterns: Vec<Bytes>) -> Vec<Frame> {
+        patterns
+            .into_iter()
+            .map(|pattern| {
+                if !self.patterns.contains_key(&pattern) {
+                    let name = pattern.clone();
+                    let forwarder = forward(pub_sub.psubscribe(pattern.clone()), Arc::clone(&self.outbox), move |(channel, message)| {
+                        Frame::Array(vec![
+                            Frame::Bulk("pmessage".into()),
+                            Frame::Bulk(name.clone()),
+                            Frame::Bulk(channel),
+                            Frame::Bulk(message),
+                        ])
+                    });
+                    self.patterns.insert(pattern.clone(), forwarder);
+                }
+                confirmation("psubscribe", Frame::Bulk(pattern), self.count(Kind::Channel))
+            })
+            .collect()
+    }
+
+    /// Subscribes to the shard channels `channels`, which have to hash to
+    /// the same slot, returning a confirmation for each with the number of
+    /// shard channels held after it.
+    pub fn ssubscribe(&mut self, pub_sub: &Arc<PubSub>, channels: Vec<Bytes>) -> Vec<Frame> {
+        if !same_slot(&channels) {
+            return vec![cross_slot()];
+        }
+        channels
+            .into_iter()
+            .map(|channel| {
+                if !self.shard_channels.contains_key(&channel) {
+                    let name = channel.clone();
+                    let forwarder = forward(pub_sub.ssubscribe(channel.clone()), Arc::clone(&self.outbox), move |message| {
+                        Frame::Array(vec![Frame::Bulk("smessage".into()), Frame::Bulk(name.clone()), Frame::Bulk(message)])
+                    });
+                    self.shard_channels.insert(channel.clone(), forwarder);
+                }
+                confirmation("ssubscribe", Frame::Bulk(channel), self.count(Kind::ShardChannel))
+            })
+            .collect()
+    }
+
+    /// Unsubscribes from `channels`, or from every channel when it is empty,
+    /// returning a confirmation for each with the number of subscriptions left.
+    pub async fn unsubscribe(&mut self, channels: Vec<Bytes>) -> Vec<Frame> {
+        self.drop_subscriptions(channels, Kind::Channel).await
+    }
+
+    /// Unsubscribes from `patterns`, or from every pattern when it is empty.
+    pub async fn punsubscribe(&mut self, patterns: Vec<Bytes>) -> Vec<Frame> {
+        self.drop_subscriptions(patterns, Kind::Pattern).await
+    }
+
+    /// Unsubscribes from the shard channels `channels`, which have to hash to
+    /// the same slot, or from every shard channel when it is empty.
+    pub async fn sunsubscribe(&mut self, channels: Vec<Bytes>) -> Vec<Frame> {
+        if !same_slot(&channels) {
+            return vec![cross_slot()];
+        }
+        self.drop_subscriptions(channels, Kind::ShardChannel).await
+    }
+
+    async fn drop_subscriptions(&mut self, names: Vec<Bytes>, kind: Kind) -> Vec<Frame> {
+        let reply = match kind {
+            Kind::Channel => "unsubscribe",
+            Kind::Pattern => "punsubscribe",
+            Kind::ShardChannel => "sunsubscribe",
+        };
+        let names = if names.is_empty() { self.held(kind).keys().cloned().collect() } else { names };
+        if names.is_empty() {
+            return vec![confirmation(reply, Frame::Null, self.count(kind))];
+        }
+
+        let mut confirmations = Vec::with_capacity(names.len());
+        for name in names {
+            if let Some(forwarder) = self.held(kind).remove(&name) {
+                stop(forwarder).await;
+            }
+            confirmations.push(confirmation(reply, Frame::Bulk(name), self.count(kind)));
+        }
+        confirmations
+    }
+
+    /// Drops every subscription without confirming it, as RESET does.
+    pub async fn reset(&mut self) {
+        let held = self.channels.drain().chain(self.patterns.drain()).chain(self.shard_channels.drain());
+        for (_, forwarder) in held {
+            stop(forwarder).await;
+        }
+    }
+
+    /// Waits for the next message published to a subscribed channel or
+    /// pattern, or returns `None` once the client is to be disconnected.
+    pub async fn recv(&mut self) -> Option<Frame> {
+        tokio::select! {
+            message = self.rx.recv() => {
+                let (frame, size) = message.expect("the subscriber holds a sender");
+                self.outbox.pending.fetch_sub(size, Ordering::Relaxed);
+                Some(frame)
+            }
+            _ = self.outbox.close.notified() => None,
+        }
+    }
+
+    /// Waits until the client is to be disconnected for not keeping up,
+    /// which a write blocked on the client has to give way to.
+    pub async fn closed(&self) {
+        self.outbox.close.notified().await
+    }
+}
+
+impl Default for Subscriber {
+    fn default() -> Subscriber {
+        Subscriber::new()
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        for (_, forwarder) in self.channels.drain().chain(self.patterns.drain()).chain(self.shard_channels.drain()) {
+            forwarder.abort();
+        }
+    }
+}
+
+/// The reply to PING in subscribed mode.
+pub fn pong(message: Option<Bytes>) -> Frame {
+    Frame::Array(vec![Frame::Bulk("pong".into()), Frame::Bulk(message.unwrap_or_default())])
+}
+
+/// Whether `channels` all hash to one slot, as shard channels of one
+/// command must so that a single node of a cluster serves them.
+fn same_slot(channels: &[Bytes]) -> bool {
+    channels.windows(2).all(|pair| crc16::key_slot(&pair[0]) == crc16::key_slot(&pair[1]))
+}
+
+fn cross_slot() -> Frame {
+    Frame::Error("CROSSSLOT Keys in request don't hash to the same slot".to_string())
+}
+
+fn confirmation(kind: &'static str, name: Frame, count: usize) -> Frame {
+    Frame::Array(vec![Frame::Bulk(kind.into()), name, Frame::Integer(count as i64)])
+}
+
+/// Spawns the task turning what `subscription` receives into frames on the connection's queue.
+fn forward<T, F>(mut subscription: Subscription<T>, outbox: Arc<Outbox>, frame: F) -> JoinHandle<()>
+where
+    T: Clone + Send + 'static,
+    F: Fn(T) -> Frame + Send + 'static,
+{
+    tokio::spawn(async move {
+        loop {
+            match subscription.recv().await {
+                Ok(message) => outbox.push(&subscription.pub_sub, frame(message)),
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("subscriber of {:?} fell behind, {} messages dropped", subscription.name, skipped);
+                    outbox.overflow(&subscription.pub_sub, skipped);
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    })
+}
+
+/// Stops a forwarding task and waits until its subscription has been released.
+async fn stop(forwarder: JoinHandle<()>) {
+    forwarder.abort();
+    let _ = forwarder.await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use clap::Parser;
+
+    fn pub_sub(args: &[&str]) -> Arc<PubSub> {
+        let config = Config::parse_from(["rusty-redis"].iter().chain(args));
+        Arc::new(PubSub::new(Arc::new(Settings::new(&config))))
+    }
+
+    #[test]
+    fn literal_prefix_stops_at_the_first_glob_character() {
+        assert_eq!(literal_prefix(b"news.*"), b"news.");
+        assert_eq!(literal_prefix(b"h?llo"), b"h");
+        assert_eq!(literal_prefix(b"a[bc]"), b"a");
+        assert_eq!(literal_prefix(b"a\\*b"), b"a");
+        assert_eq!(literal_prefix(b"*"), b"");
+        assert_eq!(literal_prefix(b"plain"), b"plain");
+    }
+
+    #[tokio::test]
+    async fn patterns_sharing_a_prefix_match_only_their_channels() {
+        let pub_sub = pub_sub(&[]);
+        let mut news = pub_sub.psubscribe("news.*".into());
+        let mut sports = pub_sub.psubscribe("news.sport?".into());
+        let mut all = pub_sub.psubscribe("*".into());
+        let mut exact = pub_sub.psubscribe("news".into());
+        assert_eq!(pub_sub.numpat(), 4);
+
+        assert_eq!(pub_sub.publish("news.sports".into(), "a".into()), 3);
+        assert_eq!(pub_sub.publish("news".into(), "b".into()), 2);
+        assert_eq!(pub_sub.publish("weather".into(), "c".into()), 1);
+        assert_eq!(pub_sub.publish("new".into(), "d".into()), 1);
+
+        assert_eq!(news.recv().await.unwrap(), (Bytes::from("news.sports"), Bytes::from("a")));
+        assert_eq!(sports.recv().await.unwrap(), (Bytes::from("news.sports"), Bytes::from("a")));
+        assert_eq!(exact.recv().await.unwrap(), (Bytes::from("news"), Bytes::from("b")));
+        let received: Vec<_> = [all.recv().await, all.recv().await, all.recv().await, all.recv().await]
+            .into_iter()
+            .map(|message| message.unwrap().1)
+            .collect();
+        assert_eq!(received, ["a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn released_patterns_leave_no_nodes_behind() {
+        let pub_sub = pub_sub(&[]);
+        let first = pub_sub.psubscribe("news.*".into());
+        let second = pub_sub.psubscribe("news.*".into());
+        let other = pub_sub.psubscribe("new?".into());
+        assert_eq!(pub_sub.numpat(), 2);
+
+        drop(first);
+        assert_eq!(pub_sub.numpat(), 2);
+        drop(second);
+        assert_eq!(pub_sub.numpat(), 1);
+        assert_eq!(pub_sub.publish("news.x".into(), "m".into()), 0);
+        drop(other);
+        assert_eq!(pub_sub.numpat(), 0);
+        assert!(pub_sub.patterns.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn channels_are_removed_with_their_last_subscriber() {
+        let pub_sub = pub_sub(&[]);
+        let first = pub_sub.subscribe("a".into());
+        let second = pub_sub.subscribe("a".into());
+        let shard = pub_sub.ssubscribe("a".into());
+        assert_eq!(pub_sub.numsub(false, b"a"), 2);
+        assert_eq!(pub_sub.numsub(true, b"a"), 1);
+        assert_eq!(pub_sub.channels(false, Some(b"?")), [Bytes::from("a")]);
+        assert!(pub_sub.channels(false, Some(b"b*")).is_empty());
+
+        // shard channels are a namespace of their own
+        assert_eq!(pub_sub.publish("a".into(), "m".into()), 2);
+        assert_eq!(pub_sub.spublish("a".into(), "m".into()), 1);
+
+        drop((first, second, shard));
+        assert!(pub_sub.channels.0.is_empty());
+        assert!(pub_sub.shard_channels.0.is_empty());
+        assert_eq!(pub_sub.publish("a".into(), "m".into()), 0);
+    }
+
+    #[test]
+    fn output_buffer_limits_parse_like_redis() {
+        let limit = OutputBufferLimit::parse("PubSub 32mb 8mb 60").unwrap();
+        assert_eq!(limit, OutputBufferLimit { hard: 32 * 1024 * 1024, soft: 8 * 1024 * 1024, soft_seconds: 60 });
+        assert_eq!(OutputBufferLimit::parse(&limit.render()), Some(limit));
+        assert_eq!(OutputBufferLimit::parse("normal 0 0 0"), None);
+        assert_eq!(OutputBufferLimit::parse("pubsub 1mb 1mb"), None);
+        assert_eq!(OutputBufferLimit::parse("pubsub 1mb 1mb x"), None);
+        assert_eq!(LagPolicy::parse("SKIP"), Some(LagPolicy::Skip));
+        assert_eq!(LagPolicy::parse("drop"), None);
+    }
+
+    #[test]
+    fn soft_limit_has_to_be_exceeded_for_its_whole_period() {
+        let subscriber = Subscriber::new();
+        let limit = OutputBufferLimit { hard: 100, soft: 10, soft_seconds: 60 };
+        assert!(subscriber.outbox.over_limit(limit, 101));
+        assert!(!subscriber.outbox.over_limit(limit, 20));
+        assert!(subscriber.outbox.soft_since.lock().unwrap().is_some());
+        assert!(!subscriber.outbox.over_limit(limit, 10));
+        assert!(subscriber.outbox.soft_since.lock().unwrap().is_none());
+
+        let immediate = OutputBufferLimit { hard: 0, soft: 10, soft_seconds: 0 };
+        assert!(subscriber.outbox.over_limit(immediate, 11));
+        assert!(!subscriber.outbox.over_limit(OutputBufferLimit { hard: 0, soft: 0, soft_seconds: 0 }, u64::MAX));
+    }
+
+    #[test]
+    fn concurrent_pushes_never_take_the_queue_over_its_hard_limit() {
+        let pub_sub = pub_sub(&["--client-output-buffer-limit", "pubsub 1000 0 0", "--pubsub-lag-policy", "skip"]);
+        let mut subscriber = Subscriber::new();
+        let frame = Frame::Bulk(Bytes::from(vec![b'x'; 40]));
+        let size = connection::encoded_len(&frame);
+
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for _ in 0..100 {
+                        subscriber.outbox.push(&pub_sub, frame.clone());
+                    }
+                });
+            }
+        });
+
+        let pending = subscriber.outbox.pending.load(Ordering::Relaxed);
+        let mut queued = 0;
+        while subscriber.rx.try_recv().is_ok() {
+            queued += 1;
+        }
+        assert_eq!(pending, queued * size);
+        assert_eq!(queued, 1000 / size);
+        assert!(pub_sub.info().contains(&format!("pubsub_dropped_messages:{}\r\n", 800 - queued)));
+    }
+
+    #[test]
+    fn push_rejected_by_the_soft_limit_gives_its_reservation_back() {
+        let pub_sub = pub_sub(&["--client-output-buffer-limit", "pubsub 0 100 0", "--pubsub-lag-policy", "skip"]);
+        let mut subscriber = Subscriber::new();
+
+        subscriber.outbox.push(&pub_sub, Frame::Bulk(Bytes::from(vec![b'x'; 200])));
+        assert_eq!(subscriber.outbox.pending.load(Ordering::Relaxed), 0);
+        subscriber.outbox.push(&pub_sub, Frame::Bulk("small".into()));
+        assert!(subscriber.rx.try_recv().is_ok_and(|(_, size)| size == subscriber.outbox.pending.load(Ordering::Relaxed)));
+    }
+
+    #[tokio::test]
+    async fn client_over_its_hard_limit_is_disconnected() {
+        let pub_sub = pub_sub(&["--client-output-buffer-limit", "pubsub 100 0 0"]);
+        let mut subscriber = Subscriber::new();
+        subscriber.subscribe(&pub_sub, vec!["a".into()]);
+
+        pub_sub.publish("a".into(), Bytes::from(vec![b'x'; 200]));
+        assert!(subscriber.recv().await.is_none());
+        assert!(pub_sub.info().contains("pubsub_dropped_messages:1\r\n"));
+        assert!(pub_sub.info().contains("client_output_buffer_limit_disconnections:1\r\n"));
+
+        // nothing more is queued for a client on its way out
+        pub_sub.publish("a".into(), "small".into());
+        tokio::task::yield_now().await;
+        assert!(subscriber.rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn skip_policy_drops_what_does_not_fit_and_keeps_the_client() {
+        let pub_sub = pub_sub(&["--client-output-buffer-limit", "pubsub 100 0 0", "--pubsub-lag-policy", "skip"]);
+        let mut subscriber = Subscriber::new();
+        subscriber.psubscribe(&pub_sub, vec!["*".into()]);
+
+        pub_sub.publish("a".into(), Bytes::from(vec![b'x'; 200]));
+        pub_sub.publish("a".into(), "small".into());
+        let message = subscriber.recv().await.unwrap();
+        assert!(matches!(&message, Frame::Array(fields) if matches!(&fields[3], Frame::Bulk(m) if m == "small")));
+        assert_eq!(subscriber.outbox.pending.load(Ordering::Relaxed), 0);
+        assert!(pub_sub.info().contains("pubsub_dropped_messages:1\r\n"));
+        assert!(pub_sub.info().contains("client_output_buffer_limit_disconnections:0\r\n"));
+    }
+
+    #[tokio::test]
+    async fn lagging_receivers_count_as_dropped_messages() {
+        let pub_sub = pub_sub(&["--pubsub-lag-policy", "skip"]);
+        let mut subscriber = Subscriber::new();
+        subscriber.subscribe(&pub_sub, vec!["a".into()]);
+
+        // the forwarding task only runs once this test yields, by which time
+        // the channel has overwritten the oldest messages
+        let sent = CHANNEL_CAPACITY + 8;
+        for i in 0..sent {
+            pub_sub.publish("a".into(), i.to_string().into());
+        }
+        for i in 8..sent {
+            let message = subscriber.recv().await.unwrap();
+            assert!(matches!(&message, Frame::Array(fields) if matches!(&fields[2], Frame::Bulk(m) if *m == i.to_string())));
+        }
+        assert!(pub_sub.info().contains("pubsub_dropped_messages:8\r\n"));
+    }
+
+    #[tokio::test]
+    async fn shard_channels_of_one_command_share_a_slot() {
+        let pub_sub = pub_sub(&[]);
+        let mut subscriber = Subscriber::new();
+        let replies = subscriber.ssubscribe(&pub_sub, vec!["foo".into(), "bar".into()]);
+        assert!(matches!(&replies[..], [Frame::Error(e)] if e.starts_with("CROSSSLOT")));
+        assert!(!subscriber.is_active());
+
+        let replies = subscriber.ssubscribe(&pub_sub, vec!["{user}a".into(), "{user}b".into()]);
+        assert!(matches!(&replies[..], [_, Frame::Array(fields)] if matches!(fields[2], Frame::Integer(2))));
+        assert_eq!(pub_sub.spublish("{user}a".into(), "m".into()), 1);
+        let message = subscriber.recv().await.unwrap();
+        assert!(matches!(&message, Frame::Array(fields) if matches!(&fields[0], Frame::Bulk(kind) if kind == "smessage")));
+
+        let replies = subscriber.sunsubscribe(vec!["foo".into(), "bar".into()]).await;
+        assert!(matches!(&replies[..], [Frame::Error(e)] if e.starts_with("CROSSSLOT")));
+        subscriber.sunsubscribe(Vec::new()).await;
+        assert!(!subscriber.is_active());
+        assert_eq!(pub_sub.numsub(true, b"{user}a"), 0);
+    }
+}
@@ -0,0 +1,1256 @@
+//! Redis RDB object and file serialization.
+//!
+//! Values are written with the plain (non-packed) encodings, which every redis
+//! release since 4.0 can load. Reading additionally understands the compact
+//! encodings redis produces for small values (ziplist, listpack, intset,
+//! zipmap and quicklist) as well as LZF-compressed strings.
+//!
+//! A file is the `REDIS` magic and a four digit version, aux fields, one
+//! section per database introduced by SELECTDB, and an EOF opcode followed by
+//! a CRC64 of everything before it.
+
+use bytes::Bytes;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, Read};
+use tracing::warn;
+
+use crate::crc64;
+use crate::value::{canonical_integer, Value};
+
+/// Version stamped on DUMP payloads; only encodings available in this version are written.
+pub const WRITE_VERSION: u16 = 9;
+/// Highest RDB version we are able to read.
+pub const MAX_VERSION: u16 = 12;
+/// Version written to RDB files.
+pub const FILE_VERSION: u16 = 11;
+/// The redis release that writes `FILE_VERSION`, reported in the `redis-ver` aux field.
+pub const REDIS_VERSION: &str = "7.2.0";
+
+const MAGIC: &[u8] = b"REDIS";
+
+pub const TYPE_STRING: u8 = 0;
+pub const TYPE_LIST: u8 = 1;
+pub const TYPE_SET: u8 = 2;
+pub const TYPE_ZSET: u8 = 3;
+pub const TYPE_HASH: u8 = 4;
+pub const TYPE_ZSET_2: u8 = 5;
+pub const TYPE_HASH_ZIPMAP: u8 = 9;
+pub const TYPE_LIST_ZIPLIST: u8 = 10;
+pub const TYPE_SET_INTSET: u8 = 11;
+pub const TYPE_ZSET_ZIPLIST: u8 = 12;
+pub const TYPE_HASH_ZIPLIST: u8 = 13;
+pub const TYPE_LIST_QUICKLIST: u8 = 14;
+pub const TYPE_STREAM_LISTPACKS: u8 = 15;
+pub const TYPE_HASH_LISTPACK: u8 = 16;
+pub const TYPE_ZSET_LISTPACK: u8 = 17;
+pub const TYPE_LIST_QUICKLIST_2: u8 = 18;
+pub const TYPE_STREAM_LISTPACKS_2: u8 = 19;
+pub const TYPE_SET_LISTPACK: u8 = 20;
+pub const TYPE_STREAM_LISTPACKS_3: u8 = 21;
+
+const OPCODE_SLOT_INFO: u8 = 0xf4;
+const OPCODE_FUNCTION2: u8 = 0xf5;
+const OPCODE_FUNCTION: u8 = 0xf6;
+const OPCODE_MODULE_AUX: u8 = 0xf7;
+const OPCODE_IDLE: u8 = 0xf8;
+const OPCODE_FREQ: u8 = 0xf9;
+const OPCODE_AUX: u8 = 0xfa;
+const OPCODE_RESIZEDB: u8 = 0xfb;
+const OPCODE_EXPIRETIME_MS: u8 = 0xfc;
+const OPCODE_EXPIRETIME: u8 = 0xfd;
+const OPCODE_SELECTDB: u8 = 0xfe;
+const OPCODE_EOF: u8 = 0xff;
+
+const ENC_INT8: u64 = 0;
+const ENC_INT16: u64 = 1;
+const ENC_INT32: u64 = 2;
+const ENC_LZF: u64 = 3;
+
+const QUICKLIST_NODE_PLAIN: u64 = 1;
+const QUICKLIST_NODE_PACKED: u64 = 2;
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+/// Serializes `value` as a DUMP payload: the object, the RDB version and a CRC64 trailer.
+pub fn dump(value: &Value) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_value(value, &mut out);
+    out.extend_from_slice(&WRITE_VERSION.to_le_bytes());
+    let crc = crc64::checksum(&out);
+    out.extend_from_slice(&crc.to_le_bytes());
+    out
+}
+
+/// Validates and decodes a DUMP payload.
+pub fn restore(payload: &[u8]) -> io::Result<Value> {
+    if payload.len() < 10 {
+        return Err(invalid("DUMP payload version or checksum are wrong"));
+    }
+
+    let (body, trailer) = payload.split_at(payload.len() - 10);
+    let version = u16::from_le_bytes([trailer[0], trailer[1]]);
+    let crc = u64::from_le_bytes(trailer[2..].try_into().expect("trailer is 8 bytes"));
+
+    if version > MAX_VERSION || crc64::checksum(&payload[..payload.len() - 8]) != crc {
+        return Err(invalid("DUMP payload version or checksum are wrong"));
+    }
+
+    let mut reader = RdbReader::new(body);
+    let value = reader.read_value()?;
+    if reader.offset() as usize != body.len() {
+        return Err(invalid("Bad data format"));
+    }
+    Ok(value)
+}
+
+/// Writes the type byte followed by the encoded object.
+pub fn write_value(value: &Value, out: &mut Vec<u8>) {
+    out.push(value_type(value));
+    write_object(value, out);
+}
+
+fn value_type(value: &Value) -> u8 {
+    match value {
+        Value::String(_) => TYPE_STRING,
+        Value::List(_) => TYPE_LIST,
+        Value::Set(_) => TYPE_SET,
+        Value::ZSet(_) => TYPE_ZSET_2,
+        Value::Hash(_) => TYPE_HASH,
+    }
+}
+
+/// Writes the encoded object, whose type byte has already been written.
+fn write_object(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::String(string) => write_string(&string.to_bytes(), out),
+        Value::List(list) => {
+            write_length(list.len() as u64, out);
+            for item in list.iter() {
+                write_string(&item, out);
+            }
+        }
+        Value::Set(set) => {
+            write_length(set.len() as u64, out);
+            for member in set.iter() {
+                write_string(&member, out);
+            }
+        }
+        Value::ZSet(zset) => {
+            write_length(zset.len() as u64, out);
+            for (member, score) in zset.iter() {
+                write_string(&member, out);
+                out.extend_from_slice(&score.to_le_bytes());
+            }
+        }
+        Value::Hash(hash) => {
+            write_length(hash.len() as u64, out);
+            for (field, value) in hash.iter() {
+                write_string(&field, out);
+                write_string(&value, out);
+            }
+        }
+    }
+}
+
+/// Starts an RDB file with the magic and version.
+pub fn write_header(out: &mut Vec<u8>) {
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(format!("{:04}", FILE_VERSION).as_bytes());
+}
+
+pub fn write_aux(name: &str, value: &[u8], out: &mut Vec<u8>) {
+    out.push(OPCODE_AUX);
+    write_string(name.as_bytes(), out);
+    write_string(value, out);
+}
+
+/// Starts the section of database `index`, sized so loaders can presize their tables.
+pub fn write_select_db(index: usize, keys: usize, expires: usize, out: &mut Vec<u8>) {
+    out.push(OPCODE_SELECTDB);
+    write_length(index as u64, out);
+    out.push(OPCODE_RESIZEDB);
+    write_length(keys as u64, out);
+    write_length(expires as u64, out);
+}
+
+/// Writes one key, expiring at the unix time `expire_at_ms` in milliseconds.
+pub fn write_record(key: &[u8], value: &Value, expire_at_ms: Option<u64>, out: &mut Vec<u8>) {
+    if let Some(deadline) = expire_at_ms {
+        out.push(OPCODE_EXPIRETIME_MS);
+        out.extend_from_slice(&deadline.to_le_bytes());
+    }
+    out.push(value_type(value));
+    write_string(key, out);
+    write_object(value, out);
+}
+
+/// Ends the contents of an RDB file; the CRC64 of everything written follows.
+pub fn write_eof(out: &mut Vec<u8>) {
+    out.push(OPCODE_EOF);
+}
+
+pub fn write_length(len: u64, out: &mut Vec<u8>) {
+    if len < 1 << 6 {
+        out.push(len as u8);
+    } else if len < 1 << 14 {
+        out.push(0x40 | (len >> 8) as u8);
+        out.push(len as u8);
+    } else if len <= u32::MAX as u64 {
+        out.push(0x80);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    } else {
+        out.push(0x81);
+        out.extend_from_slice(&len.to_be_bytes());
+    }
+}
+
+pub fn write_string(bytes: &[u8], out: &mut Vec<u8>) {
+    if let Some(n) = canonical_integer(bytes) {
+        if let Ok(n) = i8::try_from(n) {
+            out.push(0xc0 | ENC_INT8 as u8);
+            out.extend_from_slice(&n.to_le_bytes());
+            return;
+        }
+        if let Ok(n) = i16::try_from(n) {
+            out.push(0xc0 | ENC_INT16 as u8);
+            out.extend_from_slice(&n.to_le_bytes());
+            return;
+        }
+        if let Ok(n) = i32::try_from(n) {
+            out.push(0xc0 | ENC_INT32 as u8);
+            out.extend_from_slice(&n.to_le_bytes());
+            return;
+        }
+    }
+
+    write_length(bytes.len() as u64, out);
+    out.extend_from_slice(bytes);
+}
+
+/// Incremental reader over RDB-encoded data that tracks its byte offset.
+pub struct RdbReader<R> {
+    inner: R,
+    offset: u64,
+    /// CRC64 of everything read so far
+    crc: u64,
+}
+
+impl<R: Read> RdbReader<R> {
+    pub fn new(inner: R) -> RdbReader<R> {
+        RdbReader { inner, offset: 0, crc: 0 }
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// CRC64 of everything read so far.
+    pub fn checksum(&self) -> u64 {
+        self.crc
+    }
+
+    pub fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        self.inner.read_exact(buf).map_err(|e| {
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                io::Error::new(io::ErrorKind::UnexpectedEof, format!("unexpected end of data at offset {}", self.offset))
+            } else {
+                e
+            }
+        })?;
+        self.offset += buf.len() as u64;
+        self.crc = crc64::update(self.crc, buf);
+        Ok(())
+    }
+
+    pub fn read_u8(&mut self) -> io::Result<u8> {
+        let mut buf = [0u8; 1];
+        self.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    /// Reads past `len` bytes without keeping them.
+    pub fn skip(&mut self, len: u64) -> io::Result<()> {
+        let mut buf = [0u8; 8192];
+        let mut left = len;
+        while left > 0 {
+            let n = left.min(buf.len() as u64) as usize;
+            self.read_exact(&mut buf[..n])?;
+            left -= n as u64;
+        }
+        Ok(())
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        (&mut self.inner).take(len as u64).read_to_end(&mut buf)?;
+        self.offset += buf.len() as u64;
+        self.crc = crc64::update(self.crc, &buf);
+        if buf.len() != len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("unexpected end of data at offset {}", self.offset),
+            ));
+        }
+        Ok(buf)
+    }
+
+    /// Reads a length, returning it together with whether it was a special string encoding.
+    pub fn read_length_with_encoding(&mut self) -> io::Result<(u64, bool)> {
+        let first = self.read_u8()?;
+        match first >> 6 {
+            0 => Ok(((first & 0x3f) as u64, false)),
+            1 => {
+                let second = self.read_u8()?;
+                Ok(((((first & 0x3f) as u64) << 8) | second as u64, false))
+            }
+            2 => match first {
+                0x80 => {
+                    let mut buf = [0u8; 4];
+                    self.read_exact(&mut buf)?;
+                    Ok((u32::from_be_bytes(buf) as u64, false))
+                }
+                0x81 => {
+                    let mut buf = [0u8; 8];
+                    self.read_exact(&mut buf)?;
+                    Ok((u64::from_be_bytes(buf), false))
+                }
+                _ => Err(invalid(format!("unknown length encoding {:#x} at offset {}", first, self.offset - 1))),
+            },
+            _ => Ok(((first & 0x3f) as u64, true)),
+        }
+    }
+
+    pub fn read_length(&mut self) -> io::Result<u64> {
+        match self.read_length_with_encoding()? {
+            (len, false) => Ok(len),
+            (_, true) => Err(invalid(format!("unexpected string encoding at offset {}", self.offset - 1))),
+        }
+    }
+
+    pub fn read_string(&mut self) -> io::Result<Bytes> {
+        let (len, encoded) = self.read_length_with_encoding()?;
+        if !encoded {
+            return Ok(Bytes::from(self.read_bytes(len as usize)?));
+        }
+
+        match len {
+            ENC_INT8 => {
+                let mut buf = [0u8; 1];
+                self.read_exact(&mut buf)?;
+                Ok(Bytes::from(i8::from_le_bytes(buf).to_string()))
+            }
+            ENC_INT16 => {
+                let mut buf = [0u8; 2];
+                self.read_exact(&mut buf)?;
+                Ok(Bytes::from(i16::from_le_bytes(buf).to_string()))
+            }
+            ENC_INT32 => {
+                let mut buf = [0u8; 4];
+                self.read_exact(&mut buf)?;
+                Ok(Bytes::from(i32::from_le_bytes(buf).to_string()))
+            }
+            ENC_LZF => {
+                let compressed_len = self.read_length()? as usize;
+                let len = self.read_length()? as usize;
+                let compressed = self.read_bytes(compressed_len)?;
+                Ok(Bytes::from(lzf_decompress(&compressed, len)?))
+            }
+            other => Err(invalid(format!("unknown string encoding {} at offset {}", other, self.offset - 1))),
+        }
+    }
+
+    fn read_binary_double(&mut self) -> io::Result<f64> {
+        let mut buf = [0u8; 8];
+        self.read_exact(&mut buf)?;
+        Ok(f64::from_le_bytes(buf))
+    }
+
+    /// Reads the legacy string-encoded double used by `TYPE_ZSET`.
+    fn read_string_double(&mut self) -> io::Result<f64> {
+        match self.read_u8()? {
+            253 => Ok(f64::NAN),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            len => {
+                let buf = self.read_bytes(len as usize)?;
+                parse_double(&buf)
+            }
+        }
+    }
+
+    /// Reads a type byte followed by the object it describes.
+    pub fn read_value(&mut self) -> io::Result<Value> {
+        let value_type = self.read_u8()?;
+        self.read_object(value_type)
+    }
+
+    pub fn read_object(&mut self, value_type: u8) -> io::Result<Value> {
+        match value_type {
+            TYPE_STRING => Ok(Value::string(self.read_string()?)),
+            TYPE_LIST => {
+                let len = self.read_length()?;
+                let mut items = VecDeque::new();
+                for _ in 0..len {
+                    items.push_back(self.read_string()?);
+                }
+                Ok(Value::list(items))
+            }
+            TYPE_SET => {
+                let len = self.read_length()?;
+                let mut members = HashSet::new();
+                for _ in 0..len {
+                    members.insert(self.read_string()?);
+                }
+                Ok(Value::set(members))
+            }
+            TYPE_ZSET | TYPE_ZSET_2 => {
+                let len = self.read_length()?;
+                let mut members = HashMap::new();
+                for _ in 0..len {
+                    let member = self.read_string()?;
+                    let score = if value_type == TYPE_ZSET_2 {
+                        self.read_binary_double()?
+                    } else {
+                        self.read_string_double()?
+                    };
+                    members.insert(member, score);
+                }
+                Ok(Value::zset(members))
+            }
+            TYPE_HASH => {
+                let len = self.read_length()?;
+                let mut fields = HashMap::new();
+                for _ in 0..len {
+                    let field = self.read_string()?;
+                    let value = self.read_string()?;
+                    fields.insert(field, value);
+                }
+                Ok(Value::hash(fields))
+            }
+            TYPE_HASH_ZIPMAP => {
+                let blob = self.read_string()?;
+                Ok(Value::hash(zipmap_entries(&blob)?.into_iter().collect()))
+            }
+            TYPE_LIST_ZIPLIST => {
+                let blob = self.read_string()?;
+                Ok(Value::list(ziplist_entries(&blob)?.into_iter().collect()))
+            }
+            TYPE_SET_INTSET => {
+                let blob = self.read_string()?;
+                let members = intset_entries(&blob)?
+                    .into_iter()
+                    .map(|n| Bytes::from(n.to_string()))
+                    .collect();
+                Ok(Value::set(members))
+            }
+            TYPE_SET_LISTPACK => {
+                let blob = self.read_string()?;
+                Ok(Value::set(listpack_entries(&blob)?.into_iter().collect()))
+            }
+            TYPE_ZSET_ZIPLIST | TYPE_ZSET_LISTPACK => {
+                let blob = self.read_string()?;
+                let entries = if value_type == TYPE_ZSET_ZIPLIST {
+                    ziplist_entries(&blob)?
+                } else {
+                    listpack_entries(&blob)?
+                };
+                let mut members = HashMap::new();
+                for pair in pairs(entries)? {
+                    members.insert(pair.0, parse_double(&pair.1)?);
+                }
+                Ok(Value::zset(members))
+            }
+            TYPE_HASH_ZIPLIST | TYPE_HASH_LISTPACK => {
+                let blob = self.read_string()?;
+                let entries = if value_type == TYPE_HASH_ZIPLIST {
+                    ziplist_entries(&blob)?
+                } else {
+                    listpack_entries(&blob)?
+                };
+                Ok(Value::hash(pairs(entries)?.into_iter().collect()))
+            }
+            TYPE_LIST_QUICKLIST | TYPE_LIST_QUICKLIST_2 => {
+                let nodes = self.read_length()?;
+                let mut items = VecDeque::new();
+                for _ in 0..nodes {
+                    let container = if value_type == TYPE_LIST_QUICKLIST_2 {
+                        self.read_length()?
+                    } else {
+                        QUICKLIST_NODE_PACKED
+                    };
+                    let blob = self.read_string()?;
+                    match container {
+                        QUICKLIST_NODE_PLAIN => items.push_back(blob),
+                        QUICKLIST_NODE_PACKED if value_type == TYPE_LIST_QUICKLIST => {
+                            items.extend(ziplist_entries(&blob)?)
+                        }
+                        QUICKLIST_NODE_PACKED => items.extend(listpack_entries(&blob)?),
+                        other => return Err(invalid(format!("unknown quicklist container {}", other))),
+                    }
+                }
+                Ok(Value::list(items))
+            }
+            TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => {
+                Err(invalid("stream values are not supported"))
+            }
+            other => Err(invalid(format!("unsupported value type {} at offset {}", other, self.offset - 1))),
+        }
+    }
+
+    /// Reads a complete RDB file, handing each key to `on_record`, and returns its aux fields.
+    ///
+    /// Stream values have no counterpart here and are skipped with a warning,
+    /// as are function libraries.
+    pub fn read_file(&mut self, mut on_record: impl FnMut(Record) -> io::Result<()>) -> io::Result<Vec<(Bytes, Bytes)>> {
+        let mut header = [0u8; 9];
+        self.read_exact(&mut header)?;
+        if !header.starts_with(MAGIC) {
+            return Err(invalid("not an RDB file: bad magic"));
+        }
+        let version = std::str::from_utf8(&header[MAGIC.len()..])
+            .ok()
+            .and_then(|digits| digits.parse::<u16>().ok())
+            .ok_or_else(|| invalid("not an RDB file: bad version"))?;
+        if version == 0 || version > MAX_VERSION {
+            return Err(invalid(format!("unsupported RDB version {}", version)));
+        }
+
+        let mut aux = Vec::new();
+        let mut db = 0;
+        let mut expire_at_ms = None;
+
+        loop {
+            let opcode = self.read_u8()?;
+            match opcode {
+                OPCODE_EOF => break,
+                OPCODE_SELECTDB => db = self.read_length()? as usize,
+                OPCODE_RESIZEDB => {
+                    self.read_length()?;
+                    self.read_length()?;
+                }
+                OPCODE_AUX => aux.push((self.read_string()?, self.read_string()?)),
+                OPCODE_EXPIRETIME_MS => {
+                    let mut buf = [0u8; 8];
+                    self.read_exact(&mut buf)?;
+                    expire_at_ms = Some(u64::from_le_bytes(buf));
+                }
+                OPCODE_EXPIRETIME => {
+                    let mut buf = [0u8; 4];
+                    self.read_exact(&mut buf)?;
+                    expire_at_ms = Some(u32::from_le_bytes(buf) as u64 * 1000);
+                }
+                OPCODE_IDLE => {
+                    self.read_length()?;
+                }
+                OPCODE_FREQ => {
+                    self.read_u8()?;
+                }
+                OPCODE_SLOT_INFO => {
+                    // slot, keys in the slot, keys with a ttl in the slot
+                    for _ in 0..3 {
+                        self.read_length()?;
+                    }
+                }
+                OPCODE_FUNCTION2 => {
+                    self.read_string()?;
+                    warn!("skipping function library at offset {}", self.offset);
+                }
+                OPCODE_FUNCTION | OPCODE_MODULE_AUX => {
+                    return Err(invalid(format!("unsupported opcode {:#x} at offset {}", opcode, self.offset - 1)));
+                }
+                TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => {
+                    let key = self.read_string()?;
+                    self.skip_stream(opcode)?;
+                    expire_at_ms = None;
+                    warn!("skipping stream {:?} of db {}, streams are not supported", String::from_utf8_lossy(&key), db);
+                }
+                value_type => {
+                    let offset = self.offset - 1;
+                    let key = self
+                        .read_string()
+                        .map_err(|e| invalid(format!("key at offset {}: {}", offset, e)))?;
+                    let value = self.read_object(value_type).map_err(|e| {
+                        invalid(format!("key {:?} of db {} at offset {}: {}", String::from_utf8_lossy(&key), db, offset, e))
+                    })?;
+                    on_record(Record { db, key, value, expire_at_ms: expire_at_ms.take() })?;
+                }
+            }
+        }
+
+        // files before version 5 have no checksum, and a zero one means checksums were disabled
+        if version >= 5 {
+            let computed = self.checksum();
+            let mut buf = [0u8; 8];
+            self.read_exact(&mut buf)?;
+            let stored = u64::from_le_bytes(buf);
+            if stored != 0 && stored != computed {
+                return Err(invalid(format!(
+                    "RDB checksum mismatch: file has {:016x}, contents give {:016x}",
+                    stored, computed
+                )));
+            }
+        }
+
+        Ok(aux)
+    }
+
+    /// Reads past a stream object without decoding it.
+    fn skip_stream(&mut self, value_type: u8) -> io::Result<()> {
+        let nodes = self.read_length()?;
+        for _ in 0..nodes {
+            // master entry id, then the listpack of entries
+            self.read_string()?;
+            self.read_string()?;
+        }
+
+        // length and last id, then first id, max deleted id and entries added since version 2
+        let fields = if value_type == TYPE_STREAM_LISTPACKS { 3 } else { 8 };
+        for _ in 0..fields {
+            self.read_length()?;
+        }
+
+        let groups = self.read_length()?;
+        for _ in 0..groups {
+            self.read_string()?;
+            // last delivered id, and entries read since version 2
+            let fields = if value_type == TYPE_STREAM_LISTPACKS { 2 } else { 3 };
+            for _ in 0..fields {
+                self.read_length()?;
+            }
+
+            let pending = self.read_length()?;
+            for _ in 0..pending {
+                // entry id and delivery time, then delivery count
+                self.read_bytes(16 + 8)?;
+                self.read_length()?;
+            }
+
+            let consumers = self.read_length()?;
+            for _ in 0..consumers {
+                self.read_string()?;
+                // seen time, and active time since version 3
+                self.read_bytes(if value_type == TYPE_STREAM_LISTPACKS_3 { 16 } else { 8 })?;
+                let pending = self.read_length()?;
+                for _ in 0..pending {
+                    self.read_bytes(16)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A key read from an RDB file.
+pub struct Record {
+    pub db: usize,
+    pub key: Bytes,
+    pub value: Value,
+    /// unix time in milliseconds
+    pub expire_at_ms: Option<u64>,
+}
+
+fn pairs(entries: Vec<Bytes>) -> io::Result<Vec<(Bytes, Bytes)>> {
+    if !entries.len().is_multiple_of(2) {
+        return Err(invalid("packed encoding has an odd number of entries"));
+    }
+    let mut iter = entries.into_iter();
+    let mut out = Vec::new();
+    while let (Some(a), Some(b)) = (iter.next(), iter.next()) {
+        out.push((a, b));
+    }
+    Ok(out)
+}
+
+pub fn parse_double(bytes: &[u8]) -> io::Result<f64> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|s| s.parse::<f64>().ok())
+        .ok_or_else(|| invalid("invalid double value"))
+}
+
+/// The most LZF can expand its input: a three byte back reference copies 264 bytes.
+const LZF_MAX_RATIO: usize = 88;
+
+/// Decompresses LZF `input`, which must expand to exactly `expected_len`
+/// bytes. The length comes from the file, so it is checked against what
+/// the input could expand to before anything is allocated for it.
+pub fn lzf_decompress(input: &[u8], expected_len: usize) -> io::Result<Vec<u8>> {
+    if expected_len > input.len().saturating_mul(LZF_MAX_RATIO) {
+        return Err(invalid("LZF data does not match its declared length"));
+    }
+    let mut out = Vec::with_capacity(expected_len);
+    let mut ip = 0;
+
+    while ip < input.len() {
+        let ctrl = input[ip] as usize;
+        ip += 1;
+
+        if ctrl < 32 {
+            let run = ctrl + 1;
+            if ip + run > input.len() {
+                return Err(invalid("corrupt LZF data"));
+            }
+            if out.len() + run > expected_len {
+                return Err(invalid("LZF data does not match its declared length"));
+            }
+            out.extend_from_slice(&input[ip..ip + run]);
+            ip += run;
+        } else {
+            let mut len = ctrl >> 5;
+            if len == 7 {
+                len += *input.get(ip).ok_or_else(|| invalid("corrupt LZF data"))? as usize;
+                ip += 1;
+            }
+            let low = *input.get(ip).ok_or_else(|| invalid("corrupt LZF data"))? as usize;
+            ip += 1;
+
+            let back = ((ctrl & 0x1f) << 8) + low + 1;
+            if back > out.len() {
+                return Err(invalid("corrupt LZF data"));
+            }
+            if out.len() + len + 2 > expected_len {
+                return Err(invalid("LZF data does not match its declared length"));
+            }
+            let start = out.len() - back;
+            for i in 0..len + 2 {
+                let byte = out[start + i];
+                out.push(byte);
+            }
+        }
+    }
+
+    if out.len() != expected_len {
+        return Err(invalid("LZF data does not match its declared length"));
+    }
+    Ok(out)
+}
+
+fn read_le(buf: &[u8], pos: usize, len: usize) -> io::Result<u64> {
+    let bytes = buf.get(pos..pos + len).ok_or_else(|| invalid("truncated packed encoding"))?;
+    let mut value = 0u64;
+    for (i, byte) in bytes.iter().enumerate() {
+        value |= (*byte as u64) << (8 * i);
+    }
+    Ok(value)
+}
+
+fn sign_extend(value: u64, bits: u32) -> i64 {
+    let shift = 64 - bits;
+    ((value << shift) as i64) >> shift
+}
+
+pub fn ziplist_entries(buf: &[u8]) -> io::Result<Vec<Bytes>> {
+    let mut pos = 10;
+    let mut entries = Vec::new();
+
+    loop {
+        let prevlen = *buf.get(pos).ok_or_else(|| invalid("truncated ziplist"))?;
+        if prevlen == 0xff {
+            break;
+        }
+        pos += if prevlen == 0xfe { 5 } else { 1 };
+
+        let encoding = *buf.get(pos).ok_or_else(|| invalid("truncated ziplist"))?;
+        let entry = match encoding >> 6 {
+            0 => {
+                let len = (encoding & 0x3f) as usize;
+                pos += 1;
+                let data = buf.get(pos..pos + len).ok_or_else(|| invalid("truncated ziplist"))?;
+                pos += len;
+                Bytes::copy_from_slice(data)
+            }
+            1 => {
+                let next = *buf.get(pos + 1).ok_or_else(|| invalid("truncated ziplist"))?;
+                let len = (((encoding & 0x3f) as usize) << 8) | next as usize;
+                pos += 2;
+                let data = buf.get(pos..pos + len).ok_or_else(|| invalid("truncated ziplist"))?;
+                pos += len;
+                Bytes::copy_from_slice(data)
+            }
+            2 => {
+                let raw = buf.get(pos + 1..pos + 5).ok_or_else(|| invalid("truncated ziplist"))?;
+                let len = u32::from_be_bytes(raw.try_into().expect("4 bytes")) as usize;
+                pos += 5;
+                let data = buf.get(pos..pos + len).ok_or_else(|| invalid("truncated ziplist"))?;
+                pos += len;
+                Bytes::copy_from_slice(data)
+            }
+            _ => {
+                pos += 1;
+                let n = match encoding {
+                    0xc0 => {
+                        let v = sign_extend(read_le(buf, pos, 2)?, 16);
+                        pos += 2;
+                        v
+                    }
+                    0xd0 => {
+                        let v = sign_extend(read_le(buf, pos, 4)?, 32);
+                        pos += 4;
+                        v
+                    }
+                    0xe0 => {
+                        let v = read_le(buf, pos, 8)? as i64;
+                        pos += 8;
+                        v
+                    }
+                    0xf0 => {
+                        let v = sign_extend(read_le(buf, pos, 3)?, 24);
+                        pos += 3;
+                        v
+                    }
+                    0xfe => {
+                        let v = sign_extend(read_le(buf, pos, 1)?, 8);
+                        pos += 1;
+                        v
+                    }
+                    0xf1..=0xfd => (encoding & 0x0f) as i64 - 1,
+                    other => return Err(invalid(format!("unknown ziplist encoding {:#x}", other))),
+                };
+                Bytes::from(n.to_string())
+            }
+        };
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+pub fn listpack_entries(buf: &[u8]) -> io::Result<Vec<Bytes>> {
+    let mut pos = 6;
+    let mut entries = Vec::new();
+
+    loop {
+        let encoding = *buf.get(pos).ok_or_else(|| invalid("truncated listpack"))?;
+        if encoding == 0xff {
+            break;
+        }
+        let start = pos;
+
+        let entry = if encoding & 0x80 == 0 {
+            pos += 1;
+            Bytes::from((encoding & 0x7f).to_string())
+        } else if encoding & 0xc0 == 0x80 {
+            let len = (encoding & 0x3f) as usize;
+            pos += 1;
+            let data = buf.get(pos..pos + len).ok_or_else(|| invalid("truncated listpack"))?;
+            pos += len;
+            Bytes::copy_from_slice(data)
+        } else if encoding & 0xe0 == 0xc0 {
+            let value = (((encoding & 0x1f) as u64) << 8) | read_le(buf, pos + 1, 1)?;
+            pos += 2;
+            Bytes::from(sign_extend(value, 13).to_string())
+        } else if encoding & 0xf0 == 0xe0 {
+            let len = (((encoding & 0x0f) as usize) << 8) | read_le(buf, pos + 1, 1)? as usize;
+            pos += 2;
+            let data = buf.get(pos..pos + len).ok_or_else(|| invalid("truncated listpack"))?;
+            pos += len;
+            Bytes::copy_from_slice(data)
+        } else {
+            let (n, width) = match encoding {
+                0xf0 => {
+                    let len = read_le(buf, pos + 1, 4)? as usize;
+                    pos += 5;
+                    let data = buf.get(pos..pos + len).ok_or_else(|| invalid("truncated listpack"))?;
+                    pos += len;
+                    entries.push(Bytes::copy_from_slice(data));
+                    pos += backlen_size(pos - start);
+                    continue;
+                }
+                0xf1 => (read_le(buf, pos + 1, 2)?, 2),
+                0xf2 => (read_le(buf, pos + 1, 3)?, 3),
+                0xf3 => (read_le(buf, pos + 1, 4)?, 4),
+                0xf4 => (read_le(buf, pos + 1, 8)?, 8),
+                other => return Err(invalid(format!("unknown listpack encoding {:#x}", other))),
+            };
+            pos += 1 + width;
+            Bytes::from(sign_extend(n, 8 * width as u32).to_string())
+        };
+
+        pos += backlen_size(pos - start);
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+fn backlen_size(entry_len: usize) -> usize {
+    match entry_len {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    }
+}
+
+pub fn intset_entries(buf: &[u8]) -> io::Result<Vec<i64>> {
+    let width = read_le(buf, 0, 4)? as usize;
+    let len = read_le(buf, 4, 4)? as usize;
+    if !matches!(width, 2 | 4 | 8) {
+        return Err(invalid(format!("invalid intset encoding {}", width)));
+    }
+
+    (0..len)
+        .map(|i| Ok(sign_extend(read_le(buf, 8 + i * width, width)?, 8 * width as u32)))
+        .collect()
+}
+
+pub fn zipmap_entries(buf: &[u8]) -> io::Result<Vec<(Bytes, Bytes)>> {
+    let mut pos = 1;
+    let mut entries = Vec::new();
+
+    let read_len = |pos: &mut usize| -> io::Result<Option<usize>> {
+        let first = *buf.get(*pos).ok_or_else(|| invalid("truncated zipmap"))?;
+        match first {
+            0xff => Ok(None),
+            0xfe => {
+                let raw = buf.get(*pos + 1..*pos + 5).ok_or_else(|| invalid("truncated zipmap"))?;
+                *pos += 5;
+                Ok(Some(u32::from_le_bytes(raw.try_into().expect("4 bytes")) as usize))
+            }
+            len => {
+                *pos += 1;
+                Ok(Some(len as usize))
+            }
+        }
+    };
+
+    while let Some(key_len) = read_len(&mut pos)? {
+        let key = buf.get(pos..pos + key_len).ok_or_else(|| invalid("truncated zipmap"))?;
+        pos += key_len;
+
+        let value_len = read_len(&mut pos)?.ok_or_else(|| invalid("truncated zipmap"))?;
+        let free = *buf.get(pos).ok_or_else(|| invalid("truncated zipmap"))? as usize;
+        pos += 1;
+        let value = buf.get(pos..pos + value_len).ok_or_else(|| invalid("truncated zipmap"))?;
+        pos += value_len + free;
+
+        entries.push((Bytes::copy_from_slice(key), Bytes::copy_from_slice(value)));
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::Listpack;
+
+    #[test]
+    fn lzf_literals_and_back_references() {
+        // "abc", then 3 + 2 bytes from 3 back, then 7 + 10 + 2 bytes from 1 back
+        let input = [2, b'a', b'b', b'c', 0x60, 2, 0xe0, 10, 0];
+        let mut expected = b"abcabcab".to_vec();
+        expected.extend_from_slice(&[b'b'; 19]);
+        assert_eq!(lzf_decompress(&input, expected.len()).unwrap(), expected);
+    }
+
+    #[test]
+    fn lzf_rejects_wrong_lengths() {
+        let input = [2, b'a', b'b', b'c', 0x60, 2];
+        assert!(lzf_decompress(&input, 7).is_err());
+        assert!(lzf_decompress(&input, 9).is_err());
+        assert!(lzf_decompress(&input[..5], 3).is_err());
+    }
+
+    #[test]
+    fn lzf_rejects_back_references_before_the_start() {
+        assert!(lzf_decompress(&[0x20, 0], 2).is_err());
+    }
+
+    #[test]
+    fn lzf_length_beyond_any_expansion_is_rejected_up_front() {
+        // this would otherwise reserve the declared length
+        assert!(lzf_decompress(&[0, b'a'], usize::MAX).is_err());
+        assert!(lzf_decompress(&[], 1).is_err());
+    }
+
+    fn bytes(items: &[&str]) -> Vec<Bytes> {
+        items.iter().map(|item| Bytes::copy_from_slice(item.as_bytes())).collect()
+    }
+
+    fn every_prefix_fails<T: std::fmt::Debug>(blob: &[u8], parse: fn(&[u8]) -> io::Result<T>) {
+        for end in 0..blob.len() {
+            assert!(parse(&blob[..end]).is_err(), "{} of {} bytes parsed", end, blob.len());
+        }
+    }
+
+    #[test]
+    fn dump_and_restore_every_type() {
+        let values = [
+            Value::string("hello".into()),
+            Value::string("-12345678901".into()),
+            Value::string(Bytes::from(vec![b'x'; 20_000])),
+            Value::list(bytes(&["a", "", "3"]).into()),
+            Value::set(bytes(&["x", "y", "10"]).into_iter().collect()),
+            Value::zset([(Bytes::from("m"), 1.5), (Bytes::from("n"), f64::NEG_INFINITY)].into_iter().collect()),
+            Value::hash([(Bytes::from("f"), Bytes::from("v")), (Bytes::from("g"), Bytes::from("7"))].into_iter().collect()),
+        ];
+        for value in values {
+            assert_eq!(restore(&dump(&value)).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn restore_rejects_corrupt_payloads() {
+        let payload = dump(&Value::list(bytes(&["a", "b"]).into()));
+        for i in 0..payload.len() {
+            let mut corrupt = payload.clone();
+            corrupt[i] ^= 0x20;
+            assert!(restore(&corrupt).is_err(), "byte {} flipped", i);
+        }
+        assert!(restore(&payload[..9]).is_err());
+
+        // a newer version than supported
+        let mut body = payload[..payload.len() - 10].to_vec();
+        body.extend_from_slice(&(MAX_VERSION + 1).to_le_bytes());
+        body.extend_from_slice(&crc64::checksum(&body).to_le_bytes());
+        assert!(restore(&body).is_err());
+    }
+
+    #[test]
+    fn restore_rejects_trailing_bytes() {
+        let mut body = Vec::new();
+        write_value(&Value::string("a".into()), &mut body);
+        body.push(0);
+        body.extend_from_slice(&WRITE_VERSION.to_le_bytes());
+        body.extend_from_slice(&crc64::checksum(&body).to_le_bytes());
+        assert!(restore(&body).is_err());
+    }
+
+    #[test]
+    fn restore_survives_mutated_bodies() {
+        let mut seeds: Vec<Vec<u8>> = [
+            Value::list(bytes(&["a", "bb", "300"]).into()),
+            Value::zset([(Bytes::from("m"), 2.0)].into_iter().collect()),
+            Value::hash([(Bytes::from("f"), Bytes::from("v"))].into_iter().collect()),
+        ]
+        .iter()
+        .map(|value| {
+            let payload = dump(value);
+            payload[..payload.len() - 10].to_vec()
+        })
+        .collect();
+        let mut listpack = vec![TYPE_HASH_LISTPACK];
+        write_string(&[0, 0, 0, 0, 0, 0, 0x81, b'f', 2, 0x01, 1, 0xff], &mut listpack);
+        seeds.push(listpack);
+
+        // a fixed xorshift sequence, so failures reproduce
+        let mut state = 0x2545f4914f6cdd1du64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for _ in 0..20_000 {
+            let mut body = seeds[next() as usize % seeds.len()].clone();
+            for _ in 0..1 + next() % 3 {
+                let at = next() as usize % body.len();
+                body[at] = next() as u8;
+            }
+            body.extend_from_slice(&WRITE_VERSION.to_le_bytes());
+            body.extend_from_slice(&crc64::checksum(&body).to_le_bytes());
+            let _ = restore(&body);
+        }
+    }
+
+    #[test]
+    fn ziplist_strings_and_integers() {
+        let mut blob = vec![0; 10];
+        blob.extend_from_slice(&[0, 0x02, b'a', b'b']);
+        blob.extend_from_slice(&[4, 0xfd]);
+        blob.extend_from_slice(&[2, 0xfe, 0xfb]);
+        blob.extend_from_slice(&[3, 0xc0, 0xe8, 0x03]);
+        blob.extend_from_slice(&[4, 0xf0, 0x60, 0x79, 0xfe]);
+        blob.extend_from_slice(&[5, 0xd0, 0x00, 0xca, 0x9a, 0x3b]);
+        blob.extend_from_slice(&[6, 0xe0]);
+        blob.extend_from_slice(&i64::MIN.to_le_bytes());
+        blob.extend_from_slice(&[10, 0x40, 0x40]);
+        blob.extend_from_slice(&[b'z'; 64]);
+        blob.push(0xff);
+
+        let mut expected = bytes(&["ab", "12", "-5", "1000", "-100000", "1000000000", "-9223372036854775808"]);
+        expected.push(Bytes::from(vec![b'z'; 64]));
+        assert_eq!(ziplist_entries(&blob).unwrap(), expected);
+        every_prefix_fails(&blob, ziplist_entries);
+    }
+
+    #[test]
+    fn ziplist_rejects_unknown_encodings() {
+        let mut blob = vec![0; 10];
+        blob.extend_from_slice(&[0, 0xc1, 0xff]);
+        assert!(ziplist_entries(&blob).is_err());
+    }
+
+    #[test]
+    fn listpack_entries_of_every_width() {
+        let long = "l".repeat(5000);
+        let entries = [
+            "0", "127", "-4096", "4095", "-32768", "8388607", "-2147483648", "9223372036854775807", "", "short",
+            &"m".repeat(100), &long,
+        ];
+        // entries() decodes the listpack with listpack_entries
+        assert_eq!(Listpack::from_entries(entries).entries(), bytes(&entries));
+    }
+
+    #[test]
+    fn listpack_rejects_truncation_and_unknown_encodings() {
+        let mut blob = vec![0; 6];
+        blob.extend_from_slice(&[0x82, b'h', b'i', 3]);
+        blob.extend_from_slice(&[0xf1, 0x00, 0x80, 3]);
+        blob.extend_from_slice(&[0xe0, 0x02, b'x', b'y', 4]);
+        blob.push(0xff);
+        assert_eq!(listpack_entries(&blob).unwrap(), bytes(&["hi", "-32768", "xy"]));
+        every_prefix_fails(&blob, listpack_entries);
+
+        let mut unknown = vec![0; 6];
+        unknown.extend_from_slice(&[0xf5, 0xff]);
+        assert!(listpack_entries(&unknown).is_err());
+    }
+
+    #[test]
+    fn intset_entries_of_each_width() {
+        let mut blob = vec![4, 0, 0, 0, 2, 0, 0, 0];
+        blob.extend_from_slice(&(-70_000i32).to_le_bytes());
+        blob.extend_from_slice(&3i32.to_le_bytes());
+        assert_eq!(intset_entries(&blob).unwrap(), vec![-70_000, 3]);
+        every_prefix_fails(&blob, intset_entries);
+
+        assert!(intset_entries(&[3, 0, 0, 0, 0, 0, 0, 0]).is_err());
+        // a count far beyond the contents fails without reserving it
+        assert!(intset_entries(&[8, 0, 0, 0, 0xff, 0xff, 0xff, 0xff]).is_err());
+    }
+
+    #[test]
+    fn zipmap_entries_skip_free_space() {
+        let mut blob = vec![2, 1, b'a', 2, 0, b'x', b'y', 1, b'b', 1, 2, b'z', 0, 0, 0xfe];
+        blob.extend_from_slice(&3u32.to_le_bytes());
+        blob.extend_from_slice(b"key");
+        blob.extend_from_slice(&[0, 0, 0xff]);
+        assert_eq!(
+            zipmap_entries(&blob).unwrap(),
+            vec![
+                (Bytes::from("a"), Bytes::from("xy")),
+                (Bytes::from("b"), Bytes::from("z")),
+                (Bytes::from("key"), Bytes::new()),
+            ]
+        );
+        every_prefix_fails(&blob, zipmap_entries);
+    }
+
+    #[test]
+    fn packed_pairs_need_an_even_count() {
+        let mut blob = vec![0; 6];
+        blob.extend_from_slice(&[0x81, b'f', 2, 0xff]);
+        let mut payload = vec![TYPE_HASH_LISTPACK];
+        write_string(&blob, &mut payload);
+        payload.extend_from_slice(&WRITE_VERSION.to_le_bytes());
+        payload.extend_from_slice(&crc64::checksum(&payload).to_le_bytes());
+        assert!(restore(&payload).is_err());
+    }
+
+    fn rdb_file(records: &[(usize, &str, Value, Option<u64>)]) -> Vec<u8> {
+        let mut file = Vec::new();
+        write_header(&mut file);
+        write_aux("redis-ver", REDIS_VERSION.as_bytes(), &mut file);
+        let mut db = None;
+        for (index, key, value, expire_at_ms) in records {
+            if db != Some(*index) {
+                write_select_db(*index, 1, 0, &mut file);
+                db = Some(*index);
+            }
+            write_record(key.as_bytes(), value, *expire_at_ms, &mut file);
+        }
+        write_eof(&mut file);
+        let crc = crc64::checksum(&file);
+        file.extend_from_slice(&crc.to_le_bytes());
+        file
+    }
+
+    /// aux fields as read_file returns them
+    type Aux = Vec<(Bytes, Bytes)>;
+
+    fn read(file: &[u8]) -> io::Result<(Vec<Record>, Aux)> {
+        let mut records = Vec::new();
+        let aux = RdbReader::new(file).read_file(|record| {
+            records.push(record);
+            Ok(())
+        })?;
+        Ok((records, aux))
+    }
+
+    #[test]
+    fn rdb_file_round_trip() {
+        let file = rdb_file(&[
+            (0, "a", Value::string("1".into()), None),
+            (0, "b", Value::list(bytes(&["x"]).into()), Some(4_102_444_800_000)),
+            (3, "c", Value::set(bytes(&["y"]).into_iter().collect()), None),
+        ]);
+        let (records, aux) = read(&file).unwrap();
+
+        assert_eq!(aux, vec![(Bytes::from("redis-ver"), Bytes::from(REDIS_VERSION))]);
+        let keys: Vec<_> = records.iter().map(|r| (r.db, r.key.clone(), r.expire_at_ms)).collect();
+        assert_eq!(
+            keys,
+            vec![(0, Bytes::from("a"), None), (0, Bytes::from("b"), Some(4_102_444_800_000)), (3, Bytes::from("c"), None)]
+        );
+        assert_eq!(records[1].value, Value::list(bytes(&["x"]).into()));
+    }
+
+    #[test]
+    fn rdb_file_checksum() {
+        let file = rdb_file(&[(0, "a", Value::string("1".into()), None)]);
+        let mut corrupt = file.clone();
+        let at = corrupt.len() - 12;
+        corrupt[at] ^= 1;
+        assert!(matches!(read(&corrupt), Err(e) if e.to_string().contains("checksum mismatch")));
+
+        // a zero checksum means checksums were disabled
+        let mut unchecked = file.clone();
+        let len = unchecked.len();
+        unchecked[len - 8..].fill(0);
+        assert!(read(&unchecked).is_ok());
+    }
+
+    #[test]
+    fn rdb_file_header_is_checked() {
+        let file = rdb_file(&[]);
+        let mut magic = file.clone();
+        magic[0] = b'X';
+        assert!(read(&magic).is_err());
+        for version in [b"0000", b"9999"] {
+            let mut other = file.clone();
+            other[5..9].copy_from_slice(version);
+            assert!(read(&other).is_err());
+        }
+    }
+
+    #[test]
+    fn truncated_rdb_file_is_rejected() {
+        let file = rdb_file(&[(0, "a", Value::hash([(Bytes::from("f"), Bytes::from("v"))].into_iter().collect()), None)]);
+        for end in 0..file.len() {
+            assert!(read(&file[..end]).is_err(), "{} of {} bytes read", end, file.len());
+        }
+    }
+
+    #[test]
+    fn rdb_file_skips_streams() {
+        let mut file = Vec::new();
+        write_header(&mut file);
+        file.push(TYPE_STREAM_LISTPACKS);
+        write_string(b"stream", &mut file);
+        // no nodes; length, last id ms and seq; no groups
+        file.extend_from_slice(&[0, 0, 0, 0, 0]);
+        write_record(b"a", &Value::string("1".into()), None, &mut file);
+        write_eof(&mut file);
+        let crc = crc64::checksum(&file);
+        file.extend_from_slice(&crc.to_le_bytes());
+
+        let (records, _) = read(&file).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].key, Bytes::from("a"));
+    }
+}
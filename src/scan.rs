@@ -0,0 +1,376 @@
+use std::collections::BTreeSet;
+use std::ops::Bound;
+use std::sync::Mutex;
+
+use bytes::Bytes;
+use dashmap::DashMap;
+
+use crate::evict;
+
+pub const DEFAULT_COUNT: usize = 10;
+
+/// Matches `string` against a redis-style glob pattern.
+///
+/// Supports `*`, `?`, `[abc]`, `[^abc]`, `[a-z]` and `\` escapes.
+pub fn glob_match(pattern: &[u8], string: &[u8], nocase: bool) -> bool {
+    let eq = |a: u8, b: u8| {
+        if nocase {
+            a.eq_ignore_ascii_case(&b)
+        } else {
+            a == b
+        }
+    };
+
+    let mut p = 0;
+    let mut s = 0;
+
+    while p < pattern.len() {
+        match pattern[p] {
+            b'*' => {
+                while p + 1 < pattern.len() && pattern[p + 1] == b'*' {
+                    p += 1;
+                }
+                if p + 1 == pattern.len() {
+                    return true;
+                }
+                for start in s..=string.len() {
+                    if glob_match(&pattern[p + 1..], &string[start..], nocase) {
+                        return true;
+                    }
+                }
+                return false;
+            }
+            b'?' => {
+                if s >= string.len() {
+                    return false;
+                }
+                s += 1;
+            }
+            b'[' => {
+                if s >= string.len() {
+                    return false;
+                }
+                p += 1;
+                let negate = p < pattern.len() && pattern[p] == b'^';
+                if negate {
+                    p += 1;
+                }
+
+                let mut matched = false;
+                loop {
+                    if p >= pattern.len() {
+                        p -= 1;
+                        break;
+                    }
+                    if pattern[p] == b'\\' && p + 1 < pattern.len() {
+                        p += 1;
+                        if eq(pattern[p], string[s]) {
+                            matched = true;
+                        }
+                    } else if pattern[p] == b']' {
+                        break;
+                    } else if p + 2 < pattern.len() && pattern[p + 1] == b'-' {
+                        let (mut lo, mut hi) = (pattern[p], pattern[p + 2]);
+                        if lo > hi {
+                            std::mem::swap(&mut lo, &mut hi);
+                        }
+                        let c = string[s];
+                        let in_range = if nocase {
+                            let c = c.to_ascii_lowercase();
+                            c >= lo.to_ascii_lowercase() && c <= hi.to_ascii_lowercase()
+                        } else {
+                            c >= lo && c <= hi
+                        };
+                        if in_range {
+                            matched = true;
+                        }
+                        p += 2;
+                    } else if eq(pattern[p], string[s]) {
+                        matched = true;
+                    }
+                    p += 1;
+                }
+
+                if matched == negate {
+                    return false;
+                }
+                s += 1;
+            }
+            b'\\' if p + 1 < pattern.len() => {
+                p += 1;
+                if s >= string.len() || !eq(pattern[p], string[s]) {
+                    return false;
+                }
+                s += 1;
+            }
+            c => {
+                if s >= string.len() || !eq(c, string[s]) {
+                    return false;
+                }
+                s += 1;
+            }
+        }
+        p += 1;
+    }
+
+    s == string.len()
+}
+
+/// The keys of a sharded map in a fixed order of their own, so that SCAN
+/// resumes where the previous call stopped instead of walking a whole shard.
+///
+/// Keys are sorted by a position taken from their hash, one set per shard of
+/// the map, and a call does O(COUNT) work on top of a lookup in one set. A
+/// key's position never changes however the map grows or shrinks, so keys
+/// present for the whole iteration are returned at least once. The cursor
+/// packs the shard index into its high bits and the position into the low
+/// bits.
+///
+/// Keys must be added and removed while the map's lock for their shard is
+/// held, so that the index agrees with the map once a write is done.
+pub struct ScanIndex {
+    shards: Vec<Mutex<BTreeSet<(u64, Bytes)>>>,
+    shard_bits: u32,
+}
+
+impl ScanIndex {
+    pub fn new<V>(map: &DashMap<Bytes, V>) -> ScanIndex {
+        let shards = map.shards().len();
+        ScanIndex {
+            shards: (0..shards).map(|_| Mutex::new(BTreeSet::new())).collect(),
+            shard_bits: shards.trailing_zeros(),
+        }
+    }
+
+    fn hash_bits(&self) -> u32 {
+        64 - self.shard_bits
+    }
+
+    fn hash_mask(&self) -> u64 {
+        u64::MAX >> self.shard_bits
+    }
+
+    fn encode(&self, shard: usize, position: u64) -> u64 {
+        if self.shard_bits == 0 { position } else { ((shard as u64) << self.hash_bits()) | position }
+    }
+
+    /// The set and position of `key`, which lives in the same shard as in `map`.
+    fn locate<V>(&self, map: &DashMap<Bytes, V>, key: &Bytes) -> (&Mutex<BTreeSet<(u64, Bytes)>>, u64) {
+        let position = (map.hash_usize(key) as u64) & self.hash_mask();
+        (&self.shards[map.determine_map(key)], position)
+    }
+
+    pub fn insert<V>(&self, map: &DashMap<Bytes, V>, key: Bytes) {
+        let (set, position) = self.locate(map, &key);
+        set.lock().unwrap().insert((position, key));
+    }
+
+    pub fn remove<V>(&self, map: &DashMap<Bytes, V>, key: &Bytes) {
+        let (set, position) = self.locate(map, key);
+        set.lock().unwrap().remove(&(position, key.clone()));
+    }
+
+    /// Picks up to `count` keys in a row from a random position of a random
+    /// shard, wrapping around into the next shards, as redis samples
+    /// eviction candidates from consecutive buckets. A map with no more than
+    /// `count` keys is sampled whole.
+    pub fn random_keys(&self, count: usize) -> Vec<Bytes> {
+        let start = evict::random_u64() as usize % self.shards.len();
+        let from = (evict::random_u64() & self.hash_mask(), Bytes::new());
+        let mut keys = Vec::with_capacity(count);
+
+        // the first shard from the random position on, the other shards whole
+        // and finally the first shard up to the random position
+        for i in 0..=self.shards.len() {
+            if keys.len() >= count {
+                break;
+            }
+            let range = match i {
+                0 => (Bound::Included(&from), Bound::Unbounded),
+                i if i == self.shards.len() => (Bound::Unbounded, Bound::Excluded(&from)),
+                _ => (Bound::Unbounded, Bound::Unbounded),
+            };
+            let set = self.shards[(start + i) % self.shards.len()].lock().unwrap();
+            keys.extend(set.range::<(u64, Bytes), _>(range).take(count - keys.len()).map(|(_, key)| key.clone()));
+        }
+
+        keys
+    }
+
+    /// Runs one SCAN step, returning the next cursor (0 when the iteration is
+    /// complete) and the keys visited.
+    ///
+    /// Keys sharing a position are returned together so that a cursor never
+    /// lands in the middle of them, which may return a few more than `count`.
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<Bytes>) {
+        let count = count.max(1);
+        let mut shard = if self.shard_bits == 0 { 0 } else { (cursor >> self.hash_bits()) as usize };
+        let mut from = cursor & self.hash_mask();
+        let mut keys = Vec::new();
+
+        while shard < self.shards.len() && keys.len() < count {
+            let set = self.shards[shard].lock().unwrap();
+            let mut last = None;
+            for (position, key) in set.range((from, Bytes::new())..) {
+                if keys.len() >= count && last != Some(*position) {
+                    return (self.encode(shard, *position), keys);
+                }
+                last = Some(*position);
+                keys.push(key.clone());
+            }
+            drop(set);
+
+            shard += 1;
+            from = 0;
+        }
+
+        if shard >= self.shards.len() {
+            (0, keys)
+        } else {
+            (self.encode(shard, 0), keys)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn glob_wildcards_and_classes() {
+        let cases: [(&str, &str, bool); 16] = [
+            ("h?llo", "hello", true),
+            ("h?llo", "hllo", false),
+            ("h*llo", "hllo", true),
+            ("h*llo", "heeeello", true),
+            ("h*llo", "hello!", false),
+            ("*", "", true),
+            ("**x", "abx", true),
+            ("h[ae]llo", "hallo", true),
+            ("h[ae]llo", "hillo", false),
+            ("h[^e]llo", "hallo", true),
+            ("h[^e]llo", "hello", false),
+            ("h[a-b]llo", "hbllo", true),
+            ("h[b-a]llo", "hallo", true),
+            ("h[a-b]llo", "hcllo", false),
+            ("h\\*llo", "h*llo", true),
+            ("h\\*llo", "hello", false),
+        ];
+        for (pattern, string, expected) in cases {
+            assert_eq!(glob_match(pattern.as_bytes(), string.as_bytes(), false), expected, "{} {}", pattern, string);
+        }
+    }
+
+    #[test]
+    fn glob_edge_cases() {
+        assert!(glob_match(b"[\\]]", b"]", false));
+        assert!(glob_match(b"a[bc", b"ab", false));
+        assert!(!glob_match(b"a[", b"a", false));
+        assert!(glob_match(b"\\", b"\\", false));
+        assert!(glob_match(b"HE[L-L]?O", b"hello", true));
+        assert!(!glob_match(b"HELLO", b"hello", false));
+    }
+
+    fn key(n: u32) -> Bytes {
+        Bytes::from(n.to_string())
+    }
+
+    #[test]
+    fn keys_sharing_a_position_are_returned_together() {
+        let map: DashMap<Bytes, ()> = DashMap::with_shard_amount(2);
+        let index = ScanIndex::new(&map);
+        let mut set = index.shards[0].lock().unwrap();
+        for (position, name) in [(1, "a"), (2, "b"), (2, "c"), (3, "d")] {
+            set.insert((position, Bytes::from(name)));
+        }
+        drop(set);
+
+        let (next, keys) = index.scan(0, 2);
+        assert_eq!(keys, ["a", "b", "c"]);
+        assert_eq!(next, 3);
+
+        let (next, keys) = index.scan(next, 2);
+        assert_eq!(keys, ["d"]);
+        assert_eq!(next, 0);
+    }
+
+    fn scan_all(index: &ScanIndex, count: usize, mut between: impl FnMut()) -> Vec<Bytes> {
+        let mut cursor = 0;
+        let mut keys = Vec::new();
+        loop {
+            let (next, batch) = index.scan(cursor, count);
+            keys.extend(batch);
+            if next == 0 {
+                return keys;
+            }
+            between();
+            cursor = next;
+        }
+    }
+
+    fn indexed(keys: impl Iterator<Item = u32>) -> (DashMap<Bytes, ()>, ScanIndex) {
+        let map = DashMap::new();
+        let index = ScanIndex::new(&map);
+        for n in keys {
+            map.insert(key(n), ());
+            index.insert(&map, key(n));
+        }
+        (map, index)
+    }
+
+    #[test]
+    fn scan_visits_every_key_once_when_nothing_changes() {
+        let (_, index) = indexed(0..1000);
+        let mut keys = scan_all(&index, 7, || {});
+        keys.sort_unstable();
+        let mut expected: Vec<Bytes> = (0..1000).map(key).collect();
+        expected.sort_unstable();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn scan_returns_at_most_count_keys_without_shared_positions() {
+        let (_, index) = indexed(0..1000);
+        let (mut cursor, mut calls) = (0, 0);
+        loop {
+            let (next, keys) = index.scan(cursor, 10);
+            assert!(keys.len() <= 10);
+            calls += 1;
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        assert!(calls >= 100);
+    }
+
+    #[test]
+    fn scan_returns_keys_present_throughout_while_the_map_grows() {
+        let (map, index) = indexed(0..100);
+        let mut next = 1000;
+        let keys: HashSet<Bytes> = scan_all(&index, 5, || {
+            // grow to well past the initial table, dropping some of the added keys again
+            if next < 5000 {
+                for _ in 0..200 {
+                    map.insert(key(next), ());
+                    index.insert(&map, key(next));
+                    next += 1;
+                }
+                map.remove(&key(next - 100));
+                index.remove(&map, &key(next - 100));
+            }
+        })
+        .into_iter()
+        .collect();
+        assert!((0..100).all(|n| keys.contains(&key(n))));
+        assert!(!keys.contains(&key(4900)));
+    }
+
+    #[test]
+    fn cursor_past_the_last_shard_ends_the_scan() {
+        let (_, index) = indexed(0..0);
+        assert_eq!(index.scan(0, 10), (0, Vec::new()));
+        assert_eq!(index.scan(u64::MAX, 10).0, 0);
+    }
+}
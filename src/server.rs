@@ -0,0 +1,700 @@
+use std::net::SocketAddr;
+
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tracing::{error, info, warn};
+
+use crate::analyze;
+use crate::aof;
+use crate::cmd::{self, Command};
+use crate::connection::Connection;
+use crate::db::{self, Databases};
+use crate::frame::Frame;
+use crate::memory;
+use crate::migrate::{self, MigrateOptions};
+use crate::notify::Events;
+use crate::persistence;
+use crate::pubsub::{self, Subscriber};
+use crate::rdb;
+use crate::sort;
+use crate::value::Value;
+
+/// Per-connection state.
+pub struct Session {
+    pub db: usize,
+}
+
+impl Session {
+    pub fn new() -> Session {
+        Session { db: 0 }
+    }
+}
+
+impl Default for Session {
+    fn default() -> Session {
+        Session::new()
+    }
+}
+
+pub async fn handle_connection(socket: TcpStream, peer_addr: SocketAddr, databases: Databases) {
+    info!("accepted connection from: {}", peer_addr);
+
+    let mut connection = Connection::new(socket);
+    let mut session = Session::new();
+    let mut subscriber = Subscriber::new();
+
+    'connection: loop {
+        let read = if subscriber.is_active() {
+            tokio::select! {
+                message = subscriber.recv() => {
+                    let written = match message {
+                        Some(message) => tokio::select! {
+                            written = connection.write_frame(&message) => Some(written),
+                            _ = subscriber.closed() => None,
+                        },
+                        None => None,
+                    };
+                    match written {
+                        Some(Ok(())) => continue,
+                        Some(Err(e)) => error!("failed to write message: {}", e),
+                        None => warn!("closing connection from {}: pub/sub client over its output buffer limit", peer_addr),
+                    }
+                    break;
+                }
+                read = connection.read_frame() => read,
+            }
+        } else {
+            connection.read_frame().await
+        };
+        let Ok(Some(frame)) = read else {
+            break;
+        };
+
+        info!("received frame: {:?}", frame);
+        let request = databases.aof().is_some().then(|| frame.clone());
+        let name = subscriber.is_active().then(|| cmd::name(&frame));
+
+        let responses = match cmd::from_frame(frame) {
+            Ok(command) if subscriber.is_active() && !command.allowed_when_subscribed() => vec![Frame::Error(format!(
+                "ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+                name.unwrap_or_default()
+            ))],
+            Ok(Command::Subscribe { channels }) => {
+                info!("parsed command: subscribe {:?}", channels);
+                subscriber.subscribe(databases.pub_sub(), channels)
+            }
+            Ok(Command::Unsubscribe { channels }) => subscriber.unsubscribe(channels).await,
+            Ok(Command::PSubscribe { patterns }) => {
+                info!("parsed command: psubscribe {:?}", patterns);
+                subscriber.psubscribe(databases.pub_sub(), patterns)
+            }
+            Ok(Command::PUnsubscribe { patterns }) => subscriber.punsubscribe(patterns).await,
+            Ok(Command::SSubscribe { channels }) => {
+                info!("parsed command: ssubscribe {:?}", channels);
+                subscriber.ssubscribe(databases.pub_sub(), channels)
+            }
+            Ok(Command::SUnsubscribe { channels }) => subscriber.sunsubscribe(channels).await,
+            Ok(Command::Ping { message }) if subscriber.is_active() => vec![pubsub::pong(message)],
+            Ok(Command::Reset) => {
+                subscriber.reset().await;
+                session = Session::new();
+                vec![Frame::Simple("RESET".to_string())]
+            }
+            Ok(Command::Quit) => {
+                let _ = connection.write_frame(&Frame::Simple("OK".to_string())).await;
+                break;
+            }
+            Ok(command) => {
+                info!("parsed command: {:?}", command);
+                let records = match &request {
+                    Some(request) => aof::records(&command, request),
+                    None => Vec::new(),
+                };
+                let db = session.db;
+
+                if !databases.free_memory_if_needed() && command.denies_on_oom() {
+                    vec![Frame::Error("OOM command not allowed when used memory > 'maxmemory'.".to_string())]
+                } else {
+                    // a rewrite of the append only file must see each logged write
+                    // either in the dataset it copies or in the new incremental file
+                    let _logged = if records.is_empty() { None } else { Some(databases.write_guard().await) };
+                    let response = execute(command, &databases, &mut session).await;
+                    if !records.is_empty() && !matches!(response, Frame::Error(_)) {
+                        databases.propagate(db, &records);
+                    }
+                    vec![response]
+                }
+            }
+            Err(e) => {
+                error!("parse error: {}", e);
+                vec![Frame::Error(format!("ERR {}", e))]
+            }
+        };
+
+        for response in &responses {
+            if let Err(e) = connection.write_frame(response).await {
+                error!("failed to write response: {}", e);
+                break 'connection;
+            }
+        }
+    }
+
+    info!("connection closed from: {}", peer_addr);
+}
+
+pub async fn execute(command: Command, databases: &Databases, session: &mut Session) -> Frame {
+    let db = databases.db(session.db);
+
+    match command {
+        Command::Ping { message: None } => Frame::Simple("PONG".to_string()),
+        Command::Ping { message: Some(message) } => Frame::Bulk(message),
+        Command::Set { key, value, expiry } => {
+            match expiry.map(|expiry| expiry.unix_millis().saturating_sub(db::unix_millis())) {
+                // a deadline in the past leaves nothing to store
+                Some(0) => {
+                    db.del(&key);
+                }
+                ttl => db.set(key, value, ttl.map(Duration::from_millis)),
+            }
+            Frame::Simple("OK".to_string())
+        }
+        Command::Get { key } => match db.get(&key) {
+            Ok(Some(value)) => Frame::Bulk(value),
+            Ok(None) => Frame::Null,
+            Err(e) => Frame::Error(e.to_string()),
+        },
+        Command::Del { key } => {
+            let deleted = db.del(&key);
+            Frame::Integer(if deleted { 1 } else { 0 })
+        }
+        Command::Keys { pattern } => {
+            let keys = db.keys(&pattern);
+            Frame::Array(keys.into_iter().map(Frame::Bulk).collect())
+        }
+        Command::Scan { cursor, pattern, count, key_type } => {
+            let (next, keys) = db.scan(cursor, pattern.as_deref(), count, key_type.as_deref());
+            Frame::Array(vec![
+                Frame::Bulk(next.to_string().into()),
+                Frame::Array(keys.into_iter().map(Frame::Bulk).collect()),
+            ])
+        }
+        Command::Publish { channel, message } => {
+            let num_receivers = db.publish(channel, message);
+            Frame::Integer(num_receivers as i64)
+        }
+        Command::SPublish { channel, message } => Frame::Integer(databases.pub_sub().spublish(channel, message) as i64),
+        Command::Save => match persistence::save_now(databases).await {
+            Ok(()) => Frame::Simple("OK".to_string()),
+            Err(e) => Frame::Error(format!("ERR {}", e)),
+        },
+        Command::BgSave { schedule } => match persistence::bgsave(databases, schedule) {
+            Ok(status) => Frame::Simple(status.to_string()),
+            Err(e) => Frame::Error(e.to_string()),
+        },
+        Command::LastSave => Frame::Integer(databases.save_state().last_save() as i64),
+        Command::BgRewriteAof => match databases.aof() {
+            Some(aof) => match aof.rewrite(databases) {
+                Ok(()) => Frame::Simple("Background append only file rewriting started".to_string()),
+                Err(e) => Frame::Error(e.to_string()),
+            },
+            None => Frame::Error("ERR append only file is disabled, enable appendonly to rewrite it".to_string()),
+        },
+        Command::Select { index } => match db_index(databases, index) {
+            Some(index) => {
+                session.db = index;
+                Frame::Simple("OK".to_string())
+            }
+            None => Frame::Error("ERR DB index is out of range".to_string()),
+        },
+        Command::Move { key, db: target } => {
+            let Some(target) = db_index(databases, target) else {
+                return Frame::Error("ERR DB index is out of range".to_string());
+            };
+            if target == session.db {
+                return Frame::Error("ERR source and destination objects are the same".to_string());
+            }
+            let moved = databases.move_key(&key, session.db, target);
+            Frame::Integer(if moved { 1 } else { 0 })
+        }
+        Command::SwapDb { first, second } => {
+            match (db_index(databases, first), db_index(databases, second)) {
+                (Some(first), Some(second)) => {
+                    databases.swap(first, second);
+                    Frame::Simple("OK".to_string())
+                }
+                _ => Frame::Error("ERR DB index is out of range".to_string()),
+            }
+        }
+        Command::DbSize => Frame::Integer(db.len() as i64),
+        Command::FlushDb { lazy } => {
+            databases.flush(session.db, lazy);
+            Frame::Simple("OK".to_string())
+        }
+        Command::FlushAll { lazy } => {
+            databases.flush_all(lazy);
+            Frame::Simple("OK".to_string())
+        }
+        Command::Dump { key } => match db.with_value(&key, rdb::dump) {
+            Some(payload) => Frame::Bulk(payload.into()),
+            None => Frame::Null,
+        },
+        Command::Restore { key, ttl_millis, payload, replace, absolute_ttl, idle_seconds, frequency } => {
+            if !replace && db.exists(&key) {
+                return Frame::Error("BUSYKEY Target key name already exists.".to_string());
+            }
+
+            let value = match rdb::restore(&payload) {
+                Ok(value) => value,
+                Err(e) => return Frame::Error(format!("ERR {}", e)),
+            };
+
+            let ttl = match (ttl_millis, absolute_ttl) {
+                (0, _) => None,
+                (deadline, true) => Some(deadline.saturating_sub(db::unix_millis())),
+                (ttl, false) => Some(ttl),
+            };
+
+            match ttl {
+                // already expired: restoring it would only produce a key that is immediately gone
+                Some(0) => {
+                    db.del(&key);
+                }
+                ttl => {
+                    let expiry = ttl.map(|ttl| Instant::now() + Duration::from_millis(ttl));
+                    db.insert(key.clone(), value, expiry);
+                    db.notify(Events::GENERIC, "restore", &key);
+                    db.with_entry(&key, |entry| {
+                        if let Some(seconds) = idle_seconds {
+                            entry.set_idle_seconds(seconds);
+                        }
+                        if let Some(counter) = frequency {
+                            entry.set_frequency(counter);
+                        }
+                    });
+                }
+            }
+            Frame::Simple("OK".to_string())
+        }
+        Command::Migrate { host, port, keys, db: target, timeout_millis, copy, replace, auth } => {
+            let options = MigrateOptions {
+                host,
+                port,
+                db: target,
+                timeout: Duration::from_millis(timeout_millis),
+                copy,
+                replace,
+                auth,
+            };
+            // the keys deleted are logged whatever the reply, since the target
+            // may have taken some of them before refusing the rest
+            let (response, deleted) = migrate::migrate(&db, keys, options).await;
+            if !deleted.is_empty() {
+                let records: Vec<Frame> =
+                    deleted.into_iter().map(|key| aof::command_frame(["DEL".into(), key])).collect();
+                databases.propagate(session.db, &records);
+            }
+            response
+        }
+        Command::Sort { key, options, store } => {
+            let rows = match sort::sort(&db, &key, &options) {
+                Ok(rows) => rows,
+                Err(e) => return Frame::Error(e.to_string()),
+            };
+            let values = rows.into_iter().flatten();
+
+            match store {
+                Some(destination) => {
+                    let list: VecDeque<Bytes> = values.map(Option::unwrap_or_default).collect();
+                    let len = list.len();
+                    if list.is_empty() {
+                        db.del(&destination);
+                    } else {
+                        db.insert(destination.clone(), Value::list(list), None);
+                        db.notify(Events::LIST, "sortstore", &destination);
+                    }
+                    Frame::Integer(len as i64)
+                }
+                None => Frame::Array(
+                    values
+                        .map(|value| value.map_or(Frame::Null, Frame::Bulk))
+                        .collect(),
+                ),
+            }
+        }
+        Command::ConfigGet { patterns } => {
+            let mut parameters = Vec::new();
+            for pattern in patterns {
+                for parameter in databases.settings().get(&pattern) {
+                    if !parameters.contains(&parameter) {
+                        parameters.push(parameter);
+                    }
+                }
+            }
+            Frame::Array(
+                parameters
+                    .into_iter()
+                    .flat_map(|(name, value)| [Frame::Bulk(name.into()), Frame::Bulk(value.into())])
+                    .collect(),
+            )
+        }
+        Command::ConfigSet { parameters } => {
+            for (name, value) in parameters {
+                if let Err(e) = databases.settings().set(&name, &value) {
+                    return Frame::Error(e);
+                }
+            }
+            Frame::Simple("OK".to_string())
+        }
+        Command::MemoryUsage { key, samples } => match db.with_entry(&key, |entry| entry.sampled_size(&key, samples)) {
+            Some(size) => Frame::Integer(size as i64),
+            None => Frame::Null,
+        },
+        Command::MemoryStats => memory::stats(databases),
+        Command::MemoryDoctor => Frame::Bulk(memory::doctor(databases).into()),
+        Command::ObjectEncoding { key } => match db.with_entry(&key, |entry| entry.encoding()) {
+            Some(encoding) => Frame::Bulk(encoding.into()),
+            None => Frame::Null,
+        },
+        Command::ObjectIdleTime { key } => match db.with_entry(&key, |entry| entry.idle_seconds()) {
+            Some(seconds) => Frame::Integer(seconds as i64),
+            None => Frame::Null,
+        },
+        Command::ObjectFreq { key } => match db.with_entry(&key, |entry| entry.frequency()) {
+            Some(_) if !databases.settings().maxmemory_policy().is_lfu() => Frame::Error(
+                "ERR An LFU maxmemory policy is not selected, access frequency not tracked. Please note that \
+                 when switching between policies at runtime LRU and LFU data will take some time to adjust."
+                    .to_string(),
+            ),
+            Some(counter) => Frame::Integer(counter as i64),
+            None => Frame::Null,
+        },
+        // values are never shared between keys
+        Command::ObjectRefCount { key } => match db.with_entry(&key, |_| ()) {
+            Some(()) => Frame::Integer(1),
+            None => Frame::Null,
+        },
+        Command::Analyze { report, count } => Frame::Bulk(analyze::analyze(&db, report, count).await.into()),
+        Command::Info { sections } => {
+            let wants = |name: &str| {
+                sections.is_empty()
+                    || sections
+                        .iter()
+                        .any(|section| matches!(section.as_str(), "all" | "default" | "everything") || section == name)
+            };
+            let mut info = Vec::new();
+            if wants("persistence") {
+                info.push(persistence::info(databases));
+            }
+            if wants("stats") {
+                info.push(databases.pub_sub().info());
+            }
+            Frame::Bulk(info.join("\r\n").into())
+        }
+        Command::PubSubChannels { pattern, shard } => {
+            let channels = databases.pub_sub().channels(shard, pattern.as_deref());
+            Frame::Array(channels.into_iter().map(Frame::Bulk).collect())
+        }
+        Command::PubSubNumSub { channels, shard } => Frame::Array(
+            channels
+                .into_iter()
+                .flat_map(|channel| {
+                    let count = databases.pub_sub().numsub(shard, &channel);
+                    [Frame::Bulk(channel), Frame::Integer(count as i64)]
+                })
+                .collect(),
+        ),
+        Command::PubSubNumPat => Frame::Integer(databases.pub_sub().numpat() as i64),
+        Command::Subscribe { .. }
+        | Command::Unsubscribe { .. }
+        | Command::PSubscribe { .. }
+        | Command::PUnsubscribe { .. }
+        | Command::SSubscribe { .. }
+        | Command::SUnsubscribe { .. }
+        | Command::Quit
+        | Command::Reset => {
+            unreachable!("connection commands are handled by the connection loop")
+        }
+    }
+}
+
+fn db_index(databases: &Databases, index: i64) -> Option<usize> {
+    usize::try_from(index).ok().filter(|index| *index < databases.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, Settings};
+    use clap::Parser;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+
+    fn databases() -> Databases {
+        let config = Config::parse_from(["rusty-redis", "--databases", "1"]);
+        Databases::new(1, Arc::new(Settings::new(&config)))
+    }
+
+    /// Serves one connection to `databases` and returns the client end.
+    async fn connect(databases: &Databases) -> Connection {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let databases = databases.clone();
+        tokio::spawn(async move {
+            let (socket, peer_addr) = listener.accept().await.unwrap();
+            handle_connection(socket, peer_addr, databases).await;
+        });
+        Connection::new(TcpStream::connect(addr).await.unwrap())
+    }
+
+    async fn request(connection: &mut Connection, args: &[impl AsRef<[u8]>]) -> Frame {
+        let frame = aof::command_frame(args.iter().map(|arg| Bytes::copy_from_slice(arg.as_ref())));
+        connection.write_frame(&frame).await.unwrap();
+        connection.read_frame().await.unwrap().expect("connection closed")
+    }
+
+    async fn run(databases: &Databases, args: &[impl AsRef<[u8]>]) -> Frame {
+        let frame = aof::command_frame(args.iter().map(|arg| Bytes::copy_from_slice(arg.as_ref())));
+        execute(cmd::from_frame(frame).unwrap(), databases, &mut Session::new()).await
+    }
+
+    #[tokio::test]
+    async fn object_reports_encoding_idle_time_and_reference_count() {
+        let databases = databases();
+        databases.db(0).set("int".into(), "12345".into(), None);
+        databases.db(0).set("raw".into(), Bytes::from(vec![b'x'; 100]), None);
+        databases.db(0).with_entry(b"int", |entry| entry.set_idle_seconds(42));
+
+        assert!(matches!(run(&databases, &["OBJECT", "ENCODING", "int"]).await, Frame::Bulk(e) if e == "int"));
+        assert!(matches!(run(&databases, &["OBJECT", "ENCODING", "raw"]).await, Frame::Bulk(e) if e == "raw"));
+        assert!(matches!(run(&databases, &["OBJECT", "IDLETIME", "int"]).await, Frame::Integer(42 | 43)));
+        assert!(matches!(run(&databases, &["OBJECT", "REFCOUNT", "int"]).await, Frame::Integer(1)));
+        for subcommand in ["ENCODING", "IDLETIME", "FREQ", "REFCOUNT"] {
+            assert!(matches!(run(&databases, &["OBJECT", subcommand, "missing"]).await, Frame::Null));
+        }
+    }
+
+    #[tokio::test]
+    async fn object_encoding_follows_the_limits_set_at_runtime() {
+        let databases = databases();
+        let encoding = async |key: &str| match run(&databases, &["OBJECT", "ENCODING", key]).await {
+            Frame::Bulk(encoding) => encoding,
+            other => panic!("unexpected reply {:?}", other),
+        };
+        let fields = (0..3).map(|n| (Bytes::from(format!("field{}", n)), Bytes::from("value"))).collect();
+        databases.db(0).insert("hash".into(), Value::hash(fields), None);
+        let members = (1..4).map(|n| Bytes::from(n.to_string())).collect();
+        databases.db(0).insert("set".into(), Value::set(members), None);
+        assert_eq!(encoding("hash").await, "listpack");
+        assert_eq!(encoding("set").await, "intset");
+
+        // values are encoded when written, so a copy restored after CONFIG SET
+        // follows the new limits while the original keeps its encoding
+        let restore = async |key: &str, copy: &str| {
+            let Frame::Bulk(payload) = run(&databases, &["DUMP", key]).await else {
+                panic!("no payload");
+            };
+            let frame = aof::command_frame(["RESTORE".into(), Bytes::copy_from_slice(copy.as_bytes()), "0".into(), payload]);
+            assert!(matches!(execute(cmd::from_frame(frame).unwrap(), &databases, &mut Session::new()).await, Frame::Simple(_)));
+        };
+        for (name, value) in [("hash-max-listpack-entries", "2"), ("set-max-intset-entries", "2")] {
+            assert!(matches!(run(&databases, &["CONFIG", "SET", name, value]).await, Frame::Simple(_)));
+        }
+        restore("hash", "hash2").await;
+        restore("set", "set2").await;
+        assert_eq!(encoding("hash").await, "listpack");
+        assert_eq!(encoding("hash2").await, "hashtable");
+        assert_eq!(encoding("set2").await, "listpack");
+
+        run(&databases, &["CONFIG", "SET", "set-max-listpack-entries", "2"]).await;
+        restore("set", "set3").await;
+        assert_eq!(encoding("set3").await, "hashtable");
+
+        run(&databases, &["CONFIG", "SET", "hash-max-listpack-entries", "128"]).await;
+        restore("hash2", "hash3").await;
+        assert_eq!(encoding("hash3").await, "listpack");
+    }
+
+    #[tokio::test]
+    async fn object_freq_needs_an_lfu_policy() {
+        let databases = databases();
+        databases.db(0).set("a".into(), "1".into(), None);
+
+        let refused = run(&databases, &["OBJECT", "FREQ", "a"]).await;
+        assert!(matches!(refused, Frame::Error(e) if e.starts_with("ERR An LFU maxmemory policy is not selected")));
+
+        databases.settings().set("maxmemory-policy", "allkeys-lfu").unwrap();
+        databases.db(0).with_entry(b"a", |entry| entry.set_frequency(20));
+        assert!(matches!(run(&databases, &["OBJECT", "FREQ", "a"]).await, Frame::Integer(20)));
+    }
+
+    #[tokio::test]
+    async fn writes_over_maxmemory_are_refused_under_noeviction() {
+        let databases = databases();
+        let mut connection = connect(&databases).await;
+        assert!(matches!(request(&mut connection, &["SET", "a", "value"]).await, Frame::Simple(ok) if ok == "OK"));
+        let maxmemory = databases.used_memory() - 1;
+        databases.settings().set("maxmemory", &maxmemory.to_string()).unwrap();
+
+        let refused = request(&mut connection, &["SET", "b", "value"]).await;
+        assert!(matches!(refused, Frame::Error(e) if e == "OOM command not allowed when used memory > 'maxmemory'."));
+        // reads and deletes still go through
+        assert!(matches!(request(&mut connection, &["GET", "a"]).await, Frame::Bulk(value) if value == "value"));
+        assert!(matches!(request(&mut connection, &["DEL", "a"]).await, Frame::Integer(1)));
+        assert!(matches!(request(&mut connection, &["SET", "b", "value"]).await, Frame::Simple(ok) if ok == "OK"));
+    }
+
+    #[tokio::test]
+    async fn binary_keys_and_channels_round_trip() {
+        // both would turn into the same string if decoded lossily
+        let (key, other): (&[u8], &[u8]) = (b"\xffkey\x00\x80", b"\xfekey\x00\x80");
+        let value: &[u8] = b"\x00\xff\xfe";
+        let source = databases();
+
+        assert!(matches!(run(&source, &[b"SET", key, value]).await, Frame::Simple(_)));
+        assert!(matches!(run(&source, &[b"SET".as_slice(), other, b"other"]).await, Frame::Simple(_)));
+        assert!(matches!(run(&source, &[b"GET", key]).await, Frame::Bulk(got) if got == value));
+        assert!(matches!(run(&source, &["DBSIZE"]).await, Frame::Integer(2)));
+
+        let Frame::Array(reply) = run(&source, &["SCAN", "0", "COUNT", "100"]).await else {
+            panic!("unexpected SCAN reply");
+        };
+        let Frame::Array(keys) = &reply[1] else {
+            panic!("unexpected SCAN keys");
+        };
+        for wanted in [key, other] {
+            assert!(keys.iter().any(|found| matches!(found, Frame::Bulk(found) if found == wanted)));
+        }
+
+        let Frame::Bulk(payload) = run(&source, &[b"DUMP", key]).await else {
+            panic!("no payload");
+        };
+        let copy: &[u8] = b"\xc3\x28copy";
+        assert!(matches!(run(&source, &[b"RESTORE".as_slice(), copy, b"0", &payload]).await, Frame::Simple(_)));
+        assert!(matches!(run(&source, &[b"GET", copy]).await, Frame::Bulk(got) if got == value));
+
+        let has_key = |databases: &Databases, key: &[u8]| databases.db(0).get(key).unwrap().is_some_and(|got| got == value);
+        let codecs = crate::codec::Codecs { compression: crate::codec::Compression::None, key: None };
+        for format in [persistence::SnapshotFormat::Native, persistence::SnapshotFormat::Rdb] {
+            let path = std::env::temp_dir().join(format!("rusty-redis-binary-{}-{}.rdb", format.name(), std::process::id()));
+            let path = path.to_str().unwrap();
+            persistence::save(&source.snapshot(), path, format, &codecs).await.unwrap();
+            let loaded = databases();
+            let result = persistence::load(path, &loaded).await;
+            std::fs::remove_file(path).unwrap();
+            assert_eq!(result.unwrap(), 3);
+            assert!(has_key(&loaded, key) && has_key(&loaded, copy));
+        }
+
+        // the rewrite base holds the dataset, then a logged SET is replayed on top
+        let dir = std::env::temp_dir().join(format!("rusty-redis-binary-aof-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut base = Vec::new();
+        aof::write_dataset(&source.snapshot(), &mut base).unwrap();
+        std::fs::write(dir.join("appendonly.aof.1.base.aof"), base).unwrap();
+        let logged: &[u8] = b"\x80logged";
+        let set = aof::command_frame([b"SET".as_slice(), logged, value].map(Bytes::copy_from_slice));
+        let mut incr = bytes::BytesMut::new();
+        for record in aof::records(&cmd::from_frame(set.clone()).unwrap(), &set) {
+            crate::connection::serialize_frame(&record, &mut incr);
+        }
+        std::fs::write(dir.join("appendonly.aof.1.incr.aof"), incr).unwrap();
+        std::fs::write(
+            dir.join("appendonly.aof.manifest"),
+            "file appendonly.aof.1.base.aof seq 1 type b\nfile appendonly.aof.1.incr.aof seq 1 type i\n",
+        )
+        .unwrap();
+        let config = Config::parse_from(["rusty-redis", "--databases", "1", "--appenddirname", dir.to_str().unwrap()]);
+        let settings = Arc::new(Settings::new(&config));
+        let replayed = Databases::new(1, Arc::clone(&settings));
+        let result = aof::load(&settings, &replayed).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        result.unwrap();
+        assert!(has_key(&replayed, key) && has_key(&replayed, copy) && has_key(&replayed, logged));
+        assert!(replayed.db(0).get(other).unwrap().is_some_and(|got| got == "other"));
+
+        let channel: &[u8] = b"news\xff\x00";
+        let mut subscriber = connect(&source).await;
+        let Frame::Array(subscribed) = request(&mut subscriber, &[b"SUBSCRIBE", channel]).await else {
+            panic!("unexpected SUBSCRIBE reply");
+        };
+        assert!(matches!(&subscribed[1], Frame::Bulk(name) if name == channel));
+        assert!(matches!(run(&source, &[b"PUBLISH", channel, value]).await, Frame::Integer(1)));
+        assert!(matches!(run(&source, &[b"PUBLISH".as_slice(), b"news\xfe\x00", value]).await, Frame::Integer(0)));
+        let Some(Frame::Array(message)) = subscriber.read_frame().await.unwrap() else {
+            panic!("no message");
+        };
+        assert!(matches!(&message[1], Frame::Bulk(name) if name == channel));
+        assert!(matches!(&message[2], Frame::Bulk(got) if got == value));
+    }
+
+    async fn next(connection: &mut Connection) -> Frame {
+        connection.read_frame().await.unwrap().expect("connection closed")
+    }
+
+    /// The kind, name and running count of a (un)subscribe confirmation.
+    fn confirmation(frame: Frame) -> (Bytes, Option<Bytes>, i64) {
+        let Frame::Array(parts) = frame else {
+            panic!("not a confirmation: {:?}", frame);
+        };
+        match &parts[..] {
+            [Frame::Bulk(kind), Frame::Bulk(name), Frame::Integer(count)] => (kind.clone(), Some(name.clone()), *count),
+            [Frame::Bulk(kind), Frame::Null, Frame::Integer(count)] => (kind.clone(), None, *count),
+            _ => panic!("not a confirmation: {:?}", parts),
+        }
+    }
+
+    #[tokio::test]
+    async fn subscribed_connections_only_take_pub_sub_commands() {
+        let databases = databases();
+        let mut connection = connect(&databases).await;
+
+        let first = request(&mut connection, &["SUBSCRIBE", "a", "b", "c"]).await;
+        let confirmations = [first, next(&mut connection).await, next(&mut connection).await].map(confirmation);
+        for ((channel, expected), (kind, name, count)) in [("a", 1), ("b", 2), ("c", 3)].into_iter().zip(confirmations) {
+            assert_eq!(kind, "subscribe");
+            assert_eq!(name.unwrap(), channel);
+            assert_eq!(count, expected);
+        }
+        // patterns count towards the same total
+        let (_, _, count) = confirmation(request(&mut connection, &["PSUBSCRIBE", "news.*"]).await);
+        assert_eq!(count, 4);
+
+        let refused = request(&mut connection, &["GET", "a"]).await;
+        assert!(matches!(refused, Frame::Error(e) if e.starts_with("ERR Can't execute 'get': only (P|S)SUBSCRIBE")));
+        let Frame::Array(pong) = request(&mut connection, &["PING", "hello"]).await else {
+            panic!("PING while subscribed replies with an array");
+        };
+        assert!(matches!(&pong[..], [Frame::Bulk(kind), Frame::Bulk(message)] if kind == "pong" && message == "hello"));
+        assert!(matches!(run(&databases, &["PUBLISH", "b", "hi"]).await, Frame::Integer(1)));
+        let Frame::Array(message) = next(&mut connection).await else {
+            panic!("no message");
+        };
+        assert!(matches!(&message[..], [Frame::Bulk(kind), Frame::Bulk(channel), Frame::Bulk(text)]
+            if kind == "message" && channel == "b" && text == "hi"));
+
+        // without arguments every channel goes, in any order, but not the pattern
+        let first = request(&mut connection, &["UNSUBSCRIBE"]).await;
+        let mut left = [first, next(&mut connection).await, next(&mut connection).await].map(confirmation);
+        left.sort_by_key(|(_, _, count)| -count);
+        let names: Vec<_> = left.iter().map(|(_, name, _)| name.clone().unwrap()).collect();
+        assert!(["a", "b", "c"].iter().all(|channel| names.iter().any(|name| name == channel)));
+        assert_eq!(left.map(|(_, _, count)| count), [3, 2, 1]);
+        let refused = request(&mut connection, &["SET", "a", "1"]).await;
+        assert!(matches!(refused, Frame::Error(e) if e.starts_with("ERR Can't execute 'set'")));
+        assert_eq!(confirmation(request(&mut connection, &["PUNSUBSCRIBE"]).await).2, 0);
+        assert_eq!(confirmation(request(&mut connection, &["UNSUBSCRIBE"]).await), ("unsubscribe".into(), None, 0));
+        assert!(matches!(request(&mut connection, &["GET", "a"]).await, Frame::Null));
+
+        // RESET leaves subscribed mode and drops the subscriptions
+        request(&mut connection, &["SUBSCRIBE", "a"]).await;
+        assert!(matches!(request(&mut connection, &["RESET"]).await, Frame::Simple(reset) if reset == "RESET"));
+        assert!(matches!(run(&databases, &["PUBLISH", "a", "hi"]).await, Frame::Integer(0)));
+        assert!(matches!(request(&mut connection, &["PING"]).await, Frame::Simple(pong) if pong == "PONG"));
+
+        request(&mut connection, &["SUBSCRIBE", "a"]).await;
+        assert!(matches!(request(&mut connection, &["QUIT"]).await, Frame::Simple(ok) if ok == "OK"));
+        assert!(connection.read_frame().await.unwrap().is_none());
+    }
+}
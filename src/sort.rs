@@ -0,0 +1,276 @@
+use bytes::Bytes;
+use std::cmp::Ordering;
+
+use crate::db::Db;
+use crate::value::{Value, WrongType};
+
+#[derive(Debug, Default)]
+pub struct SortOptions {
+    pub by: Option<Bytes>,
+    pub limit: Option<(i64, i64)>,
+    pub get: Vec<Bytes>,
+    pub descending: bool,
+    pub alpha: bool,
+}
+
+#[derive(Debug)]
+pub enum SortError {
+    WrongType,
+    NotANumber,
+}
+
+impl std::fmt::Display for SortError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SortError::WrongType => write!(f, "{}", WrongType),
+            SortError::NotANumber => write!(f, "ERR One or more scores can't be converted into double"),
+        }
+    }
+}
+
+impl std::error::Error for SortError {}
+
+/// Sorts the elements of the list, set or sorted set at `key`.
+///
+/// Each returned row holds one value per GET pattern, or just the element
+/// itself when no GET pattern was given; missing lookups are `None`.
+pub fn sort(db: &Db, key: &[u8], options: &SortOptions) -> Result<Vec<Vec<Option<Bytes>>>, SortError> {
+    let elements = db
+        .with_value(key, |value| match value {
+            Value::List(list) => Ok(list.iter().collect()),
+            Value::Set(set) => Ok(set.iter().collect()),
+            Value::ZSet(zset) => {
+                let mut ordered: Vec<(Bytes, f64)> = zset.iter().collect();
+                ordered.sort_by(|a, b| a.1.total_cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+                Ok(ordered.into_iter().map(|(member, _)| member).collect())
+            }
+            _ => Err(SortError::WrongType),
+        })
+        .transpose()?
+        .unwrap_or_else(Vec::<Bytes>::new);
+
+    // a BY pattern without `*` can never reference per-element keys, so redis
+    // treats it as a request to skip sorting altogether
+    let dont_sort = options.by.as_ref().is_some_and(|by| !by.contains(&b'*'));
+
+    let mut rows: Vec<(Bytes, Option<Bytes>)> = elements
+        .into_iter()
+        .map(|element| {
+            let weight = match &options.by {
+                Some(by) if !dont_sort => lookup(db, by, &element),
+                _ => Some(element.clone()),
+            };
+            (element, weight)
+        })
+        .collect();
+
+    if !dont_sort {
+        if options.alpha {
+            rows.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+        } else {
+            let mut scored = Vec::with_capacity(rows.len());
+            for (element, weight) in rows {
+                let score = match &weight {
+                    Some(weight) => parse_score(weight).ok_or(SortError::NotANumber)?,
+                    None => 0.0,
+                };
+                scored.push((element, score));
+            }
+            scored.sort_by(|a, b| match a.1.partial_cmp(&b.1) {
+                Some(Ordering::Equal) | None => a.0.cmp(&b.0),
+                Some(ordering) => ordering,
+            });
+            rows = scored.into_iter().map(|(element, _)| (element, None)).collect();
+        }
+
+        if options.descending {
+            rows.reverse();
+        }
+    }
+
+    let (start, count) = match options.limit {
+        Some((offset, count)) => {
+            let start = offset.clamp(0, rows.len() as i64) as usize;
+            let count = if count < 0 { rows.len() } else { count as usize };
+            (start, count)
+        }
+        None => (0, rows.len()),
+    };
+
+    let selected = rows.into_iter().skip(start).take(count).map(|(element, _)| element);
+
+    Ok(selected
+        .map(|element| {
+            if options.get.is_empty() {
+                vec![Some(element)]
+            } else {
+                options.get.iter().map(|pattern| lookup(db, pattern, &element)).collect()
+            }
+        })
+        .collect())
+}
+
+/// Resolves a BY/GET pattern for one element.
+///
+/// The first `*` is replaced with the element; a `->field` suffix reads a
+/// hash field instead of a string. `#` returns the element itself.
+fn lookup(db: &Db, pattern: &[u8], element: &Bytes) -> Option<Bytes> {
+    if pattern == b"#" {
+        return Some(element.clone());
+    }
+
+    let star = pattern.iter().position(|b| *b == b'*')?;
+
+    // as in redis, the field starts after the first `->` following the `*`,
+    // so a field name may itself contain `->`
+    let arrow = pattern[star + 1..]
+        .windows(2)
+        .position(|w| w == b"->")
+        .map(|arrow| star + 1 + arrow)
+        .filter(|arrow| arrow + 2 < pattern.len());
+
+    let key_pattern = match arrow {
+        Some(arrow) => &pattern[..arrow],
+        None => pattern,
+    };
+
+    let mut key = Vec::with_capacity(key_pattern.len() + element.len());
+    key.extend_from_slice(&key_pattern[..star]);
+    key.extend_from_slice(element);
+    key.extend_from_slice(&key_pattern[star + 1..]);
+
+    db.with_value(&key, |value| match (value, arrow) {
+        (Value::String(string), None) => Some(string.to_bytes()),
+        (Value::Hash(hash), Some(arrow)) => hash.get(&pattern[arrow + 2..]),
+        _ => None,
+    })
+    .flatten()
+}
+
+fn parse_score(bytes: &[u8]) -> Option<f64> {
+    let score: f64 = std::str::from_utf8(bytes).ok()?.parse().ok()?;
+    (!score.is_nan()).then_some(score)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, Settings};
+    use crate::frame::Frame;
+    use crate::db::Databases;
+    use crate::server::{self, Session};
+    use crate::{aof, cmd};
+    use clap::Parser;
+    use std::collections::{HashMap, VecDeque};
+    use std::sync::Arc;
+
+    fn databases() -> Databases {
+        let config = Config::parse_from(["rusty-redis", "--databases", "1"]);
+        Databases::new(1, Arc::new(Settings::new(&config)))
+    }
+
+    fn push(db: &Db, key: &str, items: &[&str]) {
+        let list: VecDeque<Bytes> = items.iter().map(|item| Bytes::copy_from_slice(item.as_bytes())).collect();
+        db.insert(Bytes::copy_from_slice(key.as_bytes()), Value::list(list), None);
+    }
+
+    fn sorted(db: &Db, options: SortOptions) -> Vec<Option<Bytes>> {
+        sort(db, b"list", &options).unwrap().into_iter().flatten().collect()
+    }
+
+    fn bytes(items: &[&str]) -> Vec<Option<Bytes>> {
+        items.iter().map(|item| Some(Bytes::copy_from_slice(item.as_bytes()))).collect()
+    }
+
+    #[tokio::test]
+    async fn numbers_sort_by_value_and_alpha_by_bytes() {
+        let databases = databases();
+        let db = databases.db(0);
+        push(&db, "list", &["10", "9", "2.5", "-1"]);
+
+        assert_eq!(sorted(&db, SortOptions::default()), bytes(&["-1", "2.5", "9", "10"]));
+        let alpha = SortOptions { alpha: true, ..Default::default() };
+        assert_eq!(sorted(&db, alpha), bytes(&["-1", "10", "2.5", "9"]));
+    }
+
+    #[tokio::test]
+    async fn descending_and_limit() {
+        let databases = databases();
+        let db = databases.db(0);
+        push(&db, "list", &["3", "1", "2", "5", "4"]);
+
+        let options = SortOptions { descending: true, limit: Some((1, 2)), ..Default::default() };
+        assert_eq!(sorted(&db, options), bytes(&["4", "3"]));
+        let options = SortOptions { limit: Some((3, -1)), ..Default::default() };
+        assert_eq!(sorted(&db, options), bytes(&["4", "5"]));
+        let options = SortOptions { limit: Some((10, 5)), ..Default::default() };
+        assert_eq!(sorted(&db, options), bytes(&[]));
+    }
+
+    #[tokio::test]
+    async fn by_sorts_on_other_keys_unless_the_pattern_has_no_star() {
+        let databases = databases();
+        let db = databases.db(0);
+        push(&db, "list", &["a", "b", "c"]);
+        db.set("weight_a".into(), "3".into(), None);
+        db.set("weight_b".into(), "1".into(), None);
+        db.set("weight_c".into(), "2".into(), None);
+
+        let options = SortOptions { by: Some("weight_*".into()), ..Default::default() };
+        assert_eq!(sorted(&db, options), bytes(&["b", "c", "a"]));
+        // nothing to look up, so the elements keep their order, even descending
+        let options = SortOptions { by: Some("nosort".into()), descending: true, ..Default::default() };
+        assert_eq!(sorted(&db, options), bytes(&["a", "b", "c"]));
+    }
+
+    #[tokio::test]
+    async fn get_returns_the_element_and_hash_fields() {
+        let databases = databases();
+        let db = databases.db(0);
+        push(&db, "list", &["2", "1"]);
+        let fields = HashMap::from([(Bytes::from("name"), Bytes::from("one")), (Bytes::from("x->y"), Bytes::from("arrow"))]);
+        db.insert("user_1".into(), Value::hash(fields), None);
+
+        let options = SortOptions {
+            get: vec!["#".into(), "user_*->name".into(), "user_*->x->y".into(), "missing_*".into()],
+            ..Default::default()
+        };
+        let rows = sort(&db, b"list", &options).unwrap();
+        assert_eq!(rows[0], vec![Some("1".into()), Some("one".into()), Some("arrow".into()), None]);
+        assert_eq!(rows[1], vec![Some("2".into()), None, None, None]);
+    }
+
+    #[tokio::test]
+    async fn values_that_are_not_numbers_fail_unless_alpha() {
+        let databases = databases();
+        let db = databases.db(0);
+        push(&db, "list", &["1", "two"]);
+
+        let err = sort(&db, b"list", &SortOptions::default()).unwrap_err();
+        assert_eq!(err.to_string(), "ERR One or more scores can't be converted into double");
+        assert!(sort(&db, b"list", &SortOptions { alpha: true, ..Default::default() }).is_ok());
+    }
+
+    #[tokio::test]
+    async fn store_replaces_the_destination_with_a_list() {
+        let databases = databases();
+        push(&databases.db(0), "list", &["3", "1", "2"]);
+        databases.db(0).set("dest".into(), "old".into(), None);
+
+        let mut session = Session::new();
+        let mut run = async |args: &[&str]| {
+            let frame = aof::command_frame(args.iter().map(|arg| Bytes::copy_from_slice(arg.as_bytes())));
+            server::execute(cmd::from_frame(frame).unwrap(), &databases, &mut session).await
+        };
+
+        assert!(matches!(run(&["SORT", "list", "STORE", "dest"]).await, Frame::Integer(3)));
+        let stored = databases.db(0).with_value(b"dest", |value| match value {
+            Value::List(list) => list.iter().collect::<Vec<_>>(),
+            _ => panic!("not a list"),
+        });
+        assert_eq!(stored, Some(vec![Bytes::from("1"), Bytes::from("2"), Bytes::from("3")]));
+
+        assert!(matches!(run(&["SORT", "empty", "STORE", "dest"]).await, Frame::Integer(0)));
+        assert!(databases.db(0).with_value(b"dest", |_| ()).is_none());
+    }
+}
@@ -0,0 +1,505 @@
+use bytes::Bytes;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::encoding::{Intset, Listpack};
+
+/// A value stored under a key.
+///
+/// Each type has a compact encoding for small values and a full one for
+/// large values; `encode` picks between them using the configured limits.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    String(Str),
+    List(List),
+    Set(Set),
+    ZSet(ZSet),
+    Hash(Hash),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Str {
+    /// a string that is the canonical decimal form of an integer
+    Int(i64),
+    Raw(Bytes),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum List {
+    Packed(Listpack),
+    Full(VecDeque<Bytes>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Set {
+    Intset(Intset),
+    Packed(Listpack),
+    Full(HashSet<Bytes>),
+}
+
+/// Packed sorted sets store each member followed by its score.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ZSet {
+    Packed(Listpack),
+    Full(HashMap<Bytes, f64>),
+}
+
+/// Packed hashes store each field followed by its value.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Hash {
+    Packed(Listpack),
+    Full(HashMap<Bytes, Bytes>),
+}
+
+/// Size limits under which values use their compact encoding.
+#[derive(Clone, Copy, Debug)]
+pub struct EncodingLimits {
+    pub hash_max_listpack_entries: usize,
+    pub hash_max_listpack_value: usize,
+    /// positive: maximum number of elements; -1 to -5: maximum size of 4kb to 64kb
+    pub list_max_listpack_size: i64,
+    pub set_max_intset_entries: usize,
+    pub set_max_listpack_entries: usize,
+    pub set_max_listpack_value: usize,
+    pub zset_max_listpack_entries: usize,
+    pub zset_max_listpack_value: usize,
+}
+
+impl Value {
+    pub fn string(bytes: Bytes) -> Value {
+        match canonical_integer(&bytes) {
+            Some(n) => Value::String(Str::Int(n)),
+            None => Value::String(Str::Raw(bytes)),
+        }
+    }
+
+    pub fn list(items: VecDeque<Bytes>) -> Value {
+        Value::List(List::Full(items))
+    }
+
+    pub fn set(members: HashSet<Bytes>) -> Value {
+        Value::Set(Set::Full(members))
+    }
+
+    pub fn zset(members: HashMap<Bytes, f64>) -> Value {
+        Value::ZSet(ZSet::Full(members))
+    }
+
+    pub fn hash(fields: HashMap<Bytes, Bytes>) -> Value {
+        Value::Hash(Hash::Full(fields))
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Set(_) => "set",
+            Value::ZSet(_) => "zset",
+            Value::Hash(_) => "hash",
+        }
+    }
+
+    /// Number of elements in a collection, or bytes in a string.
+    pub fn element_count(&self) -> usize {
+        match self {
+            Value::String(string) => string.len(),
+            Value::List(list) => list.len(),
+            Value::Set(set) => set.len(),
+            Value::ZSet(zset) => zset.len(),
+            Value::Hash(hash) => hash.len(),
+        }
+    }
+
+    /// The internal representation, as reported by OBJECT ENCODING.
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Value::String(Str::Int(_)) => "int",
+            Value::String(Str::Raw(bytes)) if bytes.len() <= EMBSTR_MAX_LEN => "embstr",
+            Value::String(Str::Raw(_)) => "raw",
+            Value::List(List::Packed(_)) => "listpack",
+            Value::List(List::Full(_)) => "quicklist",
+            Value::Set(Set::Intset(_)) => "intset",
+            Value::Set(Set::Packed(_)) => "listpack",
+            Value::Set(Set::Full(_)) => "hashtable",
+            Value::ZSet(ZSet::Packed(_)) => "listpack",
+            Value::ZSet(ZSet::Full(_)) => "skiplist",
+            Value::Hash(Hash::Packed(_)) => "listpack",
+            Value::Hash(Hash::Full(_)) => "hashtable",
+        }
+    }
+
+    /// Converts the value to the compact encoding if it fits within `limits`,
+    /// or to the full encoding otherwise.
+    pub fn encode(self, limits: &EncodingLimits) -> Value {
+        match self {
+            Value::String(_) => self,
+            Value::List(list) => {
+                let packed = match limits.list_max_listpack_size {
+                    max if max > 0 => list.len() <= max as usize,
+                    max => {
+                        let max_bytes = 4096usize << (max.clamp(-5, -1).unsigned_abs() - 1);
+                        list.iter().map(|item| item.len() + 2).sum::<usize>() <= max_bytes
+                    }
+                };
+                Value::List(match (list, packed) {
+                    (List::Full(items), true) => List::Packed(Listpack::from_entries(items)),
+                    (List::Packed(packed), false) => List::Full(packed.entries().into()),
+                    (list, _) => list,
+                })
+            }
+            Value::Set(set) => {
+                let intset = set.len() <= limits.set_max_intset_entries
+                    && set.iter().all(|member| canonical_integer(&member).is_some());
+                let listpack = !intset
+                    && set.len() <= limits.set_max_listpack_entries
+                    && set.iter().all(|member| member.len() <= limits.set_max_listpack_value);
+
+                Value::Set(match set {
+                    Set::Intset(_) if intset => set,
+                    Set::Packed(_) if listpack => set,
+                    Set::Full(_) if !intset && !listpack => set,
+                    set if intset => {
+                        Set::Intset(Intset::from_values(set.iter().filter_map(|member| canonical_integer(&member))))
+                    }
+                    set if listpack => Set::Packed(Listpack::from_entries(set.iter())),
+                    set => Set::Full(set.iter().collect()),
+                })
+            }
+            Value::ZSet(zset) => {
+                let fits_listpack = zset.len() <= limits.zset_max_listpack_entries
+                    && zset.iter().all(|(member, _)| member.len() <= limits.zset_max_listpack_value);
+
+                Value::ZSet(match (zset, fits_listpack) {
+                    (ZSet::Full(members), true) => {
+                        let mut ordered: Vec<(Bytes, f64)> = members.into_iter().collect();
+                        ordered.sort_by(|a, b| a.1.total_cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+                        let entries = ordered
+                            .into_iter()
+                            .flat_map(|(member, score)| [member, Bytes::from(format_score(score))]);
+                        ZSet::Packed(Listpack::from_entries(entries))
+                    }
+                    (ZSet::Packed(packed), false) => ZSet::Full(ZSet::Packed(packed).iter().collect()),
+                    (zset, _) => zset,
+                })
+            }
+            Value::Hash(hash) => {
+                let fits_listpack = hash.len() <= limits.hash_max_listpack_entries
+                    && hash.iter().all(|(field, value)| {
+                        field.len() <= limits.hash_max_listpack_value && value.len() <= limits.hash_max_listpack_value
+                    });
+
+                Value::Hash(match (hash, fits_listpack) {
+                    (Hash::Full(fields), true) => {
+                        Hash::Packed(Listpack::from_entries(fields.into_iter().flat_map(|(f, v)| [f, v])))
+                    }
+                    (Hash::Packed(packed), false) => Hash::Full(packed.pairs().collect()),
+                    (hash, _) => hash,
+                })
+            }
+        }
+    }
+
+    /// Approximate heap footprint of the value, including per-element overhead.
+    pub fn memory_usage(&self) -> usize {
+        self.sampled_memory_usage(0)
+    }
+
+    /// Like `memory_usage`, but for full collections only measures the first
+    /// `samples` elements and extrapolates; 0 measures every element.
+    pub fn sampled_memory_usage(&self, samples: usize) -> usize {
+        const BYTES_SIZE: usize = std::mem::size_of::<Bytes>();
+        // hashed collections pay roughly one pointer-sized control word per slot
+        const HASH_SLOT: usize = 8;
+
+        match self {
+            Value::String(Str::Int(_)) => 0,
+            Value::String(Str::Raw(bytes)) => bytes.len(),
+            Value::List(List::Packed(packed))
+            | Value::Set(Set::Packed(packed))
+            | Value::ZSet(ZSet::Packed(packed))
+            | Value::Hash(Hash::Packed(packed)) => packed.bytes_len(),
+            Value::Set(Set::Intset(intset)) => intset.bytes_len(),
+            Value::List(List::Full(items)) => {
+                estimate(items.len(), samples, items.iter().map(|item| BYTES_SIZE + item.len()))
+            }
+            Value::Set(Set::Full(members)) => estimate(
+                members.len(),
+                samples,
+                members.iter().map(|member| BYTES_SIZE + HASH_SLOT + member.len()),
+            ),
+            Value::ZSet(ZSet::Full(members)) => estimate(
+                members.len(),
+                samples,
+                members.keys().map(|member| BYTES_SIZE + HASH_SLOT + 8 + member.len()),
+            ),
+            Value::Hash(Hash::Full(fields)) => estimate(
+                fields.len(),
+                samples,
+                fields.iter().map(|(field, value)| 2 * BYTES_SIZE + HASH_SLOT + field.len() + value.len()),
+            ),
+        }
+    }
+}
+
+impl Str {
+    pub fn to_bytes(&self) -> Bytes {
+        match self {
+            Str::Int(n) => Bytes::from(n.to_string()),
+            Str::Raw(bytes) => bytes.clone(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Str::Int(n) => n.to_string().len(),
+            Str::Raw(bytes) => bytes.len(),
+        }
+    }
+}
+
+impl List {
+    pub fn len(&self) -> usize {
+        match self {
+            List::Packed(packed) => packed.len(),
+            List::Full(items) => items.len(),
+        }
+    }
+
+    pub fn iter(&self) -> Box<dyn Iterator<Item = Bytes> + '_> {
+        match self {
+            List::Packed(packed) => Box::new(packed.entries().into_iter()),
+            List::Full(items) => Box::new(items.iter().cloned()),
+        }
+    }
+}
+
+impl Set {
+    pub fn len(&self) -> usize {
+        match self {
+            Set::Intset(intset) => intset.len(),
+            Set::Packed(packed) => packed.len(),
+            Set::Full(members) => members.len(),
+        }
+    }
+
+    pub fn iter(&self) -> Box<dyn Iterator<Item = Bytes> + '_> {
+        match self {
+            Set::Intset(intset) => Box::new(intset.iter().map(|n| Bytes::from(n.to_string()))),
+            Set::Packed(packed) => Box::new(packed.entries().into_iter()),
+            Set::Full(members) => Box::new(members.iter().cloned()),
+        }
+    }
+}
+
+impl ZSet {
+    pub fn len(&self) -> usize {
+        match self {
+            ZSet::Packed(packed) => packed.len() / 2,
+            ZSet::Full(members) => members.len(),
+        }
+    }
+
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (Bytes, f64)> + '_> {
+        match self {
+            ZSet::Packed(packed) => Box::new(packed.pairs().map(|(member, score)| {
+                let score = crate::rdb::parse_double(&score).expect("packed scores are written by format_score");
+                (member, score)
+            })),
+            ZSet::Full(members) => Box::new(members.iter().map(|(member, score)| (member.clone(), *score))),
+        }
+    }
+}
+
+impl Hash {
+    pub fn len(&self) -> usize {
+        match self {
+            Hash::Packed(packed) => packed.len() / 2,
+            Hash::Full(fields) => fields.len(),
+        }
+    }
+
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (Bytes, Bytes)> + '_> {
+        match self {
+            Hash::Packed(packed) => Box::new(packed.pairs()),
+            Hash::Full(fields) => Box::new(fields.iter().map(|(field, value)| (field.clone(), value.clone()))),
+        }
+    }
+
+    pub fn get(&self, field: &[u8]) -> Option<Bytes> {
+        match self {
+            Hash::Packed(packed) => packed.pairs().find(|(f, _)| f == field).map(|(_, value)| value),
+            Hash::Full(fields) => fields.get(field).cloned(),
+        }
+    }
+}
+
+/// Longest string redis stores in the same allocation as its object header.
+const EMBSTR_MAX_LEN: usize = 44;
+
+/// Formats a score the way redis stores it in packed sorted sets.
+fn format_score(score: f64) -> String {
+    // -0 goes through the float formatting, which keeps its sign
+    if score.fract() == 0.0 && score.abs() < 1e17 && !(score == 0.0 && score.is_sign_negative()) {
+        format!("{}", score as i64)
+    } else {
+        format!("{}", score)
+    }
+}
+
+fn estimate(len: usize, samples: usize, sizes: impl Iterator<Item = usize>) -> usize {
+    if samples == 0 || samples >= len {
+        return sizes.sum();
+    }
+    let sampled: usize = sizes.take(samples).sum();
+    sampled * len / samples
+}
+
+/// Parses `bytes` as an integer only if formatting it back yields the same bytes.
+pub fn canonical_integer(bytes: &[u8]) -> Option<i64> {
+    if bytes.is_empty() || bytes.len() > 20 {
+        return None;
+    }
+    let n: i64 = std::str::from_utf8(bytes).ok()?.parse().ok()?;
+    (n.to_string().as_bytes() == bytes).then_some(n)
+}
+
+/// Error returned when a command is run against a key holding another type.
+#[derive(Debug)]
+pub struct WrongType;
+
+impl std::fmt::Display for WrongType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "WRONGTYPE Operation against a key holding the wrong kind of value")
+    }
+}
+
+impl std::error::Error for WrongType {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(entries: usize, value: usize) -> EncodingLimits {
+        EncodingLimits {
+            hash_max_listpack_entries: entries,
+            hash_max_listpack_value: value,
+            list_max_listpack_size: entries as i64,
+            set_max_intset_entries: entries,
+            set_max_listpack_entries: entries,
+            set_max_listpack_value: value,
+            zset_max_listpack_entries: entries,
+            zset_max_listpack_value: value,
+        }
+    }
+
+    fn bytes(items: &[&str]) -> Vec<Bytes> {
+        items.iter().map(|item| Bytes::copy_from_slice(item.as_bytes())).collect()
+    }
+
+    /// Everything a value holds, in a form that does not depend on its encoding.
+    fn contents(value: &Value) -> Vec<String> {
+        let mut contents: Vec<String> = match value {
+            Value::String(string) => vec![format!("{:?}", string.to_bytes())],
+            Value::List(list) => return list.iter().map(|item| format!("{:?}", item)).collect(),
+            Value::Set(set) => set.iter().map(|member| format!("{:?}", member)).collect(),
+            Value::ZSet(zset) => zset.iter().map(|(member, score)| format!("{:?} {:?}", member, score)).collect(),
+            Value::Hash(hash) => hash.iter().map(|(field, value)| format!("{:?} {:?}", field, value)).collect(),
+        };
+        contents.sort();
+        contents
+    }
+
+    /// Encodes `value` with each of `steps` in turn, checking the encoding it
+    /// ends up in and that nothing it holds is lost on the way.
+    fn convert(value: Value, steps: &[(EncodingLimits, &str)]) {
+        let expected = contents(&value);
+        let mut value = value;
+        for (limits, encoding) in steps {
+            value = value.encode(limits);
+            assert_eq!(value.encoding(), *encoding);
+            assert_eq!(contents(&value), expected);
+        }
+    }
+
+    #[test]
+    fn lists_convert_at_list_max_listpack_size() {
+        let list = Value::list(bytes(&["a", "b", "c", "d"]).into());
+        convert(list, &[(limits(4, 64), "listpack"), (limits(3, 64), "quicklist"), (limits(4, 64), "listpack")]);
+
+        // -1 allows 4kb, counting two bytes of overhead per item
+        let mut by_size = limits(4, 64);
+        by_size.list_max_listpack_size = -1;
+        let mut larger = by_size;
+        larger.list_max_listpack_size = -2;
+        let items: VecDeque<Bytes> = (0..2).map(|_| Bytes::from(vec![b'x'; 2046])).collect();
+        convert(Value::list(items), &[(by_size, "listpack"), (limits(1, 64), "quicklist"), (by_size, "listpack")]);
+        let items: VecDeque<Bytes> = (0..2).map(|_| Bytes::from(vec![b'x'; 2047])).collect();
+        convert(Value::list(items), &[(by_size, "quicklist"), (larger, "listpack"), (by_size, "quicklist")]);
+    }
+
+    #[test]
+    fn sets_convert_at_the_intset_and_listpack_limits() {
+        let integers = Value::set(bytes(&["1", "2", "3", "-4"]).into_iter().collect());
+        let mut fewer_integers = limits(4, 64);
+        fewer_integers.set_max_intset_entries = 3;
+        convert(
+            integers,
+            &[
+                (limits(4, 64), "intset"),
+                (fewer_integers, "listpack"),
+                (limits(3, 64), "hashtable"),
+                (fewer_integers, "listpack"),
+                (limits(4, 64), "intset"),
+            ],
+        );
+
+        // "01" is not the canonical form of an integer
+        let strings = Value::set(bytes(&["01", "eight-ch"]).into_iter().collect());
+        convert(
+            strings,
+            &[(limits(2, 8), "listpack"), (limits(2, 7), "hashtable"), (limits(1, 8), "hashtable"), (limits(2, 8), "listpack")],
+        );
+    }
+
+    #[test]
+    fn sorted_sets_convert_at_the_listpack_limits() {
+        let members = HashMap::from([
+            (Bytes::from("a"), 1.5),
+            (Bytes::from("b"), -2.0),
+            (Bytes::from("eight-ch"), 0.0),
+            (Bytes::from("d"), f64::INFINITY),
+        ]);
+        convert(
+            Value::zset(members),
+            &[(limits(4, 8), "listpack"), (limits(3, 8), "skiplist"), (limits(4, 8), "listpack"), (limits(4, 7), "skiplist")],
+        );
+    }
+
+    #[test]
+    fn hashes_convert_at_the_listpack_limits() {
+        let fields = HashMap::from([
+            (Bytes::from("a"), Bytes::from("1")),
+            (Bytes::from("b"), Bytes::from("eight-ch")),
+            (Bytes::from("eight-ch"), Bytes::from("3")),
+        ]);
+        convert(
+            Value::hash(fields),
+            &[(limits(3, 8), "listpack"), (limits(2, 8), "hashtable"), (limits(3, 8), "listpack"), (limits(3, 7), "hashtable")],
+        );
+    }
+
+    #[test]
+    fn packed_scores_keep_the_sign_of_zero() {
+        assert_eq!(format_score(-0.0), "-0");
+        assert_eq!(format_score(0.0), "0");
+        assert_eq!(format_score(-3.0), "-3");
+        assert_eq!(format_score(2.5), "2.5");
+
+        let packed = Value::zset(HashMap::from([(Bytes::from("zero"), -0.0)])).encode(&limits(4, 64));
+        let Value::ZSet(zset) = &packed else {
+            panic!("not a sorted set");
+        };
+        assert_eq!(packed.encoding(), "listpack");
+        let (_, score) = zset.iter().next().unwrap();
+        assert!(score == 0.0 && score.is_sign_negative());
+    }
+}